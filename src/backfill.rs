@@ -0,0 +1,229 @@
+//! Replays past transactions through the recorder, to fill in the
+//! records missed while it was down.
+
+use crate::{
+    config::{check, opt_str},
+    db,
+    error::Error,
+    limiter::Budget,
+    recorder::fetch_logs,
+    AppState,
+};
+use anchor_client::solana_sdk::{
+    commitment_config::CommitmentConfig, signature::Signature,
+};
+use serde::{Deserialize, Serialize};
+use solana_client::rpc_client::GetConfirmedSignaturesForAddress2Config;
+use std::{str::FromStr, sync::Arc, time::Duration};
+use tokio::sync::Semaphore;
+use tracing::{info, warn, Instrument};
+
+/// The maximum number of signatures `getSignaturesForAddress`
+/// returns at once.
+const PAGE_SIZE: usize = 1000;
+
+const FETCH_RETRIES: u32 = 3;
+const FETCH_RETRY_WAIT: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct BackfillConfig {
+    /// URL of the store, see `db::connect` for the supported schemes.
+    pub store: String,
+    /// Oldest slot to backfill, inclusive.
+    pub from_slot: Option<u64>,
+    /// Newest slot to backfill, inclusive.
+    pub to_slot: Option<u64>,
+    /// Start with the transactions before this signature.
+    #[serde(with = "opt_str")]
+    pub before: Option<Signature>,
+    /// Stop once this signature is reached.
+    #[serde(with = "opt_str")]
+    pub until: Option<Signature>,
+    /// Maximum number of transactions fetched at once.
+    pub concurrency: usize,
+    /// Ignore the persisted cursor and start over.
+    pub restart: bool,
+}
+
+impl Default for BackfillConfig {
+    fn default() -> Self {
+        Self {
+            store: String::new(),
+            from_slot: None,
+            to_slot: None,
+            before: None,
+            until: None,
+            concurrency: 8,
+            restart: false,
+        }
+    }
+}
+
+impl BackfillConfig {
+    pub fn validate(&self) -> Result<(), Error> {
+        check(!self.store.is_empty(), "a store URL is required")?;
+        check(self.concurrency > 0, "concurrency must be positive")?;
+        check(
+            !matches!(
+                (self.from_slot, self.to_slot),
+                (Some(from), Some(to)) if from > to
+            ),
+            "from slot must not be after to slot",
+        )
+    }
+
+    /// Name of the persisted cursor. Backfills over different
+    /// ranges keep separate cursors.
+    fn cursor_name(&self) -> String {
+        fn opt<T: ToString>(x: &Option<T>) -> String {
+            x.as_ref().map(ToString::to_string).unwrap_or_default()
+        }
+
+        format!(
+            "{}-{}:{}-{}",
+            opt(&self.from_slot),
+            opt(&self.to_slot),
+            opt(&self.before),
+            opt(&self.until),
+        )
+    }
+}
+
+#[tracing::instrument(skip_all, level = "error", name = "backfill")]
+pub async fn run(
+    st: &'static AppState,
+    cfg: BackfillConfig,
+) -> Result<(), Error> {
+    let db: &'static dyn db::Store = Box::leak(db::connect(&cfg.store).await?);
+    let cursor_name = cfg.cursor_name();
+
+    let cursor = match cfg.restart {
+        true => None,
+        false => db.backfill_cursor(&cursor_name).await?,
+    };
+
+    let mut before = match cursor.map(|x| Signature::from_str(&x)) {
+        Some(Ok(x)) => {
+            info!("resuming before {}", x);
+            Some(x)
+        }
+        Some(Err(e)) => {
+            warn!("invalid cursor, starting over: {}", e);
+            cfg.before
+        }
+        None => cfg.before,
+    };
+
+    let permits = Arc::new(Semaphore::new(cfg.concurrency));
+    let until = cfg.until;
+    let mut total = 0usize;
+
+    loop {
+        // The cursor is saved after every page, so stopping between
+        // pages loses nothing. The requests are paced by the shared
+        // limiter, under the `backfill` budget.
+        if st.shutdown.is_triggered() {
+            info!("interrupted, processed {} transactions", total);
+            return Ok(());
+        }
+
+        // > The result field will be an array of transaction signature
+        // > information, ordered from newest to oldest transaction.
+        //
+        // https://docs.solana.com/developing/clients/jsonrpc-api#getsignaturesforaddress
+        let page = st
+            .rpc
+            .call(Budget::Backfill, "getSignaturesForAddress", |c| {
+                c.get_signatures_for_address_with_config(
+                    &st.zo_state_pubkey,
+                    GetConfirmedSignaturesForAddress2Config {
+                        before,
+                        until,
+                        limit: Some(PAGE_SIZE),
+                        commitment: Some(CommitmentConfig::finalized()),
+                    },
+                )
+            })
+            .await?;
+
+        let (last, oldest_slot) = match page.last() {
+            Some(x) => (x.signature.clone(), x.slot),
+            None => break,
+        };
+
+        let sigs: Vec<_> = page
+            .into_iter()
+            .filter(|x| x.err.is_none())
+            .filter(|x| cfg.to_slot.map_or(true, |s| x.slot <= s))
+            .filter(|x| cfg.from_slot.map_or(true, |s| x.slot >= s))
+            .map(|x| x.signature)
+            .collect();
+
+        let n = sigs.len();
+        let mut tasks = Vec::with_capacity(n);
+
+        for sg in sigs {
+            let permit = permits.clone().acquire_owned().await.unwrap();
+            let span = tracing::Span::current();
+
+            tasks.push(tokio::spawn(
+                async move {
+                    let _permit = permit;
+                    process(st, db, sg).await
+                }
+                .instrument(span),
+            ));
+        }
+
+        // The cursor only moves once the whole page is recorded, so
+        // an interrupted backfill redoes at most one page.
+        for t in futures::future::join_all(tasks).await {
+            t.unwrap()?;
+        }
+
+        db.set_backfill_cursor(&cursor_name, &last).await?;
+        before = Some(Signature::from_str(&last).unwrap());
+        total += n;
+
+        info!(
+            "processed {} transactions, down to slot {}",
+            total, oldest_slot
+        );
+
+        if cfg.from_slot.map_or(false, |s| oldest_slot < s) {
+            break;
+        }
+    }
+
+    info!("done, processed {} transactions", total);
+    Ok(())
+}
+
+async fn process(
+    st: &'static AppState,
+    db: &'static dyn db::Store,
+    sg: String,
+) -> Result<(), Error> {
+    let mut attempt = 0;
+
+    let tx = loop {
+        match fetch_logs(st, Budget::Backfill, &sg).await {
+            Ok(x) => break x,
+            Err(e) if attempt + 1 < FETCH_RETRIES => {
+                attempt += 1;
+                warn!("failed to fetch {}, retrying: {}", sg, e);
+                tokio::time::sleep(FETCH_RETRY_WAIT * attempt).await;
+            }
+            Err(e) => return Err(e),
+        }
+    };
+
+    match tx {
+        Some(tx) => {
+            crate::events::process(st, db, tx.logs, sg, tx.slot, tx.block_time)
+                .await
+        }
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,262 @@
+//! The `--config` file, holding the settings of every subcommand in a
+//! section of its own. Every setting has a default, so the file only
+//! needs the ones that differ, and flags given on the command line
+//! take precedence over the file.
+
+use crate::{
+    backfill::BackfillConfig, consumer::ConsumerConfig, crank::CrankConfig,
+    error::Error, heartbeat::HealthConfig, liquidator::LiquidatorConfig,
+    recorder::RecorderConfig, rpc::PoolConfig, shutdown::ShutdownConfig,
+    supervisor::RunConfig,
+};
+use serde::{Deserialize, Serialize};
+use std::{net::SocketAddr, path::PathBuf};
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    /// RPC endpoints, each paired with the websocket at the same index.
+    #[serde(with = "one_or_many")]
+    pub rpc_url: Vec<String>,
+    #[serde(with = "one_or_many")]
+    pub ws_url: Vec<String>,
+    pub payer: Option<PathBuf>,
+    pub dry_run: bool,
+    pub dry_run_output: Option<PathBuf>,
+    pub metrics_addr: Option<SocketAddr>,
+    pub health_addr: Option<SocketAddr>,
+    pub rpc: PoolConfig,
+    pub health: HealthConfig,
+    pub shutdown: ShutdownConfig,
+    pub crank: CrankConfig,
+    pub consumer: ConsumerConfig,
+    pub liquidator: LiquidatorConfig,
+    pub recorder: RecorderConfig,
+    pub backfill: BackfillConfig,
+    pub run: RunConfig,
+}
+
+impl Config {
+    /// Reads the file at `path`, or the defaults if there is none.
+    pub fn load(path: Option<&std::path::Path>) -> Result<Self, Error> {
+        match path {
+            Some(p) => {
+                let s = std::fs::read_to_string(p)?;
+                toml::from_str(&s).map_err(|e| {
+                    Error::Config(format!("{}: {}", p.display(), e))
+                })
+            }
+            None => Ok(Self::default()),
+        }
+    }
+
+    /// The RPC and websocket endpoints, paired up.
+    pub fn endpoints(&self) -> Result<Vec<(String, String)>, Error> {
+        check(
+            !self.rpc_url.is_empty() && !self.ws_url.is_empty(),
+            "an RPC and a websocket endpoint are required",
+        )?;
+        check(
+            self.rpc_url.len() == self.ws_url.len(),
+            "every RPC endpoint needs a websocket endpoint",
+        )?;
+
+        Ok(self
+            .rpc_url
+            .iter()
+            .cloned()
+            .zip(self.ws_url.iter().cloned())
+            .collect())
+    }
+
+    /// Checks that the health checks and metrics, if served on
+    /// their own, are not bound to the same address.
+    pub fn validate_addrs(&self) -> Result<(), Error> {
+        check(
+            self.health_addr.is_none() || self.health_addr != self.metrics_addr,
+            "health-addr must differ from metrics-addr, which already \
+             serves the health checks",
+        )
+    }
+
+    /// Checks every section. The recorder and backfill need a store
+    /// and `run` a keeper to run, so those are only checked once
+    /// given one, as the other keepers run without them.
+    pub fn validate(&self) -> Result<(), Error> {
+        self.rpc.validate()?;
+        self.health.validate()?;
+        self.validate_addrs()?;
+        self.crank.validate()?;
+        self.consumer.validate()?;
+        self.liquidator.validate()?;
+
+        if !self.recorder.store.is_empty() {
+            self.recorder.validate()?;
+        }
+        if !self.backfill.store.is_empty() {
+            self.backfill.validate()?;
+        }
+        if !self.run.keepers().is_empty() {
+            self.run.validate()?;
+        }
+
+        Ok(())
+    }
+
+    /// The effective configuration, as it would be written in the file.
+    /// Credentials and query strings are left out of the URLs, as that
+    /// is where providers put their keys.
+    pub fn dump(&self) -> Result<String, Error> {
+        let mut c = self.clone();
+
+        for url in c.rpc_url.iter_mut().chain(c.ws_url.iter_mut()) {
+            *url = redact(url);
+        }
+        c.recorder.store = redact(&c.recorder.store);
+        c.backfill.store = redact(&c.backfill.store);
+
+        toml::to_string(&c).map_err(|e| Error::Config(e.to_string()))
+    }
+}
+
+/// Replaces the password of `url`, or the user if there is none, and
+/// its query string with `REDACTED`.
+fn redact(url: &str) -> String {
+    let (scheme, rest) = match url.split_once("://") {
+        Some(x) => x,
+        None => return url.to_string(),
+    };
+    let (rest, query) = match rest.split_once('?') {
+        Some((x, _)) => (x, "?REDACTED"),
+        None => (rest, ""),
+    };
+    let (authority, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+
+    let authority = match authority.rsplit_once('@') {
+        Some((user, host)) => match user.split_once(':') {
+            Some((name, _)) => format!("{}:REDACTED@{}", name, host),
+            None => format!("REDACTED@{}", host),
+        },
+        None => authority.to_string(),
+    };
+
+    format!("{}://{}{}{}", scheme, authority, path, query)
+}
+
+/// Fails with `Error::Config` unless `ok`.
+pub(crate) fn check(ok: bool, what: &str) -> Result<(), Error> {
+    match ok {
+        true => Ok(()),
+        false => Err(Error::Config(what.to_string())),
+    }
+}
+
+/// Durations, written as a number of seconds.
+pub(crate) mod seconds {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(
+        x: &Duration,
+        s: S,
+    ) -> Result<S::Ok, S::Error> {
+        s.serialize_f64(x.as_secs_f64())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        d: D,
+    ) -> Result<Duration, D::Error> {
+        let x = f64::deserialize(d)?;
+
+        match x.is_finite() && x >= 0.0 {
+            true => Ok(Duration::from_secs_f64(x)),
+            false => Err(serde::de::Error::custom("invalid duration")),
+        }
+    }
+}
+
+/// Lists which may also be written as a single value, as they were
+/// before they became lists.
+pub(crate) mod one_or_many {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany<T> {
+        One(T),
+        Many(Vec<T>),
+    }
+
+    pub fn serialize<T: Serialize, S: Serializer>(
+        x: &[T],
+        s: S,
+    ) -> Result<S::Ok, S::Error> {
+        x.serialize(s)
+    }
+
+    pub fn deserialize<'de, T, D>(d: D) -> Result<Vec<T>, D::Error>
+    where
+        T: Deserialize<'de>,
+        D: Deserializer<'de>,
+    {
+        match OneOrMany::deserialize(d)? {
+            OneOrMany::One(x) => Ok(vec![x]),
+            OneOrMany::Many(x) => Ok(x),
+        }
+    }
+}
+
+/// Implements `Serialize` and `Deserialize` through `Display` and
+/// `FromStr`, so that the file takes the same syntax as the flags.
+macro_rules! serde_from_str {
+    ($T:ty) => {
+        impl serde::Serialize for $T {
+            fn serialize<S: serde::Serializer>(
+                &self,
+                s: S,
+            ) -> Result<S::Ok, S::Error> {
+                s.collect_str(self)
+            }
+        }
+
+        impl<'de> serde::Deserialize<'de> for $T {
+            fn deserialize<D: serde::Deserializer<'de>>(
+                d: D,
+            ) -> Result<Self, D::Error> {
+                let s = <String as serde::Deserialize>::deserialize(d)?;
+                s.parse().map_err(serde::de::Error::custom)
+            }
+        }
+    };
+}
+
+pub(crate) use serde_from_str;
+
+/// Optional values written as strings, for foreign types which
+/// `serde_from_str` can't be implemented for.
+pub(crate) mod opt_str {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::{fmt::Display, str::FromStr};
+
+    pub fn serialize<T: Display, S: Serializer>(
+        x: &Option<T>,
+        s: S,
+    ) -> Result<S::Ok, S::Error> {
+        match x {
+            Some(x) => s.collect_str(x),
+            None => s.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, T, D>(d: D) -> Result<Option<T>, D::Error>
+    where
+        T: FromStr,
+        T::Err: Display,
+        D: Deserializer<'de>,
+    {
+        match Option::<String>::deserialize(d)? {
+            Some(s) => s.parse().map(Some).map_err(serde::de::Error::custom),
+            None => Ok(None),
+        }
+    }
+}
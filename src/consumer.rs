@@ -1,102 +1,442 @@
-use crate::{error::Error, AppState};
+use crate::{
+    config::{check, seconds},
+    error::Error,
+    heartbeat::{Heartbeat, Task},
+    limiter::Budget,
+    metrics,
+    shutdown::{InFlight, Work},
+    tx::{FeeConfig, TxBuilder},
+    utils::{load_buf, run_per_listing, wait_for_dex_market, AbortOnDrop},
+    AppState,
+};
 use anchor_client::{
     anchor_lang::prelude::AccountMeta,
-    solana_sdk::{commitment_config::CommitmentConfig, pubkey::Pubkey},
+    solana_sdk::{
+        commitment_config::CommitmentConfig, instruction::Instruction,
+        pubkey::Pubkey, signature::Signature,
+    },
 };
+use futures::StreamExt;
+use jsonrpc_core_client::transports::ws;
+use serde::{Deserialize, Serialize};
+use solana_account_decoder::{UiAccountData, UiAccountEncoding};
+use solana_client::rpc_config::RpcAccountInfoConfig;
+use solana_rpc::rpc_pubsub::RpcSolPubSubClient;
 use std::{
     collections::{BTreeSet, HashMap},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
     time::{Duration, Instant},
 };
-use tracing::{debug, info, trace, warn};
+use tokio::sync::mpsc;
+use tracing::{debug, info, trace, warn, Instrument};
 
-#[derive(Clone)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
 pub struct ConsumerConfig {
     pub to_consume: usize,
+    #[serde(with = "seconds")]
     pub max_wait: Duration,
     pub max_queue_length: usize,
+    /// How often the event queue is polled while the websocket
+    /// subscription is down.
+    #[serde(with = "seconds")]
+    pub poll_interval: Duration,
+    /// Number of times a transaction is resent with an escalated
+    /// priority fee when it fails to confirm.
+    pub send_retries: u32,
+    pub fee: FeeConfig,
+}
+
+impl Default for ConsumerConfig {
+    fn default() -> Self {
+        Self {
+            to_consume: 12,
+            max_wait: Duration::from_secs(30),
+            max_queue_length: 1,
+            poll_interval: Duration::from_millis(250),
+            send_retries: 3,
+            fee: FeeConfig::default(),
+        }
+    }
+}
+
+impl ConsumerConfig {
+    pub fn validate(&self) -> Result<(), Error> {
+        check(
+            self.to_consume > 0 && self.to_consume <= u16::MAX as usize,
+            "events to consume must be between 1 and 65535",
+        )?;
+        check(
+            !self.max_wait.is_zero() && !self.poll_interval.is_zero(),
+            "consumer intervals must be positive",
+        )?;
+        check(self.send_retries > 0, "send retries must be positive")?;
+        self.fee.validate()
+    }
+}
+
+/// Bounds for the exponential reconnect backoff of the subscription.
+const MIN_RECONNECT_WAIT: Duration = Duration::from_millis(500);
+const MAX_RECONNECT_WAIT: Duration = Duration::from_secs(30);
+
+/// A snapshot of the event queue account, along with the slot
+/// at which it was observed.
+struct QueueUpdate {
+    slot: u64,
+    buf: Vec<u8>,
+}
+
+/// Per market state carried across calls to `consume`.
+struct MarketState {
+    last_head: u64,
+    last_cranked_at: Instant,
+    // Control -> (Open Orders, Margin)
+    accounts_table: HashMap<Pubkey, (Pubkey, Pubkey)>,
 }
 
 pub async fn run(
     st: &'static AppState,
     cfg: ConsumerConfig,
 ) -> Result<(), Error> {
-    let handles = st.load_dex_markets()?.into_iter().map(|(symbol, mkt)| {
-        let cfg = cfg.clone();
-
-        tokio::task::spawn_blocking(move || {
-            let mut last_cranked_at = Instant::now() - cfg.max_wait;
-            let mut accounts_table = HashMap::new();
-
-            // The seq_num wraps at 1 << 32, so for the initial
-            // value pick a number larger than that.
-            let mut last_head = 1u64 << 48;
-
-            loop {
-                std::thread::sleep(Duration::from_millis(250));
-                consume(
-                    st,
-                    &symbol,
-                    &mkt,
-                    &cfg,
-                    &mut last_head,
-                    &mut last_cranked_at,
-                    &mut accounts_table,
-                );
+    let tx = TxBuilder::new(st, cfg.fee.clone(), Budget::Consumer);
+
+    let consume_tasks = run_per_listing(
+        st,
+        || {
+            st.iter_markets()
+                .map(|m| (m.symbol.into(), m.dex_market))
+                .collect::<Vec<(String, Pubkey)>>()
+        },
+        |(symbol, key): (String, Pubkey)| {
+            let tx = tx.clone();
+            let cfg = cfg.clone();
+
+            async move {
+                let mkt = wait_for_dex_market(st, key).await;
+                consume_loop(st, tx, symbol, mkt, cfg).await
             }
-        })
-    });
+        },
+    );
+
+    tokio::select! {
+        _ = consume_tasks => {}
+        _ = st.shutdown.triggered() => {}
+    };
+
+    st.shutdown.drained().await;
 
-    let _ = futures::future::join_all(handles).await;
     Ok(())
 }
 
-#[tracing::instrument(
-    skip_all,
-    level = "error",
-    fields(symbol = symbol, slot = tracing::field::Empty)
-)]
-fn consume(
+#[tracing::instrument(skip_all, level = "error", fields(symbol = %symbol))]
+async fn consume_loop(
     st: &'static AppState,
-    symbol: &str,
-    market: &zo_abi::dex::ZoDexMarket,
-    cfg: &ConsumerConfig,
-    last_head: &mut u64,
-    last_cranked_at: &mut Instant,
-    // Control -> (Open Orders, Margin)
-    accounts_table: &mut HashMap<Pubkey, (Pubkey, Pubkey)>,
+    tx_builder: TxBuilder,
+    symbol: String,
+    mkt: zo_abi::dex::ZoDexMarket,
+    cfg: ConsumerConfig,
 ) {
-    let t = Instant::now();
+    let (tx, mut rx) = mpsc::channel::<QueueUpdate>(64);
+    let is_subscribed = Arc::new(AtomicBool::new(false));
+    let span = tracing::Span::current();
+    let hb = Heartbeat::new(Task::EventQueue, symbol.clone());
+
+    // Both are aborted along with this task when the market is delisted.
+    let _listener = AbortOnDrop(tokio::spawn(
+        listen_event_queue(st, mkt.event_q, tx.clone(), is_subscribed.clone())
+            .instrument(span.clone()),
+    ));
+    let _poller = AbortOnDrop(tokio::spawn(
+        poll_event_queue(
+            st,
+            mkt.event_q,
+            tx,
+            is_subscribed.clone(),
+            Heartbeat::new(Task::EventQueue, symbol.clone()),
+            cfg.poll_interval,
+        )
+        .instrument(span),
+    ));
+
+    let symbol = Arc::new(symbol);
+    let mut last_slot = 0u64;
+    let mut state = MarketState {
+        // The seq_num wraps at 1 << 32, so for the initial
+        // value pick a number larger than that.
+        last_head: 1u64 << 48,
+        last_cranked_at: Instant::now() - cfg.max_wait,
+        accounts_table: HashMap::new(),
+    };
+
+    // The latest update that was skipped by `consume`. It is retried
+    // once `max_wait` passes without any new update, since the
+    // queue may otherwise sit idle with unconsumed events.
+    let mut pending: Option<Arc<QueueUpdate>> = None;
+
+    loop {
+        let update = match tokio::time::timeout(cfg.max_wait, rx.recv()).await {
+            Ok(Some(mut update)) => {
+                hb.beat();
+
+                // Only the latest queue state matters, so drain anything
+                // that piled up while the previous crank was running.
+                while let Ok(x) = rx.try_recv() {
+                    if x.slot >= update.slot {
+                        update = x;
+                    }
+                }
+
+                // Updates from the subscription and the poller can
+                // arrive out of order, and a stale queue would crank
+                // events that were already consumed.
+                if update.slot <= last_slot {
+                    trace!(
+                        "stale update at slot {} (last {}), skipping",
+                        update.slot,
+                        last_slot
+                    );
+                    continue;
+                }
+
+                last_slot = update.slot;
+                Arc::new(update)
+            }
+            Ok(None) => return,
+            Err(_) => {
+                // A quiet market sends nothing while subscribed, which
+                // doesn't mean the queue is no longer read.
+                if is_subscribed.load(Ordering::Relaxed) {
+                    hb.beat();
+                }
+
+                match pending.take() {
+                    Some(x) => x,
+                    None => continue,
+                }
+            }
+        };
 
-    let (event_q_buf, slot) = {
-        let res = st.rpc.get_account_with_commitment(
-            &market.event_q,
-            CommitmentConfig::confirmed(),
-        );
+        // Handed to the task sending the transactions, which is not
+        // aborted along with this one.
+        let work = match st.shutdown.begin(Work::Transaction) {
+            Some(x) => x,
+            None => return,
+        };
+
+        let cranked = consume(
+            st,
+            &tx_builder,
+            &symbol,
+            &mkt,
+            &cfg,
+            &update,
+            &mut state,
+            work,
+        )
+        .await;
+
+        pending = match cranked {
+            true => None,
+            false => Some(update),
+        };
+    }
+}
+
+async fn listen_event_queue(
+    st: &'static AppState,
+    event_q: Pubkey,
+    tx: mpsc::Sender<QueueUpdate>,
+    is_subscribed: Arc<AtomicBool>,
+) {
+    let mut wait = MIN_RECONNECT_WAIT;
 
-        let res = match res {
+    loop {
+        let ws_url = st.rpc.ws_url();
+        let sub = match ws::try_connect::<RpcSolPubSubClient>(&ws_url) {
+            Ok(x) => x.await,
+            Err(e) => Err(e),
+        }
+        .and_then(|p| {
+            p.account_subscribe(
+                event_q.to_string(),
+                Some(RpcAccountInfoConfig {
+                    encoding: Some(UiAccountEncoding::Base64),
+                    data_slice: None,
+                    commitment: Some(CommitmentConfig::confirmed()),
+                }),
+            )
+        });
+
+        let mut sub = match sub {
             Ok(x) => x,
             Err(e) => {
                 let e = Error::from(e);
-                warn!("{}", e);
+                warn!("failed to subscribe, retrying in {:?}: {}", wait, e);
+                st.rpc.ws_failed(&ws_url);
+                metrics::LISTENER_RECONNECTS
+                    .with_label_values(&["event_queue"])
+                    .inc();
+                tokio::time::sleep(wait).await;
+                wait = std::cmp::min(wait * 2, MAX_RECONNECT_WAIT);
+                continue;
+            }
+        };
+
+        info!("subscribed to event queue");
+        let _subscribed = Subscribed::new(&is_subscribed);
+        wait = MIN_RECONNECT_WAIT;
+
+        while let Some(resp) = sub.next().await {
+            let resp = match resp {
+                Ok(x) => x,
+                Err(e) => {
+                    warn!("{}", Error::from(e));
+                    continue;
+                }
+            };
+
+            let buf = match resp.value.data {
+                UiAccountData::Binary(b, _) => match base64::decode(b) {
+                    Ok(x) => x,
+                    Err(e) => {
+                        warn!("invalid event queue data: {}", e);
+                        continue;
+                    }
+                },
+                _ => {
+                    warn!("event queue data is not base64 encoded");
+                    continue;
+                }
+            };
+
+            let update = QueueUpdate {
+                slot: resp.context.slot,
+                buf,
+            };
+
+            if tx.send(update).await.is_err() {
                 return;
             }
+        }
+
+        warn!("event queue subscription dropped, falling back to polling");
+        st.rpc.ws_failed(&ws_url);
+        metrics::LISTENER_RECONNECTS
+            .with_label_values(&["event_queue"])
+            .inc();
+    }
+}
+
+/// Marks the event queue as subscribed for as long as it lives, so
+/// that the poller takes over however the subscription ends, even if
+/// the listener is aborted.
+struct Subscribed<'a>(&'a AtomicBool);
+
+impl<'a> Subscribed<'a> {
+    fn new(flag: &'a AtomicBool) -> Self {
+        flag.store(true, Ordering::Relaxed);
+        Self(flag)
+    }
+}
+
+impl Drop for Subscribed<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+}
+
+async fn poll_event_queue(
+    st: &'static AppState,
+    event_q: Pubkey,
+    tx: mpsc::Sender<QueueUpdate>,
+    is_subscribed: Arc<AtomicBool>,
+    hb: Heartbeat,
+    period: Duration,
+) {
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+
+    loop {
+        interval.tick().await;
+
+        if is_subscribed.load(Ordering::Relaxed) {
+            continue;
+        }
+
+        let res = st
+            .rpc
+            .call(Budget::Consumer, "getAccountInfo", |c| {
+                c.get_account_with_commitment(
+                    &event_q,
+                    CommitmentConfig::confirmed(),
+                )
+            })
+            .await;
+
+        let update = match res {
+            Ok(x) => match x.value {
+                Some(a) => QueueUpdate {
+                    slot: x.context.slot,
+                    buf: a.data,
+                },
+                None => {
+                    warn!("event queue {} not found", event_q);
+                    continue;
+                }
+            },
+            Err(e) => {
+                warn!("{}", Error::from(e));
+                continue;
+            }
         };
 
-        let slot = res.context.slot;
-        let buf = res.value.unwrap().data;
+        hb.beat();
 
-        (buf, slot)
-    };
+        if tx.send(update).await.is_err() {
+            return;
+        }
+    }
+}
+
+#[tracing::instrument(
+    skip_all,
+    level = "error",
+    fields(symbol = symbol, slot = tracing::field::Empty)
+)]
+async fn consume(
+    st: &'static AppState,
+    tx: &TxBuilder,
+    symbol: &str,
+    market: &zo_abi::dex::ZoDexMarket,
+    cfg: &ConsumerConfig,
+    update: &QueueUpdate,
+    state: &mut MarketState,
+    work: InFlight<'static>,
+) -> bool {
+    let t = Instant::now();
+    let MarketState {
+        last_head,
+        last_cranked_at,
+        accounts_table,
+    } = state;
+    let (event_q_buf, slot) = (&update.buf, update.slot);
 
     tracing::Span::current().record("slot", &slot);
 
     let (events_header, events) =
-        zo_abi::dex::Event::deserialize_queue(&event_q_buf).unwrap();
+        zo_abi::dex::Event::deserialize_queue(event_q_buf).unwrap();
     let events = events.cloned().collect::<Vec<_>>();
 
+    metrics::CONSUMER_QUEUE_DEPTH
+        .with_label_values(&[symbol])
+        .set(events.len() as i64);
+
     if events.is_empty() {
         trace!("no events, skipping");
-        return;
+        return true;
     }
 
     if last_cranked_at.elapsed() < cfg.max_wait {
@@ -106,7 +446,7 @@ fn consume(
                 last_cranked_at.elapsed().as_secs(),
                 { events_header.head },
             );
-            return;
+            return false;
         }
 
         if events.len() < cfg.max_queue_length {
@@ -115,7 +455,7 @@ fn consume(
                 last_cranked_at.elapsed().as_secs(),
                 events.len(),
             );
-            return;
+            return false;
         }
     }
 
@@ -135,19 +475,25 @@ fn consume(
     let mut margin_accounts = Vec::with_capacity(used_control.len());
 
     for control in used_control.into_iter().map(bytemuck::cast) {
-        let (oo, margin) = accounts_table.entry(control).or_insert_with(|| {
-            (
-                open_orders_pda(&control, &market.own_address),
-                margin_pda(
-                    &st.program().account(control).unwrap(),
-                    &st.zo_state_pubkey,
-                ),
-            )
-        });
+        if !accounts_table.contains_key(&control) {
+            let margin = match load_margin_key(st, &control).await {
+                Ok(x) => x,
+                Err(e) => {
+                    warn!("failed to load control {}: {}", control, e);
+                    return false;
+                }
+            };
+
+            accounts_table.insert(
+                control,
+                (open_orders_pda(&control, &market.own_address), margin),
+            );
+        }
 
+        let (oo, margin) = accounts_table[&control];
         control_accounts.push(AccountMeta::new(control, false));
-        orders_accounts.push(AccountMeta::new(*oo, false));
-        margin_accounts.push(AccountMeta::new(*margin, false));
+        orders_accounts.push(AccountMeta::new(oo, false));
+        margin_accounts.push(AccountMeta::new(margin, false));
     }
 
     info!(
@@ -159,23 +505,49 @@ fn consume(
 
     let market = *market;
     let limit = cfg.to_consume as u16;
+    let retries = cfg.send_retries;
+    let consumed = events.len().min(cfg.to_consume) as u64;
+    let symbol = symbol.to_string();
     let span = tracing::Span::current();
-
-    std::thread::spawn(move || {
-        let _g = span.enter();
-        consume_events(st, &market, limit, &control_accounts, &orders_accounts);
+    let tx = tx.clone();
+
+    // Sent in the background, so that the next update is picked up
+    // while these confirm.
+    let send = async move {
+        let _work = work;
+        let sent = consume_events(
+            st,
+            &tx,
+            retries,
+            &market,
+            limit,
+            &control_accounts,
+            &orders_accounts,
+        )
+        .await;
+
+        if sent {
+            metrics::CONSUMER_EVENTS_CONSUMED
+                .with_label_values(&[&symbol])
+                .inc_by(consumed);
+        }
 
         let mid = control_accounts.len() / 2;
         let controls = control_accounts.split_at(mid);
         let orders = orders_accounts.split_at(mid);
         let margins = margin_accounts.split_at(mid);
 
-        crank_pnl(st, &market, &controls.0, &orders.0, &margins.0);
-        crank_pnl(st, &market, &controls.1, &orders.1, &margins.1);
-    });
+        crank_pnl(st, &tx, retries, &market, controls.0, orders.0, margins.0)
+            .await;
+        crank_pnl(st, &tx, retries, &market, controls.1, orders.1, margins.1)
+            .await;
+    };
+
+    tokio::spawn(send.instrument(span));
 
     *last_head = events_header.head;
     *last_cranked_at = Instant::now();
+    true
 }
 
 fn open_orders_pda(control: &Pubkey, zo_dex_market: &Pubkey) -> Pubkey {
@@ -186,6 +558,25 @@ fn open_orders_pda(control: &Pubkey, zo_dex_market: &Pubkey) -> Pubkey {
     .0
 }
 
+/// The margin account of `control`, which is derived from its
+/// authority.
+async fn load_margin_key(
+    st: &AppState,
+    control: &Pubkey,
+) -> Result<Pubkey, Error> {
+    let buf = st
+        .rpc
+        .call(Budget::Consumer, "getAccountInfo", |c| {
+            c.get_account_data(control)
+        })
+        .await?;
+
+    match load_buf::<zo_abi::Control>(&buf) {
+        Some(x) => Ok(margin_pda(x, &st.zo_state_pubkey)),
+        None => Err(Error::InvalidAccount(*control)),
+    }
+}
+
 fn margin_pda(control: &zo_abi::Control, state: &Pubkey) -> Pubkey {
     Pubkey::find_program_address(
         &[control.authority.as_ref(), state.as_ref(), b"marginv1"],
@@ -194,71 +585,88 @@ fn margin_pda(control: &zo_abi::Control, state: &Pubkey) -> Pubkey {
     .0
 }
 
-fn consume_events(
+async fn consume_events(
     st: &AppState,
+    tx: &TxBuilder,
+    retries: u32,
     market: &zo_abi::dex::ZoDexMarket,
     limit: u16,
     control_accounts: &[AccountMeta],
     orders_accounts: &[AccountMeta],
-) {
-    let program = st.program();
-    let req = program
-        .request()
-        .args(zo_abi::instruction::ConsumeEvents { limit })
-        .accounts(zo_abi::accounts::ConsumeEvents {
-            state: st.zo_state_pubkey,
-            state_signer: st.zo_state_signer_pubkey,
-            dex_program: zo_abi::ZO_DEX_PID,
-            market: market.own_address,
-            event_queue: market.event_q,
-        });
-
-    let res = control_accounts
-        .iter()
-        .chain(orders_accounts.iter())
-        .fold(req, |r, x| r.accounts(x.clone()))
-        .send();
+) -> bool {
+    // The program is not `Send`, so it must be gone before awaiting.
+    let ixs = {
+        let program = st.program();
+        let req = program
+            .request()
+            .args(zo_abi::instruction::ConsumeEvents { limit })
+            .accounts(zo_abi::accounts::ConsumeEvents {
+                state: st.zo_state_pubkey,
+                state_signer: st.zo_state_signer_pubkey,
+                dex_program: zo_abi::ZO_DEX_PID,
+                market: market.own_address,
+                event_queue: market.event_q,
+            });
+
+        control_accounts
+            .iter()
+            .chain(orders_accounts.iter())
+            .fold(req, |r, x| r.accounts(x.clone()))
+            .instructions()
+    };
 
-    match res {
-        Ok(sg) => info!("consume_events: {}", sg),
+    match send(tx, retries, ixs).await {
+        Ok(sg) => {
+            info!("consume_events: {}", sg);
+            true
+        }
         Err(e) => {
-            let e = Error::from(e);
             warn!("consume_events: {}", e);
+            false
         }
     }
 }
 
-fn crank_pnl(
+async fn crank_pnl(
     st: &AppState,
+    tx: &TxBuilder,
+    retries: u32,
     market: &zo_abi::dex::ZoDexMarket,
     control_accounts: &[AccountMeta],
     orders_accounts: &[AccountMeta],
     margin_accounts: &[AccountMeta],
 ) {
-    let program = st.program();
-    let req = program
-        .request()
-        .args(zo_abi::instruction::CrankPnl)
-        .accounts(zo_abi::accounts::CrankPnl {
-            state: st.zo_state_pubkey,
-            state_signer: st.zo_state_signer_pubkey,
-            cache: st.zo_cache_pubkey,
-            dex_program: zo_abi::ZO_DEX_PID,
-            market: market.own_address,
-        });
-
-    let res = control_accounts
-        .iter()
-        .chain(orders_accounts.iter())
-        .chain(margin_accounts.iter())
-        .fold(req, |r, x| r.accounts(x.clone()))
-        .send();
+    let ixs = {
+        let program = st.program();
+        let req = program
+            .request()
+            .args(zo_abi::instruction::CrankPnl)
+            .accounts(zo_abi::accounts::CrankPnl {
+                state: st.zo_state_pubkey,
+                state_signer: st.zo_state_signer_pubkey,
+                cache: st.zo_cache_pubkey,
+                dex_program: zo_abi::ZO_DEX_PID,
+                market: market.own_address,
+            });
+
+        control_accounts
+            .iter()
+            .chain(orders_accounts.iter())
+            .chain(margin_accounts.iter())
+            .fold(req, |r, x| r.accounts(x.clone()))
+            .instructions()
+    };
 
-    match res {
+    match send(tx, retries, ixs).await {
         Ok(sg) => info!("crank_pnl: {}", sg),
-        Err(e) => {
-            let e = Error::from(e);
-            warn!("crank_pnl: {}", e);
-        }
+        Err(e) => warn!("crank_pnl: {}", e),
     }
 }
+
+async fn send(
+    tx: &TxBuilder,
+    retries: u32,
+    ixs: Result<Vec<Instruction>, anchor_client::ClientError>,
+) -> Result<Signature, Error> {
+    tx.send_with_retries(&ixs?, retries).await
+}
@@ -1,73 +1,194 @@
-use crate::{error::Error, AppState};
-use anchor_client::solana_sdk::instruction::AccountMeta;
-use std::{cmp::min, marker::Send, sync::Arc, time::Duration};
+use crate::{
+    config::{check, seconds},
+    error::Error,
+    heartbeat::{Heartbeat, Task},
+    limiter::Budget,
+    metrics,
+    shutdown::Work,
+    tx::{FeeConfig, TxBuilder},
+    utils::{run_per_listing, wait_for_dex_market},
+    AppState,
+};
+use anchor_client::solana_sdk::{
+    instruction::{AccountMeta, Instruction},
+    pubkey::Pubkey,
+};
+use serde::{Deserialize, Serialize};
+use std::{cmp::min, future::Future, sync::Arc, time::Duration};
 use tokio::time::{Interval, MissedTickBehavior};
 use tracing::{info, warn};
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
 pub struct CrankConfig {
+    #[serde(with = "seconds")]
     pub cache_oracle_interval: Duration,
+    #[serde(with = "seconds")]
     pub cache_interest_interval: Duration,
+    #[serde(with = "seconds")]
     pub update_funding_interval: Duration,
+    /// Number of oracles cached by each transaction.
+    pub cache_oracle_chunk_size: usize,
+    /// Number of collaterals whose interest is cached by each
+    /// transaction.
+    pub cache_interest_chunk_size: usize,
+    /// Number of times a transaction is resent with an escalated
+    /// priority fee when it fails to confirm.
+    pub send_retries: u32,
+    pub fee: FeeConfig,
 }
 
-const CACHE_ORACLE_CHUNK_SIZE: usize = 6;
-const CACHE_INTEREST_CHUNK_SIZE: usize = 12;
+impl Default for CrankConfig {
+    fn default() -> Self {
+        Self {
+            cache_oracle_interval: Duration::from_millis(2500),
+            cache_interest_interval: Duration::from_secs(5),
+            update_funding_interval: Duration::from_secs(15),
+            cache_oracle_chunk_size: 6,
+            cache_interest_chunk_size: 12,
+            send_retries: 3,
+            fee: FeeConfig::default(),
+        }
+    }
+}
+
+impl CrankConfig {
+    pub fn validate(&self) -> Result<(), Error> {
+        check(
+            !self.cache_oracle_interval.is_zero()
+                && !self.cache_interest_interval.is_zero()
+                && !self.update_funding_interval.is_zero(),
+            "crank intervals must be positive",
+        )?;
+        check(
+            self.cache_oracle_chunk_size > 0
+                && self.cache_interest_chunk_size > 0
+                && self.cache_interest_chunk_size <= u8::MAX as usize,
+            "crank chunk sizes must be between 1 and 255",
+        )?;
+        check(self.send_retries > 0, "send retries must be positive")?;
+        self.fee.validate()
+    }
+}
 
 pub async fn run(st: &'static AppState, cfg: CrankConfig) -> Result<(), Error> {
-    let cache_oracle_tasks = st
-        .iter_oracles()
-        .collect::<Vec<_>>()
-        .chunks(CACHE_ORACLE_CHUNK_SIZE)
-        .map(|x| {
-            let (symbols, accounts): (Vec<String>, Vec<AccountMeta>) = x
-                .iter()
-                .map(|x| {
-                    let symbol = x.symbol.into();
-                    let acc =
-                        AccountMeta::new_readonly(x.sources[0].key, false);
-                    (symbol, acc)
+    let tx = TxBuilder::new(st, cfg.fee.clone(), Budget::Crank);
+    let retries = cfg.send_retries;
+
+    let cache_oracle_tasks = run_per_listing(
+        st,
+        || {
+            st.iter_oracles()
+                .map(|x| (x.symbol.into(), x.sources[0].key))
+                .collect::<Vec<(String, Pubkey)>>()
+                .chunks(cfg.cache_oracle_chunk_size)
+                .map(|x| x.to_vec())
+                .collect()
+        },
+        |chunk: Vec<(String, Pubkey)>| {
+            let (symbols, accounts): (Vec<String>, Vec<AccountMeta>) = chunk
+                .into_iter()
+                .map(|(symbol, key)| {
+                    (symbol, AccountMeta::new_readonly(key, false))
                 })
                 .unzip();
 
+            let hb = Arc::new(Heartbeat::new(
+                Task::Crank,
+                format!("cache_oracle {}", symbols.join(",")),
+            ));
             let symbols = Arc::new(symbols);
             let accounts = Arc::new(accounts);
+            let tx = tx.clone();
 
-            loop_blocking(interval(cfg.cache_oracle_interval), move || {
-                cache_oracle(st, &symbols, &accounts)
-            })
-        })
-        .collect::<Vec<_>>();
-
-    let cache_interest_tasks = (0..st.zo_state.total_collaterals as u8)
-        .step_by(CACHE_INTEREST_CHUNK_SIZE)
-        .map(|i| {
-            let start = i;
-            let end = min(
-                i + CACHE_INTEREST_CHUNK_SIZE as u8,
-                st.zo_state.total_collaterals as u8,
-            );
-
-            loop_blocking(interval(cfg.cache_interest_interval), move || {
-                cache_interest(st, start, end)
+            run_every(st, interval(cfg.cache_oracle_interval), move || {
+                let (tx, hb) = (tx.clone(), hb.clone());
+                let (symbols, accounts) = (symbols.clone(), accounts.clone());
+
+                async move {
+                    cache_oracle(st, &tx, &hb, retries, &symbols, &accounts)
+                        .await
+                }
             })
-        });
+        },
+    );
+
+    let cache_interest_tasks = run_per_listing(
+        st,
+        || {
+            let total = st.zo_state().total_collaterals as u8;
+            let size = cfg.cache_interest_chunk_size as u8;
+
+            (0..total)
+                .step_by(size as usize)
+                .map(|i| (i, min(i.saturating_add(size), total)))
+                .collect()
+        },
+        |(start, end): (u8, u8)| {
+            let hb = Arc::new(Heartbeat::new(
+                Task::Crank,
+                format!("cache_interest_rates {}..{}", start, end),
+            ));
+            let tx = tx.clone();
 
-    let update_funding_tasks =
-        st.load_dex_markets()?.into_iter().map(|(symbol, market)| {
-            let symbol = Arc::new(symbol);
-            let market = Arc::new(market);
+            run_every(st, interval(cfg.cache_interest_interval), move || {
+                let (tx, hb) = (tx.clone(), hb.clone());
 
-            loop_blocking(interval(cfg.update_funding_interval), move || {
-                update_funding(st, &symbol, &market)
+                async move {
+                    cache_interest(st, &tx, &hb, retries, start, end).await
+                }
             })
-        });
+        },
+    );
+
+    let update_funding_tasks = run_per_listing(
+        st,
+        || {
+            st.iter_markets()
+                .map(|m| (m.symbol.into(), m.dex_market))
+                .collect::<Vec<(String, Pubkey)>>()
+        },
+        |(symbol, key): (String, Pubkey)| {
+            let tx = tx.clone();
+            let period = cfg.update_funding_interval;
 
-    futures::join!(
-        futures::future::join_all(cache_oracle_tasks),
-        futures::future::join_all(cache_interest_tasks),
-        futures::future::join_all(update_funding_tasks),
+            async move {
+                let hb = Arc::new(Heartbeat::new(
+                    Task::Crank,
+                    format!("update_perp_funding {}", symbol),
+                ));
+                let symbol = Arc::new(symbol);
+                let market = Arc::new(wait_for_dex_market(st, key).await);
+
+                run_every(st, interval(period), move || {
+                    let (tx, hb) = (tx.clone(), hb.clone());
+                    let (symbol, market) = (symbol.clone(), market.clone());
+
+                    async move {
+                        update_funding(st, &tx, &hb, retries, &symbol, &market)
+                            .await
+                    }
+                })
+                .await
+            }
+        },
     );
 
+    tokio::select! {
+        _ = async {
+            futures::join!(
+                cache_oracle_tasks,
+                cache_interest_tasks,
+                update_funding_tasks,
+            )
+        } => {}
+        _ = st.shutdown.triggered() => {}
+    };
+
+    // The workers are stopped, but their transactions may still be
+    // on their way.
+    st.shutdown.drained().await;
+
     Ok(())
 }
 
@@ -77,117 +198,143 @@ fn interval(d: Duration) -> Interval {
     interval
 }
 
-fn dispatch(st: &AppState, req: anchor_client::RequestBuilder) {
-    use anchor_client::solana_sdk::{
-        commitment_config::CommitmentConfig, signer::Signer as _,
-        transaction::Transaction,
+/// Sends `ixs`, recording the result under `instruction` and `symbol`,
+/// and beating `hb` if it landed.
+async fn dispatch(
+    tx: &TxBuilder,
+    hb: &Heartbeat,
+    retries: u32,
+    instruction: &str,
+    symbol: &str,
+    ixs: Result<Vec<Instruction>, anchor_client::ClientError>,
+) {
+    let res = match ixs {
+        Ok(ixs) => tx.send_with_retries(&ixs, retries).await,
+        Err(e) => Err(e.into()),
     };
 
-    const GET_STATUS_RETRIES: usize = 25;
-    const GET_STATUS_WAIT: u64 = 2000;
-
-    // This auxiliary function emulates the same logic as the solana
-    // client's `send_and_confirm_transaction` function, but does not
-    // retry `usize::MAX` times as that ends up spawning too many
-    // processes.
-    let aux = move || -> Result<_, Error> {
-        let ixs = req.instructions().unwrap();
-        let bh = st.rpc.get_latest_blockhash()?;
-        let payer = st.payer_key();
-        let tx = Transaction::new_signed_with_payer(
-            &ixs,
-            Some(&payer.pubkey()),
-            // NOTE: For cranking, no other signer is required.
-            &[payer],
-            bh,
-        );
-        let sg = st.rpc.send_transaction(&tx)?;
-
-        for _ in 0..GET_STATUS_RETRIES {
-            match st.rpc.get_signature_status(&sg)? {
-                Some(Ok(_)) => return Ok(sg),
-                Some(Err(e)) => return Err(e.into()),
-                None => {
-                    if !st.rpc.is_blockhash_valid(
-                        &bh,
-                        CommitmentConfig::processed(),
-                    )? {
-                        break;
-                    }
-
-                    std::thread::sleep(Duration::from_millis(GET_STATUS_WAIT));
-                }
-            }
+    let result = match res {
+        Ok(sg) => {
+            info!("{}", sg);
+            hb.beat();
+            "success"
+        }
+        Err(e) => {
+            warn!("{}", e);
+            "failure"
         }
-
-        Err(Error::ConfirmationTimeout(sg))
     };
 
-    match aux() {
-        Ok(sg) => info!("{}", sg),
-        Err(e) => warn!("{}", e),
-    };
+    metrics::CRANK_TRANSACTIONS
+        .with_label_values(&[instruction, symbol, result])
+        .inc();
 }
 
-async fn loop_blocking<F>(mut interval: Interval, f: F)
+/// Spawns the future made by `f` on every tick of `interval`, until
+/// shutting down. A slow transaction does not hold up the next one.
+async fn run_every<F, Fut>(st: &'static AppState, mut interval: Interval, f: F)
 where
-    F: Fn() + Send + Clone + 'static,
+    F: Fn() -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
 {
-    loop {
-        interval.tick().await;
-        tokio::task::spawn_blocking(f.clone());
+    while st.shutdown.tick(&mut interval).await {
+        let work = match st.shutdown.begin(Work::Transaction) {
+            Some(x) => x,
+            None => return,
+        };
+        let fut = f();
+
+        tokio::spawn(async move {
+            let _work = work;
+            fut.await
+        });
     }
 }
 
 #[tracing::instrument(skip_all, level = "error", fields(symbols = ?s))]
-fn cache_oracle(st: &AppState, s: &[String], accs: &[AccountMeta]) {
-    let program = st.program();
-    let req = program
-        .request()
-        .args(zo_abi::instruction::CacheOracle {
-            symbols: s.to_owned(),
-            mock_prices: None,
-        })
-        .accounts(zo_abi::accounts::CacheOracle {
-            signer: st.payer(),
-            cache: st.zo_cache_pubkey,
-        });
+async fn cache_oracle(
+    st: &AppState,
+    tx: &TxBuilder,
+    hb: &Heartbeat,
+    retries: u32,
+    s: &[String],
+    accs: &[AccountMeta],
+) {
+    // The program is not `Send`, so it must be gone before awaiting.
+    let ixs = {
+        let program = st.program();
+        let req = program
+            .request()
+            .args(zo_abi::instruction::CacheOracle {
+                symbols: s.to_owned(),
+                mock_prices: None,
+            })
+            .accounts(zo_abi::accounts::CacheOracle {
+                signer: st.payer(),
+                cache: st.zo_cache_pubkey,
+            });
 
-    let req = accs.iter().fold(req, |r, x| r.accounts(x.clone()));
+        accs.iter()
+            .fold(req, |r, x| r.accounts(x.clone()))
+            .instructions()
+    };
 
-    dispatch(st, req);
+    dispatch(tx, hb, retries, "cache_oracle", &s.join(","), ixs).await;
 }
 
 #[tracing::instrument(skip_all, level = "error", fields(from = start, to = end))]
-fn cache_interest(st: &AppState, start: u8, end: u8) {
-    dispatch(
-        st,
-        st.program()
-            .request()
-            .args(zo_abi::instruction::CacheInterestRates { start, end })
-            .accounts(zo_abi::accounts::CacheInterestRates {
-                signer: st.payer(),
-                state: st.zo_state_pubkey,
-                cache: st.zo_cache_pubkey,
-            }),
-    );
+async fn cache_interest(
+    st: &AppState,
+    tx: &TxBuilder,
+    hb: &Heartbeat,
+    retries: u32,
+    start: u8,
+    end: u8,
+) {
+    let state = st.zo_state();
+    let symbols = state.collaterals[start as usize..end as usize]
+        .iter()
+        .map(|c| String::from(c.oracle_symbol))
+        .collect::<Vec<_>>()
+        .join(",");
+
+    let ixs = st
+        .program()
+        .request()
+        .args(zo_abi::instruction::CacheInterestRates { start, end })
+        .accounts(zo_abi::accounts::CacheInterestRates {
+            signer: st.payer(),
+            state: st.zo_state_pubkey,
+            cache: st.zo_cache_pubkey,
+        })
+        .instructions();
+
+    dispatch(tx, hb, retries, "cache_interest_rates", &symbols, ixs).await;
 }
 
 #[tracing::instrument(skip_all, level = "error", fields(symbol = symbol))]
-fn update_funding(st: &AppState, symbol: &str, m: &zo_abi::dex::ZoDexMarket) {
-    dispatch(
-        st,
-        st.program()
-            .request()
-            .args(zo_abi::instruction::UpdatePerpFunding {})
-            .accounts(zo_abi::accounts::UpdatePerpFunding {
-                state: st.zo_state_pubkey,
-                state_signer: st.zo_state_signer_pubkey,
-                cache: st.zo_cache_pubkey,
-                dex_market: m.own_address,
-                market_bids: m.bids,
-                market_asks: m.asks,
-                dex_program: zo_abi::ZO_DEX_PID,
-            }),
-    );
+async fn update_funding(
+    st: &AppState,
+    tx: &TxBuilder,
+    hb: &Heartbeat,
+    retries: u32,
+    symbol: &str,
+    m: &zo_abi::dex::ZoDexMarket,
+) {
+    let ixs = st
+        .program()
+        .request()
+        .args(zo_abi::instruction::UpdatePerpFunding {})
+        .accounts(zo_abi::accounts::UpdatePerpFunding {
+            state: st.zo_state_pubkey,
+            state_signer: st.zo_state_signer_pubkey,
+            cache: st.zo_cache_pubkey,
+            dex_market: m.own_address,
+            market_bids: m.bids,
+            market_asks: m.asks,
+            dex_program: zo_abi::ZO_DEX_PID,
+        })
+        .instructions();
+
+    dispatch(tx, hb, retries, "update_perp_funding", symbol, ixs).await;
 }
@@ -0,0 +1,312 @@
+//! Append-only store writing one file of JSON lines per record type,
+//! meant for local runs and tests. Records are deduplicated by their
+//! key, which is kept in memory for every record written so far. An
+//! upsert that changes a record appends it again, and the last line
+//! with a given key wins.
+
+use super::*;
+use serde::de::DeserializeOwned;
+use std::{
+    collections::hash_map::Entry,
+    fs::{self, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use tracing::{debug, info};
+
+struct Inner {
+    dir: PathBuf,
+    seq_nums: HashMap<String, i64>,
+    cursors: HashMap<String, String>,
+    high_water: HashMap<String, i64>,
+    // Keys of the records in each file, mapped to their last line.
+    // A file is read the first time it is written to.
+    lines: HashMap<&'static str, HashMap<String, String>>,
+}
+
+pub struct Jsonl {
+    // Writes are serialized so that lines from concurrent inserts
+    // into the same file never interleave.
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Jsonl {
+    pub fn connect(url: &str) -> Result<Self, Error> {
+        let dir = match url.strip_prefix("jsonl://").unwrap_or_default() {
+            "" => PathBuf::from("."),
+            x => PathBuf::from(x),
+        };
+
+        fs::create_dir_all(&dir)?;
+
+        let seq_nums = load_checkpoint(&dir, &EVENT_QUEUE)?;
+        let cursors = load_checkpoint(&dir, &BACKFILL)?;
+        let high_water = load_checkpoint(&dir, &HIGH_WATER)?;
+
+        Ok(Self {
+            inner: Arc::new(Mutex::new(Inner {
+                dir,
+                seq_nums,
+                cursors,
+                high_water,
+                lines: HashMap::new(),
+            })),
+        })
+    }
+
+    async fn run<R, F>(&self, f: F) -> Result<R, Error>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut Inner) -> Result<R, Error> + Send + 'static,
+    {
+        let inner = self.inner.clone();
+
+        tokio::task::spawn_blocking(move || f(&mut inner.lock().unwrap()))
+            .await
+            .unwrap()
+    }
+
+    /// Appends the records whose key is new, and with `replace` also
+    /// those whose key exists but with a different value.
+    #[tracing::instrument(skip_all, level = "error", fields(file = T::NAME))]
+    async fn append<T: Record>(
+        &self,
+        xs: &[T],
+        replace: bool,
+    ) -> Result<(), Error> {
+        if xs.is_empty() {
+            debug!("0 records, skipping");
+            return Ok(());
+        }
+
+        let mut rows = Vec::with_capacity(xs.len());
+
+        for x in xs {
+            let line = serde_json::to_string(x)?;
+            rows.push((key::<T>(&serde_json::from_str(&line)?), line));
+        }
+
+        let n = self
+            .run(move |inner| {
+                let path = inner.dir.join(format!("{}.jsonl", T::NAME));
+                let lines = match inner.lines.entry(T::NAME) {
+                    Entry::Occupied(e) => e.into_mut(),
+                    Entry::Vacant(e) => e.insert(load_lines::<T>(&path)?),
+                };
+
+                let mut buf = String::new();
+                let mut n = 0;
+
+                for (k, line) in rows {
+                    let new = match lines.get(&k) {
+                        None => true,
+                        Some(x) => replace && *x != line,
+                    };
+
+                    if !new {
+                        continue;
+                    }
+
+                    buf.push_str(&line);
+                    buf.push('\n');
+                    lines.insert(k, line);
+                    n += 1;
+                }
+
+                if n > 0 {
+                    OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(path)?
+                        .write_all(buf.as_bytes())?;
+                }
+
+                Ok(n)
+            })
+            .await?;
+
+        if n < xs.len() {
+            debug!("skipped {} existing records", xs.len() - n);
+        }
+
+        info!("appended {} records", n);
+        count_inserted(T::NAME, n);
+        Ok(())
+    }
+}
+
+/// The values of the key columns of a serialized record.
+fn key<T: Record>(x: &serde_json::Value) -> String {
+    let k: Vec<_> = T::KEY.iter().map(|c| &x[*c]).collect();
+    serde_json::to_string(&k).unwrap()
+}
+
+fn load_lines<T: Record>(
+    path: &Path,
+) -> Result<HashMap<String, String>, Error> {
+    let f = match fs::File::open(path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(HashMap::new())
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut lines = HashMap::new();
+
+    for line in BufReader::new(f).lines() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        lines.insert(key::<T>(&serde_json::from_str(&line)?), line);
+    }
+
+    Ok(lines)
+}
+
+fn checkpoint_path(dir: &Path, c: &Checkpoint) -> PathBuf {
+    dir.join(format!("{}.json", c.name))
+}
+
+fn load_checkpoint<T: DeserializeOwned>(
+    dir: &Path,
+    c: &Checkpoint,
+) -> Result<HashMap<String, T>, Error> {
+    match fs::read(checkpoint_path(dir, c)) {
+        Ok(x) => Ok(serde_json::from_slice(&x)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            Ok(HashMap::new())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn save_checkpoint<T: Serialize>(
+    dir: &Path,
+    c: &Checkpoint,
+    xs: &HashMap<String, T>,
+) -> Result<(), Error> {
+    // Write to a temporary file first, so that a crash midway
+    // never leaves a truncated checkpoint behind.
+    let path = checkpoint_path(dir, c);
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, serde_json::to_vec(xs)?)?;
+    fs::rename(tmp, path)?;
+    Ok(())
+}
+
+#[async_trait::async_trait]
+impl Store for Jsonl {
+    async fn insert_trades(&self, xs: &[Trade]) -> Result<(), Error> {
+        self.append(xs, false).await
+    }
+
+    async fn insert_funding(&self, xs: &[Funding]) -> Result<(), Error> {
+        self.append(xs, false).await
+    }
+
+    async fn insert_realized_pnl(
+        &self,
+        xs: &[RealizedPnl],
+    ) -> Result<(), Error> {
+        self.append(xs, false).await
+    }
+
+    async fn insert_liquidations(
+        &self,
+        xs: &[Liquidation],
+    ) -> Result<(), Error> {
+        self.append(xs, false).await
+    }
+
+    async fn insert_bankruptcies(
+        &self,
+        xs: &[Bankruptcy],
+    ) -> Result<(), Error> {
+        self.append(xs, false).await
+    }
+
+    async fn insert_balance_changes(
+        &self,
+        xs: &[BalanceChange],
+    ) -> Result<(), Error> {
+        self.append(xs, false).await
+    }
+
+    async fn insert_swaps(&self, xs: &[Swap]) -> Result<(), Error> {
+        self.append(xs, false).await
+    }
+
+    async fn insert_open_interest(
+        &self,
+        x: &OpenInterest,
+    ) -> Result<(), Error> {
+        self.append(std::slice::from_ref(x), false).await
+    }
+
+    async fn upsert_mark_twap(&self, x: &MarkTwap) -> Result<(), Error> {
+        self.append(std::slice::from_ref(x), true).await
+    }
+
+    async fn event_queue_seq_num(
+        &self,
+        symbol: &str,
+    ) -> Result<Option<i64>, Error> {
+        let symbol = symbol.to_string();
+        self.run(move |inner| Ok(inner.seq_nums.get(&symbol).copied()))
+            .await
+    }
+
+    async fn set_event_queue_seq_num(
+        &self,
+        symbol: &str,
+        seq_num: i64,
+    ) -> Result<(), Error> {
+        let symbol = symbol.to_string();
+
+        self.run(move |inner| {
+            inner.seq_nums.insert(symbol, seq_num);
+            save_checkpoint(&inner.dir, &EVENT_QUEUE, &inner.seq_nums)
+        })
+        .await
+    }
+
+    async fn backfill_cursor(
+        &self,
+        name: &str,
+    ) -> Result<Option<String>, Error> {
+        let name = name.to_string();
+        self.run(move |inner| Ok(inner.cursors.get(&name).cloned()))
+            .await
+    }
+
+    async fn set_backfill_cursor(
+        &self,
+        name: &str,
+        signature: &str,
+    ) -> Result<(), Error> {
+        let name = name.to_string();
+        let signature = signature.to_string();
+
+        self.run(move |inner| {
+            inner.cursors.insert(name, signature);
+            save_checkpoint(&inner.dir, &BACKFILL, &inner.cursors)
+        })
+        .await
+    }
+
+    async fn high_water_slot(&self) -> Result<Option<i64>, Error> {
+        self.run(|inner| Ok(inner.high_water.get(HIGH_WATER_KEY).copied()))
+            .await
+    }
+
+    async fn set_high_water_slot(&self, slot: i64) -> Result<(), Error> {
+        self.run(move |inner| {
+            inner.high_water.insert(HIGH_WATER_KEY.to_string(), slot);
+            save_checkpoint(&inner.dir, &HIGH_WATER, &inner.high_water)
+        })
+        .await
+    }
+}
@@ -0,0 +1,531 @@
+//! Storage for the records produced by the recorder. The backend is
+//! picked from the scheme of the store URL, see `connect`.
+
+mod jsonl;
+mod mongo;
+mod postgres;
+mod sql;
+mod sqlite;
+
+use crate::{
+    error::Error,
+    heartbeat::{self, Task},
+    metrics,
+};
+use serde::Serialize;
+use std::collections::HashMap;
+
+#[derive(Serialize)]
+pub struct Trade {
+    symbol: String,
+    time: i64,
+    price: f64,
+    side: String,
+    size: f64,
+    #[serde(rename = "isMaker")]
+    is_maker: bool,
+    control: String,
+    #[serde(rename = "orderId")]
+    order_id: String,
+    #[serde(rename = "seqNum")]
+    seq_num: i64,
+    slot: i64,
+}
+
+#[derive(Serialize)]
+pub struct Funding {
+    pub symbol: String,
+    #[serde(rename = "fundingIndex")]
+    pub funding_index: String,
+    #[serde(rename = "time")]
+    pub time: i64,
+}
+
+#[derive(Serialize)]
+pub struct RealizedPnl {
+    pub symbol: String,
+    pub sig: String,
+    pub margin: String,
+    #[serde(rename = "isLong")]
+    pub is_long: bool,
+    pub pnl: i64,
+    #[serde(rename = "qtyPaid")]
+    pub qty_paid: i64,
+    #[serde(rename = "qtyReceived")]
+    pub qty_received: i64,
+    pub time: i64,
+    pub slot: i64,
+}
+
+#[derive(Serialize)]
+pub struct Liquidation {
+    pub sig: String,
+    #[serde(rename = "liquidationEvent")]
+    pub liquidation_event: String,
+    #[serde(rename = "baseSymbol")]
+    pub base_symbol: String,
+    #[serde(rename = "quoteSymbol")]
+    pub quote_symbol: String,
+    #[serde(rename = "liqorMargin")]
+    pub liqor_margin: String,
+    #[serde(rename = "liqeeMargin")]
+    pub liqee_margin: String,
+    #[serde(rename = "assetsToLiqor")]
+    pub assets_to_liqor: i64,
+    #[serde(rename = "quoteToLiqor")]
+    pub quote_to_liqor: i64,
+    pub time: i64,
+    pub slot: i64,
+}
+
+#[derive(Serialize)]
+pub struct Bankruptcy {
+    pub sig: String,
+    #[serde(rename = "baseSymbol")]
+    pub base_symbol: String,
+    #[serde(rename = "liqorMargin")]
+    pub liqor_margin: String,
+    #[serde(rename = "liqeeMargin")]
+    pub liqee_margin: String,
+    #[serde(rename = "assetsToLiqor")]
+    pub assets_to_liqor: i64,
+    #[serde(rename = "quoteToLiqor")]
+    pub quote_to_liqor: i64,
+    #[serde(rename = "insuranceLoss")]
+    pub insurance_loss: i64,
+    #[serde(rename = "socializedLoss")]
+    pub socialized_loss: i64,
+    pub time: i64,
+    pub slot: i64,
+}
+
+#[derive(Serialize)]
+pub struct BalanceChange {
+    pub time: i64,
+    pub slot: i64,
+    pub sig: String,
+    pub margin: String,
+    pub symbol: String,
+    pub amount: i64,
+}
+
+#[derive(Serialize)]
+pub struct Swap {
+    pub time: i64,
+    pub slot: i64,
+    pub sig: String,
+    pub margin: String,
+    #[serde(rename = "baseSymbol")]
+    pub base_symbol: String,
+    #[serde(rename = "quoteSymbol")]
+    pub quote_symbol: String,
+    #[serde(rename = "baseDelta")]
+    pub base_delta: i64,
+    #[serde(rename = "quoteDelta")]
+    pub quote_delta: i64,
+}
+
+#[derive(Serialize)]
+pub struct OpenInterest {
+    pub time: i64,
+    pub values: HashMap<String, i64>,
+}
+
+#[derive(Serialize)]
+pub struct MarkTwap {
+    #[serde(rename = "lastSampleStartTime")]
+    pub last_sample_start_time: i64,
+    pub symbol: String,
+    pub twap: f64,
+}
+
+/// Destination for everything the recorder writes. Inserts skip
+/// records that already exist, so that the same transaction can be
+/// processed more than once, and every backend identifies records
+/// by the same `Record::KEY`.
+#[async_trait::async_trait]
+pub trait Store: Send + Sync {
+    async fn insert_trades(&self, xs: &[Trade]) -> Result<(), Error>;
+    async fn insert_funding(&self, xs: &[Funding]) -> Result<(), Error>;
+    async fn insert_realized_pnl(
+        &self,
+        xs: &[RealizedPnl],
+    ) -> Result<(), Error>;
+    async fn insert_liquidations(
+        &self,
+        xs: &[Liquidation],
+    ) -> Result<(), Error>;
+    async fn insert_bankruptcies(&self, xs: &[Bankruptcy])
+        -> Result<(), Error>;
+    async fn insert_balance_changes(
+        &self,
+        xs: &[BalanceChange],
+    ) -> Result<(), Error>;
+    async fn insert_swaps(&self, xs: &[Swap]) -> Result<(), Error>;
+    async fn insert_open_interest(&self, x: &OpenInterest)
+        -> Result<(), Error>;
+    async fn upsert_mark_twap(&self, x: &MarkTwap) -> Result<(), Error>;
+
+    /// The sequence number of the last event queue entry recorded
+    /// for the market, if any.
+    async fn event_queue_seq_num(
+        &self,
+        symbol: &str,
+    ) -> Result<Option<i64>, Error>;
+    async fn set_event_queue_seq_num(
+        &self,
+        symbol: &str,
+        seq_num: i64,
+    ) -> Result<(), Error>;
+
+    /// The oldest signature processed by the named backfill, which
+    /// is where the backfill resumes from.
+    async fn backfill_cursor(
+        &self,
+        name: &str,
+    ) -> Result<Option<String>, Error>;
+    async fn set_backfill_cursor(
+        &self,
+        name: &str,
+        signature: &str,
+    ) -> Result<(), Error>;
+
+    /// The slot up to which every transaction seen by the recorder
+    /// has been processed.
+    async fn high_water_slot(&self) -> Result<Option<i64>, Error>;
+    async fn set_high_water_slot(&self, slot: i64) -> Result<(), Error>;
+}
+
+/// Connects to the store at `url`. The backend is picked from the
+/// scheme:
+///
+/// - `mongodb://` or `mongodb+srv://`
+/// - `postgres://` or `postgresql://`
+/// - `sqlite://<path>`, or `sqlite::memory:`
+/// - `jsonl://<directory>`
+pub async fn connect(url: &str) -> Result<Box<dyn Store>, Error> {
+    let scheme = url.split_once(':').map(|(x, _)| x).unwrap_or_default();
+
+    Ok(match scheme {
+        "mongodb" | "mongodb+srv" => {
+            Box::new(mongo::Mongo::connect(url).await?)
+        }
+        "postgres" | "postgresql" => {
+            Box::new(postgres::Postgres::connect(url).await?)
+        }
+        "sqlite" => Box::new(sqlite::Sqlite::connect(url).await?),
+        "jsonl" => Box::new(jsonl::Jsonl::connect(url)?),
+        _ => return Err(Error::UnsupportedStore(scheme.to_string())),
+    })
+}
+
+/// Key of the `DbWrite` heartbeat, beaten on every write.
+pub const HEARTBEAT: &str = "store";
+
+/// Counts the records a backend wrote to `collection`.
+fn count_inserted(collection: &str, n: usize) {
+    metrics::RECORDER_INSERTED
+        .with_label_values(&[collection])
+        .inc_by(n as u64);
+    heartbeat::beat(Task::DbWrite, HEARTBEAT);
+}
+
+/// SQL column types, mapped to the closest type of each database.
+#[derive(Clone, Copy)]
+enum SqlType {
+    Text,
+    BigInt,
+    Double,
+    Bool,
+}
+
+enum Value {
+    Text(String),
+    BigInt(i64),
+    Double(f64),
+    Bool(bool),
+}
+
+impl From<&String> for Value {
+    fn from(x: &String) -> Self {
+        Self::Text(x.clone())
+    }
+}
+
+impl From<&i64> for Value {
+    fn from(x: &i64) -> Self {
+        Self::BigInt(*x)
+    }
+}
+
+impl From<&f64> for Value {
+    fn from(x: &f64) -> Self {
+        Self::Double(*x)
+    }
+}
+
+impl From<&bool> for Value {
+    fn from(x: &bool) -> Self {
+        Self::Bool(*x)
+    }
+}
+
+impl From<&HashMap<String, i64>> for Value {
+    fn from(x: &HashMap<String, i64>) -> Self {
+        Self::Text(serde_json::to_string(x).unwrap())
+    }
+}
+
+/// Layout of a record in the table oriented stores. Names are the
+/// same as the serialized field names, so that every store agrees.
+trait Record: Serialize + Send + Sync {
+    /// The table, collection or file the record is written to.
+    const NAME: &'static str;
+    const COLUMNS: &'static [(&'static str, SqlType)];
+    /// The columns that uniquely identify a record.
+    const KEY: &'static [&'static str];
+
+    /// The value of each column, in the order of `COLUMNS`.
+    fn values(&self) -> Vec<Value>;
+}
+
+macro_rules! record_impl {
+    { $(
+        $T:ty => $name:literal {
+            $( $field:ident: $ty:ident as $col:literal ),* $(,)?
+        } key [ $( $key:literal ),* $(,)? ]
+    )* } => {
+        $(
+            impl Record for $T {
+                const NAME: &'static str = $name;
+                const COLUMNS: &'static [(&'static str, SqlType)] =
+                    &[ $( ($col, SqlType::$ty) ),* ];
+                const KEY: &'static [&'static str] = &[ $( $key ),* ];
+
+                fn values(&self) -> Vec<Value> {
+                    vec![ $( Value::from(&self.$field) ),* ]
+                }
+            }
+        )*
+    }
+}
+
+record_impl! {
+    Trade => "trades" {
+        symbol: Text as "symbol",
+        time: BigInt as "time",
+        price: Double as "price",
+        side: Text as "side",
+        size: Double as "size",
+        is_maker: Bool as "isMaker",
+        control: Text as "control",
+        order_id: Text as "orderId",
+        seq_num: BigInt as "seqNum",
+        slot: BigInt as "slot",
+    } key ["symbol", "control", "orderId", "seqNum"]
+
+    Funding => "funding" {
+        symbol: Text as "symbol",
+        funding_index: Text as "fundingIndex",
+        time: BigInt as "time",
+    } key ["symbol", "time"]
+
+    RealizedPnl => "rpnl" {
+        symbol: Text as "symbol",
+        sig: Text as "sig",
+        margin: Text as "margin",
+        is_long: Bool as "isLong",
+        pnl: BigInt as "pnl",
+        qty_paid: BigInt as "qtyPaid",
+        qty_received: BigInt as "qtyReceived",
+        time: BigInt as "time",
+        slot: BigInt as "slot",
+    } key ["sig", "symbol", "margin", "pnl"]
+
+    Liquidation => "liq" {
+        sig: Text as "sig",
+        liquidation_event: Text as "liquidationEvent",
+        base_symbol: Text as "baseSymbol",
+        quote_symbol: Text as "quoteSymbol",
+        liqor_margin: Text as "liqorMargin",
+        liqee_margin: Text as "liqeeMargin",
+        assets_to_liqor: BigInt as "assetsToLiqor",
+        quote_to_liqor: BigInt as "quoteToLiqor",
+        time: BigInt as "time",
+        slot: BigInt as "slot",
+    } key ["sig", "liqeeMargin", "assetsToLiqor"]
+
+    Bankruptcy => "bank" {
+        sig: Text as "sig",
+        base_symbol: Text as "baseSymbol",
+        liqor_margin: Text as "liqorMargin",
+        liqee_margin: Text as "liqeeMargin",
+        assets_to_liqor: BigInt as "assetsToLiqor",
+        quote_to_liqor: BigInt as "quoteToLiqor",
+        insurance_loss: BigInt as "insuranceLoss",
+        socialized_loss: BigInt as "socializedLoss",
+        time: BigInt as "time",
+        slot: BigInt as "slot",
+    } key ["sig", "liqeeMargin", "assetsToLiqor"]
+
+    BalanceChange => "balanceChange" {
+        time: BigInt as "time",
+        slot: BigInt as "slot",
+        sig: Text as "sig",
+        margin: Text as "margin",
+        symbol: Text as "symbol",
+        amount: BigInt as "amount",
+    } key ["sig", "symbol", "margin", "amount"]
+
+    Swap => "swap" {
+        time: BigInt as "time",
+        slot: BigInt as "slot",
+        sig: Text as "sig",
+        margin: Text as "margin",
+        base_symbol: Text as "baseSymbol",
+        quote_symbol: Text as "quoteSymbol",
+        base_delta: BigInt as "baseDelta",
+        quote_delta: BigInt as "quoteDelta",
+    } key ["sig", "baseSymbol", "quoteSymbol", "baseDelta", "quoteDelta"]
+
+    OpenInterest => "oi" {
+        time: BigInt as "time",
+        values: Text as "values",
+    } key ["time"]
+
+    MarkTwap => "markTwap" {
+        last_sample_start_time: BigInt as "lastSampleStartTime",
+        symbol: Text as "symbol",
+        twap: Double as "twap",
+    } key ["lastSampleStartTime", "symbol"]
+}
+
+/// A table of values keyed by name, used to resume work across
+/// restarts.
+struct Checkpoint {
+    name: &'static str,
+    key: &'static str,
+    value: &'static str,
+    ty: SqlType,
+}
+
+/// The last sequence number recorded for each event queue.
+const EVENT_QUEUE: Checkpoint = Checkpoint {
+    name: "eventQueue",
+    key: "symbol",
+    value: "seqNum",
+    ty: SqlType::BigInt,
+};
+
+/// The oldest signature processed by each backfill.
+const BACKFILL: Checkpoint = Checkpoint {
+    name: "backfill",
+    key: "name",
+    value: "before",
+    ty: SqlType::Text,
+};
+
+/// The recorder's high-water slot, stored under `HIGH_WATER_KEY`.
+const HIGH_WATER: Checkpoint = Checkpoint {
+    name: "highWater",
+    key: "name",
+    value: "slot",
+    ty: SqlType::BigInt,
+};
+
+const HIGH_WATER_KEY: &str = "recorder";
+
+impl Trade {
+    #[tracing::instrument(
+        skip_all,
+        level = "error",
+        name = "update_trade",
+        fields(
+            symbol = symbol,
+            from = tracing::field::Empty,
+            to = tracing::field::Empty,
+        ),
+    )]
+    /// Records the fills in the event queue `buf`, observed at
+    /// `slot` whose block time is `time`.
+    pub async fn update(
+        db: &dyn Store,
+        symbol: &str,
+        base_decimals: u8,
+        quote_decimals: u8,
+        slot: u64,
+        time: i64,
+        buf: &[u8],
+    ) -> Result<(), Error> {
+        let base_mul = 10f64.powi(base_decimals as i32);
+        let quote_mul = 10f64.powi(quote_decimals as i32);
+
+        let last_seq_num = db.event_queue_seq_num(symbol).await?.unwrap_or(0);
+
+        let (trades, new_seq_num) =
+            zo_abi::dex::Event::deserialize_since(buf, last_seq_num as u64)
+                .unwrap();
+
+        let new_seq_num = new_seq_num as i64;
+
+        {
+            let span = tracing::Span::current();
+            span.record("last_seq_num", &last_seq_num);
+            span.record("new_seq_num", &new_seq_num);
+        }
+
+        let trades: Vec<_> = trades
+            .filter(|(_, e)| e.is_fill())
+            .map(|(seq_num, e)| {
+                let (side, price, size) = match e.is_bid() {
+                    true => {
+                        let price = match e.is_maker() {
+                            true => e.native_qty_paid + e.native_fee_or_rebate,
+                            false => e.native_qty_paid - e.native_fee_or_rebate,
+                        };
+                        let price = ((price as f64) * base_mul)
+                            / ((e.native_qty_released as f64) * quote_mul);
+                        let size = (e.native_qty_released as f64) / base_mul;
+
+                        ("buy", price, size)
+                    }
+                    false => {
+                        let price = match e.is_maker() {
+                            true => {
+                                e.native_qty_released - e.native_fee_or_rebate
+                            }
+                            false => {
+                                e.native_qty_released + e.native_fee_or_rebate
+                            }
+                        };
+                        let price = ((price as f64) * base_mul)
+                            / ((e.native_qty_paid as f64) * quote_mul);
+                        let size = (e.native_qty_paid as f64) / base_mul;
+
+                        ("sell", price, size)
+                    }
+                };
+
+                Self {
+                    symbol: symbol.to_string(),
+                    time,
+                    price,
+                    side: side.to_string(),
+                    size,
+                    is_maker: e.is_maker(),
+                    control: e.control.to_string(),
+                    order_id: format!("{:#x}", { e.order_id }),
+                    seq_num: seq_num as i64,
+                    slot: slot as i64,
+                }
+            })
+            .collect();
+
+        db.insert_trades(&trades).await?;
+
+        // Do this after inserting documents to ensure that
+        // the sequence number doesn't get updated with a
+        // failed insertion.
+        db.set_event_queue_seq_num(symbol, new_seq_num).await
+    }
+}
@@ -0,0 +1,308 @@
+use super::*;
+use mongodb::{
+    bson::{doc, Document},
+    error::{BulkWriteFailure, Error as MongoError, ErrorKind, WriteFailure},
+    options::{IndexOptions, InsertManyOptions, ReplaceOptions, UpdateOptions},
+    Collection, Database, IndexModel,
+};
+use tracing::{debug, info};
+
+#[cfg(not(feature = "devnet"))]
+static DB_NAME: &str = "keeper";
+
+#[cfg(feature = "devnet")]
+static DB_NAME: &str = "keeper-devnet";
+
+pub struct Mongo {
+    db: Database,
+}
+
+impl Mongo {
+    pub async fn connect(url: &str) -> Result<Self, Error> {
+        let db = mongodb::Client::with_uri_str(url).await?.database(DB_NAME);
+        Ok(Self { db })
+    }
+
+    async fn insert_simple<T: Record>(&self, xs: &[T]) -> Result<(), Error> {
+        let mut keys = Document::new();
+
+        for k in T::KEY {
+            keys.insert(*k, 1);
+        }
+
+        insert(
+            &self.db.collection::<T>(T::NAME),
+            xs,
+            [IndexModel::builder()
+                .keys(keys)
+                .options(IndexOptions::builder().unique(true).build())
+                .build()],
+        )
+        .await
+        .map_err(Into::into)
+    }
+}
+
+#[tracing::instrument(
+    skip_all,
+    level = "error",
+    fields(coll = c.name()),
+)]
+async fn insert<T, const N: usize>(
+    c: &Collection<T>,
+    xs: &[T],
+    indices: [IndexModel; N],
+) -> Result<(), MongoError>
+where
+    T: Serialize,
+{
+    if xs.is_empty() {
+        debug!("0 documents, skipping");
+        return Ok(());
+    }
+
+    if !indices.is_empty() {
+        c.create_indexes(indices, None).await?;
+    }
+
+    let res = c
+        .insert_many(
+            xs,
+            // > With unordered inserts, if an error occurs during an
+            // > insert of one of the documents, MongoDB continues to
+            // > insert the remaining documents in the array.
+            //
+            // https://docs.mongodb.com/v3.6/reference/method/db.collection.insert/#perform-an-unordered-insert
+            Some(InsertManyOptions::builder().ordered(false).build()),
+        )
+        .await;
+
+    match res {
+        Err(err) => {
+            match *err.kind {
+                // We want to skip any document that already exists. To
+                // do so, we match explicitly against "duplicate key"
+                // errors, which have the error code 11000. If every
+                // error is a duplicate key error, then the error is
+                // benign and canbe safely ignored.
+                ErrorKind::BulkWrite(BulkWriteFailure {
+                    write_errors: Some(ref es),
+                    ..
+                }) if es.iter().all(|e| e.code == 11000) => {
+                    // Here, we know any failures that occured are
+                    // because the document already exists in the DB.
+                    // Thus, we can get the total number of documents
+                    // inserted by subtracting out the "failed" inserts.
+                    info!("inserted {} documents", xs.len() - es.len());
+                    count_inserted(c.name(), xs.len() - es.len());
+                    Ok(())
+                }
+
+                _ => Err(err),
+            }
+        }
+        Ok(r) => {
+            info!("inserted {} documents", r.inserted_ids.len());
+            count_inserted(c.name(), r.inserted_ids.len());
+            Ok(())
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Store for Mongo {
+    async fn insert_trades(&self, xs: &[Trade]) -> Result<(), Error> {
+        insert(
+            &self.db.collection::<Trade>(Trade::NAME),
+            xs,
+            [
+                IndexModel::builder()
+                    .keys(doc! {
+                        "symbol": 1, "control": 1, "orderId": 1, "seqNum": 1
+                    })
+                    .options(IndexOptions::builder().unique(true).build())
+                    .build(),
+                IndexModel::builder().keys(doc! { "time": 1 }).build(),
+                IndexModel::builder().keys(doc! { "symbol": 1 }).build(),
+            ],
+        )
+        .await
+        .map_err(Into::into)
+    }
+
+    async fn insert_funding(&self, xs: &[Funding]) -> Result<(), Error> {
+        self.insert_simple(xs).await
+    }
+
+    async fn insert_realized_pnl(
+        &self,
+        xs: &[RealizedPnl],
+    ) -> Result<(), Error> {
+        self.insert_simple(xs).await
+    }
+
+    async fn insert_liquidations(
+        &self,
+        xs: &[Liquidation],
+    ) -> Result<(), Error> {
+        self.insert_simple(xs).await
+    }
+
+    async fn insert_bankruptcies(
+        &self,
+        xs: &[Bankruptcy],
+    ) -> Result<(), Error> {
+        self.insert_simple(xs).await
+    }
+
+    async fn insert_balance_changes(
+        &self,
+        xs: &[BalanceChange],
+    ) -> Result<(), Error> {
+        self.insert_simple(xs).await
+    }
+
+    async fn insert_swaps(&self, xs: &[Swap]) -> Result<(), Error> {
+        self.insert_simple(xs).await
+    }
+
+    async fn insert_open_interest(
+        &self,
+        x: &OpenInterest,
+    ) -> Result<(), Error> {
+        self.insert_simple(std::slice::from_ref(x)).await
+    }
+
+    #[tracing::instrument(
+        skip_all,
+        level = "error",
+        fields(coll = "markTwap", symbol = %x.symbol),
+    )]
+    async fn upsert_mark_twap(&self, x: &MarkTwap) -> Result<(), Error> {
+        let c = self.db.collection::<MarkTwap>(MarkTwap::NAME);
+
+        c.create_indexes(
+            [IndexModel::builder()
+                .keys(doc! { "lastSampleStartTime": 1, "symbol": 1 })
+                .options(IndexOptions::builder().unique(true).build())
+                .build()],
+            None,
+        )
+        .await?;
+
+        let r = c
+            .replace_one(
+                doc! {
+                    "lastSampleStartTime": x.last_sample_start_time,
+                    "symbol": &x.symbol,
+                },
+                x,
+                Some(ReplaceOptions::builder().upsert(true).build()),
+            )
+            .await;
+
+        match r {
+            Err(e) => match *e.kind {
+                ErrorKind::Write(WriteFailure::WriteError(e))
+                    if e.code == 11000 =>
+                {
+                    debug!("ignored due to duplicate key");
+                }
+                _ => return Err(e.into()),
+            },
+            Ok(r) => {
+                debug!("updated {} documents", r.modified_count);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn event_queue_seq_num(
+        &self,
+        symbol: &str,
+    ) -> Result<Option<i64>, Error> {
+        Ok(self
+            .db
+            .collection::<Document>(EVENT_QUEUE.name)
+            .find_one(None, None)
+            .await?
+            .and_then(|doc| doc.get_i64(symbol).ok()))
+    }
+
+    async fn set_event_queue_seq_num(
+        &self,
+        symbol: &str,
+        seq_num: i64,
+    ) -> Result<(), Error> {
+        self.db
+            .collection::<Document>(EVENT_QUEUE.name)
+            .update_one(
+                doc! {},
+                doc! { "$set": { symbol: seq_num } },
+                Some(UpdateOptions::builder().upsert(true).build()),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn backfill_cursor(
+        &self,
+        name: &str,
+    ) -> Result<Option<String>, Error> {
+        let Checkpoint { key, value, .. } = BACKFILL;
+
+        Ok(self
+            .db
+            .collection::<Document>(BACKFILL.name)
+            .find_one(doc! { key: name }, None)
+            .await?
+            .and_then(|doc| doc.get_str(value).ok().map(Into::into)))
+    }
+
+    async fn set_backfill_cursor(
+        &self,
+        name: &str,
+        signature: &str,
+    ) -> Result<(), Error> {
+        let Checkpoint { key, value, .. } = BACKFILL;
+
+        self.db
+            .collection::<Document>(BACKFILL.name)
+            .update_one(
+                doc! { key: name },
+                doc! { "$set": { value: signature } },
+                Some(UpdateOptions::builder().upsert(true).build()),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn high_water_slot(&self) -> Result<Option<i64>, Error> {
+        let Checkpoint { key, value, .. } = HIGH_WATER;
+
+        Ok(self
+            .db
+            .collection::<Document>(HIGH_WATER.name)
+            .find_one(doc! { key: HIGH_WATER_KEY }, None)
+            .await?
+            .and_then(|doc| doc.get_i64(value).ok()))
+    }
+
+    async fn set_high_water_slot(&self, slot: i64) -> Result<(), Error> {
+        let Checkpoint { key, value, .. } = HIGH_WATER;
+
+        self.db
+            .collection::<Document>(HIGH_WATER.name)
+            .update_one(
+                doc! { key: HIGH_WATER_KEY },
+                doc! { "$set": { value: slot } },
+                Some(UpdateOptions::builder().upsert(true).build()),
+            )
+            .await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,218 @@
+use super::{sql::POSTGRES, *};
+use tokio_postgres::{
+    types::{FromSql, ToSql},
+    Client, NoTls,
+};
+use tracing::{debug, info, warn};
+
+/// Postgres caps the number of parameters of a statement at 65535.
+const MAX_PARAMS: usize = 65535;
+
+pub struct Postgres {
+    client: Client,
+}
+
+impl Postgres {
+    pub async fn connect(url: &str) -> Result<Self, Error> {
+        let (client, conn) = tokio_postgres::connect(url, NoTls).await?;
+
+        tokio::spawn(async move {
+            if let Err(e) = conn.await {
+                warn!("postgres connection closed: {}", Error::from(e));
+            }
+        });
+
+        client
+            .batch_execute(
+                &[
+                    POSTGRES.create_table::<Trade>(),
+                    POSTGRES.create_table::<Funding>(),
+                    POSTGRES.create_table::<RealizedPnl>(),
+                    POSTGRES.create_table::<Liquidation>(),
+                    POSTGRES.create_table::<Bankruptcy>(),
+                    POSTGRES.create_table::<BalanceChange>(),
+                    POSTGRES.create_table::<Swap>(),
+                    POSTGRES.create_table::<OpenInterest>(),
+                    POSTGRES.create_table::<MarkTwap>(),
+                    POSTGRES.create_checkpoint(&EVENT_QUEUE),
+                    POSTGRES.create_checkpoint(&BACKFILL),
+                    POSTGRES.create_checkpoint(&HIGH_WATER),
+                ]
+                .join(";\n"),
+            )
+            .await?;
+
+        Ok(Self { client })
+    }
+
+    #[tracing::instrument(skip_all, level = "error", fields(table = T::NAME))]
+    async fn insert<T: Record>(&self, xs: &[T]) -> Result<(), Error> {
+        if xs.is_empty() {
+            debug!("0 rows, skipping");
+            return Ok(());
+        }
+
+        let mut inserted = 0;
+
+        for chunk in xs.chunks(MAX_PARAMS / T::COLUMNS.len()) {
+            let params = to_params(chunk.iter().flat_map(|x| x.values()));
+            inserted += self
+                .client
+                .execute(
+                    POSTGRES.insert::<T>(chunk.len()).as_str(),
+                    &refs(&params),
+                )
+                .await?;
+        }
+
+        info!("inserted {} rows", inserted);
+        count_inserted(T::NAME, inserted as usize);
+        Ok(())
+    }
+}
+
+impl Postgres {
+    async fn checkpoint<T>(
+        &self,
+        c: &Checkpoint,
+        key: &str,
+    ) -> Result<Option<T>, Error>
+    where
+        T: for<'a> FromSql<'a>,
+    {
+        Ok(self
+            .client
+            .query_opt(POSTGRES.select_checkpoint(c).as_str(), &[&key])
+            .await?
+            .map(|r| r.get(0)))
+    }
+
+    async fn set_checkpoint(
+        &self,
+        c: &Checkpoint,
+        key: &str,
+        value: &(dyn ToSql + Sync),
+    ) -> Result<(), Error> {
+        self.client
+            .execute(POSTGRES.upsert_checkpoint(c).as_str(), &[&key, value])
+            .await?;
+        Ok(())
+    }
+}
+
+type Param = Box<dyn ToSql + Sync + Send>;
+
+fn to_params(xs: impl Iterator<Item = Value>) -> Vec<Param> {
+    xs.map(|x| -> Param {
+        match x {
+            Value::Text(x) => Box::new(x),
+            Value::BigInt(x) => Box::new(x),
+            Value::Double(x) => Box::new(x),
+            Value::Bool(x) => Box::new(x),
+        }
+    })
+    .collect()
+}
+
+fn refs(xs: &[Param]) -> Vec<&(dyn ToSql + Sync)> {
+    xs.iter()
+        .map(|x| x.as_ref() as &(dyn ToSql + Sync))
+        .collect()
+}
+
+#[async_trait::async_trait]
+impl Store for Postgres {
+    async fn insert_trades(&self, xs: &[Trade]) -> Result<(), Error> {
+        self.insert(xs).await
+    }
+
+    async fn insert_funding(&self, xs: &[Funding]) -> Result<(), Error> {
+        self.insert(xs).await
+    }
+
+    async fn insert_realized_pnl(
+        &self,
+        xs: &[RealizedPnl],
+    ) -> Result<(), Error> {
+        self.insert(xs).await
+    }
+
+    async fn insert_liquidations(
+        &self,
+        xs: &[Liquidation],
+    ) -> Result<(), Error> {
+        self.insert(xs).await
+    }
+
+    async fn insert_bankruptcies(
+        &self,
+        xs: &[Bankruptcy],
+    ) -> Result<(), Error> {
+        self.insert(xs).await
+    }
+
+    async fn insert_balance_changes(
+        &self,
+        xs: &[BalanceChange],
+    ) -> Result<(), Error> {
+        self.insert(xs).await
+    }
+
+    async fn insert_swaps(&self, xs: &[Swap]) -> Result<(), Error> {
+        self.insert(xs).await
+    }
+
+    async fn insert_open_interest(
+        &self,
+        x: &OpenInterest,
+    ) -> Result<(), Error> {
+        self.insert(std::slice::from_ref(x)).await
+    }
+
+    async fn upsert_mark_twap(&self, x: &MarkTwap) -> Result<(), Error> {
+        let params = to_params(x.values().into_iter());
+        self.client
+            .execute(POSTGRES.upsert::<MarkTwap>().as_str(), &refs(&params))
+            .await?;
+        Ok(())
+    }
+
+    async fn event_queue_seq_num(
+        &self,
+        symbol: &str,
+    ) -> Result<Option<i64>, Error> {
+        self.checkpoint(&EVENT_QUEUE, symbol).await
+    }
+
+    async fn set_event_queue_seq_num(
+        &self,
+        symbol: &str,
+        seq_num: i64,
+    ) -> Result<(), Error> {
+        self.set_checkpoint(&EVENT_QUEUE, symbol, &seq_num).await
+    }
+
+    async fn backfill_cursor(
+        &self,
+        name: &str,
+    ) -> Result<Option<String>, Error> {
+        self.checkpoint(&BACKFILL, name).await
+    }
+
+    async fn set_backfill_cursor(
+        &self,
+        name: &str,
+        signature: &str,
+    ) -> Result<(), Error> {
+        self.set_checkpoint(&BACKFILL, name, &signature).await
+    }
+
+    async fn high_water_slot(&self) -> Result<Option<i64>, Error> {
+        self.checkpoint(&HIGH_WATER, HIGH_WATER_KEY).await
+    }
+
+    async fn set_high_water_slot(&self, slot: i64) -> Result<(), Error> {
+        self.set_checkpoint(&HIGH_WATER, HIGH_WATER_KEY, &slot)
+            .await
+    }
+}
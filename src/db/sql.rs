@@ -0,0 +1,144 @@
+//! SQL shared by the Postgres and SQLite stores. Identifiers are
+//! quoted, since the column names are camel case.
+
+use super::*;
+
+/// How a database spells its types and parameters.
+pub struct Dialect {
+    /// Prefix of numbered parameters, e.g. `$` for `$1`.
+    pub param: &'static str,
+    pub text: &'static str,
+    pub big_int: &'static str,
+    pub double: &'static str,
+    pub bool: &'static str,
+}
+
+pub const POSTGRES: Dialect = Dialect {
+    param: "$",
+    text: "TEXT",
+    big_int: "BIGINT",
+    double: "DOUBLE PRECISION",
+    bool: "BOOLEAN",
+};
+
+pub const SQLITE: Dialect = Dialect {
+    param: "?",
+    text: "TEXT",
+    big_int: "INTEGER",
+    double: "REAL",
+    bool: "BOOLEAN",
+};
+
+fn quote(x: &str) -> String {
+    format!("\"{}\"", x)
+}
+
+fn quote_all(xs: impl IntoIterator<Item = &'static str>) -> String {
+    xs.into_iter().map(quote).collect::<Vec<_>>().join(", ")
+}
+
+impl Dialect {
+    fn type_name(&self, t: SqlType) -> &'static str {
+        match t {
+            SqlType::Text => self.text,
+            SqlType::BigInt => self.big_int,
+            SqlType::Double => self.double,
+            SqlType::Bool => self.bool,
+        }
+    }
+
+    pub fn create_table<T: Record>(&self) -> String {
+        let cols = T::COLUMNS
+            .iter()
+            .map(|(c, t)| {
+                format!("{} {} NOT NULL", quote(c), self.type_name(*t))
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        format!(
+            "CREATE TABLE IF NOT EXISTS {} ({}, UNIQUE ({}))",
+            quote(T::NAME),
+            cols,
+            quote_all(T::KEY.iter().copied()),
+        )
+    }
+
+    pub fn create_checkpoint(&self, c: &Checkpoint) -> String {
+        format!(
+            "CREATE TABLE IF NOT EXISTS {} ({} {} PRIMARY KEY, {} {} NOT NULL)",
+            quote(c.name),
+            quote(c.key),
+            self.text,
+            quote(c.value),
+            self.type_name(c.ty),
+        )
+    }
+
+    /// Inserts `rows` records, skipping those whose key already exists.
+    /// Parameters are numbered row by row, starting from 1.
+    pub fn insert<T: Record>(&self, rows: usize) -> String {
+        format!(
+            "INSERT INTO {} ({}) VALUES {} ON CONFLICT DO NOTHING",
+            quote(T::NAME),
+            quote_all(T::COLUMNS.iter().map(|(c, _)| *c)),
+            self.values::<T>(rows),
+        )
+    }
+
+    /// Inserts a single record, replacing the one with the same key.
+    pub fn upsert<T: Record>(&self) -> String {
+        let set = T::COLUMNS
+            .iter()
+            .map(|(c, _)| *c)
+            .filter(|c| !T::KEY.contains(c))
+            .map(|c| format!("{0} = excluded.{0}", quote(c)))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        format!(
+            "INSERT INTO {} ({}) VALUES {} ON CONFLICT ({}) DO UPDATE SET {}",
+            quote(T::NAME),
+            quote_all(T::COLUMNS.iter().map(|(c, _)| *c)),
+            self.values::<T>(1),
+            quote_all(T::KEY.iter().copied()),
+            set,
+        )
+    }
+
+    pub fn select_checkpoint(&self, c: &Checkpoint) -> String {
+        format!(
+            "SELECT {} FROM {} WHERE {} = {}1",
+            quote(c.value),
+            quote(c.name),
+            quote(c.key),
+            self.param,
+        )
+    }
+
+    pub fn upsert_checkpoint(&self, c: &Checkpoint) -> String {
+        format!(
+            "INSERT INTO {0} ({1}, {2}) VALUES ({3}1, {3}2) \
+            ON CONFLICT ({1}) DO UPDATE SET {2} = excluded.{2}",
+            quote(c.name),
+            quote(c.key),
+            quote(c.value),
+            self.param,
+        )
+    }
+
+    fn values<T: Record>(&self, rows: usize) -> String {
+        let n = T::COLUMNS.len();
+
+        (0..rows)
+            .map(|r| {
+                let ps = (1..=n)
+                    .map(|i| format!("{}{}", self.param, r * n + i))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                format!("({})", ps)
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
@@ -0,0 +1,252 @@
+use super::{sql::SQLITE, *};
+use rusqlite::{
+    params_from_iter,
+    types::{FromSql, Value as SqlValue},
+    Connection, OptionalExtension,
+};
+use std::sync::{Arc, Mutex};
+use tracing::{debug, info};
+
+pub struct Sqlite {
+    // rusqlite is blocking, so every statement runs on the blocking
+    // thread pool, one at a time.
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl Sqlite {
+    pub async fn connect(url: &str) -> Result<Self, Error> {
+        let path = url
+            .strip_prefix("sqlite://")
+            .or_else(|| url.strip_prefix("sqlite:"))
+            .unwrap_or_default();
+
+        let conn = match path {
+            "" | ":memory:" => Connection::open_in_memory()?,
+            p => Connection::open(p)?,
+        };
+
+        conn.execute_batch(
+            &[
+                SQLITE.create_table::<Trade>(),
+                SQLITE.create_table::<Funding>(),
+                SQLITE.create_table::<RealizedPnl>(),
+                SQLITE.create_table::<Liquidation>(),
+                SQLITE.create_table::<Bankruptcy>(),
+                SQLITE.create_table::<BalanceChange>(),
+                SQLITE.create_table::<Swap>(),
+                SQLITE.create_table::<OpenInterest>(),
+                SQLITE.create_table::<MarkTwap>(),
+                SQLITE.create_checkpoint(&EVENT_QUEUE),
+                SQLITE.create_checkpoint(&BACKFILL),
+                SQLITE.create_checkpoint(&HIGH_WATER),
+            ]
+            .join(";\n"),
+        )?;
+
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    async fn run<R, F>(&self, f: F) -> Result<R, Error>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<R, rusqlite::Error>
+            + Send
+            + 'static,
+    {
+        let conn = self.conn.clone();
+
+        tokio::task::spawn_blocking(move || f(&mut conn.lock().unwrap()))
+            .await
+            .unwrap()
+            .map_err(Into::into)
+    }
+
+    #[tracing::instrument(skip_all, level = "error", fields(table = T::NAME))]
+    async fn insert<T: Record>(&self, xs: &[T]) -> Result<(), Error> {
+        if xs.is_empty() {
+            debug!("0 rows, skipping");
+            return Ok(());
+        }
+
+        let sql = SQLITE.insert::<T>(1);
+        let rows: Vec<_> = xs.iter().map(Record::values).collect();
+
+        let inserted = self
+            .run(move |c| {
+                let tx = c.transaction()?;
+                let mut n = 0;
+
+                {
+                    let mut stmt = tx.prepare_cached(&sql)?;
+                    for r in rows {
+                        n += stmt.execute(params_from_iter(
+                            r.into_iter().map(to_sql_value),
+                        ))?;
+                    }
+                }
+
+                tx.commit()?;
+                Ok(n)
+            })
+            .await?;
+
+        info!("inserted {} rows", inserted);
+        count_inserted(T::NAME, inserted);
+        Ok(())
+    }
+}
+
+impl Sqlite {
+    async fn checkpoint<T>(
+        &self,
+        c: &'static Checkpoint,
+        key: &str,
+    ) -> Result<Option<T>, Error>
+    where
+        T: FromSql + Send + 'static,
+    {
+        let key = key.to_string();
+
+        self.run(move |conn| {
+            conn.query_row(&SQLITE.select_checkpoint(c), [key], |r| r.get(0))
+                .optional()
+        })
+        .await
+    }
+
+    async fn set_checkpoint(
+        &self,
+        c: &'static Checkpoint,
+        key: &str,
+        value: SqlValue,
+    ) -> Result<(), Error> {
+        let key = SqlValue::Text(key.to_string());
+
+        self.run(move |conn| {
+            conn.execute(&SQLITE.upsert_checkpoint(c), [key, value])
+        })
+        .await?;
+
+        Ok(())
+    }
+}
+
+fn to_sql_value(x: Value) -> SqlValue {
+    match x {
+        Value::Text(x) => SqlValue::Text(x),
+        Value::BigInt(x) => SqlValue::Integer(x),
+        Value::Double(x) => SqlValue::Real(x),
+        Value::Bool(x) => SqlValue::Integer(x as i64),
+    }
+}
+
+#[async_trait::async_trait]
+impl Store for Sqlite {
+    async fn insert_trades(&self, xs: &[Trade]) -> Result<(), Error> {
+        self.insert(xs).await
+    }
+
+    async fn insert_funding(&self, xs: &[Funding]) -> Result<(), Error> {
+        self.insert(xs).await
+    }
+
+    async fn insert_realized_pnl(
+        &self,
+        xs: &[RealizedPnl],
+    ) -> Result<(), Error> {
+        self.insert(xs).await
+    }
+
+    async fn insert_liquidations(
+        &self,
+        xs: &[Liquidation],
+    ) -> Result<(), Error> {
+        self.insert(xs).await
+    }
+
+    async fn insert_bankruptcies(
+        &self,
+        xs: &[Bankruptcy],
+    ) -> Result<(), Error> {
+        self.insert(xs).await
+    }
+
+    async fn insert_balance_changes(
+        &self,
+        xs: &[BalanceChange],
+    ) -> Result<(), Error> {
+        self.insert(xs).await
+    }
+
+    async fn insert_swaps(&self, xs: &[Swap]) -> Result<(), Error> {
+        self.insert(xs).await
+    }
+
+    async fn insert_open_interest(
+        &self,
+        x: &OpenInterest,
+    ) -> Result<(), Error> {
+        self.insert(std::slice::from_ref(x)).await
+    }
+
+    async fn upsert_mark_twap(&self, x: &MarkTwap) -> Result<(), Error> {
+        let row = x.values();
+
+        self.run(move |c| {
+            c.execute(
+                &SQLITE.upsert::<MarkTwap>(),
+                params_from_iter(row.into_iter().map(to_sql_value)),
+            )
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    async fn event_queue_seq_num(
+        &self,
+        symbol: &str,
+    ) -> Result<Option<i64>, Error> {
+        self.checkpoint(&EVENT_QUEUE, symbol).await
+    }
+
+    async fn set_event_queue_seq_num(
+        &self,
+        symbol: &str,
+        seq_num: i64,
+    ) -> Result<(), Error> {
+        self.set_checkpoint(&EVENT_QUEUE, symbol, SqlValue::Integer(seq_num))
+            .await
+    }
+
+    async fn backfill_cursor(
+        &self,
+        name: &str,
+    ) -> Result<Option<String>, Error> {
+        self.checkpoint(&BACKFILL, name).await
+    }
+
+    async fn set_backfill_cursor(
+        &self,
+        name: &str,
+        signature: &str,
+    ) -> Result<(), Error> {
+        let signature = SqlValue::Text(signature.to_string());
+        self.set_checkpoint(&BACKFILL, name, signature).await
+    }
+
+    async fn high_water_slot(&self) -> Result<Option<i64>, Error> {
+        self.checkpoint(&HIGH_WATER, HIGH_WATER_KEY).await
+    }
+
+    async fn set_high_water_slot(&self, slot: i64) -> Result<(), Error> {
+        self.set_checkpoint(
+            &HIGH_WATER,
+            HIGH_WATER_KEY,
+            SqlValue::Integer(slot),
+        )
+        .await
+    }
+}
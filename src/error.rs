@@ -4,6 +4,14 @@ pub enum Error {
     OraclesSkipped(Vec<String>),
     #[error("Failed to confirm: {0}")]
     ConfirmationTimeout(anchor_client::solana_sdk::signature::Signature),
+    #[error("Unsupported store scheme `{0}`")]
+    UnsupportedStore(String),
+    #[error("Invalid configuration: {0}")]
+    Config(String),
+    #[error("Invalid account {0}")]
+    InvalidAccount(anchor_client::solana_sdk::pubkey::Pubkey),
+    #[error("Unknown market {0}")]
+    UnknownMarket(anchor_client::solana_sdk::pubkey::Pubkey),
 
     // Library errors
     #[error("{0}: {0:?}")]
@@ -19,5 +27,15 @@ pub enum Error {
     #[error("{0}")]
     Db(#[from] mongodb::error::Error),
     #[error("{0}")]
+    Postgres(#[from] tokio_postgres::Error),
+    #[error("{0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("{0}")]
+    Hyper(#[from] hyper::Error),
+    #[error("{0}")]
+    Io(#[from] std::io::Error),
+    #[error("{0}")]
+    Json(#[from] serde_json::Error),
+    #[error("{0}")]
     Var(#[from] std::env::VarError),
 }
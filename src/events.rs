@@ -2,30 +2,37 @@
 
 use crate::{db, AppState, Error};
 use anchor_client::anchor_lang::Event;
-use futures::TryFutureExt;
-use std::time::SystemTime;
 use tracing::warn;
 use zo_abi::events;
 
+/// Records the events logged by a transaction. `block_time` is
+/// resolved from `slot` when the caller does not have it. Fails if
+/// the block time is unknown, or if any of the records could not be
+/// parsed or stored, in which case the whole transaction should be
+/// processed again.
 #[tracing::instrument(skip_all, level = "error")]
 pub async fn process(
-    st: &AppState,
-    db: &mongodb::Database,
+    st: &'static AppState,
+    db: &dyn db::Store,
     ss: Vec<String>,
     sig: String,
-) {
-    let (rpnl, liq, bank, bal, swap, oracle) = parse(st, ss.iter(), sig);
-
-    let on_err = |e| {
-        let e = Error::from(e);
-        warn!("{}", e);
+    slot: u64,
+    block_time: Option<i64>,
+) -> Result<(), Error> {
+    let time = match block_time {
+        Some(t) => t,
+        None => st.block_time(slot).await?,
     };
-    let _ = futures::join!(
-        db::RealizedPnl::update(db, &rpnl).map_err(on_err),
-        db::Liquidation::update(db, &liq).map_err(on_err),
-        db::Bankruptcy::update(db, &bank).map_err(on_err),
-        db::BalanceChange::update(db, &bal).map_err(on_err),
-        db::Swap::update(db, &swap).map_err(on_err),
+
+    let (rpnl, liq, bank, bal, swap, oracle) =
+        parse(st, ss.iter(), sig, slot as i64, time)?;
+
+    let res = futures::join!(
+        db.insert_realized_pnl(&rpnl),
+        db.insert_liquidations(&liq),
+        db.insert_bankruptcies(&bank),
+        db.insert_balance_changes(&bal),
+        db.insert_swaps(&swap),
     );
 
     match oracle {
@@ -35,26 +42,34 @@ pub async fn process(
         }
         _ => {}
     }
+
+    res.0.and(res.1).and(res.2).and(res.3).and(res.4)
 }
 
 fn parse<'a>(
     st: &AppState,
     logs: impl Iterator<Item = &'a String> + 'a,
     sig: String,
-) -> (
-    Vec<db::RealizedPnl>,
-    Vec<db::Liquidation>,
-    Vec<db::Bankruptcy>,
-    Vec<db::BalanceChange>,
-    Vec<db::Swap>,
-    Option<events::CacheOracleNoops>,
-) {
+    slot: i64,
+    time: i64,
+) -> Result<
+    (
+        Vec<db::RealizedPnl>,
+        Vec<db::Liquidation>,
+        Vec<db::Bankruptcy>,
+        Vec<db::BalanceChange>,
+        Vec<db::Swap>,
+        Option<events::CacheOracleNoops>,
+    ),
+    Error,
+> {
     const PROG_LOG_PREFIX: &str = "Program log: ";
 
     let prog_start_str = format!("Program {} invoke", zo_abi::ID);
     let prog_end_str = format!("Program {} success", zo_abi::ID);
 
     let mut is_zo_log = false;
+    let state = st.zo_state();
 
     let mut rpnl = Vec::new();
     let mut liq = Vec::new();
@@ -63,11 +78,6 @@ fn parse<'a>(
     let mut swap = Vec::new();
     let mut oracle = None;
 
-    let time = SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .unwrap()
-        .as_secs() as i64;
-
     for l in logs {
         if !is_zo_log {
             is_zo_log = l.starts_with(&prog_start_str);
@@ -95,12 +105,16 @@ fn parse<'a>(
                 continue;
             }
 
-            let symbol = st
+            // The market may have been listed after the last State
+            // update was received, in which case the transaction is
+            // retried once the State catches up.
+            let symbol = match st
                 .iter_markets()
                 .find(|x| x.dex_market == e.market_key)
-                .unwrap()
-                .symbol
-                .into();
+            {
+                Some(m) => m.symbol.into(),
+                None => return Err(Error::UnknownMarket(e.market_key)),
+            };
 
             rpnl.push(db::RealizedPnl {
                 symbol,
@@ -111,6 +125,7 @@ fn parse<'a>(
                 qty_paid: e.qty_paid,
                 qty_received: e.qty_received,
                 time,
+                slot,
             });
 
             continue;
@@ -127,6 +142,7 @@ fn parse<'a>(
                 assets_to_liqor: e.assets_to_liqor,
                 quote_to_liqor: e.quote_to_liqor,
                 time,
+                slot,
             });
 
             continue;
@@ -143,6 +159,7 @@ fn parse<'a>(
                 insurance_loss: e.insurance_loss,
                 socialized_loss: e.socialized_loss,
                 time,
+                slot,
             });
 
             continue;
@@ -151,9 +168,10 @@ fn parse<'a>(
         if let Some(e) = load::<events::DepositLog>(&bytes) {
             bal.push(db::BalanceChange {
                 time,
+                slot,
                 sig: sig.clone(),
                 margin: e.margin_key.to_string(),
-                symbol: st.zo_state.collaterals[e.col_index as usize]
+                symbol: state.collaterals[e.col_index as usize]
                     .oracle_symbol
                     .into(),
                 amount: e.deposit_amount as i64,
@@ -163,9 +181,10 @@ fn parse<'a>(
         if let Some(e) = load::<events::WithdrawLog>(&bytes) {
             bal.push(db::BalanceChange {
                 time,
+                slot,
                 sig: sig.clone(),
                 margin: e.margin_key.to_string(),
-                symbol: st.zo_state.collaterals[e.col_index as usize]
+                symbol: state.collaterals[e.col_index as usize]
                     .oracle_symbol
                     .into(),
                 amount: -(e.withdraw_amount as i64),
@@ -175,12 +194,13 @@ fn parse<'a>(
         if let Some(e) = load::<events::SwapLog>(&bytes) {
             swap.push(db::Swap {
                 time,
+                slot,
                 sig: sig.clone(),
                 margin: e.margin_key.to_string(),
-                base_symbol: st.zo_state.collaterals[e.base_index as usize]
+                base_symbol: state.collaterals[e.base_index as usize]
                     .oracle_symbol
                     .into(),
-                quote_symbol: st.zo_state.collaterals[e.quote_index as usize]
+                quote_symbol: state.collaterals[e.quote_index as usize]
                     .oracle_symbol
                     .into(),
                 base_delta: e.base_delta,
@@ -193,7 +213,7 @@ fn parse<'a>(
         }
     }
 
-    (rpnl, liq, bank, bal, swap, oracle)
+    Ok((rpnl, liq, bank, bal, swap, oracle))
 }
 
 #[inline(always)]
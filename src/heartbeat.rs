@@ -0,0 +1,209 @@
+//! Heartbeats of the long running tasks. A task registers itself and
+//! beats whenever it makes progress, so that one which is wedged, e.g.
+//! on a websocket that stopped sending, is reported by `/healthz`.
+
+use crate::{
+    config::{check, seconds},
+    error::Error,
+};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Task {
+    /// A crank instruction landed.
+    Crank,
+    /// An event queue was read.
+    EventQueue,
+    /// A websocket subscription got a message.
+    Listener,
+    /// Records were written to the store.
+    DbWrite,
+}
+
+impl fmt::Display for Task {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Task::Crank => "crank",
+            Task::EventQueue => "event_queue",
+            Task::Listener => "listener",
+            Task::DbWrite => "db_write",
+        })
+    }
+}
+
+/// How long each kind of task may go without a beat before it is
+/// considered stale.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct HealthConfig {
+    #[serde(with = "seconds")]
+    pub crank: Duration,
+    #[serde(with = "seconds")]
+    pub event_queue: Duration,
+    #[serde(with = "seconds")]
+    pub listener: Duration,
+    #[serde(with = "seconds")]
+    pub db_write: Duration,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            crank: Duration::from_secs(60),
+            event_queue: Duration::from_secs(300),
+            listener: Duration::from_secs(60),
+            db_write: Duration::from_secs(900),
+        }
+    }
+}
+
+impl HealthConfig {
+    pub fn validate(&self) -> Result<(), Error> {
+        check(
+            [self.crank, self.event_queue, self.listener, self.db_write]
+                .iter()
+                .all(|x| !x.is_zero()),
+            "health thresholds must be positive",
+        )
+    }
+
+    fn threshold(&self, task: Task) -> Duration {
+        match task {
+            Task::Crank => self.crank,
+            Task::EventQueue => self.event_queue,
+            Task::Listener => self.listener,
+            Task::DbWrite => self.db_write,
+        }
+    }
+}
+
+struct Entry {
+    // Registrations of the same key, which is removed with the last.
+    refs: usize,
+    last: Instant,
+    beaten: bool,
+}
+
+static ENTRIES: Lazy<Mutex<HashMap<(Task, String), Entry>>> =
+    Lazy::new(Default::default);
+
+/// A registered task, removed when dropped, e.g. when the worker of
+/// a delisted market is aborted.
+pub struct Heartbeat {
+    task: Task,
+    key: String,
+}
+
+impl Heartbeat {
+    /// Registers the task. It starts out fresh, so it has its whole
+    /// threshold to beat for the first time.
+    pub fn new(task: Task, key: impl Into<String>) -> Self {
+        let key = key.into();
+        let mut entries = ENTRIES.lock().unwrap();
+        let e = entries.entry((task, key.clone())).or_insert(Entry {
+            refs: 0,
+            last: Instant::now(),
+            beaten: false,
+        });
+        e.refs += 1;
+
+        Self { task, key }
+    }
+
+    pub fn beat(&self) {
+        beat(self.task, &self.key);
+    }
+}
+
+impl Drop for Heartbeat {
+    fn drop(&mut self) {
+        let mut entries = ENTRIES.lock().unwrap();
+        let k = (self.task, std::mem::take(&mut self.key));
+
+        if let Some(e) = entries.get_mut(&k) {
+            e.refs -= 1;
+            if e.refs == 0 {
+                entries.remove(&k);
+            }
+        }
+    }
+}
+
+/// Beats the task registered under `key`, if any. For code shared by
+/// several keepers, where only some of them register the task.
+pub fn beat(task: Task, key: &str) {
+    let mut entries = ENTRIES.lock().unwrap();
+
+    if let Some(e) = entries.get_mut(&(task, key.to_string())) {
+        e.last = Instant::now();
+        e.beaten = true;
+    }
+}
+
+pub struct Report {
+    /// The tasks past their threshold, with their time since the
+    /// last beat.
+    pub stale: Vec<(Task, String, Duration)>,
+    /// The tasks that never beat yet.
+    pub pending: Vec<(Task, String)>,
+    pub total: usize,
+}
+
+impl Report {
+    pub fn is_healthy(&self) -> bool {
+        self.stale.is_empty()
+    }
+
+    /// Every task made progress at least once, and none is stale.
+    pub fn is_ready(&self) -> bool {
+        self.total > 0 && self.pending.is_empty() && self.is_healthy()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} tasks", self.total)?;
+
+        for (task, key, age) in &self.stale {
+            writeln!(f, "stale: {} {} ({}s)", task, key, age.as_secs())?;
+        }
+
+        for (task, key) in &self.pending {
+            writeln!(f, "pending: {} {}", task, key)?;
+        }
+
+        Ok(())
+    }
+}
+
+pub fn check(cfg: &HealthConfig) -> Report {
+    let entries = ENTRIES.lock().unwrap();
+    let mut stale = Vec::new();
+    let mut pending = Vec::new();
+
+    for ((task, key), e) in entries.iter() {
+        let age = e.last.elapsed();
+
+        if age > cfg.threshold(*task) {
+            stale.push((*task, key.clone(), age));
+        } else if !e.beaten {
+            pending.push((*task, key.clone()));
+        }
+    }
+
+    stale.sort();
+    pending.sort();
+
+    Report {
+        stale,
+        pending,
+        total: entries.len(),
+    }
+}
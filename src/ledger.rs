@@ -0,0 +1,168 @@
+//! Bookkeeping of the transaction signatures seen by the recorder, so
+//! that each one is processed exactly once no matter how many sources
+//! report it.
+
+use lru::LruCache;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    sync::Mutex,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    /// Waiting in the processing queue.
+    Queued,
+    Processing,
+    /// Failed this many times, waiting to be retried.
+    Retrying(u32),
+    Done,
+    /// Failed too many times and was given up on. The high-water
+    /// slot stays below it, so that it is retried after a restart.
+    Failed,
+}
+
+struct Inner {
+    /// Signatures that have not finished yet, with their slot.
+    /// These are never evicted.
+    pending: HashMap<String, (u64, Status)>,
+    /// Number of pending signatures in each slot.
+    pending_slots: BTreeMap<u64, usize>,
+    /// Slots with a failed signature. These are never removed.
+    failed_slots: BTreeSet<u64>,
+    /// Recently finished signatures.
+    finished: LruCache<String, Status>,
+    /// Highest slot seen so far.
+    max_slot: u64,
+}
+
+pub struct Ledger {
+    inner: Mutex<Inner>,
+}
+
+impl Ledger {
+    /// Creates a ledger remembering up to `capacity` finished
+    /// signatures, where everything up to `slot` is already done.
+    pub fn new(capacity: usize, slot: u64) -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                pending: HashMap::new(),
+                pending_slots: BTreeMap::new(),
+                failed_slots: BTreeSet::new(),
+                finished: LruCache::new(capacity),
+                max_slot: slot,
+            }),
+        }
+    }
+
+    /// Marks the signature as queued. If it was already seen, returns
+    /// its status instead, and it must not be processed again.
+    pub fn admit(&self, sig: &str, slot: u64) -> Result<(), Status> {
+        let mut inner = self.inner.lock().unwrap();
+
+        if let Some((_, s)) = inner.pending.get(sig) {
+            return Err(*s);
+        }
+
+        if let Some(s) = inner.finished.get(sig) {
+            return Err(*s);
+        }
+
+        inner
+            .pending
+            .insert(sig.to_string(), (slot, Status::Queued));
+        *inner.pending_slots.entry(slot).or_default() += 1;
+        inner.max_slot = inner.max_slot.max(slot);
+        Ok(())
+    }
+
+    /// Updates the status of a pending signature. Finishing it with
+    /// `Done` or `Failed` moves it out of the pending set, and a
+    /// `Failed` one pins the high-water slot below its own.
+    pub fn set_status(&self, sig: &str, status: Status) {
+        let mut inner = self.inner.lock().unwrap();
+
+        match status {
+            Status::Done | Status::Failed => {
+                if let Some((slot, _)) = inner.pending.remove(sig) {
+                    if let Some(n) = inner.pending_slots.get_mut(&slot) {
+                        *n -= 1;
+                        if *n == 0 {
+                            inner.pending_slots.remove(&slot);
+                        }
+                    }
+
+                    if status == Status::Failed {
+                        inner.failed_slots.insert(slot);
+                    }
+                }
+                inner.finished.put(sig.to_string(), status);
+            }
+            _ => {
+                if let Some((_, s)) = inner.pending.get_mut(sig) {
+                    *s = status;
+                }
+            }
+        }
+    }
+
+    /// The slot up to which every signature seen is done.
+    pub fn high_water_slot(&self) -> u64 {
+        let inner = self.inner.lock().unwrap();
+        let pending = inner.pending_slots.keys().next();
+        let failed = inner.failed_slots.iter().next();
+
+        match pending.into_iter().chain(failed).min() {
+            Some(s) => s.saturating_sub(1),
+            None => inner.max_slot,
+        }
+    }
+
+    /// Number of signatures that have not finished yet.
+    pub fn pending(&self) -> usize {
+        self.inner.lock().unwrap().pending.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_high_water_slot() {
+        let ledger = Ledger::new(16, 10);
+        assert_eq!(ledger.high_water_slot(), 10);
+
+        ledger.admit("a", 12).unwrap();
+        ledger.admit("b", 15).unwrap();
+        ledger.admit("c", 20).unwrap();
+        assert_eq!(ledger.high_water_slot(), 11);
+
+        // Retrying is still pending.
+        ledger.set_status("a", Status::Retrying(1));
+        assert_eq!(ledger.high_water_slot(), 11);
+
+        ledger.set_status("a", Status::Done);
+        assert_eq!(ledger.high_water_slot(), 14);
+
+        // Failed stays pinned once the others are done.
+        ledger.set_status("b", Status::Failed);
+        ledger.set_status("c", Status::Done);
+        assert_eq!(ledger.pending(), 0);
+        assert_eq!(ledger.high_water_slot(), 14);
+
+        ledger.admit("d", 25).unwrap();
+        ledger.set_status("d", Status::Done);
+        assert_eq!(ledger.high_water_slot(), 14);
+    }
+
+    #[test]
+    fn test_admit_duplicates() {
+        let ledger = Ledger::new(16, 0);
+
+        ledger.admit("a", 1).unwrap();
+        assert_eq!(ledger.admit("a", 1), Err(Status::Queued));
+
+        ledger.set_status("a", Status::Failed);
+        assert_eq!(ledger.admit("a", 1), Err(Status::Failed));
+    }
+}
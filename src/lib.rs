@@ -1,11 +1,21 @@
+pub mod backfill;
+pub mod config;
 pub mod consumer;
 pub mod crank;
+pub mod heartbeat;
+pub mod limiter;
 pub mod liquidator;
+pub mod metrics;
 pub mod recorder;
+pub mod rpc;
+pub mod shutdown;
+pub mod supervisor;
+pub mod tx;
 
 mod db;
 mod error;
 mod events;
+mod ledger;
 mod state;
 mod utils;
 
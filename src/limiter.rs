@@ -0,0 +1,291 @@
+//! Client-side rate limiting of RPC requests, so that the keepers stay
+//! under the provider's limits instead of being throttled by it. Every
+//! request costs the weight of its method, taken from a token bucket
+//! shared by every keeper, and from the keeper's own bucket if it has
+//! a budget.
+
+use crate::{
+    config::{check, serde_from_str},
+    error::Error,
+    metrics,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Who a request is made for, to charge it to their budget.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Budget {
+    /// Requests made for every keeper, e.g. by `AppState`.
+    Shared,
+    Crank,
+    Consumer,
+    Liquidator,
+    Recorder,
+    Backfill,
+}
+
+impl FromStr for Budget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "shared" => Ok(Self::Shared),
+            "crank" => Ok(Self::Crank),
+            "consumer" => Ok(Self::Consumer),
+            "liquidator" => Ok(Self::Liquidator),
+            "recorder" => Ok(Self::Recorder),
+            "backfill" => Ok(Self::Backfill),
+            _ => Err(format!("unknown budget: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Budget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Shared => "shared",
+            Self::Crank => "crank",
+            Self::Consumer => "consumer",
+            Self::Liquidator => "liquidator",
+            Self::Recorder => "recorder",
+            Self::Backfill => "backfill",
+        })
+    }
+}
+
+/// A number given to a method or a budget, e.g. `getProgramAccounts=10`.
+#[derive(Clone, Debug)]
+pub struct Quota {
+    pub key: String,
+    pub value: f64,
+}
+
+impl FromStr for Quota {
+    type Err = String;
+
+    /// Parses `<key>=<value>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (key, value) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected <key>=<value>: {}", s))?;
+
+        match value.parse() {
+            Ok(value) if value >= 0.0 => Ok(Self {
+                key: key.to_string(),
+                value,
+            }),
+            Ok(_) => Err("quota must not be negative".to_string()),
+            Err(e) => Err(format!("invalid quota: {}", e)),
+        }
+    }
+}
+
+impl fmt::Display for Quota {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.key, self.value)
+    }
+}
+
+serde_from_str!(Quota);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct LimitConfig {
+    /// Weight of the requests sent per second, across all keepers.
+    pub rate: f64,
+    /// Weight which may be sent at once, after being idle.
+    pub burst: f64,
+    /// Weights of the methods costing more than one request, as
+    /// `<method>=<weight>`.
+    pub weights: Vec<Quota>,
+    /// Rates of the keepers held below `rate`, as `<budget>=<rate>`.
+    pub budgets: Vec<Quota>,
+}
+
+impl Default for LimitConfig {
+    fn default() -> Self {
+        Self {
+            rate: 50.0,
+            burst: 100.0,
+            weights: vec![
+                "getProgramAccounts=10".parse().unwrap(),
+                "getSignaturesForAddress=2".parse().unwrap(),
+                "getTransaction=2".parse().unwrap(),
+            ],
+            budgets: Vec::new(),
+        }
+    }
+}
+
+impl LimitConfig {
+    pub fn validate(&self) -> Result<(), Error> {
+        check(
+            self.rate > 0.0 && self.burst > 0.0,
+            "rate limit and burst must be positive",
+        )?;
+
+        for q in &self.budgets {
+            q.key.parse::<Budget>().map_err(Error::Config)?;
+            check(q.value > 0.0, "budgets must be positive")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A token bucket which may go into debt, so that a request heavier
+/// than the burst still goes through once the debt is paid off.
+struct Bucket {
+    rate: f64,
+    burst: f64,
+    // The tokens left, as of the instant.
+    tokens: Mutex<(f64, Instant)>,
+}
+
+impl Bucket {
+    fn new(rate: f64, burst: f64) -> Self {
+        Self {
+            rate,
+            burst,
+            tokens: Mutex::new((burst, Instant::now())),
+        }
+    }
+
+    /// Takes `n` tokens, returning how long to wait until they are
+    /// actually available.
+    fn take(&self, n: f64) -> Duration {
+        let mut tokens = self.tokens.lock().unwrap();
+        let now = Instant::now();
+        let refill = now.duration_since(tokens.1).as_secs_f64() * self.rate;
+
+        tokens.0 = (tokens.0 + refill).min(self.burst) - n;
+        tokens.1 = now;
+
+        match tokens.0 < 0.0 {
+            true => Duration::from_secs_f64(-tokens.0 / self.rate),
+            false => Duration::ZERO,
+        }
+    }
+}
+
+pub struct Limiter {
+    weights: HashMap<String, f64>,
+    total: Bucket,
+    budgets: HashMap<Budget, Bucket>,
+}
+
+impl Limiter {
+    pub fn new(cfg: &LimitConfig) -> Self {
+        // Budgets allow a burst as long as the total one, in seconds.
+        let burst_secs = cfg.burst / cfg.rate;
+
+        Self {
+            weights: cfg
+                .weights
+                .iter()
+                .map(|q| (q.key.clone(), q.value))
+                .collect(),
+            total: Bucket::new(cfg.rate, cfg.burst),
+            budgets: cfg
+                .budgets
+                .iter()
+                .filter_map(|q| {
+                    let b = q.key.parse().ok()?;
+                    Some((b, Bucket::new(q.value, q.value * burst_secs)))
+                })
+                .collect(),
+        }
+    }
+
+    /// Takes the weight of `method` from the buckets, returning how
+    /// long to wait before sending it.
+    fn reserve(&self, budget: Budget, method: &str) -> Duration {
+        let n = self.weights.get(method).copied().unwrap_or(1.0);
+        let wait = self.total.take(n);
+
+        match self.budgets.get(&budget) {
+            Some(b) => wait.max(b.take(n)),
+            None => wait,
+        }
+    }
+
+    /// Waits until `method` may be sent on behalf of `budget`.
+    pub async fn acquire(&self, budget: Budget, method: &str) {
+        let wait = self.reserve(budget, method);
+
+        if wait.is_zero() {
+            return;
+        }
+
+        let label = budget.to_string();
+        metrics::RPC_THROTTLED
+            .with_label_values(&[&label, method])
+            .inc();
+        metrics::RPC_THROTTLED_SECONDS
+            .with_label_values(&[&label])
+            .inc_by(wait.as_secs_f64());
+
+        tokio::time::sleep(wait).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The tests take far less than this, so the refill in between
+    // calls is negligible.
+    fn approx(d: Duration, secs: f64) -> bool {
+        (d.as_secs_f64() - secs).abs() < 0.01
+    }
+
+    #[test]
+    fn test_bucket_debt() {
+        let b = Bucket::new(1.0, 2.0);
+
+        assert!(b.take(1.0).is_zero());
+        assert!(b.take(1.0).is_zero());
+        assert!(approx(b.take(1.0), 1.0));
+        assert!(approx(b.take(2.0), 3.0));
+
+        // Heavier than the burst, it waits for the whole debt.
+        let b = Bucket::new(10.0, 5.0);
+        assert!(approx(b.take(20.0), 1.5));
+    }
+
+    #[test]
+    fn test_bucket_refill() {
+        let b = Bucket::new(1.0, 2.0);
+        *b.tokens.lock().unwrap() =
+            (-3.0, Instant::now() - Duration::from_secs(10));
+
+        // Refilled up to the burst only.
+        assert!(b.take(1.0).is_zero());
+        assert!(approx(b.take(2.0), 1.0));
+    }
+
+    #[test]
+    fn test_reserve() {
+        let cfg = LimitConfig {
+            rate: 10.0,
+            burst: 10.0,
+            budgets: vec!["crank=1".parse().unwrap()],
+            ..LimitConfig::default()
+        };
+        let l = Limiter::new(&cfg);
+
+        // The budget's burst lasts as long as the total one.
+        assert!(l.reserve(Budget::Crank, "getSlot").is_zero());
+        assert!(approx(l.reserve(Budget::Crank, "getSlot"), 1.0));
+        assert!(l.reserve(Budget::Consumer, "getSlot").is_zero());
+
+        // 3 taken from the total, and 10 for the weight.
+        assert!(approx(l.reserve(Budget::Shared, "getProgramAccounts"), 0.3));
+    }
+}
@@ -7,51 +7,81 @@
  * Let's start by storing everything to make sure the logic is good,
  * then deal with compression.
 */
-use crate::liquidator::{
-    error::ErrorCode, liquidation, margin_utils::*, utils::*,
+use crate::{
+    limiter::Budget,
+    liquidator::{
+        error::ErrorCode,
+        health::HealthIndex,
+        inflight::{Outcome, Registry},
+        liquidation,
+        margin_utils::*,
+        profit::ProfitConfig,
+        queue::{Action, Candidate, JobQueue},
+        risk::{RiskConfig, Tolerance},
+        swap::{self, SwapConfig},
+        utils::*,
+    },
+    metrics,
+    tx::TxBuilder,
 };
 
-use fixed::types::I80F48;
+use arc_swap::ArcSwap;
 use serum_dex::state::{
     Market as SerumMarket, MarketState as SerumMarketState,
 };
 use solana_sdk::pubkey::Pubkey;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     ops::Deref,
-    sync::{Arc, Mutex, MutexGuard},
+    sync::{Arc, Mutex},
 };
 
-use tracing::{error, error_span, info};
+use tracing::{debug, error, error_span, info, warn};
 use zo_abi::{
-    dex::ZoDexMarket as MarketState, Cache, Control, FractionType, Margin,
-    State, MAX_MARKETS,
+    dex::ZoDexMarket as MarketState, Cache, Control, Margin, State, MAX_MARKETS,
 };
 
-// Let's start with a simple hashtable
-// It has to be sharable.
+// The `ErrorCode` of a job, as a metrics label.
+fn result_label(r: &Result<(), ErrorCode>) -> String {
+    match r {
+        Ok(()) => "success".to_string(),
+        Err(e) => format!("{:?}", e),
+    }
+}
+
+// A snapshot of the accounts. Cloning it is cheap, since the tables
+// are persistent maps and the bigger accounts are behind an Arc, so
+// that every update publishes a new snapshot.
+#[derive(Clone)]
 pub struct AccountTable {
+    // Bumped on every update
+    version: u64,
+
     // Table for margin accounts
-    margin_table: HashMap<Pubkey, Margin>,
+    margin_table: im::HashMap<Pubkey, Margin>,
 
     // The control accounts table
-    control_table: HashMap<Pubkey, Control>,
+    control_table: im::HashMap<Pubkey, Arc<Control>>,
+
+    // Margin key of each control, to find the margin to recompute
+    // when its control changes.
+    margin_by_control: im::HashMap<Pubkey, Pubkey>,
 
     // The cache account
-    cache: Cache,
+    cache: Arc<Cache>,
     cache_key: Pubkey,
 
     // The state account
-    state: State,
+    state: Arc<State>,
     state_key: Pubkey,
     state_signer: Pubkey,
 
     // The market state accounts
-    market_state: Vec<MarketState>,
+    market_state: Arc<Vec<MarketState>>,
 
     // The serum markets for swapping
-    serum_markets: HashMap<usize, SerumMarketState>,
-    serum_vault_signers: HashMap<usize, Pubkey>,
+    serum_markets: Arc<HashMap<usize, SerumMarketState>>,
+    serum_vault_signers: Arc<HashMap<usize, Pubkey>>,
 
     payer_key: Pubkey,
     payer_margin_key: Pubkey,
@@ -64,7 +94,7 @@ pub struct AccountTable {
 }
 
 impl AccountTable {
-    pub fn new(
+    pub async fn new(
         st: &crate::AppState,
         worker_index: u8,
         worker_count: u8,
@@ -83,33 +113,56 @@ impl AccountTable {
             &payer_margin_key,
             &mut st
                 .rpc
-                .get_account(&payer_margin_key)
+                .call(Budget::Liquidator, "getAccountInfo", |c| {
+                    c.get_account(&payer_margin_key)
+                })
+                .await
                 .expect("Could not get payer margin account"),
         );
         let payer_control_key = payer_margin.control;
         let payer_control = get_type_from_account::<Control>(
             &payer_control_key,
-            &mut st.rpc.get_account(&payer_control_key).unwrap(),
-        );
-
-        let margin_table: HashMap<_, _> =
-            load_program_accounts::<Margin>(&st.rpc, &zo_abi::ID)?
-                .into_iter()
-                .filter(|(_, a)| {
-                    is_right_remainder(&a.control, worker_count, worker_index)
-                })
-                .collect();
-
-        let control_table: HashMap<_, _> =
-            load_program_accounts::<Control>(&st.rpc, &zo_abi::ID)?
-                .into_iter()
-                .filter(|(k, _)| {
-                    is_right_remainder(&k, worker_count, worker_index)
+            &mut st
+                .rpc
+                .call(Budget::Liquidator, "getAccountInfo", |c| {
+                    c.get_account(&payer_control_key)
                 })
-                .collect();
+                .await
+                .unwrap(),
+        );
 
-        let market_state: Vec<_> =
-            st.load_dex_markets()?.into_iter().map(|(_, m)| m).collect();
+        let margin_table: im::HashMap<_, _> = st
+            .rpc
+            .call(Budget::Liquidator, "getProgramAccounts", |c| {
+                load_program_accounts::<Margin>(c, &zo_abi::ID)
+            })
+            .await?
+            .into_iter()
+            .filter(|(_, a)| {
+                is_right_remainder(&a.control, worker_count, worker_index)
+            })
+            .collect();
+
+        let control_table: im::HashMap<_, _> = st
+            .rpc
+            .call(Budget::Liquidator, "getProgramAccounts", |c| {
+                load_program_accounts::<Control>(c, &zo_abi::ID)
+            })
+            .await?
+            .into_iter()
+            .filter(|(k, _)| is_right_remainder(&k, worker_count, worker_index))
+            .map(|(k, a)| (k, Arc::new(a)))
+            .collect();
+
+        let margin_by_control: im::HashMap<_, _> =
+            margin_table.iter().map(|(k, a)| (a.control, *k)).collect();
+
+        let market_state: Vec<_> = st
+            .load_dex_markets()
+            .await?
+            .into_iter()
+            .map(|(_, m)| m)
+            .collect();
 
         let mut serum_markets: HashMap<usize, _> = HashMap::new();
         let mut serum_vault_signers: HashMap<usize, _> = HashMap::new();
@@ -121,13 +174,21 @@ impl AccountTable {
 
             let serum_oo_account = st
                 .rpc
-                .get_account(&collateral_info.serum_open_orders)
+                .call(Budget::Liquidator, "getAccountInfo", |c| {
+                    c.get_account(&collateral_info.serum_open_orders)
+                })
+                .await
                 .unwrap();
 
             let serum_market_address =
                 Pubkey::new(&serum_oo_account.data[13..45]);
-            let mut serum_market_account =
-                st.rpc.get_account(&serum_market_address).unwrap();
+            let mut serum_market_account = st
+                .rpc
+                .call(Budget::Liquidator, "getAccountInfo", |c| {
+                    c.get_account(&serum_market_address)
+                })
+                .await
+                .unwrap();
             let serum_market_account_info = get_account_info(
                 &serum_market_address,
                 &mut serum_market_account,
@@ -156,16 +217,18 @@ impl AccountTable {
         }
 
         Ok(Self {
+            version: 0,
             margin_table,
             control_table,
-            cache: st.zo_cache,
+            margin_by_control,
+            cache: st.zo_cache(),
             cache_key: st.zo_cache_pubkey,
-            state: st.zo_state,
+            state: st.zo_state(),
             state_key: st.zo_state_pubkey,
             state_signer: st.zo_state_signer_pubkey,
-            market_state,
-            serum_markets,
-            serum_vault_signers,
+            market_state: Arc::new(market_state),
+            serum_markets: Arc::new(serum_markets),
+            serum_vault_signers: Arc::new(serum_vault_signers),
             payer_key: payer,
             payer_margin_key,
             payer_margin,
@@ -176,36 +239,46 @@ impl AccountTable {
         })
     }
 
-    pub fn refresh_accounts(
-        &mut self,
-        st: &crate::AppState,
-    ) -> Result<(), crate::Error> {
-        *self = Self::new(st, self.worker_index, self.worker_count)?;
-        Ok(())
-    }
+    fn update_margin(&mut self, key: Pubkey, account: Margin) -> bool {
+        // The payer's accounts are needed by every worker, whichever
+        // shard they fall in.
+        if key == self.payer_margin_key {
+            self.payer_margin = account;
+        }
 
-    pub fn update_margin(&mut self, key: Pubkey, account: Margin) {
-        if is_right_remainder(
+        let tracked = is_right_remainder(
             &account.control,
             self.worker_count,
             self.worker_index,
-        ) {
+        );
+
+        if tracked {
             self.margin_table.insert(key, account);
+            self.margin_by_control.insert(account.control, key);
         }
+
+        tracked
     }
 
-    pub fn update_control(&mut self, key: Pubkey, account: Control) {
-        if is_right_remainder(&key, self.worker_count, self.worker_index) {
-            self.control_table.insert(key, account);
+    fn update_control(
+        &mut self,
+        key: Pubkey,
+        account: Control,
+    ) -> Option<Pubkey> {
+        if key == self.payer_control_key {
+            self.payer_control = account;
         }
-    }
 
-    pub fn update_cache(&mut self, cache: Cache) {
-        self.cache = cache;
+        if is_right_remainder(&key, self.worker_count, self.worker_index) {
+            self.control_table.insert(key, Arc::new(account));
+            self.margin_by_control.get(&key).copied()
+        } else {
+            None
+        }
     }
 
-    pub fn update_state(&mut self, state: State) {
-        self.state = state;
+    pub fn version(&self) -> u64 {
+        self.version
     }
 
     /// The number of control accounts.
@@ -237,64 +310,231 @@ impl AccountTable {
         &self,
         margin: &Margin,
     ) -> Option<(&Pubkey, &Control)> {
-        self.control_table.get_key_value(&margin.control)
+        self.control_table
+            .get_key_value(&margin.control)
+            .map(|(k, a)| (k, a.as_ref()))
     }
 }
 
-pub type Db = Arc<Mutex<AccountTable>>;
+/// A change to one of the accounts, as received by the listener.
+#[derive(Clone)]
+pub enum Update {
+    Margin(Pubkey, Margin),
+    Control(Pubkey, Control),
+    Cache(Cache),
+    State(State),
+}
+
+// What changed since the last scan.
+#[derive(Default)]
+struct Dirty {
+    margins: HashSet<Pubkey>,
+    cache: bool,
+    all: bool,
+}
+
+struct Writer {
+    table: AccountTable,
+    dirty: Dirty,
+    // Updates received while the table is being rebuilt, replayed
+    // on top of the new table so that they are not lost.
+    replay: Option<Vec<Update>>,
+}
+
+impl Writer {
+    fn apply(&mut self, update: Update) {
+        if let Some(r) = &mut self.replay {
+            r.push(update.clone());
+        }
+
+        match update {
+            Update::Margin(key, a) => {
+                if self.table.update_margin(key, a) {
+                    self.dirty.margins.insert(key);
+                }
+            }
+            Update::Control(key, a) => {
+                if let Some(m) = self.table.update_control(key, a) {
+                    self.dirty.margins.insert(m);
+                }
+            }
+            Update::Cache(a) => {
+                self.table.cache = Arc::new(a);
+                self.dirty.cache = true;
+            }
+            Update::State(a) => {
+                // The weights may have changed, so everything is
+                // recomputed.
+                self.table.state = Arc::new(a);
+                self.dirty.all = true;
+            }
+        }
+
+        self.table.version += 1;
+    }
+}
 
+/// Readers work on the latest snapshot without locking. Writers
+/// update their own copy of the table under a lock that is only held
+/// for the update itself, then publish it as the new snapshot.
 #[derive(Clone)]
 pub struct DbWrapper {
-    db: Db,
+    snapshot: Arc<ArcSwap<AccountTable>>,
+    writer: Arc<Mutex<Writer>>,
+    // Only used by the scan.
+    health: Arc<Mutex<HealthIndex>>,
+    in_flight: Arc<Registry>,
 }
 
 impl DbWrapper {
-    pub fn new(
+    pub async fn new(
         st: &crate::AppState,
         worker_index: u8,
         worker_count: u8,
+        tolerance: Tolerance,
     ) -> Self {
+        let table = AccountTable::new(st, worker_index, worker_count)
+            .await
+            .unwrap();
+        let health =
+            HealthIndex::new(table.margin_table.keys().copied(), tolerance);
+
         DbWrapper {
-            db: Arc::new(Mutex::new(
-                AccountTable::new(st, worker_index, worker_count).unwrap(),
-            )),
+            snapshot: Arc::new(ArcSwap::from_pointee(table.clone())),
+            writer: Arc::new(Mutex::new(Writer {
+                table,
+                dirty: Dirty::default(),
+                replay: None,
+            })),
+            health: Arc::new(Mutex::new(health)),
+            in_flight: Arc::new(Registry::default()),
         }
     }
 
-    pub async fn check_all_accounts(
+    pub fn snapshot(&self) -> Arc<AccountTable> {
+        self.snapshot.load_full()
+    }
+
+    pub fn apply(&self, update: Update) {
+        let mut w = self.writer.lock().unwrap();
+        w.apply(update);
+        self.snapshot.store(Arc::new(w.table.clone()));
+    }
+
+    /// Queues a job for every account found liquidatable or
+    /// cancellable, without waiting for any of them to run.
+    pub fn check_all_accounts(
         &self,
         st: &'static crate::AppState,
+        tx: &TxBuilder,
+        swap_cfg: SwapConfig,
+        profit_cfg: ProfitConfig,
+        risk_cfg: &Arc<RiskConfig>,
         dex_program: &Pubkey,
         serum_dex_program: &Pubkey,
+        jobs: &JobQueue,
     ) -> Result<usize, ErrorCode> {
-        let (size, handles) =
-            self.check_all_accounts_aux(st, dex_program, serum_dex_program)?;
-        match futures::future::try_join_all(handles).await {
-            Ok(_) => Ok(size),
-            Err(_) => Err(ErrorCode::LiquidationFailure),
+        let (size, queue) = self.check_all_accounts_aux(
+            st,
+            tx,
+            swap_cfg,
+            profit_cfg,
+            risk_cfg,
+            dex_program,
+            serum_dex_program,
+        )?;
+
+        for c in queue {
+            jobs.push(c);
         }
+
+        Ok(size)
     }
 
     pub fn check_all_accounts_aux(
         &self,
         st: &'static crate::AppState,
+        tx: &TxBuilder,
+        swap_cfg: SwapConfig,
+        profit_cfg: ProfitConfig,
+        risk_cfg: &Arc<RiskConfig>,
         dex_program: &Pubkey,
         serum_dex_program: &Pubkey,
-    ) -> Result<(usize, Vec<tokio::task::JoinHandle<()>>), ErrorCode> {
-        let db_clone = self.get_clone();
-        let db: &mut MutexGuard<AccountTable> =
-            &mut db_clone.lock().map_err(|_| ErrorCode::LockFailure)?;
-
-        let mut handles: Vec<tokio::task::JoinHandle<_>> = Vec::new();
-        let span = error_span!("check_all_accounts");
-        for (key, margin) in db.margin_table.clone().into_iter() {
-            let (cancel_orders, liquidate) =
-                DbWrapper::is_liquidatable(&margin, &db, &db.state, &db.cache)?;
+    ) -> Result<(usize, Vec<Candidate>), ErrorCode> {
+        // Taken before the snapshot, so that the snapshot has at
+        // least every change marked dirty.
+        let dirty = std::mem::take(
+            &mut self
+                .writer
+                .lock()
+                .map_err(|_| ErrorCode::LockFailure)?
+                .dirty,
+        );
+        let db = self.snapshot();
+        self.in_flight.prune();
+
+        let mut health =
+            self.health.lock().map_err(|_| ErrorCode::LockFailure)?;
+
+        if dirty.all {
+            *health = HealthIndex::new(
+                db.margin_table.keys().copied(),
+                health.tolerance(),
+            );
+        } else {
+            if dirty.cache {
+                health.update_cache(&db.state, &db.cache);
+            }
+
+            for k in dirty.margins {
+                health.mark_dirty(k);
+            }
+        }
+
+        let mut queue = Vec::new();
+        let span = error_span!("check_all_accounts", version = db.version());
+        let n = health.dirty();
+
+        // An account whose control is not known yet was just
+        // created, and the listener didn't catch the control. It is
+        // very low risk, so it is skipped until the control comes in,
+        // or until the next time all accounts are fetched.
+        let actionable = health.refresh(&db.state, &db.cache, |k| {
+            let margin = db.margin_table.get(k)?;
+            let (_, control) = db.get_control_from_margin(margin)?;
+            Some((margin, control))
+        })?;
+        span.in_scope(|| debug!("Recomputed {} accounts", n));
+
+        let liquidatable = actionable.iter().filter(|x| x.2).count();
+        let cancellable = actionable.iter().filter(|x| x.1 && !x.2).count();
+        metrics::LIQUIDATOR_ACCOUNTS.set(db.size() as i64);
+        metrics::LIQUIDATOR_ACTIONABLE
+            .with_label_values(&["liquidate"])
+            .set(liquidatable as i64);
+        metrics::LIQUIDATOR_ACTIONABLE
+            .with_label_values(&["cancel"])
+            .set(cancellable as i64);
+
+        for (key, cancel_orders, liquidate) in actionable {
+            let margin = db.margin_table[&key];
             if liquidate {
+                let claim = match self.in_flight.claim(&key) {
+                    Some(x) => x,
+                    None => continue,
+                };
+
+                let control_pair = db.get_control_from_margin(&margin).unwrap();
+                let control = *control_pair.1;
+                let deficit = get_maintenance_deficit(
+                    &margin, &control, &db.state, &db.cache,
+                );
+
                 span.in_scope(|| {
                     info!(
-                        "Found liquidatable account: {}",
-                        margin.authority.to_string()
+                        "Found liquidatable account: {} (deficit {})",
+                        margin.authority.to_string(),
+                        deficit
                     )
                 });
                 // Get the updated payer accounts
@@ -309,22 +549,24 @@ impl DbWrapper {
                 let payer_control = *db.payer_control();
                 let payer_oo: [Pubkey; MAX_MARKETS as usize] =
                     get_oo_keys(&payer_control.open_orders_agg);
-                let control_pair = db.get_control_from_margin(&margin).unwrap();
-                let control = *control_pair.1;
-                let cache = db.cache;
+                let cache = *db.cache;
                 let cache_key = db.cache_key;
-                let state = db.state;
+                let state = *db.state;
                 let state_key = db.state_key;
                 let state_signer = db.state_signer;
-                let market_state = db.market_state.clone();
-                let serum_markets = db.serum_markets.clone();
-                let serum_vault_signers = db.serum_vault_signers.clone();
+                let market_state = db.market_state.to_vec();
+                let serum_markets = (*db.serum_markets).clone();
+                let serum_vault_signers = (*db.serum_vault_signers).clone();
 
                 // TODO: Refactor to have a struct for this, right now it's a mess
                 let span_clone = span.clone();
-                let handle = tokio::task::spawn_blocking(move || {
+                let tx = tx.clone();
+                let this = self.clone();
+                let risk_cfg = risk_cfg.clone();
+                let job = Box::pin(async move {
                     let result = liquidation::liquidate(
-                        &st.program(),
+                        st,
+                        &tx,
                         &dex_program,
                         &payer_pubkey,
                         &payer_margin,
@@ -344,7 +586,19 @@ impl DbWrapper {
                         serum_markets,
                         &serum_dex_program,
                         serum_vault_signers,
-                    );
+                        &swap_cfg,
+                        &profit_cfg,
+                        &risk_cfg,
+                    )
+                    .await;
+
+                    let outcome = Outcome::from(&result);
+                    metrics::LIQUIDATOR_OUTCOMES
+                        .with_label_values(&[
+                            "liquidate",
+                            &result_label(&result),
+                        ])
+                        .inc();
 
                     match result {
                         Ok(()) => {
@@ -352,6 +606,9 @@ impl DbWrapper {
                                 info!("Liquidated {}", margin.authority);
                             });
                         }
+                        // Already logged with the reason.
+                        Err(ErrorCode::Unprofitable | ErrorCode::RiskLimit) => {
+                        }
                         Err(e) => {
                             span_clone.in_scope(|| {
                                 error!(
@@ -361,10 +618,30 @@ impl DbWrapper {
                             });
                         }
                     }
+
+                    // Failed attempts may still have landed some of
+                    // their transactions, so refresh either way.
+                    if let Err(e) = this.refresh_payer(st).await {
+                        span_clone.in_scope(|| {
+                            warn!("Failed to refresh payer accounts: {}", e)
+                        });
+                    }
+
+                    claim.finish(outcome);
                 });
 
-                handles.push(handle);
+                queue.push(Candidate {
+                    action: Action::Liquidate,
+                    deficit,
+                    key,
+                    job,
+                });
             } else if cancel_orders {
+                let claim = match self.in_flight.claim(&key) {
+                    Some(x) => x,
+                    None => continue,
+                };
+
                 span.in_scope(|| {
                     info!(
                         "Found cancellable account: {}",
@@ -375,17 +652,21 @@ impl DbWrapper {
                 let payer_pubkey = db.payer_key();
                 let control_pair = db.get_control_from_margin(&margin).unwrap();
                 let control = *control_pair.1;
-                let cache = db.cache;
+                let deficit = get_maintenance_deficit(
+                    &margin, &control, &db.state, &db.cache,
+                );
+                let cache = *db.cache;
                 let cache_key = db.cache_key;
-                let state = db.state;
+                let state = *db.state;
                 let state_key = db.state_key;
                 let state_signer = db.state_signer;
-                let market_state = db.market_state.clone();
+                let market_state = db.market_state.to_vec();
 
                 let span_clone = span.clone();
-                let handle = tokio::task::spawn_blocking(move || {
+                let tx = tx.clone();
+                let job = Box::pin(async move {
                     let result = liquidation::cancel(
-                        &st.program(),
+                        &tx,
                         &dex_program,
                         &payer_pubkey,
                         &key,
@@ -396,8 +677,14 @@ impl DbWrapper {
                         &state,
                         &state_key,
                         &state_signer,
-                        market_state.clone(),
-                    );
+                        market_state,
+                    )
+                    .await;
+
+                    let outcome = Outcome::from(&result);
+                    metrics::LIQUIDATOR_OUTCOMES
+                        .with_label_values(&["cancel", &result_label(&result)])
+                        .inc();
 
                     match result {
                         Ok(()) => (),
@@ -410,71 +697,123 @@ impl DbWrapper {
                             });
                         }
                     }
+
+                    claim.finish(outcome);
+                });
+
+                queue.push(Candidate {
+                    action: Action::Cancel,
+                    deficit,
+                    key,
+                    job,
                 });
-                handles.push(handle);
             }
         }
 
-        Ok((db.size(), handles))
+        Ok((db.size(), queue))
     }
 
-    fn is_liquidatable(
-        margin: &Margin,
-        table: &AccountTable,
-        state: &State,
-        cache: &Cache,
-    ) -> Result<(bool, bool), ErrorCode> {
-        // Do the math on the margin account.
-        // let span = error_span!("is_liquidatable");
-        // let col = get_total_collateral(margin, cache, state);
-        // println!("{}", margin.authority);
-        let control = match table.get_control_from_margin(margin) {
-            Some((_key, control)) => control,
-            None => {
-                // In this case, a margin account was just created with it's control, but the listener didn't catch the control.
-                // I.e. This account is very low risk, so just skip checking this account.
-                // It will be fetched the next time all accounts are fetched, i.e. in five minutes
-                // TODO: Fetch the margin
-                return Ok((false, false));
-            }
-        };
-        let has_oo = has_open_orders(cache, control)?;
-
-        let is_above_cancel = check_mf(
-            FractionType::Cancel,
-            margin,
-            control,
-            state,
-            cache,
-            I80F48::from_num(0.99995f64),
-        );
+    /// Swaps the payer's leftover inventory back into the quote, see
+    /// `swap::rebalance`.
+    pub async fn rebalance(
+        &self,
+        st: &crate::AppState,
+        tx: &TxBuilder,
+        serum_dex_program: &Pubkey,
+        swap_cfg: &SwapConfig,
+    ) -> Result<(), ErrorCode> {
+        let db = self.snapshot();
+
+        let sent = swap::rebalance(
+            st,
+            tx,
+            &db.payer_key,
+            &db.state,
+            &db.cache,
+            &db.state_key,
+            &db.state_signer,
+            &db.payer_margin,
+            &db.payer_margin_key,
+            &db.payer_control_key,
+            &db.serum_markets,
+            serum_dex_program,
+            &db.serum_vault_signers,
+            swap_cfg,
+        )
+        .await?;
 
-        let is_above_maintenance = check_mf(
-            FractionType::Maintenance,
-            margin,
-            control,
-            state,
-            cache,
-            I80F48::from_num(0.99995f64),
-        );
+        if sent > 0 {
+            if let Err(e) = self.refresh_payer(st).await {
+                warn!("Failed to refresh payer accounts: {}", e);
+            }
+        }
 
-        Ok((!is_above_cancel && has_oo, !is_above_maintenance))
+        Ok(())
     }
 
-    pub fn get_clone(&self) -> Db {
-        self.db.clone()
-    }
+    /// Re-fetches the payer's margin and control, which change with
+    /// every liquidation and are used to size the next one.
+    pub async fn refresh_payer(
+        &self,
+        st: &crate::AppState,
+    ) -> Result<(), crate::Error> {
+        let db = self.snapshot();
+        let (margin_key, control_key) =
+            (db.payer_margin_key, db.payer_control_key);
+
+        let margin = get_type_from_account::<Margin>(
+            &margin_key,
+            &mut st
+                .rpc
+                .call(Budget::Liquidator, "getAccountInfo", |c| {
+                    c.get_account(&margin_key)
+                })
+                .await?,
+        );
+        let control = get_type_from_account::<Control>(
+            &control_key,
+            &mut st
+                .rpc
+                .call(Budget::Liquidator, "getAccountInfo", |c| {
+                    c.get_account(&control_key)
+                })
+                .await?,
+        );
 
-    pub fn get(&self) -> &Db {
-        &self.db
+        self.apply(Update::Margin(margin_key, margin));
+        self.apply(Update::Control(control_key, control));
+        Ok(())
     }
 
-    pub fn refresh_accounts(
+    /// Rebuilds the table from scratch. The accounts are fetched
+    /// without holding the lock, and the new table is swapped in
+    /// once complete.
+    pub async fn refresh_accounts(
         &self,
         st: &crate::AppState,
     ) -> Result<(), crate::Error> {
-        let mut db = self.db.lock().unwrap();
-        db.refresh_accounts(st)?;
+        let (worker_index, worker_count) = {
+            let mut w = self.writer.lock().unwrap();
+            w.replay = Some(Vec::new());
+            (w.table.worker_index, w.table.worker_count)
+        };
+
+        let table = AccountTable::new(st, worker_index, worker_count).await;
+
+        let mut w = self.writer.lock().unwrap();
+        let replay = w.replay.take().unwrap_or_default();
+
+        w.table = AccountTable {
+            version: w.table.version + 1,
+            ..table?
+        };
+
+        for u in replay {
+            w.apply(u);
+        }
+
+        w.dirty.all = true;
+        self.snapshot.store(Arc::new(w.table.clone()));
         Ok(())
     }
 }
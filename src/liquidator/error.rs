@@ -15,4 +15,6 @@ pub enum ErrorCode {
     NoAsks,
     UnrecoverableTransactionError,
     LiquidationOverExposure,
+    Unprofitable,
+    RiskLimit,
 }
@@ -0,0 +1,209 @@
+/*
+ * Keeps the health of every account between scans, so that a scan
+ * only recomputes the accounts that may have changed: those whose
+ * margin or control was updated, and those exposed to prices that
+ * moved enough to possibly bring them under a margin fraction.
+ *
+ * Funding is not tracked, since it moves slowly and the whole table
+ * is rebuilt every few minutes anyway.
+*/
+use crate::liquidator::{error::ErrorCode, margin_utils::*, risk::Tolerance};
+
+use fixed::types::I80F48;
+use solana_sdk::pubkey::Pubkey;
+use std::collections::{HashMap, HashSet};
+use zo_abi::{Cache, Control, FractionType, Margin, State, MAX_COLLATERALS};
+
+// How many times its exposure an account's buffer may move for a
+// given relative price move. Both the weighted positions and the
+// unrealized pnl move with the price, so this errs on the safe side.
+const SENSITIVITY: u8 = 3;
+
+pub struct Health {
+    pub cancel: bool,
+    pub liquidate: bool,
+    // How far the account is from the nearest fraction it is
+    // checked against, see `get_mf_buffer`.
+    buffer: I80F48,
+    exposure: I80F48,
+    // The prices the account was computed with.
+    deps: Vec<(usize, bool, I80F48)>,
+}
+
+impl Health {
+    pub fn compute(
+        margin: &Margin,
+        control: &Control,
+        state: &State,
+        cache: &Cache,
+        tolerance: &Tolerance,
+    ) -> Result<Self, ErrorCode> {
+        let has_oo = has_open_orders(cache, control)?;
+
+        let maintenance = get_mf_buffer(
+            FractionType::Maintenance,
+            margin,
+            control,
+            state,
+            cache,
+            I80F48::from_num(tolerance.maintenance),
+        );
+
+        let cancel = get_mf_buffer(
+            FractionType::Cancel,
+            margin,
+            control,
+            state,
+            cache,
+            I80F48::from_num(tolerance.cancel),
+        );
+
+        let buffer = match has_oo {
+            true => maintenance.min(cancel),
+            false => maintenance,
+        };
+
+        let (deps, exposure) =
+            get_price_exposure(margin, control, state, cache);
+
+        Ok(Self {
+            cancel: has_oo && cancel.is_negative(),
+            liquidate: maintenance.is_negative(),
+            buffer,
+            exposure,
+            deps,
+        })
+    }
+
+    /// Whether the prices moved enough since the account was computed
+    /// that it may now be under one of its fractions.
+    fn is_stale(&self, supply: &[I80F48], borrow: &[I80F48]) -> bool {
+        if self.cancel || self.liquidate {
+            return true;
+        }
+
+        let max_move = self
+            .deps
+            .iter()
+            .map(|&(i, is_borrow, old)| {
+                let new = match is_borrow {
+                    true => borrow[i],
+                    false => supply[i],
+                };
+
+                match old.is_zero() {
+                    true => I80F48::MAX,
+                    false => ((new - old) / old).abs(),
+                }
+            })
+            .max()
+            .unwrap_or(I80F48::ZERO);
+
+        self.exposure
+            .saturating_mul(max_move)
+            .saturating_mul(I80F48::from_num(SENSITIVITY))
+            >= self.buffer
+    }
+}
+
+pub struct HealthIndex {
+    // Keyed by margin.
+    health: HashMap<Pubkey, Health>,
+    dirty: HashSet<Pubkey>,
+    tolerance: Tolerance,
+}
+
+impl HealthIndex {
+    pub fn new(
+        keys: impl IntoIterator<Item = Pubkey>,
+        tolerance: Tolerance,
+    ) -> Self {
+        Self {
+            health: HashMap::new(),
+            dirty: keys.into_iter().collect(),
+            tolerance,
+        }
+    }
+
+    pub fn tolerance(&self) -> Tolerance {
+        self.tolerance
+    }
+
+    pub fn mark_dirty(&mut self, key: Pubkey) {
+        self.dirty.insert(key);
+    }
+
+    /// Marks the accounts that the new cache may have made unhealthy.
+    pub fn update_cache(&mut self, state: &State, cache: &Cache) {
+        use zo_abi::MAX_MARKETS;
+
+        // The prices only depend on the position through its sign,
+        // which picks the borrow or the supply multiplier.
+        let supply = get_price_vector(
+            state,
+            cache,
+            &[I80F48::ZERO; MAX_COLLATERALS + MAX_MARKETS],
+        );
+        let borrow = get_price_vector(
+            state,
+            cache,
+            &[-I80F48::ONE; MAX_COLLATERALS + MAX_MARKETS],
+        );
+
+        let stale = self
+            .health
+            .iter()
+            .filter(|(_, h)| h.is_stale(&supply, &borrow))
+            .map(|(k, _)| *k);
+
+        self.dirty.extend(stale);
+    }
+
+    /// Recomputes the dirty accounts, and returns the ones needing
+    /// action, with whether to cancel their orders and whether to
+    /// liquidate them. `get` returns the margin and control of an
+    /// account, or `None` if either is not known yet, in which case
+    /// the account is skipped until it is updated.
+    pub fn refresh<'a>(
+        &mut self,
+        state: &State,
+        cache: &Cache,
+        get: impl Fn(&Pubkey) -> Option<(&'a Margin, &'a Control)>,
+    ) -> Result<Vec<(Pubkey, bool, bool)>, ErrorCode> {
+        let keys: Vec<_> = self.dirty.iter().copied().collect();
+
+        // Keys are only cleared once computed, so that an error
+        // leaves the rest dirty for the next scan.
+        for key in keys {
+            match get(&key) {
+                Some((margin, control)) => {
+                    let h = Health::compute(
+                        margin,
+                        control,
+                        state,
+                        cache,
+                        &self.tolerance,
+                    )?;
+                    self.health.insert(key, h);
+                }
+                None => {
+                    self.health.remove(&key);
+                }
+            }
+
+            self.dirty.remove(&key);
+        }
+
+        Ok(self
+            .health
+            .iter()
+            .filter(|(_, h)| h.cancel || h.liquidate)
+            .map(|(k, h)| (*k, h.cancel, h.liquidate))
+            .collect())
+    }
+
+    /// The number of accounts recomputed by the next `refresh`.
+    pub fn dirty(&self) -> usize {
+        self.dirty.len()
+    }
+}
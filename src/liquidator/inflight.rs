@@ -0,0 +1,275 @@
+/*
+ * Keeps track of the accounts being liquidated, so that an account
+ * found liquidatable on every tick only has one task at a time, and
+ * accounts that keep failing are retried less and less often.
+*/
+use crate::liquidator::error::ErrorCode;
+
+use solana_sdk::pubkey::Pubkey;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tracing::debug;
+
+// Wait after a success, to let the listener catch up with
+// the new state of the account.
+const SUCCESS_COOLDOWN: Duration = Duration::from_secs(2);
+
+// Backoff after a failure, doubled with every consecutive failure.
+const FAILURE_COOLDOWN: Duration = Duration::from_secs(1);
+const UNRECOVERABLE_COOLDOWN: Duration = Duration::from_secs(10);
+const MAX_COOLDOWN: Duration = Duration::from_secs(300);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Success,
+    Failure,
+    Unrecoverable,
+}
+
+impl<T> From<&Result<T, ErrorCode>> for Outcome {
+    fn from(r: &Result<T, ErrorCode>) -> Self {
+        match r {
+            Ok(_) => Outcome::Success,
+            Err(ErrorCode::UnrecoverableTransactionError) => {
+                Outcome::Unrecoverable
+            }
+            Err(_) => Outcome::Failure,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Entry {
+    in_flight: bool,
+    attempts: u64,
+    // Consecutive failures, reset on success.
+    failures: u32,
+    last_outcome: Option<Outcome>,
+    not_before: Option<Instant>,
+}
+
+#[derive(Default)]
+pub struct Registry {
+    entries: Mutex<HashMap<Pubkey, Entry>>,
+}
+
+impl Registry {
+    /// Claims the account for a new task. Returns `None` if a task is
+    /// already running for it, or if it is cooling down.
+    pub fn claim(self: &Arc<Self>, key: &Pubkey) -> Option<Claim> {
+        let mut entries = self.entries.lock().unwrap();
+        let e = entries.entry(*key).or_default();
+
+        if e.in_flight {
+            debug!("{} already in flight, skipping", key);
+            return None;
+        }
+
+        if matches!(e.not_before, Some(t) if Instant::now() < t) {
+            debug!("{} cooling down after {:?}, skipping", key, e.last_outcome);
+            return None;
+        }
+
+        e.in_flight = true;
+        e.attempts += 1;
+
+        Some(Claim {
+            registry: self.clone(),
+            key: *key,
+            done: false,
+        })
+    }
+
+    /// Forgets the accounts left alone for longer than the longest
+    /// cooldown, so that the registry does not keep every account it
+    /// ever saw. Those failing again start over from the first
+    /// backoff step.
+    pub fn prune(&self) {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        let n = entries.len();
+
+        entries.retain(|_, e| {
+            e.in_flight
+                || matches!(e.not_before, Some(t) if now < t + MAX_COOLDOWN)
+        });
+
+        if entries.len() < n {
+            debug!("forgot {} accounts", n - entries.len());
+        }
+    }
+
+    fn release(&self, key: &Pubkey, outcome: Outcome) {
+        let mut entries = self.entries.lock().unwrap();
+        let e = entries.entry(*key).or_default();
+
+        e.in_flight = false;
+        e.last_outcome = Some(outcome);
+
+        let cooldown = match outcome {
+            Outcome::Success => {
+                e.failures = 0;
+                SUCCESS_COOLDOWN
+            }
+            Outcome::Failure => {
+                e.failures += 1;
+                backoff(FAILURE_COOLDOWN, e.failures)
+            }
+            Outcome::Unrecoverable => {
+                e.failures += 1;
+                backoff(UNRECOVERABLE_COOLDOWN, e.failures)
+            }
+        };
+
+        debug!(
+            "{}: {:?} after {} attempts, cooling down for {:?}",
+            key, outcome, e.attempts, cooldown
+        );
+
+        e.not_before = Some(Instant::now() + cooldown);
+    }
+}
+
+fn backoff(base: Duration, failures: u32) -> Duration {
+    base.saturating_mul(1 << failures.saturating_sub(1).min(16))
+        .min(MAX_COOLDOWN)
+}
+
+/// An account claimed by a task. Dropping it without calling
+/// `finish`, e.g. on panic, counts as a failure.
+pub struct Claim {
+    registry: Arc<Registry>,
+    key: Pubkey,
+    done: bool,
+}
+
+impl Claim {
+    pub fn finish(mut self, outcome: Outcome) {
+        self.done = true;
+        self.registry.release(&self.key, outcome);
+    }
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        if !self.done {
+            self.registry.release(&self.key, Outcome::Failure);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cooldown(r: &Registry, key: &Pubkey) -> Duration {
+        let entries = r.entries.lock().unwrap();
+        entries[key].not_before.unwrap() - Instant::now()
+    }
+
+    // Lets the account be claimed again, as if the cooldown passed.
+    fn expire(r: &Registry, key: &Pubkey, ago: Duration) {
+        let mut entries = r.entries.lock().unwrap();
+        entries.get_mut(key).unwrap().not_before = Some(Instant::now() - ago);
+    }
+
+    fn approx(d: Duration, expected: Duration) -> bool {
+        d <= expected && expected - d < Duration::from_millis(100)
+    }
+
+    #[test]
+    fn test_double_claim() {
+        let r = Arc::new(Registry::default());
+        let key = Pubkey::new_unique();
+
+        let c = r.claim(&key).unwrap();
+        assert!(r.claim(&key).is_none());
+        assert!(r.claim(&Pubkey::new_unique()).is_some());
+
+        c.finish(Outcome::Success);
+        assert!(r.claim(&key).is_none());
+
+        expire(&r, &key, Duration::ZERO);
+        assert!(r.claim(&key).is_some());
+    }
+
+    #[test]
+    fn test_cooldown() {
+        let r = Arc::new(Registry::default());
+        let cases = [
+            (Outcome::Success, SUCCESS_COOLDOWN),
+            (Outcome::Failure, FAILURE_COOLDOWN),
+            (Outcome::Unrecoverable, UNRECOVERABLE_COOLDOWN),
+        ];
+
+        for (outcome, expected) in cases {
+            let key = Pubkey::new_unique();
+            r.claim(&key).unwrap().finish(outcome);
+            assert!(approx(cooldown(&r, &key), expected), "{:?}", outcome);
+        }
+    }
+
+    #[test]
+    fn test_backoff() {
+        let r = Arc::new(Registry::default());
+        let key = Pubkey::new_unique();
+
+        for i in 0..4u32 {
+            r.claim(&key).unwrap().finish(Outcome::Failure);
+            assert!(approx(cooldown(&r, &key), FAILURE_COOLDOWN * (1 << i)));
+            expire(&r, &key, Duration::ZERO);
+        }
+
+        // A success starts over.
+        r.claim(&key).unwrap().finish(Outcome::Success);
+        expire(&r, &key, Duration::ZERO);
+        r.claim(&key).unwrap().finish(Outcome::Failure);
+        assert!(approx(cooldown(&r, &key), FAILURE_COOLDOWN));
+
+        assert_eq!(backoff(FAILURE_COOLDOWN, 9), Duration::from_secs(256));
+        assert_eq!(backoff(FAILURE_COOLDOWN, 10), MAX_COOLDOWN);
+        assert_eq!(backoff(UNRECOVERABLE_COOLDOWN, 6), MAX_COOLDOWN);
+        assert_eq!(backoff(FAILURE_COOLDOWN, u32::MAX), MAX_COOLDOWN);
+    }
+
+    #[test]
+    fn test_dropped_claim() {
+        let r = Arc::new(Registry::default());
+        let key = Pubkey::new_unique();
+
+        drop(r.claim(&key).unwrap());
+
+        let entries = r.entries.lock().unwrap();
+        let e = &entries[&key];
+        assert!(!e.in_flight);
+        assert_eq!(e.failures, 1);
+        assert_eq!(e.last_outcome, Some(Outcome::Failure));
+    }
+
+    #[test]
+    fn test_prune() {
+        let r = Arc::new(Registry::default());
+        let (a, b, c) = (
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        );
+
+        r.claim(&a).unwrap().finish(Outcome::Failure);
+        r.claim(&b).unwrap().finish(Outcome::Failure);
+        let _c = r.claim(&c).unwrap();
+
+        expire(&r, &a, MAX_COOLDOWN);
+        expire(&r, &b, Duration::ZERO);
+        r.prune();
+
+        let entries = r.entries.lock().unwrap();
+        assert!(!entries.contains_key(&a));
+        assert!(entries.contains_key(&b));
+        assert!(entries.contains_key(&c));
+    }
+}
@@ -1,5 +1,3 @@
-use anchor_client::Program;
-
 use anchor_lang::{
     prelude::ToAccountMetas, solana_program::instruction::Instruction,
     InstructionData,
@@ -9,11 +7,9 @@ use fixed::types::I80F48;
 
 use serum_dex::state::MarketState as SerumMarketState;
 
-use solana_sdk::{
-    commitment_config::CommitmentConfig, pubkey::Pubkey, signature::Signature,
-};
+use solana_sdk::{pubkey::Pubkey, signature::Signature};
 
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
 use zo_abi::{
     accounts as ix_accounts, dex::ZoDexMarket as MarketState, instruction,
@@ -23,34 +19,90 @@ use zo_abi::{
 
 use std::cell::RefCell;
 
-use tracing::{debug, error, error_span, info, warn};
-
-use crate::liquidator::{
-    accounts::*, error::ErrorCode, margin_utils::*, math::*, swap, utils::*,
+use tracing::{debug, error, error_span, info, warn, Instrument};
+
+use crate::{
+    liquidator::{
+        accounts::*,
+        error::ErrorCode,
+        margin_utils::*,
+        math::*,
+        profit::{self, ProfitConfig},
+        queue::{self, JobQueue},
+        risk::{Exposure, RiskConfig},
+        swap,
+        utils::*,
+    },
+    metrics,
+    shutdown::Work,
+    tx::TxBuilder,
+    utils::AbortOnDrop,
+    AppState,
 };
 
 #[tracing::instrument(skip_all, level = "error")]
-pub async fn liquidate_loop(st: &'static crate::AppState, database: DbWrapper) {
+pub async fn liquidate_loop(
+    st: &'static crate::AppState,
+    database: DbWrapper,
+    tx: TxBuilder,
+    swap_cfg: swap::SwapConfig,
+    profit_cfg: ProfitConfig,
+    risk_cfg: Arc<RiskConfig>,
+    scan_interval: Duration,
+    refresh_interval: Duration,
+    rebalance_interval: Duration,
+    job_concurrency: usize,
+) {
     info!("starting liquidator v0.1.0...");
 
-    let mut last_refresh = std::time::Instant::now();
-    let mut interval =
-        tokio::time::interval(std::time::Duration::from_millis(250));
-    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    // The table is rebuilt in the background, so that the scans go on
+    // while the accounts are fetched.
+    let _refresh = AbortOnDrop(tokio::spawn(
+        refresh_loop(st, database.clone(), refresh_interval)
+            .instrument(tracing::Span::current()),
+    ));
+
+    let _rebalance = AbortOnDrop(tokio::spawn(
+        rebalance_loop(
+            st,
+            database.clone(),
+            tx.clone(),
+            swap_cfg,
+            rebalance_interval,
+        )
+        .instrument(tracing::Span::current()),
+    ));
+
+    let jobs = Arc::new(JobQueue::default());
+    let mut workers: Vec<_> = (0..job_concurrency)
+        .map(|_| {
+            let jobs = jobs.clone();
+
+            AbortOnDrop(tokio::spawn(
+                async move { queue::worker(st, &jobs).await }
+                    .instrument(tracing::Span::current()),
+            ))
+        })
+        .collect();
 
-    loop {
-        interval.tick().await;
+    let mut interval = tokio::time::interval(scan_interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
 
+    while st.shutdown.tick(&mut interval).await {
         let loop_start = std::time::Instant::now();
-        match database
-            .check_all_accounts(
-                &st,
-                &zo_abi::ZO_DEX_PID,
-                &zo_abi::SERUM_DEX_PID,
-            )
-            .await
-        {
+        match database.check_all_accounts(
+            &st,
+            &tx,
+            swap_cfg,
+            profit_cfg,
+            &risk_cfg,
+            &zo_abi::ZO_DEX_PID,
+            &zo_abi::SERUM_DEX_PID,
+            &jobs,
+        ) {
             Ok(n) => {
+                metrics::LIQUIDATOR_SCAN_SECONDS
+                    .observe(loop_start.elapsed().as_secs_f64());
                 debug!(
                     "Checked {} accounts in {} ms",
                     n,
@@ -61,13 +113,54 @@ pub async fn liquidate_loop(st: &'static crate::AppState, database: DbWrapper) {
                 error!("Had an oopsie-doopsie {:?}", e);
             }
         };
+    }
 
-        if last_refresh.elapsed().as_secs() > 300 {
-            match database.refresh_accounts(st) {
-                Ok(_) => info!("Refreshed account table"),
-                Err(e) => warn!("Failed to refresh: {}", e),
-            }
-            last_refresh = std::time::Instant::now();
+    // The workers stop once done with the job they are running.
+    futures::future::join_all(workers.iter_mut().map(|w| &mut w.0)).await;
+}
+
+async fn refresh_loop(
+    st: &'static crate::AppState,
+    database: DbWrapper,
+    period: Duration,
+) {
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    // The first tick completes immediately, and the table was
+    // just built.
+    interval.tick().await;
+
+    while st.shutdown.tick(&mut interval).await {
+        match database.refresh_accounts(st).await {
+            Ok(_) => info!("Refreshed account table"),
+            Err(e) => warn!("Failed to refresh: {}", e),
+        }
+    }
+}
+
+// Swaps that were capped or deferred by the slippage limit leave some
+// inventory behind, which is swapped back once the book allows.
+async fn rebalance_loop(
+    st: &'static crate::AppState,
+    database: DbWrapper,
+    tx: TxBuilder,
+    swap_cfg: swap::SwapConfig,
+    period: Duration,
+) {
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    while st.shutdown.tick(&mut interval).await {
+        let _work = match st.shutdown.begin(Work::Transaction) {
+            Some(x) => x,
+            None => return,
+        };
+        if let Err(e) = database
+            .rebalance(st, &tx, &zo_abi::SERUM_DEX_PID, &swap_cfg)
+            .await
+        {
+            warn!("Failed to rebalance: {:?}", e);
         }
     }
 }
@@ -77,8 +170,9 @@ pub async fn liquidate_loop(st: &'static crate::AppState, database: DbWrapper) {
     level = "error",
     fields(authority = %margin.authority),
 )]
-pub fn liquidate(
-    program: &Program,
+pub async fn liquidate(
+    st: &AppState,
+    tx: &TxBuilder,
     dex_program: &Pubkey,
     payer_pubkey: &Pubkey,
     payer_margin: &Margin,
@@ -98,6 +192,9 @@ pub fn liquidate(
     serum_markets: HashMap<usize, SerumMarketState>,
     serum_dex_program: &Pubkey,
     serum_vault_signers: HashMap<usize, Pubkey>,
+    swap_cfg: &swap::SwapConfig,
+    profit_cfg: &ProfitConfig,
+    risk_cfg: &RiskConfig,
 ) -> Result<(), ErrorCode> {
     // Given an account to liquidate
     // Go through its positions and pick the largest one.
@@ -192,7 +289,8 @@ pub fn liquidate(
         && (min_col.abs() <= max_position_notional.abs() || is_spot_bankrupt)
     {
         liquidate_perp_position(
-            program,
+            st,
+            tx,
             payer_pubkey,
             payer_margin,
             payer_margin_key,
@@ -210,14 +308,18 @@ pub fn liquidate(
             &market_info,
             &dex_market,
             position_index,
-            max_position_notional.is_positive(),
-        )?;
+            max_position_notional,
+            swap_cfg,
+            profit_cfg,
+            risk_cfg,
+        )
+        .await?;
     } else if is_spot_bankrupt && !has_positions {
         let oo_index_result = largest_open_order(cache, control)?;
 
         if let Some(_order_index) = oo_index_result {
             cancel(
-                program,
+                tx,
                 dex_program,
                 payer_pubkey,
                 margin_key,
@@ -229,11 +331,14 @@ pub fn liquidate(
                 state_key,
                 state_signer,
                 market_infos,
-            )?;
+            )
+            .await?;
         } else {
             settle_bankruptcy(
-                program,
+                st,
+                tx,
                 state,
+                cache,
                 state_key,
                 state_signer,
                 cache_key,
@@ -246,7 +351,10 @@ pub fn liquidate(
                 serum_markets,
                 serum_dex_program,
                 serum_vault_signers,
-            )?;
+                swap_cfg,
+                profit_cfg,
+            )
+            .await?;
         };
     } else if *min_col < 0u64 && quote_info.is_some() {
         // Close a spot position
@@ -257,7 +365,8 @@ pub fn liquidate(
         };
 
         liquidate_spot_position(
-            program,
+            st,
+            tx,
             payer_pubkey,
             payer_margin,
             payer_margin_key,
@@ -275,12 +384,16 @@ pub fn liquidate(
             serum_markets,
             serum_dex_program,
             serum_vault_signers,
-        )?;
+            swap_cfg,
+            profit_cfg,
+            risk_cfg,
+        )
+        .await?;
     } else if let Some(_order_index) = largest_open_order(cache, control)? {
         // Must cancel perp open orders
         info!("Closing {}'s {} perp order", margin.authority, col_index);
         cancel(
-            program,
+            tx,
             dex_program,
             payer_pubkey,
             margin_key,
@@ -292,7 +405,8 @@ pub fn liquidate(
             state_key,
             state_signer,
             market_infos,
-        )?;
+        )
+        .await?;
     }
 
     // TODO: Refactor so that you return an enum
@@ -300,8 +414,8 @@ pub fn liquidate(
     Ok(())
 }
 
-pub fn cancel(
-    program: &Program,
+pub async fn cancel(
+    tx: &TxBuilder,
     dex_program: &Pubkey,
     payer_pubkey: &Pubkey,
     margin_key: &Pubkey,
@@ -335,7 +449,7 @@ pub fn cancel(
     let market_info = market_info[oo_index];
 
     cancel_orders(
-        program,
+        tx,
         payer_pubkey,
         margin_key,
         &margin.control,
@@ -349,13 +463,14 @@ pub fn cancel(
         &market_info.bids,
         &market_info.asks,
         dex_program,
-    )?;
+    )
+    .await?;
 
     Ok(())
 }
 
-fn cancel_orders(
-    program: &Program,
+async fn cancel_orders(
+    tx: &TxBuilder,
     payer_pubkey: &Pubkey,
     margin_key: &Pubkey,
     control_key: &Pubkey,
@@ -374,30 +489,27 @@ fn cancel_orders(
     // e.g. the state_signer and open_orders.
 
     let span = error_span!("cancel_orders");
-    let signature = retry_send(
-        || {
-            program
-                .request()
-                .accounts(ix_accounts::ForceCancelAllPerpOrders {
-                    pruner: *payer_pubkey,
-                    state: *state_key,
-                    cache: *cache_key,
-                    state_signer: *state_signer,
-                    liqee_margin: *margin_key,
-                    liqee_control: *control_key,
-                    liqee_oo: *open_orders,
-                    dex_market: *dex_market,
-                    req_q: *req_q,
-                    event_q: *event_q,
-                    market_bids: *market_bids,
-                    market_asks: *market_asks,
-                    dex_program: *dex_program,
-                })
-                .args(instruction::ForceCancelAllPerpOrders { limit: 300 })
-                .options(CommitmentConfig::confirmed())
-        },
-        5,
-    );
+    let cancel_ix = Instruction {
+        accounts: ix_accounts::ForceCancelAllPerpOrders {
+            pruner: *payer_pubkey,
+            state: *state_key,
+            cache: *cache_key,
+            state_signer: *state_signer,
+            liqee_margin: *margin_key,
+            liqee_control: *control_key,
+            liqee_oo: *open_orders,
+            dex_market: *dex_market,
+            req_q: *req_q,
+            event_q: *event_q,
+            market_bids: *market_bids,
+            market_asks: *market_asks,
+            dex_program: *dex_program,
+        }
+        .to_account_metas(None),
+        data: instruction::ForceCancelAllPerpOrders { limit: 300 }.data(),
+        program_id: zo_abi::ID,
+    };
+    let signature = retry_send(tx, &[cancel_ix], 5).await;
 
     match signature {
         Ok(tx) => {
@@ -411,8 +523,9 @@ fn cancel_orders(
 }
 
 // Need the ix for liquidating a single account for a particular market.
-fn liquidate_perp_position(
-    program: &Program,
+async fn liquidate_perp_position(
+    st: &AppState,
+    tx: &TxBuilder,
     payer_pubkey: &Pubkey,
     liqor_margin: &Margin,
     liqor_margin_key: &Pubkey,
@@ -430,8 +543,12 @@ fn liquidate_perp_position(
     market_info: &MarketState,
     dex_market: &Pubkey,
     index: usize,
-    liqee_was_long: bool,
+    position_notional: I80F48,
+    swap_cfg: &swap::SwapConfig,
+    profit_cfg: &ProfitConfig,
+    risk_cfg: &RiskConfig,
 ) -> Result<(), ErrorCode> {
+    let liqee_was_long = position_notional.is_positive();
     let span = error_span!(
         "liquidate_perp_position",
         "{}",
@@ -458,18 +575,27 @@ fn liquidate_perp_position(
         }
         .to_account_metas(None),
         data: instruction::ForceCancelAllPerpOrders { limit: 300 }.data(),
-        program_id: program.id(),
+        program_id: zo_abi::ID,
     };
 
-    let mut asset_transfer_lots =
-        get_total_account_value(liqor_margin, liqor_control, state, cache)
-            .checked_div(cache.marks[index].price.into())
-            .unwrap()
-            .to_num::<i64>()
-            .safe_div(market_info.coin_lot_size)
-            .unwrap()
-            .safe_mul(5i64) // 5x leverage
-            .unwrap();
+    let exposure = Exposure::compute(liqor_margin, liqor_control, state, cache);
+    let mut asset_transfer_lots = risk_cfg
+        .perp_room(&exposure, state, index)
+        .checked_div(cache.marks[index].price.into())
+        .unwrap()
+        .to_num::<i64>()
+        .safe_div(market_info.coin_lot_size)
+        .unwrap();
+
+    if asset_transfer_lots <= 0 {
+        span.in_scope(|| {
+            warn!(
+                "No room left under the risk limits for {}",
+                String::from(state.perp_markets[index].symbol)
+            )
+        });
+        return Err(ErrorCode::RiskLimit);
+    }
 
     debug!(
         "{} | {} {}",
@@ -478,7 +604,7 @@ fn liquidate_perp_position(
         String::from(state.perp_markets[index].symbol)
     );
 
-    let mut liq_ix = Instruction {
+    let liq_ix = Instruction {
         accounts: ix_accounts::LiquidatePerpPosition {
             state: *state_key,
             cache: *cache_key,
@@ -503,11 +629,10 @@ fn liquidate_perp_position(
             asset_transfer_lots: asset_transfer_lots as u64,
         }
         .data(),
-        program_id: program.id(),
+        program_id: zo_abi::ID,
     };
 
     let rebalance_ix: Option<Instruction> = match swap::close_position_ix(
-        program,
         state,
         state_key,
         state_signer,
@@ -526,25 +651,34 @@ fn liquidate_perp_position(
         }
     };
 
+    // Capped by both the liqor's size and the liqee's position.
+    let notional = position_notional.abs().min(
+        I80F48::from_num(asset_transfer_lots)
+            * I80F48::from_num(market_info.coin_lot_size)
+            * I80F48::from(cache.marks[index].price),
+    );
+    let mut ixs = vec![cancel_ix, liq_ix];
+    ixs.extend(rebalance_ix);
+    let estimate = profit::estimate_perp(
+        st,
+        tx,
+        state,
+        cache,
+        market_info,
+        index,
+        notional,
+        liqee_was_long,
+        swap_cfg,
+        &ixs,
+    )
+    .await?;
+    span.in_scope(|| estimate.check("perp liquidation", profit_cfg))?;
+
     let reduction_max = 5;
 
     let mut signature;
     for _reduction in 0..reduction_max {
-        signature = retry_send(
-            || {
-                let request = program
-                    .request()
-                    .instruction(cancel_ix.clone())
-                    .instruction(liq_ix.clone())
-                    .options(CommitmentConfig::confirmed());
-                if let Some(ix) = rebalance_ix.clone() {
-                    request.instruction(ix)
-                } else {
-                    request
-                }
-            },
-            5,
-        );
+        signature = retry_send(tx, &ixs, 5).await;
 
         match signature {
             Ok(tx) => {
@@ -559,11 +693,14 @@ fn liquidate_perp_position(
             Err(e) => match e {
                 ErrorCode::LiquidationOverExposure => {
                     asset_transfer_lots /= 2;
-                    liq_ix.data = instruction::LiquidatePerpPosition {
+                    ixs[1].data = instruction::LiquidatePerpPosition {
                         asset_transfer_lots: asset_transfer_lots as u64,
                     }
                     .data();
                 }
+                ErrorCode::UnrecoverableTransactionError => {
+                    return Err(e);
+                }
                 _ => {
                     return Err(ErrorCode::LiquidationFailure);
                 }
@@ -574,8 +711,9 @@ fn liquidate_perp_position(
     Err(ErrorCode::LiquidationFailure)
 }
 
-fn liquidate_spot_position(
-    program: &Program,
+async fn liquidate_spot_position(
+    st: &AppState,
+    tx: &TxBuilder,
     payer_pubkey: &Pubkey,
     liqor_margin: &Margin,
     liqor_margin_key: &Pubkey,
@@ -593,6 +731,9 @@ fn liquidate_spot_position(
     serum_markets: HashMap<usize, SerumMarketState>,
     serum_dex_program: &Pubkey,
     serum_vault_signers: HashMap<usize, Pubkey>,
+    swap_cfg: &swap::SwapConfig,
+    profit_cfg: &ProfitConfig,
+    risk_cfg: &RiskConfig,
 ) -> Result<(), ErrorCode> {
     let span = error_span!("liquidate_spot_position");
 
@@ -611,9 +752,23 @@ fn liquidate_spot_position(
             .price
             .into();
 
-    let asset_transfer_lots =
-        get_total_account_value(liqor_margin, liqor_control, state, cache)
-            * I80F48::from_num(5u8);
+    // The liqor takes on the asset and receives the quote. The room
+    // is a notional, in native quote.
+    let exposure = Exposure::compute(liqor_margin, liqor_control, state, cache);
+    let room = risk_cfg
+        .spot_room(&exposure, state, asset_index)
+        .min(risk_cfg.spot_room(&exposure, state, quote_index));
+
+    if room.is_zero() {
+        span.in_scope(|| {
+            warn!(
+                "No room left under the risk limits for s{} -> s{}",
+                String::from(quote_collateral_info.oracle_symbol),
+                String::from(asset_collateral_info.oracle_symbol),
+            )
+        });
+        return Err(ErrorCode::RiskLimit);
+    }
 
     let size_estimate = estimate_spot_liquidation_size(
         liqee_margin,
@@ -628,7 +783,7 @@ fn liquidate_spot_position(
     let mut usdc_amount = match size_estimate {
         Some(size_estimate) => {
             let amount = size_estimate * fudge;
-            amount.min(asset_transfer_lots)
+            amount.min(room)
         }
         None => I80F48::ZERO,
     };
@@ -641,7 +796,7 @@ fn liquidate_spot_position(
         String::from(asset_collateral_info.oracle_symbol),
     );
 
-    let mut liq_ix = Instruction {
+    let liq_ix = Instruction {
         accounts: ix_accounts::LiquidateSpotPosition {
             state: *state_key,
             cache: *cache_key,
@@ -660,7 +815,7 @@ fn liquidate_spot_position(
                 .to_num::<i64>(),
         }
         .data(),
-        program_id: program.id(),
+        program_id: zo_abi::ID,
     };
 
     let mut swap_ixs: Vec<Instruction> = Vec::new();
@@ -680,9 +835,10 @@ fn liquidate_spot_position(
                 String::from(asset_collateral_info.oracle_symbol)
             );
             let remove_quote = swap::make_swap_ix(
-                program,
+                st,
                 payer_pubkey,
                 state,
+                cache,
                 state_key,
                 state_signer,
                 liqor_margin_key,
@@ -690,12 +846,14 @@ fn liquidate_spot_position(
                 serum_market,
                 serum_dex_program,
                 serum_vault_signer,
-                999_999_999_999_999u64,
+                swap::SwapAmount::All,
                 false,
                 quote_index,
-            )?;
+                swap_cfg,
+            )
+            .await?;
 
-            swap_ixs.push(remove_quote);
+            swap_ixs.extend(remove_quote);
         }
     }
 
@@ -715,9 +873,10 @@ fn liquidate_spot_position(
             );
             let remove_debt = swap::make_swap_ix(
                 // amount is what is what is being sold always usdc here
-                program,
+                st,
                 payer_pubkey,
                 state,
+                cache,
                 state_key,
                 state_signer,
                 liqor_margin_key,
@@ -725,15 +884,18 @@ fn liquidate_spot_position(
                 serum_market,
                 serum_dex_program,
                 serum_vault_signer,
-                usdc_amount.ceil().to_num(),
+                swap::SwapAmount::Max(usdc_amount.ceil().to_num()),
                 true,
                 asset_index,
-            )?;
+                swap_cfg,
+            )
+            .await?;
 
             let remove_excess = swap::make_swap_ix(
-                program,
+                st,
                 payer_pubkey,
                 state,
+                cache,
                 state_key,
                 state_signer,
                 liqor_margin_key,
@@ -741,32 +903,40 @@ fn liquidate_spot_position(
                 serum_market,
                 serum_dex_program,
                 serum_vault_signer,
-                999_999_999_999_999u64,
+                swap::SwapAmount::All,
                 false,
                 asset_index,
-            )?;
+                swap_cfg,
+            )
+            .await?;
 
-            swap_ixs.push(remove_debt);
-            swap_ixs.push(remove_excess);
+            // Either may be capped or deferred by the slippage limit,
+            // what's left is swapped by the next rebalance.
+            swap_ixs.extend(remove_debt);
+            swap_ixs.extend(remove_excess);
         }
     }
 
+    let mut ixs = vec![liq_ix];
+    ixs.extend(swap_ixs);
+    let estimate = profit::estimate_spot(
+        st,
+        tx,
+        state,
+        cache,
+        asset_index,
+        quote_index,
+        usdc_amount,
+        &serum_markets,
+        swap_cfg,
+        &ixs,
+    )
+    .await?;
+    span.in_scope(|| estimate.check("spot liquidation", profit_cfg))?;
+
     let reduction_max = 5;
     for _reduction in 0..reduction_max {
-        let signature = retry_send(
-            || {
-                let mut request_builder = program
-                    .request()
-                    .instruction(liq_ix.clone())
-                    .options(CommitmentConfig::confirmed());
-
-                for ix in swap_ixs.clone() {
-                    request_builder = request_builder.instruction(ix);
-                }
-                request_builder
-            },
-            5,
-        );
+        let signature = retry_send(tx, &ixs, 5).await;
 
         match signature {
             Ok(tx) => {
@@ -781,12 +951,15 @@ fn liquidate_spot_position(
             Err(e) => match e {
                 ErrorCode::LiquidationOverExposure => {
                     usdc_amount /= 2;
-                    liq_ix.data = instruction::LiquidateSpotPosition {
+                    ixs[0].data = instruction::LiquidateSpotPosition {
                         asset_transfer_amount: -(usdc_amount / asset_price)
                             .to_num::<i64>(),
                     }
                     .data();
                 }
+                ErrorCode::UnrecoverableTransactionError => {
+                    return Err(e);
+                }
                 _ => {
                     return Err(ErrorCode::LiquidationFailure);
                 }
@@ -796,9 +969,11 @@ fn liquidate_spot_position(
     return Err(ErrorCode::LiquidationFailure);
 }
 
-fn settle_bankruptcy(
-    program: &Program,
+async fn settle_bankruptcy(
+    st: &AppState,
+    tx: &TxBuilder,
     state: &State,
+    cache: &Cache,
     state_key: &Pubkey,
     state_signer: &Pubkey,
     cache_key: &Pubkey,
@@ -811,6 +986,8 @@ fn settle_bankruptcy(
     serum_markets: HashMap<usize, SerumMarketState>,
     serum_dex_program: &Pubkey,
     serum_vault_signers: HashMap<usize, Pubkey>,
+    swap_cfg: &swap::SwapConfig,
+    profit_cfg: &ProfitConfig,
 ) -> Result<(), ErrorCode> {
     let span = error_span!(
         "settle_bankruptcy",
@@ -835,10 +1012,11 @@ fn settle_bankruptcy(
                 if amount == 0 || amount <= 2 * serum_market.coin_lot_size {
                     None
                 } else {
-                    Some(swap::make_swap_ix(
-                        program,
+                    swap::make_swap_ix(
+                        st,
                         liqor_key,
                         state,
+                        cache,
                         state_key,
                         state_signer,
                         liqor_margin_key,
@@ -846,43 +1024,57 @@ fn settle_bankruptcy(
                         serum_market,
                         serum_dex_program,
                         serum_vault_signer,
-                        amount,
+                        swap::SwapAmount::Max(amount),
                         true,
                         i,
-                    )?)
+                        swap_cfg,
+                    )
+                    .await?
                 }
             } else {
                 None
             };
 
-        signature_results.push((
+        let mut ixs: Vec<Instruction> = swap.into_iter().collect();
+        let estimate = profit::estimate_bankruptcy(
+            st,
+            tx,
+            state,
+            cache,
             i,
-            retry_send(
-                || {
-                    let request_builder = program
-                        .request()
-                        .accounts(ix_accounts::SettleBankruptcy {
-                            state: *state_key,
-                            state_signer: *state_signer,
-                            cache: *cache_key,
-                            liqor: *liqor_key,
-                            liqor_margin: *liqor_margin_key,
-                            liqor_control: *liqor_control_key,
-                            liqee_margin: *liqee_margin_key,
-                            liqee_control: liqee_margin.control,
-                            asset_mint: *mint,
-                        })
-                        .args(instruction::SettleBankruptcy {})
-                        .options(CommitmentConfig::confirmed());
-
-                    match swap.clone() {
-                        Some(ix) => request_builder.instruction(ix),
-                        None => request_builder,
-                    }
-                },
-                5,
-            ),
-        ));
+            liqee_colls[i],
+            serum_markets.get(&i),
+            swap_cfg,
+            &ixs,
+        )
+        .await?;
+
+        if span
+            .in_scope(|| estimate.check("bankruptcy settlement", profit_cfg))
+            .is_err()
+        {
+            continue;
+        }
+
+        // The settlement goes after the swap.
+        ixs.push(Instruction {
+            accounts: ix_accounts::SettleBankruptcy {
+                state: *state_key,
+                state_signer: *state_signer,
+                cache: *cache_key,
+                liqor: *liqor_key,
+                liqor_margin: *liqor_margin_key,
+                liqor_control: *liqor_control_key,
+                liqee_margin: *liqee_margin_key,
+                liqee_control: liqee_margin.control,
+                asset_mint: *mint,
+            }
+            .to_account_metas(None),
+            data: instruction::SettleBankruptcy {}.data(),
+            program_id: zo_abi::ID,
+        });
+
+        signature_results.push((i, retry_send(tx, &ixs, 5).await));
     }
 
     for (i, signature) in signature_results.iter() {
@@ -1,9 +1,14 @@
-use crate::{liquidator::accounts::DbWrapper, Error};
+use crate::{
+    heartbeat::{Heartbeat, Task},
+    liquidator::accounts::{DbWrapper, Update},
+    metrics,
+    rpc::RpcPool,
+    utils::load_buf,
+    Error,
+};
 use anchor_client::solana_client::rpc_config::{
     RpcAccountInfoConfig, RpcProgramAccountsConfig,
 };
-use anchor_lang::Discriminator;
-use bytemuck::Pod;
 use futures::StreamExt;
 use jsonrpc_core_client::transports::ws;
 use solana_account_decoder::{UiAccountData, UiAccountEncoding};
@@ -13,19 +18,10 @@ use std::str::FromStr;
 use tracing::{debug, info, warn};
 use zo_abi::{Cache, Control, Margin, State};
 
-fn load_buf<T: Pod + Discriminator>(b: &[u8]) -> Option<&T> {
-    match b.len() == 8 + std::mem::size_of::<T>()
-        && b[..8] == T::discriminator()
-    {
-        false => None,
-        true => bytemuck::try_from_bytes(&b[8..]).ok(),
-    }
-}
-
 #[tracing::instrument(skip_all, level = "error", name = "listener")]
 pub async fn start_listener(
     pid: &Pubkey,
-    ws_url: String,
+    rpc: &'static RpcPool,
     db: DbWrapper,
 ) {
     let mut interval = tokio::time::interval(std::time::Duration::from_secs(5));
@@ -41,22 +37,30 @@ pub async fn start_listener(
         with_context: Some(false),
     };
 
+    let hb = Heartbeat::new(Task::Listener, "liquidator");
+
     loop {
         interval.tick().await;
         info!("connecting...");
 
-        let sub = ws::try_connect::<RpcSolPubSubClient>(&ws_url)
-            .unwrap()
-            .await
-            .and_then(|p| {
-                p.program_subscribe(pid.to_string(), Some(config.clone()))
-            });
+        let ws_url = rpc.ws_url();
+        let sub = match ws::try_connect::<RpcSolPubSubClient>(&ws_url) {
+            Ok(x) => x.await,
+            Err(e) => Err(e),
+        }
+        .and_then(|p| {
+            p.program_subscribe(pid.to_string(), Some(config.clone()))
+        });
 
         let mut sub = match sub {
             Ok(x) => x,
             Err(e) => {
                 let e = Error::from(e);
                 warn!("failed to connect: {0}: {0:?}", e);
+                rpc.ws_failed(&ws_url);
+                metrics::LISTENER_RECONNECTS
+                    .with_label_values(&["liquidator"])
+                    .inc();
                 continue;
             }
         };
@@ -70,6 +74,8 @@ pub async fn start_listener(
                 }
             };
 
+            hb.beat();
+
             let buf = &match resp.value.account.data {
                 UiAccountData::Binary(b, _) => base64::decode(b).unwrap(),
                 _ => panic!(),
@@ -79,22 +85,26 @@ pub async fn start_listener(
             if let Some(a) = load_buf::<Control>(buf) {
                 debug!("got control data: {}", pk);
                 let pk = Pubkey::from_str(pk).unwrap();
-                db.get().lock().unwrap().update_control(pk, *a);
+                db.apply(Update::Control(pk, *a));
             } else if let Some(a) = load_buf::<Margin>(buf) {
                 debug!("got margin data: {}", pk);
                 let pk = Pubkey::from_str(pk).unwrap();
-                db.get().lock().unwrap().update_margin(pk, *a);
+                db.apply(Update::Margin(pk, *a));
             } else if let Some(a) = load_buf::<Cache>(buf) {
                 debug!("got cache data: {}", pk);
-                db.get().lock().unwrap().update_cache(*a);
+                db.apply(Update::Cache(*a));
             } else if let Some(a) = load_buf::<State>(buf) {
                 debug!("got state data: {}", pk);
-                db.get().lock().unwrap().update_state(*a);
+                db.apply(Update::State(*a));
             } else {
                 debug!("unknown account type, skipping");
             }
         }
 
         warn!("disconnect");
+        rpc.ws_failed(&ws_url);
+        metrics::LISTENER_RECONNECTS
+            .with_label_values(&["liquidator"])
+            .inc();
     }
 }
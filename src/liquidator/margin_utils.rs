@@ -1,6 +1,10 @@
 use fixed::types::I80F48;
 
-use std::cell::Ref;
+use once_cell::sync::Lazy;
+
+use std::{cell::Ref, collections::HashSet, sync::Mutex};
+
+use tracing::warn;
 
 use zo_abi::{
     Cache, Control, FractionType, Margin, PerpType, State, MAX_COLLATERALS,
@@ -172,26 +176,79 @@ pub fn get_price_vector(
 
     for i in 0..state.total_markets {
         let i = i as usize;
-        match state.perp_markets[i].perp_type {
-            PerpType::Future => {
-                price[i + MAX_COLLATERALS] =
-                    get_oracle(cache, &state.perp_markets[i].oracle_symbol)
-                        .unwrap()
-                        .price
-                        .into();
-            }
-            PerpType::Square => {
-                price[i + MAX_COLLATERALS] = cache.marks[i].price.into();
-            }
-            _ => {
-                println!("Not implemented bruh");
-            }
-        }
+        price[i + MAX_COLLATERALS] = get_perp_price_or_mark(state, cache, i);
     }
 
     price
 }
 
+/// The price of the i'th perp market, in sUSD/sAsset, or `None` if
+/// its oracle is missing from the cache.
+pub fn get_perp_price(
+    state: &State,
+    cache: &Cache,
+    i: usize,
+) -> Option<I80F48> {
+    let info = &state.perp_markets[i];
+    let mark: I80F48 = cache.marks[i].price.into();
+
+    // Squares price from the mark alone.
+    if matches!(info.perp_type, PerpType::Square) {
+        return Some(mark);
+    }
+
+    let oracle: I80F48 = get_oracle(cache, &info.oracle_symbol)?.price.into();
+
+    Some(perp_index_price(
+        info.perp_type,
+        oracle,
+        mark,
+        strike_price(info.strike, info.asset_decimals),
+    ))
+}
+
+// The markets priced from the mark so far, to only warn once each.
+static NO_ORACLE: Lazy<Mutex<HashSet<String>>> = Lazy::new(Mutex::default);
+
+/// Falls back to the mark when the oracle is missing, rather than
+/// failing every account holding the market.
+fn get_perp_price_or_mark(state: &State, cache: &Cache, i: usize) -> I80F48 {
+    get_perp_price(state, cache, i).unwrap_or_else(|| {
+        let symbol = String::from(state.perp_markets[i].symbol);
+
+        if NO_ORACLE.lock().unwrap().insert(symbol.clone()) {
+            warn!("No oracle for {}, pricing from the mark", symbol);
+        }
+
+        cache.marks[i].price.into()
+    })
+}
+
+/// Same as the program's index price. Futures follow the oracle,
+/// options are worth their intrinsic value at the oracle price, and
+/// squares follow the mark, which the program derives from the oracle
+/// when caching it.
+fn perp_index_price(
+    perp_type: PerpType,
+    oracle: I80F48,
+    mark: I80F48,
+    strike: I80F48,
+) -> I80F48 {
+    match perp_type {
+        PerpType::Future => oracle,
+        PerpType::CallOption => (oracle - strike).max(I80F48::ZERO),
+        PerpType::PutOption => (strike - oracle).max(I80F48::ZERO),
+        PerpType::Square => mark,
+    }
+}
+
+/// The strike is in sUSD per big asset, convert it to sUSD/sAsset
+/// like the oracle prices.
+fn strike_price(strike: u64, asset_decimals: u8) -> I80F48 {
+    I80F48::from_num(strike)
+        .unwrapped_div(I80F48::from_num(10u64.pow(asset_decimals as u32)))
+}
+
 pub fn get_pnl_vectors(
     control: &Control,
     state: &State,
@@ -218,19 +275,7 @@ pub fn get_pnl_vectors(
                 )); // In smol asset
 
         // Unrealized pnl calcs
-        let price = match state.perp_markets[i].perp_type {
-            PerpType::Future => {
-                get_oracle(cache, &state.perp_markets[i].oracle_symbol)
-                    .unwrap()
-                    .price
-                    .into()
-            }
-            PerpType::Square => cache.marks[i].price.into(),
-            _ => {
-                println!("Not implemented bruh");
-                I80F48::ZERO
-            }
-        };
+        let price = get_perp_price_or_mark(state, cache, i);
 
         let unrealized_pnl =
             safe_mul_i80f48(I80F48::from_num(info.pos_size), price)
@@ -429,6 +474,9 @@ fn get_mf_wrapped(
     )
 }
 
+// Only the tests check an account outright, the scan goes by
+// `get_mf_buffer`.
+#[cfg(test)]
 pub fn check_mf(
     check: FractionType,
     margin: &Margin,
@@ -437,6 +485,20 @@ pub fn check_mf(
     cache: &Cache,
     tolerance: I80F48, // for making sure the account is liquidatable, should be less than 1.0
 ) -> bool {
+    get_mf_buffer(check, margin, control, state, cache, tolerance)
+        >= I80F48::ZERO
+}
+
+/// How far the account's fraction is above the one required by
+/// `check`, scaled by `tolerance`. Negative when `check_mf` fails.
+pub fn get_mf_buffer(
+    check: FractionType,
+    margin: &Margin,
+    control: &Control,
+    state: &State,
+    cache: &Cache,
+    tolerance: I80F48,
+) -> I80F48 {
     let position_vector = match check {
         FractionType::Initial | FractionType::Cancel => {
             get_position_open_vector(margin, control)
@@ -477,7 +539,7 @@ pub fn check_mf(
                 &unrealized_pnl,
                 &weight_vector,
             );
-            omf >= safe_mul_i80f48(imf, tolerance)
+            omf - safe_mul_i80f48(imf, tolerance)
         }
         FractionType::Cancel => {
             let omf = get_mf(
@@ -496,7 +558,7 @@ pub fn check_mf(
                 &unrealized_pnl,
                 &weight_vector,
             );
-            omf >= safe_mul_i80f48(cmf, tolerance)
+            omf - safe_mul_i80f48(cmf, tolerance)
         }
         FractionType::Maintenance => {
             let mf = get_mf(
@@ -515,7 +577,7 @@ pub fn check_mf(
                 &unrealized_pnl,
                 &weight_vector,
             );
-            mf >= safe_mul_i80f48(mmf, tolerance)
+            mf - safe_mul_i80f48(mmf, tolerance)
         }
     }
 }
@@ -529,6 +591,72 @@ pub fn get_total_account_value(
     get_mf_wrapped(MfReturnOption::Mf, margin, control, state, cache)
 }
 
+/// The prices the account's fractions depend on, with the sign of the
+/// position they price, and the account's gross exposure to them, in
+/// the same units as `get_total_account_value`.
+pub fn get_price_exposure(
+    margin: &Margin,
+    control: &Control,
+    state: &State,
+    cache: &Cache,
+) -> (Vec<(usize, bool, I80F48)>, I80F48) {
+    let position = get_position_open_vector(margin, control);
+    let prices = get_price_vector(state, cache, &position);
+
+    let mut deps = Vec::new();
+    let mut exposure = I80F48::ZERO;
+
+    for i in 0..(MAX_COLLATERALS + MAX_MARKETS) {
+        if position[i].is_zero() {
+            continue;
+        }
+
+        deps.push((i, position[i].is_negative(), prices[i]));
+        exposure = safe_add_i80f48(
+            exposure,
+            safe_mul_i80f48(position[i], prices[i]).abs(),
+        );
+    }
+
+    (deps, exposure)
+}
+
+/// The signed value of each of the account's collaterals, then each
+/// of its perp positions, in the same units as
+/// `get_total_account_value`.
+pub fn get_notional_vector(
+    margin: &Margin,
+    control: &Control,
+    state: &State,
+    cache: &Cache,
+) -> [I80F48; MAX_COLLATERALS + MAX_MARKETS] {
+    let position = get_position_vector(margin, control);
+    let prices = get_price_vector(state, cache, &position);
+
+    let mut notional = [I80F48::ZERO; MAX_COLLATERALS + MAX_MARKETS];
+    for i in 0..(MAX_COLLATERALS + MAX_MARKETS) {
+        notional[i] = safe_mul_i80f48(position[i], prices[i]);
+    }
+
+    notional
+}
+
+/// How far the account's MF is below its MMF, in the same units as
+/// `get_total_account_value`. Positive when the account can be
+/// liquidated, and the larger it is, the more the protocol is exposed.
+pub fn get_maintenance_deficit(
+    margin: &Margin,
+    control: &Control,
+    state: &State,
+    cache: &Cache,
+) -> I80F48 {
+    let mf = get_mf_wrapped(MfReturnOption::Mf, margin, control, state, cache);
+    let mmf =
+        get_mf_wrapped(MfReturnOption::Mmf, margin, control, state, cache);
+
+    mmf - mf
+}
+
 pub fn largest_open_order(
     cache: &Cache,
     control: &Control,
@@ -667,7 +795,7 @@ pub fn estimate_spot_liquidation_size(
 mod tests {
     use super::*;
     use anchor_lang::prelude::Pubkey;
-    use solana_client::rpc_client::RpcClient;
+    use solana_client::nonblocking::rpc_client::RpcClient;
     use std::str::FromStr;
 
     #[test]
@@ -701,15 +829,18 @@ mod tests {
         }
     }
 
-    #[test]
-    fn test_get_position_vector() {
+    #[tokio::test]
+    async fn test_get_position_vector() {
         let rpc_client =
             RpcClient::new("https://solana-api.syndica.io/access-token/3IAUwhDwhzjX2Fg5s9HLYfjyoAfSz80hYyOPACaVZhJsqo4HsjIzUr74aN01F8QQ/rpc".to_string());
 
-        let margins =
-            load_program_accounts::<Margin>(&rpc_client, &zo_abi::ID).unwrap();
+        let margins = load_program_accounts::<Margin>(&rpc_client, &zo_abi::ID)
+            .await
+            .unwrap();
         let controls =
-            load_program_accounts::<Control>(&rpc_client, &zo_abi::ID).unwrap();
+            load_program_accounts::<Control>(&rpc_client, &zo_abi::ID)
+                .await
+                .unwrap();
 
         let mut test_margin: Option<Margin> = None;
         for (_key, margin) in margins.iter() {
@@ -747,25 +878,30 @@ mod tests {
         }
     }
 
-    #[test]
-    fn test_get_account_value() {
+    #[tokio::test]
+    async fn test_get_account_value() {
         let rpc_client =
             RpcClient::new("https://solana-api.syndica.io/access-token/3IAUwhDwhzjX2Fg5s9HLYfjyoAfSz80hYyOPACaVZhJsqo4HsjIzUr74aN01F8QQ/rpc".to_string());
 
         let state: State =
-            load_program_accounts::<State>(&rpc_client, &zo_abi::ID).unwrap()
-                [0]
-            .1;
+            load_program_accounts::<State>(&rpc_client, &zo_abi::ID)
+                .await
+                .unwrap()[0]
+                .1;
 
         let cache: Cache =
-            load_program_accounts::<Cache>(&rpc_client, &&zo_abi::ID).unwrap()
-                [0]
-            .1;
-
-        let margins =
-            load_program_accounts::<Margin>(&rpc_client, &zo_abi::ID).unwrap();
+            load_program_accounts::<Cache>(&rpc_client, &&zo_abi::ID)
+                .await
+                .unwrap()[0]
+                .1;
+
+        let margins = load_program_accounts::<Margin>(&rpc_client, &zo_abi::ID)
+            .await
+            .unwrap();
         let controls =
-            load_program_accounts::<Control>(&rpc_client, &zo_abi::ID).unwrap();
+            load_program_accounts::<Control>(&rpc_client, &zo_abi::ID)
+                .await
+                .unwrap();
 
         let mut test_margin: Option<Margin> = None;
         for (_key, margin) in margins.iter() {
@@ -801,25 +937,30 @@ mod tests {
         println!("{}", mf)
     }
 
-    #[test]
-    fn test_get_mmf() {
+    #[tokio::test]
+    async fn test_get_mmf() {
         let rpc_client =
             RpcClient::new("https://solana-api.syndica.io/access-token/3IAUwhDwhzjX2Fg5s9HLYfjyoAfSz80hYyOPACaVZhJsqo4HsjIzUr74aN01F8QQ/rpc".to_string());
 
         let state: State =
-            load_program_accounts::<State>(&rpc_client, &zo_abi::ID).unwrap()
-                [0]
-            .1;
+            load_program_accounts::<State>(&rpc_client, &zo_abi::ID)
+                .await
+                .unwrap()[0]
+                .1;
 
         let cache: Cache =
-            load_program_accounts::<Cache>(&rpc_client, &&zo_abi::ID).unwrap()
-                [0]
-            .1;
-
-        let margins =
-            load_program_accounts::<Margin>(&rpc_client, &zo_abi::ID).unwrap();
+            load_program_accounts::<Cache>(&rpc_client, &&zo_abi::ID)
+                .await
+                .unwrap()[0]
+                .1;
+
+        let margins = load_program_accounts::<Margin>(&rpc_client, &zo_abi::ID)
+            .await
+            .unwrap();
         let controls =
-            load_program_accounts::<Control>(&rpc_client, &zo_abi::ID).unwrap();
+            load_program_accounts::<Control>(&rpc_client, &zo_abi::ID)
+                .await
+                .unwrap();
 
         let mut test_margin: Option<Margin> = None;
         for (_key, margin) in margins.iter() {
@@ -855,25 +996,30 @@ mod tests {
         println!("{}", mmf)
     }
 
-    #[test]
-    fn test_get_imf() {
+    #[tokio::test]
+    async fn test_get_imf() {
         let rpc_client =
             RpcClient::new("https://solana-api.syndica.io/access-token/3IAUwhDwhzjX2Fg5s9HLYfjyoAfSz80hYyOPACaVZhJsqo4HsjIzUr74aN01F8QQ/rpc".to_string());
 
         let state: State =
-            load_program_accounts::<State>(&rpc_client, &zo_abi::ID).unwrap()
-                [0]
-            .1;
+            load_program_accounts::<State>(&rpc_client, &zo_abi::ID)
+                .await
+                .unwrap()[0]
+                .1;
 
         let cache: Cache =
-            load_program_accounts::<Cache>(&rpc_client, &&zo_abi::ID).unwrap()
-                [0]
-            .1;
-
-        let margins =
-            load_program_accounts::<Margin>(&rpc_client, &zo_abi::ID).unwrap();
+            load_program_accounts::<Cache>(&rpc_client, &&zo_abi::ID)
+                .await
+                .unwrap()[0]
+                .1;
+
+        let margins = load_program_accounts::<Margin>(&rpc_client, &zo_abi::ID)
+            .await
+            .unwrap();
         let controls =
-            load_program_accounts::<Control>(&rpc_client, &zo_abi::ID).unwrap();
+            load_program_accounts::<Control>(&rpc_client, &zo_abi::ID)
+                .await
+                .unwrap();
 
         let mut test_margin: Option<Margin> = None;
         for (_key, margin) in margins.iter() {
@@ -909,25 +1055,30 @@ mod tests {
         println!("{}", imf);
     }
 
-    #[test]
-    fn test_imf_cmf() {
+    #[tokio::test]
+    async fn test_imf_cmf() {
         let rpc_client =
             RpcClient::new("https://solana-api.syndica.io/access-token/3IAUwhDwhzjX2Fg5s9HLYfjyoAfSz80hYyOPACaVZhJsqo4HsjIzUr74aN01F8QQ/rpc".to_string());
 
         let state: State =
-            load_program_accounts::<State>(&rpc_client, &zo_abi::ID).unwrap()
-                [0]
-            .1;
+            load_program_accounts::<State>(&rpc_client, &zo_abi::ID)
+                .await
+                .unwrap()[0]
+                .1;
 
         let cache: Cache =
-            load_program_accounts::<Cache>(&rpc_client, &&zo_abi::ID).unwrap()
-                [0]
-            .1;
-
-        let margins =
-            load_program_accounts::<Margin>(&rpc_client, &zo_abi::ID).unwrap();
+            load_program_accounts::<Cache>(&rpc_client, &&zo_abi::ID)
+                .await
+                .unwrap()[0]
+                .1;
+
+        let margins = load_program_accounts::<Margin>(&rpc_client, &zo_abi::ID)
+            .await
+            .unwrap();
         let controls =
-            load_program_accounts::<Control>(&rpc_client, &zo_abi::ID).unwrap();
+            load_program_accounts::<Control>(&rpc_client, &zo_abi::ID)
+                .await
+                .unwrap();
 
         let mut test_margin: Option<Margin> = None;
         for (_key, margin) in margins.iter() {
@@ -979,25 +1130,30 @@ mod tests {
         );
     }
 
-    #[test]
-    fn test_check_mf_maintenance() {
+    #[tokio::test]
+    async fn test_check_mf_maintenance() {
         let rpc_client =
             RpcClient::new("https://solana-api.syndica.io/access-token/3IAUwhDwhzjX2Fg5s9HLYfjyoAfSz80hYyOPACaVZhJsqo4HsjIzUr74aN01F8QQ/rpc".to_string());
 
         let state: State =
-            load_program_accounts::<State>(&rpc_client, &zo_abi::ID).unwrap()
-                [0]
-            .1;
+            load_program_accounts::<State>(&rpc_client, &zo_abi::ID)
+                .await
+                .unwrap()[0]
+                .1;
 
         let cache: Cache =
-            load_program_accounts::<Cache>(&rpc_client, &&zo_abi::ID).unwrap()
-                [0]
-            .1;
-
-        let margins =
-            load_program_accounts::<Margin>(&rpc_client, &zo_abi::ID).unwrap();
+            load_program_accounts::<Cache>(&rpc_client, &&zo_abi::ID)
+                .await
+                .unwrap()[0]
+                .1;
+
+        let margins = load_program_accounts::<Margin>(&rpc_client, &zo_abi::ID)
+            .await
+            .unwrap();
         let controls =
-            load_program_accounts::<Control>(&rpc_client, &zo_abi::ID).unwrap();
+            load_program_accounts::<Control>(&rpc_client, &zo_abi::ID)
+                .await
+                .unwrap();
 
         let mut test_margin: Option<Margin> = None;
         for (_key, margin) in margins.iter() {
@@ -1035,25 +1191,30 @@ mod tests {
         assert!(is_ok);
     }
 
-    #[test]
-    fn test_check_mf_cancel() {
+    #[tokio::test]
+    async fn test_check_mf_cancel() {
         let rpc_client =
             RpcClient::new("https://solana-api.syndica.io/access-token/3IAUwhDwhzjX2Fg5s9HLYfjyoAfSz80hYyOPACaVZhJsqo4HsjIzUr74aN01F8QQ/rpc".to_string());
 
         let state: State =
-            load_program_accounts::<State>(&rpc_client, &zo_abi::ID).unwrap()
-                [0]
-            .1;
+            load_program_accounts::<State>(&rpc_client, &zo_abi::ID)
+                .await
+                .unwrap()[0]
+                .1;
 
         let cache: Cache =
-            load_program_accounts::<Cache>(&rpc_client, &&zo_abi::ID).unwrap()
-                [0]
-            .1;
-
-        let margins =
-            load_program_accounts::<Margin>(&rpc_client, &zo_abi::ID).unwrap();
+            load_program_accounts::<Cache>(&rpc_client, &&zo_abi::ID)
+                .await
+                .unwrap()[0]
+                .1;
+
+        let margins = load_program_accounts::<Margin>(&rpc_client, &zo_abi::ID)
+            .await
+            .unwrap();
         let controls =
-            load_program_accounts::<Control>(&rpc_client, &zo_abi::ID).unwrap();
+            load_program_accounts::<Control>(&rpc_client, &zo_abi::ID)
+                .await
+                .unwrap();
 
         let mut test_margin: Option<Margin> = None;
         for (_key, margin) in margins.iter() {
@@ -1090,25 +1251,30 @@ mod tests {
         assert!(is_ok);
     }
 
-    #[test]
-    fn test_check_mf_initial() {
+    #[tokio::test]
+    async fn test_check_mf_initial() {
         let rpc_client =
             RpcClient::new("https://solana-api.syndica.io/access-token/3IAUwhDwhzjX2Fg5s9HLYfjyoAfSz80hYyOPACaVZhJsqo4HsjIzUr74aN01F8QQ/rpc".to_string());
 
         let state: State =
-            load_program_accounts::<State>(&rpc_client, &zo_abi::ID).unwrap()
-                [0]
-            .1;
+            load_program_accounts::<State>(&rpc_client, &zo_abi::ID)
+                .await
+                .unwrap()[0]
+                .1;
 
         let cache: Cache =
-            load_program_accounts::<Cache>(&rpc_client, &&zo_abi::ID).unwrap()
-                [0]
-            .1;
-
-        let margins =
-            load_program_accounts::<Margin>(&rpc_client, &zo_abi::ID).unwrap();
+            load_program_accounts::<Cache>(&rpc_client, &&zo_abi::ID)
+                .await
+                .unwrap()[0]
+                .1;
+
+        let margins = load_program_accounts::<Margin>(&rpc_client, &zo_abi::ID)
+            .await
+            .unwrap();
         let controls =
-            load_program_accounts::<Control>(&rpc_client, &zo_abi::ID).unwrap();
+            load_program_accounts::<Control>(&rpc_client, &zo_abi::ID)
+                .await
+                .unwrap();
 
         let mut test_margin: Option<Margin> = None;
         for (_key, margin) in margins.iter() {
@@ -1146,14 +1312,15 @@ mod tests {
         assert!(is_ok);
     }
 
-    #[test]
-    fn test_get_base_weights() {
+    #[tokio::test]
+    async fn test_get_base_weights() {
         let rpc_client =
             RpcClient::new("https://solana-api.syndica.io/access-token/3IAUwhDwhzjX2Fg5s9HLYfjyoAfSz80hYyOPACaVZhJsqo4HsjIzUr74aN01F8QQ/rpc".to_string());
         let state: State =
-            load_program_accounts::<State>(&rpc_client, &zo_abi::ID).unwrap()
-                [0]
-            .1;
+            load_program_accounts::<State>(&rpc_client, &zo_abi::ID)
+                .await
+                .unwrap()[0]
+                .1;
 
         let base = get_base_weight_vector(&state);
         let mut true_weights = [I80F48::ZERO; MAX_COLLATERALS + MAX_MARKETS];
@@ -1175,25 +1342,30 @@ mod tests {
         }
     }
 
-    #[test]
-    fn test_estimate_spot_liq_size() {
+    #[tokio::test]
+    async fn test_estimate_spot_liq_size() {
         let rpc_client =
             RpcClient::new("https://solana-api.syndica.io/access-token/3IAUwhDwhzjX2Fg5s9HLYfjyoAfSz80hYyOPACaVZhJsqo4HsjIzUr74aN01F8QQ/rpc".to_string());
 
         let state: State =
-            load_program_accounts::<State>(&rpc_client, &zo_abi::ID).unwrap()
-                [0]
-            .1;
+            load_program_accounts::<State>(&rpc_client, &zo_abi::ID)
+                .await
+                .unwrap()[0]
+                .1;
 
         let cache: Cache =
-            load_program_accounts::<Cache>(&rpc_client, &&zo_abi::ID).unwrap()
-                [0]
-            .1;
-
-        let margins =
-            load_program_accounts::<Margin>(&rpc_client, &zo_abi::ID).unwrap();
+            load_program_accounts::<Cache>(&rpc_client, &&zo_abi::ID)
+                .await
+                .unwrap()[0]
+                .1;
+
+        let margins = load_program_accounts::<Margin>(&rpc_client, &zo_abi::ID)
+            .await
+            .unwrap();
         let controls =
-            load_program_accounts::<Control>(&rpc_client, &zo_abi::ID).unwrap();
+            load_program_accounts::<Control>(&rpc_client, &zo_abi::ID)
+                .await
+                .unwrap();
 
         let mut test_margin: Option<Margin> = None;
         for (_key, margin) in margins.iter() {
@@ -1242,25 +1414,30 @@ mod tests {
         assert!(t2.is_some());
     }
 
-    #[test]
-    fn test_estimate_spot_liq_size2() {
+    #[tokio::test]
+    async fn test_estimate_spot_liq_size2() {
         let rpc_client =
             RpcClient::new("https://solana-api.syndica.io/access-token/3IAUwhDwhzjX2Fg5s9HLYfjyoAfSz80hYyOPACaVZhJsqo4HsjIzUr74aN01F8QQ/rpc".to_string());
 
         let state: State =
-            load_program_accounts::<State>(&rpc_client, &zo_abi::ID).unwrap()
-                [0]
-            .1;
+            load_program_accounts::<State>(&rpc_client, &zo_abi::ID)
+                .await
+                .unwrap()[0]
+                .1;
 
         let cache: Cache =
-            load_program_accounts::<Cache>(&rpc_client, &&zo_abi::ID).unwrap()
-                [0]
-            .1;
-
-        let margins =
-            load_program_accounts::<Margin>(&rpc_client, &zo_abi::ID).unwrap();
+            load_program_accounts::<Cache>(&rpc_client, &&zo_abi::ID)
+                .await
+                .unwrap()[0]
+                .1;
+
+        let margins = load_program_accounts::<Margin>(&rpc_client, &zo_abi::ID)
+            .await
+            .unwrap();
         let controls =
-            load_program_accounts::<Control>(&rpc_client, &zo_abi::ID).unwrap();
+            load_program_accounts::<Control>(&rpc_client, &zo_abi::ID)
+                .await
+                .unwrap();
 
         let mut test_margin: Option<Margin> = None;
         for (_key, margin) in margins.iter() {
@@ -1309,25 +1486,30 @@ mod tests {
         assert!(t2.is_some());
     }
 
-    #[test]
-    fn test_check_mf_maintenance_main() {
+    #[tokio::test]
+    async fn test_check_mf_maintenance_main() {
         let rpc_client = RpcClient::new(
             "https://solana-api.syndica.io/access-token/3IAUwhDwhzjX2Fg5s9HLYfjyoAfSz80hYyOPACaVZhJsqo4HsjIzUr74aN01F8QQ/rpc".to_string(),
         );
 
         let state: State =
-            load_program_accounts::<State>(&rpc_client, &zo_abi::ID).unwrap()
-                [0]
-            .1;
+            load_program_accounts::<State>(&rpc_client, &zo_abi::ID)
+                .await
+                .unwrap()[0]
+                .1;
 
         let cache: Cache =
-            load_program_accounts::<Cache>(&rpc_client, &zo_abi::ID).unwrap()
-                [0]
-            .1;
-        let margins =
-            load_program_accounts::<Margin>(&rpc_client, &zo_abi::ID).unwrap();
+            load_program_accounts::<Cache>(&rpc_client, &zo_abi::ID)
+                .await
+                .unwrap()[0]
+                .1;
+        let margins = load_program_accounts::<Margin>(&rpc_client, &zo_abi::ID)
+            .await
+            .unwrap();
         let controls =
-            load_program_accounts::<Control>(&rpc_client, &zo_abi::ID).unwrap();
+            load_program_accounts::<Control>(&rpc_client, &zo_abi::ID)
+                .await
+                .unwrap();
 
         let mut test_margin: Option<Margin> = None;
         for (_key, margin) in margins.iter() {
@@ -1382,25 +1564,30 @@ mod tests {
         assert!(is_ok);
     }
 
-    #[test]
-    fn test_check_mf_maintenance_dev() {
+    #[tokio::test]
+    async fn test_check_mf_maintenance_dev() {
         let rpc_client = RpcClient::new(
             "https://psytrbhymqlkfrhudd.dev.genesysgo.net:8899/".to_string(),
         );
 
         let state: State =
-            load_program_accounts::<State>(&rpc_client, &zo_abi::ID).unwrap()
-                [0]
-            .1;
+            load_program_accounts::<State>(&rpc_client, &zo_abi::ID)
+                .await
+                .unwrap()[0]
+                .1;
 
         let cache: Cache =
-            load_program_accounts::<Cache>(&rpc_client, &zo_abi::ID).unwrap()
-                [0]
-            .1;
-        let margins =
-            load_program_accounts::<Margin>(&rpc_client, &zo_abi::ID).unwrap();
+            load_program_accounts::<Cache>(&rpc_client, &zo_abi::ID)
+                .await
+                .unwrap()[0]
+                .1;
+        let margins = load_program_accounts::<Margin>(&rpc_client, &zo_abi::ID)
+            .await
+            .unwrap();
         let controls =
-            load_program_accounts::<Control>(&rpc_client, &zo_abi::ID).unwrap();
+            load_program_accounts::<Control>(&rpc_client, &zo_abi::ID)
+                .await
+                .unwrap();
 
         let mut test_margin: Option<Margin> = None;
         for (_key, margin) in margins.iter() {
@@ -1454,4 +1641,84 @@ mod tests {
         // The liquidator is ok
         assert!(is_ok);
     }
+
+    #[test]
+    fn test_perp_index_price_future() {
+        let oracle = I80F48::from_num(3);
+        let mark = I80F48::from_num(4);
+        let strike = I80F48::from_num(2);
+
+        let price = perp_index_price(PerpType::Future, oracle, mark, strike);
+        assert_eq!(price, oracle);
+    }
+
+    #[test]
+    fn test_perp_index_price_call() {
+        let mark = I80F48::from_num(4);
+        let strike = I80F48::from_num(2);
+
+        // In the money, worth the difference.
+        let price = perp_index_price(
+            PerpType::CallOption,
+            I80F48::from_num(3),
+            mark,
+            strike,
+        );
+        assert_eq!(price, I80F48::ONE);
+
+        // Out of the money, worthless.
+        let price =
+            perp_index_price(PerpType::CallOption, I80F48::ONE, mark, strike);
+        assert_eq!(price, I80F48::ZERO);
+    }
+
+    #[test]
+    fn test_perp_index_price_put() {
+        let mark = I80F48::from_num(4);
+        let strike = I80F48::from_num(2);
+
+        let price = perp_index_price(
+            PerpType::PutOption,
+            I80F48::from_num(0.5f64),
+            mark,
+            strike,
+        );
+        assert_eq!(price, I80F48::from_num(1.5f64));
+
+        let price = perp_index_price(
+            PerpType::PutOption,
+            I80F48::from_num(3),
+            mark,
+            strike,
+        );
+        assert_eq!(price, I80F48::ZERO);
+    }
+
+    #[test]
+    fn test_perp_index_price_square() {
+        let mark = I80F48::from_num(4);
+
+        let price = perp_index_price(
+            PerpType::Square,
+            I80F48::from_num(3),
+            mark,
+            I80F48::ZERO,
+        );
+        assert_eq!(price, mark);
+    }
+
+    #[test]
+    fn test_strike_price() {
+        // $100 strike on a 9 decimals asset, e.g. SOL.
+        let strike = strike_price(100_000_000, 9);
+        assert_eq!(
+            strike,
+            I80F48::from_num(100_000_000u64)
+                / I80F48::from_num(1_000_000_000u64)
+        );
+
+        // $2 strike on a 6 decimals asset.
+        let strike = strike_price(2_000_000, 6);
+        assert_eq!(strike, I80F48::from_num(2));
+    }
 }
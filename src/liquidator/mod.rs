@@ -1,34 +1,143 @@
 mod accounts;
 mod error;
+mod health;
+mod inflight;
 mod liquidation;
 mod listener;
 mod margin_utils;
 mod math;
+mod profit;
+mod queue;
+mod risk;
 mod swap;
 mod utils;
 
-use crate::{AppState, Error};
+pub use profit::ProfitConfig;
+pub use risk::{NotionalLimit, RiskConfig, Tolerance};
+pub use swap::SwapConfig;
+
+use crate::{
+    config::{check, seconds},
+    limiter::Budget,
+    tx::{FeeConfig, TxBuilder},
+    utils::AbortOnDrop,
+    AppState, Error,
+};
+use serde::{Deserialize, Serialize};
+use std::{sync::Arc, time::Duration};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct LiquidatorConfig {
+    /// The total number of bots run.
+    pub worker_count: u8,
+    /// The slice of addresses this bot is responsible for.
+    pub worker_index: u8,
+    /// The number of liquidations and cancellations run at once.
+    pub job_concurrency: usize,
+    /// How often the accounts are scanned.
+    #[serde(with = "seconds")]
+    pub scan_interval: Duration,
+    /// How often every account is fetched again, to catch the updates
+    /// the listener missed.
+    #[serde(with = "seconds")]
+    pub refresh_interval: Duration,
+    /// How often the leftover inventory is swapped back.
+    #[serde(with = "seconds")]
+    pub rebalance_interval: Duration,
+    pub fee: FeeConfig,
+    pub swap: SwapConfig,
+    pub profit: ProfitConfig,
+    pub risk: RiskConfig,
+}
+
+impl Default for LiquidatorConfig {
+    fn default() -> Self {
+        Self {
+            worker_count: 1,
+            worker_index: 0,
+            job_concurrency: 8,
+            scan_interval: Duration::from_millis(250),
+            refresh_interval: Duration::from_secs(300),
+            rebalance_interval: Duration::from_secs(30),
+            fee: FeeConfig::default(),
+            swap: SwapConfig::default(),
+            profit: ProfitConfig::default(),
+            risk: RiskConfig::default(),
+        }
+    }
+}
+
+impl LiquidatorConfig {
+    pub fn validate(&self) -> Result<(), Error> {
+        check(
+            self.worker_index < self.worker_count,
+            "worker index must be less than the worker count",
+        )?;
+        check(self.job_concurrency > 0, "job concurrency must be positive")?;
+        check(
+            !self.scan_interval.is_zero()
+                && !self.refresh_interval.is_zero()
+                && !self.rebalance_interval.is_zero(),
+            "liquidator intervals must be positive",
+        )?;
+        check(
+            self.swap.max_slippage_bps < 10_000,
+            "max slippage must be under 10000 bps",
+        )?;
+        check(
+            self.profit.min_profit.is_finite(),
+            "min profit must be a finite number",
+        )?;
+        self.fee.validate()?;
+        self.risk.validate()
+    }
+}
 
 pub async fn run(
     st: &'static AppState,
-    worker_count: u8,
-    worker_index: u8,
+    cfg: LiquidatorConfig,
 ) -> Result<(), Error> {
-    let database = accounts::DbWrapper::new(st, worker_index, worker_count);
+    let database = accounts::DbWrapper::new(
+        st,
+        cfg.worker_index,
+        cfg.worker_count,
+        cfg.risk.tolerance,
+    )
+    .await;
+    let tx = TxBuilder::new(st, cfg.fee, Budget::Liquidator);
+    let risk = Arc::new(cfg.risk);
 
-    let f = tokio::spawn(self::listener::start_listener(
+    // Aborted if this is dropped, e.g. to be restarted by `run`.
+    let mut f = AbortOnDrop(tokio::spawn(self::listener::start_listener(
         &zo_abi::ID,
-        st.cluster.ws_url().to_string(),
+        &st.rpc,
         database.clone(),
-    ));
+    )));
 
-    let g = tokio::spawn(self::liquidation::liquidate_loop(&st, database));
+    let mut g = AbortOnDrop(tokio::spawn(self::liquidation::liquidate_loop(
+        &st,
+        database,
+        tx,
+        cfg.swap,
+        cfg.profit,
+        risk,
+        cfg.scan_interval,
+        cfg.refresh_interval,
+        cfg.rebalance_interval,
+        cfg.job_concurrency,
+    )));
 
     // Propagate panic.
     tokio::select! {
-        t = f => t.unwrap(),
-        t = g => t.unwrap(),
+        t = &mut f.0 => t.unwrap(),
+        t = &mut g.0 => t.unwrap(),
+        _ = st.shutdown.triggered() => {}
     };
 
+    // Liquidations that started are seen through, so that the liqor
+    // is not left holding their positions.
+    st.shutdown.drained().await;
+
     Ok(())
 }
@@ -0,0 +1,251 @@
+/*
+ * Estimates what a liquidation earns the liqor once it is back to a
+ * flat position, so that the ones that would not cover their costs
+ * are skipped. The reward is the liquidation fee on the amount
+ * liquidated, and the costs are the slippage and fees of rebalancing
+ * it, and the transaction fee.
+*/
+use crate::{
+    liquidator::{
+        error::ErrorCode,
+        swap::{self, SwapConfig},
+        utils::get_oracle,
+    },
+    tx::TxBuilder,
+    AppState,
+};
+
+use anchor_lang::solana_program::instruction::Instruction;
+use fixed::types::I80F48;
+use serde::{Deserialize, Serialize};
+use serum_dex::state::MarketState as SerumMarketState;
+use std::{collections::HashMap, fmt};
+use tracing::{info, warn};
+use zo_abi::{dex::ZoDexMarket as MarketState, Cache, State};
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ProfitConfig {
+    /// Minimum expected profit, in USD, for a liquidation to be sent.
+    pub min_profit: f64,
+}
+
+/// The expected outcome of a liquidation, in USD.
+pub struct Estimate {
+    pub reward: I80F48,
+    pub rebalance_cost: I80F48,
+    pub tx_cost: I80F48,
+}
+
+impl Estimate {
+    // Takes amounts in native quote.
+    fn new(
+        state: &State,
+        reward: I80F48,
+        rebalance_cost: I80F48,
+        tx_cost: I80F48,
+    ) -> Self {
+        let unit =
+            I80F48::from_num(10u64.pow(state.collaterals[0].decimals as u32));
+
+        Self {
+            reward: reward / unit,
+            rebalance_cost: rebalance_cost / unit,
+            tx_cost: tx_cost / unit,
+        }
+    }
+
+    pub fn profit(&self) -> I80F48 {
+        self.reward - self.rebalance_cost - self.tx_cost
+    }
+
+    /// Fails with `Unprofitable` if the liquidation is not worth
+    /// sending, logging the estimate.
+    pub fn check(
+        &self,
+        what: &str,
+        cfg: &ProfitConfig,
+    ) -> Result<(), ErrorCode> {
+        if self.profit() >= I80F48::from_num(cfg.min_profit) {
+            return Ok(());
+        }
+
+        info!("Skipping unprofitable {}: {}", what, self);
+        Err(ErrorCode::Unprofitable)
+    }
+}
+
+impl fmt::Display for Estimate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "reward ${:.4}, rebalance ${:.4}, tx ${:.4}, profit ${:.4}",
+            self.reward,
+            self.rebalance_cost,
+            self.tx_cost,
+            self.profit()
+        )
+    }
+}
+
+fn oracle_price(
+    state: &State,
+    cache: &Cache,
+    index: usize,
+) -> Result<I80F48, ErrorCode> {
+    match get_oracle(cache, &state.collaterals[index].oracle_symbol) {
+        Some(o) => Ok(o.price.into()),
+        None => Err(ErrorCode::CollateralFailure),
+    }
+}
+
+fn liq_fee(fee: u16) -> I80F48 {
+    I80F48::from_num(fee) / I80F48::from_num(1000u32)
+}
+
+// The transaction fee, in native quote at the oracle price of SOL,
+// with the compute units the liquidation is simulated to use.
+async fn tx_cost(
+    tx: &TxBuilder,
+    state: &State,
+    cache: &Cache,
+    ixs: &[Instruction],
+) -> I80F48 {
+    let lamports = tx.estimate_fee(ixs).await;
+    let sol = state
+        .collaterals
+        .iter()
+        .find(|c| c.mint == spl_token::native_mint::ID)
+        .and_then(|c| get_oracle(cache, &c.oracle_symbol));
+
+    match sol {
+        Some(o) => I80F48::from_num(lamports) * I80F48::from(o.price),
+        None => {
+            warn!("No SOL oracle, ignoring transaction fees");
+            I80F48::ZERO
+        }
+    }
+}
+
+/// A perp liquidation of `notional`, in native quote, where `long` is
+/// the side of the liqee's position. The liqor takes over the position
+/// at the market's liquidation fee, and closes it on the dex.
+pub async fn estimate_perp(
+    st: &AppState,
+    tx: &TxBuilder,
+    state: &State,
+    cache: &Cache,
+    dex_market: &MarketState,
+    index: usize,
+    notional: I80F48,
+    long: bool,
+    swap_cfg: &SwapConfig,
+    ixs: &[Instruction],
+) -> Result<Estimate, ErrorCode> {
+    let notional = notional.abs();
+    let size = notional
+        .checked_div(cache.marks[index].price.into())
+        .ok_or(ErrorCode::MathFailure)?;
+    let close_cost =
+        swap::close_cost(st, cache, dex_market, index, size, !long, swap_cfg)
+            .await?;
+
+    Ok(Estimate::new(
+        state,
+        notional * liq_fee(state.perp_markets[index].liq_fee),
+        close_cost,
+        tx_cost(tx, state, cache, ixs).await,
+    ))
+}
+
+/// A spot liquidation of `amount`, in native quote. The liqor repays
+/// the asset and receives the quote collateral with the fees of both,
+/// as in `estimate_spot_liquidation_size`, then swaps them back.
+pub async fn estimate_spot(
+    st: &AppState,
+    tx: &TxBuilder,
+    state: &State,
+    cache: &Cache,
+    asset_index: usize,
+    quote_index: usize,
+    amount: I80F48,
+    serum_markets: &HashMap<usize, SerumMarketState>,
+    swap_cfg: &SwapConfig,
+    ixs: &[Instruction],
+) -> Result<Estimate, ErrorCode> {
+    let amount = amount.abs();
+    let received = amount
+        * (I80F48::ONE + liq_fee(state.collaterals[asset_index].liq_fee))
+        / (I80F48::ONE - liq_fee(state.collaterals[quote_index].liq_fee));
+
+    let mut rebalance_cost = I80F48::ZERO;
+
+    if let Some(m) = serum_markets.get(&quote_index) {
+        let price = oracle_price(state, cache, quote_index)?;
+        rebalance_cost += swap::swap_cost(
+            st,
+            state,
+            cache,
+            m,
+            received / price,
+            false,
+            quote_index,
+            swap_cfg,
+        )
+        .await?;
+    }
+
+    if let Some(m) = serum_markets.get(&asset_index) {
+        rebalance_cost += swap::swap_cost(
+            st,
+            state,
+            cache,
+            m,
+            amount,
+            true,
+            asset_index,
+            swap_cfg,
+        )
+        .await?;
+    }
+
+    Ok(Estimate::new(
+        state,
+        received - amount,
+        rebalance_cost,
+        tx_cost(tx, state, cache, ixs).await,
+    ))
+}
+
+/// Settling the bankruptcy of collateral `index`, where `amount` is the
+/// debt taken over, in native units. The liqor is compensated for the
+/// debt at the oracle price plus the collateral's liquidation fee, and
+/// buys the asset back.
+pub async fn estimate_bankruptcy(
+    st: &AppState,
+    tx: &TxBuilder,
+    state: &State,
+    cache: &Cache,
+    index: usize,
+    amount: I80F48,
+    serum_market: Option<&SerumMarketState>,
+    swap_cfg: &SwapConfig,
+    ixs: &[Instruction],
+) -> Result<Estimate, ErrorCode> {
+    let value = amount.abs() * oracle_price(state, cache, index)?;
+
+    let rebalance_cost = match serum_market {
+        Some(m) => {
+            swap::swap_cost(st, state, cache, m, value, true, index, swap_cfg)
+                .await?
+        }
+        None => I80F48::ZERO,
+    };
+
+    Ok(Estimate::new(
+        state,
+        value * liq_fee(state.collaterals[index].liq_fee),
+        rebalance_cost,
+        tx_cost(tx, state, cache, ixs).await,
+    ))
+}
@@ -0,0 +1,104 @@
+/*
+ * Orders the accounts found by the scans, so that the ones the
+ * protocol is most exposed to are handled first. A fixed number of
+ * workers take jobs from one queue shared by every scan, so that an
+ * account found by a later scan can still go before the ones queued
+ * earlier, and the scans never wait on the jobs.
+*/
+use crate::{shutdown::Work, AppState};
+
+use fixed::types::I80F48;
+use futures::future::BoxFuture;
+use solana_sdk::pubkey::Pubkey;
+use std::{cmp::Ordering, collections::BinaryHeap, sync::Mutex};
+use tokio::sync::Notify;
+use tracing::debug;
+
+// Liquidations always go before cancellations.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Action {
+    Cancel,
+    Liquidate,
+}
+
+pub struct Candidate {
+    pub action: Action,
+    // How far the account is below maintenance, see
+    // `get_maintenance_deficit`. Larger goes first.
+    pub deficit: I80F48,
+    pub key: Pubkey,
+    pub job: BoxFuture<'static, ()>,
+}
+
+impl Candidate {
+    fn priority(&self) -> (Action, I80F48, Pubkey) {
+        (self.action, self.deficit, self.key)
+    }
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.priority() == other.priority()
+    }
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority().cmp(&other.priority())
+    }
+}
+
+#[derive(Default)]
+pub struct JobQueue {
+    heap: Mutex<BinaryHeap<Candidate>>,
+    ready: Notify,
+}
+
+impl JobQueue {
+    pub fn push(&self, c: Candidate) {
+        self.heap.lock().unwrap().push(c);
+        self.ready.notify_one();
+    }
+
+    /// Waits for the job with the highest priority.
+    async fn pop(&self) -> Candidate {
+        loop {
+            // Created before checking, so that a push is not missed.
+            let ready = self.ready.notified();
+
+            if let Some(c) = self.heap.lock().unwrap().pop() {
+                return c;
+            }
+
+            ready.await;
+        }
+    }
+}
+
+/// Runs the jobs of `queue` one at a time, until shutting down. Once
+/// shutting down, the jobs left in the queue are dropped, while the
+/// one running goes on to completion, rebalance included.
+pub async fn worker(st: &'static AppState, queue: &JobQueue) {
+    loop {
+        let c = tokio::select! {
+            c = queue.pop() => c,
+            _ = st.shutdown.triggered() => return,
+        };
+
+        let _work = match st.shutdown.begin(Work::Liquidation) {
+            Some(x) => x,
+            None => return,
+        };
+
+        debug!("{:?} {} (deficit {})", c.action, c.key, c.deficit);
+        c.job.await;
+    }
+}
@@ -0,0 +1,320 @@
+/*
+ * Limits on the exposure the liqor takes on. Every liquidation is
+ * sized against the liqor's current positions, so that it never goes
+ * over any of them.
+*/
+use crate::{
+    config::{check, serde_from_str},
+    error::Error,
+    liquidator::margin_utils::*,
+};
+
+use fixed::types::I80F48;
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
+use zo_abi::{Cache, Control, Margin, State, MAX_COLLATERALS, MAX_MARKETS};
+
+/// How close to a fraction an account must be to be acted on, as a
+/// multiplier on the fraction, see `check_mf`. Should be at most 1.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Tolerance {
+    pub maintenance: f64,
+    pub cancel: f64,
+}
+
+impl Default for Tolerance {
+    fn default() -> Self {
+        Self {
+            maintenance: 0.99995,
+            cancel: 0.99995,
+        }
+    }
+}
+
+/// A limit on the notional of a market or collateral, in USD. Limits
+/// without a symbol apply to those without a limit of their own.
+#[derive(Clone, Debug)]
+pub struct NotionalLimit {
+    pub symbol: Option<String>,
+    pub max: f64,
+}
+
+impl FromStr for NotionalLimit {
+    type Err = String;
+
+    /// Parses either `<symbol>=<usd>` or `<usd>`, e.g. `SOL-PERP=10000`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (symbol, max) = match s.split_once('=') {
+            Some((k, v)) => (Some(k.to_string()), v),
+            None => (None, s),
+        };
+
+        match max.parse() {
+            Ok(max) if max >= 0.0 => Ok(Self { symbol, max }),
+            Ok(_) => Err("limit must not be negative".to_string()),
+            Err(e) => Err(format!("invalid limit: {}", e)),
+        }
+    }
+}
+
+impl fmt::Display for NotionalLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.symbol {
+            Some(s) => write!(f, "{}={}", s, self.max),
+            None => write!(f, "{}", self.max),
+        }
+    }
+}
+
+serde_from_str!(NotionalLimit);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct RiskConfig {
+    /// Maximum gross notional of the liqor's positions and non-quote
+    /// collateral, as a multiple of its account value.
+    pub max_leverage: f64,
+    /// Maximum absolute notional on each perp market.
+    pub max_market_notional: Vec<NotionalLimit>,
+    /// Maximum absolute notional of each non-quote collateral.
+    pub max_collateral_notional: Vec<NotionalLimit>,
+    /// Maximum gross notional across all markets and collaterals,
+    /// in USD.
+    pub max_inventory: Option<f64>,
+    pub tolerance: Tolerance,
+}
+
+impl Default for RiskConfig {
+    fn default() -> Self {
+        Self {
+            max_leverage: 5.0,
+            max_market_notional: Vec::new(),
+            max_collateral_notional: Vec::new(),
+            max_inventory: None,
+            tolerance: Tolerance::default(),
+        }
+    }
+}
+
+fn find_limit(limits: &[NotionalLimit], symbol: &str) -> Option<f64> {
+    limits
+        .iter()
+        .find(|l| l.symbol.as_deref() == Some(symbol))
+        .or_else(|| limits.iter().find(|l| l.symbol.is_none()))
+        .map(|l| l.max)
+}
+
+/// The liqor's current exposure, in native quote.
+pub struct Exposure {
+    value: I80F48,
+    // Signed, collaterals first, then perp markets.
+    notional: [I80F48; MAX_COLLATERALS + MAX_MARKETS],
+    // The USD limits are converted to native quote with this.
+    unit: I80F48,
+}
+
+impl Exposure {
+    pub fn compute(
+        margin: &Margin,
+        control: &Control,
+        state: &State,
+        cache: &Cache,
+    ) -> Self {
+        Self {
+            value: get_total_account_value(margin, control, state, cache),
+            notional: get_notional_vector(margin, control, state, cache),
+            unit: I80F48::from_num(
+                10u64.pow(state.collaterals[0].decimals as u32),
+            ),
+        }
+    }
+
+    // The quote collateral is not inventory.
+    fn inventory(&self) -> I80F48 {
+        self.notional[1..].iter().map(|x| x.abs()).sum()
+    }
+
+    fn usd(&self, x: f64) -> I80F48 {
+        I80F48::checked_from_num(x)
+            .and_then(|x| x.checked_mul(self.unit))
+            .unwrap_or(I80F48::MAX)
+    }
+}
+
+impl RiskConfig {
+    pub fn validate(&self) -> Result<(), Error> {
+        check(self.max_leverage > 0.0, "max leverage must be positive")?;
+        check(
+            self.max_inventory.map_or(true, |x| x >= 0.0),
+            "max inventory must not be negative",
+        )?;
+        check(
+            [self.tolerance.maintenance, self.tolerance.cancel]
+                .iter()
+                .all(|x| *x > 0.0 && *x <= 1.0),
+            "tolerances must be in (0, 1]",
+        )
+    }
+
+    // Room left under the limits shared by every liquidation.
+    fn total_room(&self, e: &Exposure) -> I80F48 {
+        let leverage = e
+            .value
+            .max(I80F48::ZERO)
+            .saturating_mul(I80F48::from_num(self.max_leverage));
+        let mut room = leverage - e.inventory();
+
+        if let Some(max) = self.max_inventory {
+            room = room.min(e.usd(max) - e.inventory());
+        }
+
+        room
+    }
+
+    /// The most notional a liquidation may add on perp market `index`,
+    /// in native quote.
+    pub fn perp_room(
+        &self,
+        e: &Exposure,
+        state: &State,
+        index: usize,
+    ) -> I80F48 {
+        let mut room = self.total_room(e);
+        let symbol = String::from(state.perp_markets[index].symbol);
+
+        if let Some(max) = find_limit(&self.max_market_notional, &symbol) {
+            let current = e.notional[MAX_COLLATERALS + index].abs();
+            room = room.min(e.usd(max) - current);
+        }
+
+        room.max(I80F48::ZERO)
+    }
+
+    /// The most notional a liquidation may add on collateral `index`,
+    /// in native quote. The quote collateral is only bound by the
+    /// shared limits.
+    pub fn spot_room(
+        &self,
+        e: &Exposure,
+        state: &State,
+        index: usize,
+    ) -> I80F48 {
+        let mut room = self.total_room(e);
+        let symbol = String::from(state.collaterals[index].oracle_symbol);
+
+        if index != 0 {
+            if let Some(max) =
+                find_limit(&self.max_collateral_notional, &symbol)
+            {
+                room = room.min(e.usd(max) - e.notional[index].abs());
+            }
+        }
+
+        room.max(I80F48::ZERO)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UNIT: u64 = 1_000_000;
+
+    fn usd(x: f64) -> I80F48 {
+        I80F48::from_num(x) * I80F48::from_num(UNIT)
+    }
+
+    // Collateral 1 and perp market 0 are the only open positions.
+    fn exposure(value: f64, spot: f64, perp: f64) -> Exposure {
+        let mut notional = [I80F48::ZERO; MAX_COLLATERALS + MAX_MARKETS];
+        notional[0] = usd(value);
+        notional[1] = usd(spot);
+        notional[MAX_COLLATERALS] = usd(perp);
+
+        Exposure {
+            value: usd(value),
+            notional,
+            unit: I80F48::from_num(UNIT),
+        }
+    }
+
+    fn limit(symbol: Option<&str>, max: f64) -> NotionalLimit {
+        NotionalLimit {
+            symbol: symbol.map(String::from),
+            max,
+        }
+    }
+
+    #[test]
+    fn test_notional_limit_from_str() {
+        let l = NotionalLimit::from_str("SOL-PERP=10000").unwrap();
+        assert_eq!(l.symbol.as_deref(), Some("SOL-PERP"));
+        assert_eq!(l.max, 10000.0);
+        assert_eq!(l.to_string(), "SOL-PERP=10000");
+
+        let l = NotionalLimit::from_str("2500.5").unwrap();
+        assert_eq!(l.symbol, None);
+        assert_eq!(l.max, 2500.5);
+
+        assert!(NotionalLimit::from_str("-1").is_err());
+        assert!(NotionalLimit::from_str("SOL-PERP=-1").is_err());
+        assert!(NotionalLimit::from_str("SOL-PERP=abc").is_err());
+        assert!(NotionalLimit::from_str("").is_err());
+    }
+
+    #[test]
+    fn test_find_limit() {
+        let limits = [limit(None, 100.0), limit(Some("SOL-PERP"), 50.0)];
+
+        assert_eq!(find_limit(&limits, "SOL-PERP"), Some(50.0));
+        assert_eq!(find_limit(&limits, "BTC-PERP"), Some(100.0));
+        assert_eq!(find_limit(&limits[1..], "BTC-PERP"), None);
+    }
+
+    #[test]
+    fn test_perp_room() {
+        let state: State = bytemuck::Zeroable::zeroed();
+        let e = exposure(1000.0, -200.0, 300.0);
+
+        // 5x leverage on 1000 USD, less 500 USD of inventory.
+        let mut cfg = RiskConfig::default();
+        assert_eq!(cfg.perp_room(&e, &state, 0), usd(4500.0));
+
+        cfg.max_inventory = Some(1000.0);
+        assert_eq!(cfg.perp_room(&e, &state, 0), usd(500.0));
+
+        // The market's own limit counts only its current notional.
+        cfg.max_market_notional = vec![limit(None, 400.0)];
+        assert_eq!(cfg.perp_room(&e, &state, 0), usd(100.0));
+
+        cfg.max_market_notional = vec![limit(None, 200.0)];
+        assert_eq!(cfg.perp_room(&e, &state, 0), I80F48::ZERO);
+
+        // A limit on another market does not apply.
+        cfg.max_market_notional = vec![limit(Some("BTC-PERP"), 0.0)];
+        assert_eq!(cfg.perp_room(&e, &state, 0), usd(500.0));
+    }
+
+    #[test]
+    fn test_spot_room() {
+        let state: State = bytemuck::Zeroable::zeroed();
+        let e = exposure(1000.0, -200.0, 300.0);
+
+        let mut cfg = RiskConfig::default();
+        cfg.max_collateral_notional = vec![limit(None, 250.0)];
+
+        assert_eq!(cfg.spot_room(&e, &state, 1), usd(50.0));
+        assert_eq!(cfg.spot_room(&e, &state, 0), usd(4500.0));
+    }
+
+    #[test]
+    fn test_room_without_value() {
+        let state: State = bytemuck::Zeroable::zeroed();
+        let e = exposure(-100.0, 0.0, 0.0);
+        let cfg = RiskConfig::default();
+
+        assert_eq!(cfg.perp_room(&e, &state, 0), I80F48::ZERO);
+        assert_eq!(cfg.spot_room(&e, &state, 0), I80F48::ZERO);
+    }
+}
@@ -2,8 +2,6 @@
  * This file is responsible for handling swapping assets to USDC.
  * This is done after every liquidation to prevent risk exposure.
 */
-use anchor_client::Program;
-
 use anchor_lang::{
     prelude::ToAccountMetas, solana_program::instruction::Instruction,
     InstructionData,
@@ -16,27 +14,409 @@ use serum_dex::{
     state::MarketState as SerumMarketState,
 };
 
-use solana_sdk::{
-    commitment_config::CommitmentConfig, pubkey::Pubkey,
-    sysvar::rent::ID as RENT_ID,
-};
+use serde::{Deserialize, Serialize};
+use solana_sdk::{pubkey::Pubkey, sysvar::rent::ID as RENT_ID};
 use spl_token::ID as TOKEN_ID;
 
-use std::cell::RefMut;
+use std::{
+    cell::{RefCell, RefMut},
+    collections::HashMap,
+};
 
 use tracing::{error, error_span, info, warn};
 
 use zo_abi::{
-    accounts, dex::ZoDexMarket as MarketState, instruction, Control, Margin,
-    OrderType, State,
+    accounts, dex::ZoDexMarket as MarketState, instruction, Cache, Control,
+    Margin, OrderType, State,
 };
 
-use crate::liquidator::{error::ErrorCode, math::SafeOp, utils::*};
+use crate::{
+    limiter::Budget,
+    liquidator::{
+        error::ErrorCode, margin_utils::get_actual_collateral_vec,
+        math::SafeOp, utils::*,
+    },
+    tx::TxBuilder,
+    AppState,
+};
+
+/// Limits on how far from the oracle swaps may fill.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct SwapConfig {
+    /// Maximum distance from the oracle price, in basis points. The
+    /// serum taker fee is paid out of it.
+    pub max_slippage_bps: u16,
+}
+
+impl Default for SwapConfig {
+    fn default() -> Self {
+        Self {
+            max_slippage_bps: 50,
+        }
+    }
+}
+
+/// How much of the sold side a swap is for.
+#[derive(Clone, Copy, Debug)]
+pub enum SwapAmount {
+    /// Everything held, as far as the book allows.
+    All,
+    /// Up to this amount, in native units.
+    Max(u64),
+}
+
+// A swap capped to what the book can fill within the slippage limit.
+struct Quote {
+    // What is sold, in native units: the quote when buying, the
+    // asset when selling.
+    amount: u64,
+    // The minimum amount received, in native units, per whole unit
+    // sold. The program fails the swap if the fill is worse.
+    min_rate: u64,
+}
+
+// Serum's base taker fee, charged on what is received.
+const TAKER_FEE_BPS: u16 = 22;
+
+fn bps(x: u16) -> I80F48 {
+    I80F48::from_num(x) / I80F48::from_num(10_000u16)
+}
+
+// The oracle price of the asset and the worst price a swap may fill
+// at, both in native quote per native asset like the serum prices.
+// The taker fee is charged on what is received, so the limit is
+// narrowed by it to keep the price after fees within the slippage.
+fn limit_price(
+    state: &State,
+    cache: &Cache,
+    buy: bool,
+    asset_index: usize,
+    cfg: &SwapConfig,
+) -> Result<(I80F48, I80F48), ErrorCode> {
+    let oracle: I80F48 = match get_oracle(
+        cache,
+        &state.collaterals[asset_index].oracle_symbol,
+    ) {
+        Some(o) if I80F48::from(o.price).is_positive() => o.price.into(),
+        _ => {
+            error!("No oracle price for swapping {}", asset_index);
+            return Err(ErrorCode::SwapError);
+        }
+    };
+
+    let slippage = bps(cfg.max_slippage_bps);
+    let after_fee = I80F48::ONE - bps(TAKER_FEE_BPS);
+    let limit = match buy {
+        true => oracle * (I80F48::ONE + slippage) * after_fee,
+        false => {
+            oracle * (I80F48::ONE - slippage).max(I80F48::ZERO) / after_fee
+        }
+    };
+
+    Ok((oracle, limit))
+}
+
+// Fetches the side of the serum book a swap takes from, and returns
+// its levels up to the limit price, see `walk_levels`.
+async fn fetch_levels(
+    st: &AppState,
+    serum_market: &SerumMarketState,
+    buy: bool,
+    limit: I80F48,
+) -> Result<Vec<(I80F48, I80F48)>, ErrorCode> {
+    let book_key = match buy {
+        true => array_to_pubkey(&{ serum_market.asks }),
+        false => array_to_pubkey(&{ serum_market.bids }),
+    };
+    let book_account = st
+        .rpc
+        .call(Budget::Liquidator, "getAccountInfo", |c| {
+            c.get_account(&book_key)
+        })
+        .await;
+    let mut book_account = match book_account {
+        Ok(x) => x,
+        Err(e) => {
+            error!("Failed to fetch book {}", e);
+            return Err(ErrorCode::SwapError);
+        }
+    };
+    let book_info = get_account_info(&book_key, &mut book_account);
+    let book = match buy {
+        true => serum_market.load_asks_mut(&book_info),
+        false => serum_market.load_bids_mut(&book_info),
+    };
+    let mut book: RefMut<Slab> = match book {
+        Ok(x) => x,
+        Err(e) => {
+            error!("Failed to load book {}", e);
+            return Err(ErrorCode::SwapError);
+        }
+    };
+
+    Ok(walk_levels(
+        &mut book,
+        buy,
+        limit,
+        serum_market.pc_lot_size,
+        serum_market.coin_lot_size,
+    ))
+}
+
+// Fetches the side of a zo dex book that a perp order takes from, as
+// in `fetch_levels`. The zo dex is a fork of the serum dex, and its
+// books have the same layout.
+async fn fetch_perp_levels(
+    st: &AppState,
+    dex_market: &MarketState,
+    buy: bool,
+    limit: I80F48,
+) -> Result<Vec<(I80F48, I80F48)>, ErrorCode> {
+    let book_key = match buy {
+        true => dex_market.asks,
+        false => dex_market.bids,
+    };
+    let book_account = st
+        .rpc
+        .call(Budget::Liquidator, "getAccountInfo", |c| {
+            c.get_account(&book_key)
+        })
+        .await;
+    let mut book_account = match book_account {
+        Ok(x) => x,
+        Err(e) => {
+            error!("Failed to fetch perp book {}", e);
+            return Err(ErrorCode::SwapError);
+        }
+    };
+
+    // Past the padding and the account flags, as serum's
+    // `load_bids_mut` does.
+    let len = book_account.data.len();
+    if len < 5 + 8 + 7 {
+        error!("Perp book {} is too small", book_key);
+        return Err(ErrorCode::SwapError);
+    }
+    let book = Slab::new(&mut book_account.data[5 + 8..len - 7]);
+
+    Ok(walk_levels(
+        book,
+        buy,
+        limit,
+        dex_market.pc_lot_size,
+        dex_market.coin_lot_size,
+    ))
+}
+
+// Returns the levels of the book up to the limit price, best first,
+// as native prices and native asset sizes. The book is a local copy,
+// so it is consumed to walk it in order.
+fn walk_levels(
+    book: &mut Slab,
+    buy: bool,
+    limit: I80F48,
+    pc_lot_size: u64,
+    coin_lot_size: u64,
+) -> Vec<(I80F48, I80F48)> {
+    let factor =
+        I80F48::from_num(pc_lot_size) / I80F48::from_num(coin_lot_size);
+    let mut levels = Vec::new();
+
+    loop {
+        let order = match buy {
+            true => book.remove_min(),
+            false => book.remove_max(),
+        };
+        let order = match order {
+            Some(x) => x,
+            None => break,
+        };
+
+        let price = I80F48::from_num(u64::from(order.price())) * factor;
+        if (buy && price > limit) || (!buy && price < limit) {
+            break;
+        }
+
+        let size = I80F48::from_num(order.quantity())
+            * I80F48::from_num(coin_lot_size);
+        levels.push((price, size));
+    }
+
+    levels
+}
+
+/// Walks the serum book on the side the swap takes from, and caps the
+/// swap to the depth within the slippage limit around the oracle
+/// price. Returns `None` if there is no such depth at all, in which
+/// case the swap is left to the next rebalance.
+#[tracing::instrument(
+    skip_all,
+    level = "error",
+    fields(asset = asset_index, buy)
+)]
+async fn quote_swap(
+    st: &AppState,
+    state: &State,
+    cache: &Cache,
+    serum_market: &SerumMarketState,
+    amount: SwapAmount,
+    buy: bool,
+    asset_index: usize,
+    cfg: &SwapConfig,
+) -> Result<Option<Quote>, ErrorCode> {
+    let (_, limit) = limit_price(state, cache, buy, asset_index, cfg)?;
+    let levels = fetch_levels(st, serum_market, buy, limit).await?;
+
+    // In what the taker pays: the quote when buying, the asset when
+    // selling.
+    let depth: I80F48 = levels
+        .iter()
+        .map(|&(price, size)| match buy {
+            true => size * price,
+            false => size,
+        })
+        .sum();
+
+    let capped: u64 = match amount {
+        SwapAmount::All => depth.to_num(),
+        SwapAmount::Max(x) => I80F48::from_num(x).min(depth).to_num(),
+    };
+
+    if capped == 0 {
+        warn!(
+            "No depth within {}bps of oracle, deferring swap",
+            cfg.max_slippage_bps
+        );
+        return Ok(None);
+    }
+
+    if let SwapAmount::Max(x) = amount {
+        if capped < x {
+            info!(
+                "Swapping {} of {}, the rest is left for the next rebalance",
+                capped, x
+            );
+        }
+    }
+
+    let unit = |i: usize| {
+        I80F48::from_num(10u64.pow(state.collaterals[i].decimals as u32))
+    };
+
+    // What is received at the limit, after the taker fee.
+    let after_fee = I80F48::ONE - bps(TAKER_FEE_BPS);
+    let min_rate = match buy {
+        true => unit(0) / limit * after_fee,
+        false => unit(asset_index) * limit * after_fee,
+    };
+
+    Ok(Some(Quote {
+        amount: capped,
+        min_rate: min_rate.to_num(),
+    }))
+}
+
+/// The expected cost of a swap of `amount` compared to filling at the
+/// oracle price, in native quote, taker fee included. What the book
+/// can't fill within the slippage limit is charged at the limit, as
+/// it is left for a later rebalance.
+pub async fn swap_cost(
+    st: &AppState,
+    state: &State,
+    cache: &Cache,
+    serum_market: &SerumMarketState,
+    amount: I80F48,
+    buy: bool,
+    asset_index: usize,
+    cfg: &SwapConfig,
+) -> Result<I80F48, ErrorCode> {
+    let (oracle, limit) = limit_price(state, cache, buy, asset_index, cfg)?;
+    let levels = fetch_levels(st, serum_market, buy, limit).await?;
+    let after_fee = I80F48::ONE - bps(TAKER_FEE_BPS);
+
+    let mut left = amount;
+    let mut received = I80F48::ZERO;
+
+    for (price, size) in levels {
+        if !left.is_positive() {
+            break;
+        }
+
+        let filled = match buy {
+            true => left.min(size * price),
+            false => left.min(size),
+        };
+        received += match buy {
+            true => filled / price,
+            false => filled * price,
+        };
+        left -= filled;
+    }
+
+    if left.is_positive() {
+        received += match buy {
+            true => left / limit,
+            false => left * limit,
+        };
+    }
+
+    let received = received * after_fee;
+
+    Ok(match buy {
+        true => amount - received * oracle,
+        false => amount * oracle - received,
+    })
+}
+
+/// The expected cost of closing a perp position of `size` native
+/// coin on the zo dex compared to filling at the mark price, in
+/// native quote. `buy` closes a short. What the book can't fill within
+/// the slippage limit is charged at the limit.
+pub async fn close_cost(
+    st: &AppState,
+    cache: &Cache,
+    dex_market: &MarketState,
+    index: usize,
+    size: I80F48,
+    buy: bool,
+    cfg: &SwapConfig,
+) -> Result<I80F48, ErrorCode> {
+    let mark: I80F48 = cache.marks[index].price.into();
+    let slippage = bps(cfg.max_slippage_bps);
+    let limit = match buy {
+        true => mark * (I80F48::ONE + slippage),
+        false => mark * (I80F48::ONE - slippage).max(I80F48::ZERO),
+    };
+    let levels = fetch_perp_levels(st, dex_market, buy, limit).await?;
+
+    let size = size.abs();
+    let mut left = size;
+    let mut paid = I80F48::ZERO;
+
+    for (price, qty) in levels {
+        if !left.is_positive() {
+            break;
+        }
+
+        let filled = left.min(qty);
+        paid += filled * price;
+        left -= filled;
+    }
+
+    paid += left * limit;
+
+    Ok(match buy {
+        true => paid - size * mark,
+        false => size * mark - paid,
+    })
+}
 
 #[deprecated]
 #[allow(dead_code)]
-pub fn swap_asset(
-    program: &Program,
+pub async fn swap_asset(
+    st: &AppState,
+    tx: &TxBuilder,
     payer: &Pubkey,
     state: &State,
     state_key: &Pubkey,
@@ -47,6 +427,8 @@ pub fn swap_asset(
     serum_dex_program: &Pubkey,
     serum_vault_signer: &Pubkey,
     asset_index: usize,
+    cache: &Cache,
+    cfg: &SwapConfig,
 ) -> Result<(), ErrorCode> {
     let span = error_span!("swap_asset", asset = asset_index);
 
@@ -55,9 +437,13 @@ pub fn swap_asset(
     let asset_mint = state.collaterals[asset_index].mint;
     let asset_vault = state.vaults[asset_index];
 
-    let client = program.rpc();
-
-    let margin_account = client.get_account(payer_margin).unwrap();
+    let margin_account = st
+        .rpc
+        .call(Budget::Liquidator, "getAccountInfo", |c| {
+            c.get_account(payer_margin)
+        })
+        .await
+        .unwrap();
     let col_index = 41 + asset_index * 16;
     let collateral: [u8; 16] = margin_account.data[col_index..col_index + 16]
         .to_vec()
@@ -66,9 +452,15 @@ pub fn swap_asset(
     let collateral_amount: I80F48 = I80F48::from_le_bytes(collateral);
 
     let buy = collateral_amount.is_negative();
-    let swap_amount: u64 = if buy {
+    let swap_amount = if buy {
         let asks_key = array_to_pubkey(&{ serum_market.asks });
-        let mut asks_account = client.get_account(&asks_key).unwrap();
+        let mut asks_account = st
+            .rpc
+            .call(Budget::Liquidator, "getAccountInfo", |c| {
+                c.get_account(&asks_key)
+            })
+            .await
+            .unwrap();
         let asks_info = get_account_info(&asks_key, &mut asks_account);
         let asks: RefMut<Slab> = match serum_market.load_asks_mut(&asks_info) {
             Ok(asks) => asks,
@@ -102,77 +494,89 @@ pub fn swap_asset(
             .checked_mul(factor)
             .unwrap();
 
-        collateral_amount
-            .abs()
-            .checked_mul(I80F48::from(
-                (10u64)
-                    .checked_pow(state.collaterals[asset_index].decimals as u32)
-                    .unwrap(),
-            ))
-            .unwrap()
-            .checked_mul(price)
-            .unwrap()
-            .to_num::<u64>()
+        SwapAmount::Max(
+            collateral_amount
+                .abs()
+                .checked_mul(I80F48::from(
+                    (10u64)
+                        .checked_pow(
+                            state.collaterals[asset_index].decimals as u32,
+                        )
+                        .unwrap(),
+                ))
+                .unwrap()
+                .checked_mul(price)
+                .unwrap()
+                .to_num::<u64>(),
+        )
     } else {
-        999_999_999_999_999u64
+        SwapAmount::All
     };
 
-    if swap_amount <= 50 * 1000000 {
-        // 50 USDC
-        span.in_scope(|| warn!("No coins to swap for asset {}", asset_index));
-        return Ok(());
+    if let SwapAmount::Max(x) = swap_amount {
+        if x <= 50 * 1000000 {
+            // 50 USDC
+            span.in_scope(|| {
+                warn!("No coins to swap for asset {}", asset_index)
+            });
+            return Ok(());
+        }
     }
 
-    let result = retry_send(
-        || {
-            program
-                .request()
-                .accounts(accounts::Swap {
-                    authority: *payer,
-                    state: *state_key,
-                    state_signer: *state_signer,
-                    cache: state.cache,
-                    margin: *payer_margin,
-                    control: *payer_control,
-                    quote_mint,
-                    quote_vault,
-                    asset_mint,
-                    asset_vault,
-                    swap_fee_vault: state.swap_fee_vault,
-                    serum_open_orders: state.collaterals[asset_index]
-                        .serum_open_orders,
-                    serum_market: array_to_pubkey(&{
-                        serum_market.own_address
-                    }),
-                    serum_request_queue: array_to_pubkey(&{
-                        serum_market.req_q
-                    }),
-                    serum_event_queue: array_to_pubkey(&{
-                        serum_market.event_q
-                    }),
-                    serum_bids: array_to_pubkey(&{ serum_market.bids }),
-                    serum_asks: array_to_pubkey(&{ serum_market.asks }),
-                    serum_coin_vault: array_to_pubkey(&{
-                        serum_market.coin_vault
-                    }),
-                    serum_pc_vault: array_to_pubkey(&{ serum_market.pc_vault }),
-                    serum_vault_signer: *serum_vault_signer,
-                    srm_spot_program: *serum_dex_program,
-                    token_program: TOKEN_ID,
-                    rent: RENT_ID,
-                })
-                .args(instruction::Swap {
-                    buy,
-                    allow_borrow: false,
-                    amount: swap_amount,
-                    min_rate: 1u64, // WARNING: this can have a lot of slippage
-                })
-                .options(CommitmentConfig::confirmed())
-        },
-        5,
-    );
-
-    match result {
+    let quote = match quote_swap(
+        st,
+        state,
+        cache,
+        serum_market,
+        swap_amount,
+        buy,
+        asset_index,
+        cfg,
+    )
+    .await?
+    {
+        Some(x) => x,
+        None => return Ok(()),
+    };
+
+    let swap_ix = Instruction {
+        accounts: accounts::Swap {
+            authority: *payer,
+            state: *state_key,
+            state_signer: *state_signer,
+            cache: state.cache,
+            margin: *payer_margin,
+            control: *payer_control,
+            quote_mint,
+            quote_vault,
+            asset_mint,
+            asset_vault,
+            swap_fee_vault: state.swap_fee_vault,
+            serum_open_orders: state.collaterals[asset_index].serum_open_orders,
+            serum_market: array_to_pubkey(&{ serum_market.own_address }),
+            serum_request_queue: array_to_pubkey(&{ serum_market.req_q }),
+            serum_event_queue: array_to_pubkey(&{ serum_market.event_q }),
+            serum_bids: array_to_pubkey(&{ serum_market.bids }),
+            serum_asks: array_to_pubkey(&{ serum_market.asks }),
+            serum_coin_vault: array_to_pubkey(&{ serum_market.coin_vault }),
+            serum_pc_vault: array_to_pubkey(&{ serum_market.pc_vault }),
+            serum_vault_signer: *serum_vault_signer,
+            srm_spot_program: *serum_dex_program,
+            token_program: TOKEN_ID,
+            rent: RENT_ID,
+        }
+        .to_account_metas(None),
+        data: instruction::Swap {
+            buy,
+            allow_borrow: false,
+            amount: quote.amount,
+            min_rate: quote.min_rate,
+        }
+        .data(),
+        program_id: zo_abi::ID,
+    };
+
+    match retry_send(tx, &[swap_ix], 5).await {
         Ok(_tx) => Ok(()),
         Err(e) => {
             span.in_scope(|| error!("Failed to swap asset {:?}", e));
@@ -181,10 +585,13 @@ pub fn swap_asset(
     }
 }
 
-pub fn make_swap_ix(
-    program: &Program,
+/// Makes a swap of up to `amount`, or `None` if it
+/// would exceed the slippage limit entirely, see `quote_swap`.
+pub async fn make_swap_ix(
+    st: &AppState,
     payer: &Pubkey,
     state: &State,
+    cache: &Cache,
     state_key: &Pubkey,
     state_signer: &Pubkey,
     payer_margin: &Pubkey,
@@ -192,10 +599,27 @@ pub fn make_swap_ix(
     serum_market: &SerumMarketState,
     serum_dex_program: &Pubkey,
     serum_vault_signer: &Pubkey,
-    max_transfer_amount: u64,
+    amount: SwapAmount,
     buy_asset: bool,
     asset_index: usize,
-) -> Result<Instruction, ErrorCode> {
+    cfg: &SwapConfig,
+) -> Result<Option<Instruction>, ErrorCode> {
+    let quote = match quote_swap(
+        st,
+        state,
+        cache,
+        serum_market,
+        amount,
+        buy_asset,
+        asset_index,
+        cfg,
+    )
+    .await?
+    {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+
     let quote_mint = state.collaterals[0].mint;
     let quote_vault = state.vaults[0];
     let asset_mint = state.collaterals[asset_index].mint;
@@ -239,18 +663,112 @@ pub fn make_swap_ix(
         data: instruction::Swap {
             buy: buy_asset,
             allow_borrow: false,
-            amount: max_transfer_amount,
-            min_rate: 1u64, // WARNING: this can have a lot of slippage
+            amount: quote.amount,
+            min_rate: quote.min_rate,
         }.data(),
-        program_id: program.id(),
+        program_id: zo_abi::ID,
     };
 
-    Ok(swap_ix)
+    Ok(Some(swap_ix))
+}
+
+/// Swaps whatever the liquidator holds besides the quote back into
+/// it, including what earlier swaps left over for lack of depth
+/// within the slippage limit. Returns the number of swaps sent.
+pub async fn rebalance(
+    st: &AppState,
+    tx: &TxBuilder,
+    payer: &Pubkey,
+    state: &State,
+    cache: &Cache,
+    state_key: &Pubkey,
+    state_signer: &Pubkey,
+    payer_margin: &Margin,
+    payer_margin_key: &Pubkey,
+    payer_control_key: &Pubkey,
+    serum_markets: &HashMap<usize, SerumMarketState>,
+    serum_dex_program: &Pubkey,
+    serum_vault_signers: &HashMap<usize, Pubkey>,
+    cfg: &SwapConfig,
+) -> Result<usize, ErrorCode> {
+    let colls = get_actual_collateral_vec(
+        payer_margin,
+        &RefCell::new(*state).borrow(),
+        &RefCell::new(*cache).borrow(),
+        false,
+    )?;
+
+    let mut sent = 0;
+
+    for (i, coll) in colls.iter().enumerate().skip(1) {
+        let (serum_market, serum_vault_signer) =
+            match (serum_markets.get(&i), serum_vault_signers.get(&i)) {
+                (Some(m), Some(s)) => (m, s),
+                _ => continue,
+            };
+
+        if coll.abs() <= I80F48::from_num(2 * serum_market.coin_lot_size) {
+            continue;
+        }
+
+        let span = error_span!("rebalance", asset = i);
+
+        // Borrows are bought back with the quote at the oracle price,
+        // anything within the slippage limit is close enough.
+        let buy = coll.is_negative();
+        let amount: u64 = match buy {
+            true => {
+                match get_oracle(cache, &state.collaterals[i].oracle_symbol) {
+                    Some(o) => (coll.abs() * I80F48::from(o.price)).to_num(),
+                    None => continue,
+                }
+            }
+            false => coll.to_num(),
+        };
+
+        let ix = match make_swap_ix(
+            st,
+            payer,
+            state,
+            cache,
+            state_key,
+            state_signer,
+            payer_margin_key,
+            payer_control_key,
+            serum_market,
+            serum_dex_program,
+            serum_vault_signer,
+            SwapAmount::Max(amount),
+            buy,
+            i,
+            cfg,
+        )
+        .await?
+        {
+            Some(x) => x,
+            None => continue,
+        };
+
+        match retry_send(tx, &[ix], 5).await {
+            Ok(sg) => {
+                span.in_scope(|| {
+                    info!("Rebalanced {} of collateral {}: {}", coll, i, sg)
+                });
+                sent += 1;
+            }
+            Err(e) => {
+                span.in_scope(|| warn!("Failed to rebalance: {:?}", e));
+            }
+        }
+    }
+
+    Ok(sent)
 }
 
 #[allow(dead_code)]
-pub fn close_position(
-    program: &Program,
+pub async fn close_position(
+    st: &AppState,
+    tx: &TxBuilder,
     state: &State,
     state_key: &Pubkey,
     state_signer: &Pubkey,
@@ -263,10 +781,13 @@ pub fn close_position(
 ) -> Result<(), ErrorCode> {
     // Pick the right market and place a market order to close the position you received from liquidating someone.
     // Need to know the amount to close
-    let client = program.rpc();
-
-    let oo_account = client
-        .get_account(&control.open_orders_agg[index].key)
+    let oo_key = control.open_orders_agg[index].key;
+    let oo_account = st
+        .rpc
+        .call(Budget::Liquidator, "getAccountInfo", |c| {
+            c.get_account(&oo_key)
+        })
+        .await
         .unwrap();
 
     let native_coin_total_bytes: [u8; 8] =
@@ -280,83 +801,58 @@ pub fn close_position(
         return Ok(());
     }
 
-    let result = if native_coin_total < 0 {
+    let accounts = accounts::PlacePerpOrder {
+        state: *state_key,
+        state_signer: *state_signer,
+        cache: state.cache,
+        authority: margin.authority,
+        margin: *margin_key,
+        control: margin.control,
+        open_orders: control.open_orders_agg[index].key,
+        dex_market: dex_market.own_address,
+        req_q: dex_market.req_q,
+        event_q: dex_market.event_q,
+        market_bids: dex_market.bids,
+        market_asks: dex_market.asks,
+        dex_program: *dex_program,
+        rent: RENT_ID,
+    };
+
+    let args = if native_coin_total < 0 {
         // Short order
-        retry_send(
-            || {
-                program
-                    .request()
-                    .accounts(accounts::PlacePerpOrder {
-                        state: *state_key,
-                        state_signer: *state_signer,
-                        cache: state.cache,
-                        authority: margin.authority,
-                        margin: *margin_key,
-                        control: margin.control,
-                        open_orders: control.open_orders_agg[index].key,
-                        dex_market: dex_market.own_address,
-                        req_q: dex_market.req_q,
-                        event_q: dex_market.event_q,
-                        market_bids: dex_market.bids,
-                        market_asks: dex_market.asks,
-                        dex_program: *dex_program,
-                        rent: RENT_ID,
-                    })
-                    .args(instruction::PlacePerpOrder {
-                        is_long: true,                       // Long to cancel it out
-                        limit_price: 999_999_999_999_999u64, // TODO: make this more principled
-                        max_base_quantity: (native_coin_total.abs() as u64)
-                            .safe_div(dex_market.coin_lot_size)
-                            .unwrap(),
-                        max_quote_quantity: 999_999_999_999_999u64,
-                        order_type: OrderType::ReduceOnlyIoc,
-                        limit: 10,
-                        client_id: 0u64,
-                    })
-                    .options(CommitmentConfig::confirmed())
-            },
-            5,
-        )
+        instruction::PlacePerpOrder {
+            is_long: true,                       // Long to cancel it out
+            limit_price: 999_999_999_999_999u64, // TODO: make this more principled
+            max_base_quantity: (native_coin_total.abs() as u64)
+                .safe_div(dex_market.coin_lot_size)
+                .unwrap(),
+            max_quote_quantity: 999_999_999_999_999u64,
+            order_type: OrderType::ReduceOnlyIoc,
+            limit: 10,
+            client_id: 0u64,
+        }
     } else {
         // Long order
-        retry_send(
-            || {
-                program
-                    .request()
-                    .accounts(accounts::PlacePerpOrder {
-                        state: *state_key,
-                        state_signer: *state_signer,
-                        cache: state.cache,
-                        authority: margin.authority,
-                        margin: *margin_key,
-                        control: margin.control,
-                        open_orders: control.open_orders_agg[index].key,
-                        dex_market: dex_market.own_address,
-                        req_q: dex_market.req_q,
-                        event_q: dex_market.event_q,
-                        market_bids: dex_market.bids,
-                        market_asks: dex_market.asks,
-                        dex_program: *dex_program,
-                        rent: RENT_ID,
-                    })
-                    .args(instruction::PlacePerpOrder {
-                        is_long: false,    // Short to cancel it out
-                        limit_price: 1u64, // TODO: make this more principled
-                        max_base_quantity: (native_coin_total as u64)
-                            .safe_div(dex_market.coin_lot_size)
-                            .unwrap(),
-                        max_quote_quantity: 1u64,
-                        order_type: OrderType::Limit,
-                        limit: 10,
-                        client_id: 0u64,
-                    })
-                    .options(CommitmentConfig::confirmed())
-            },
-            5,
-        )
+        instruction::PlacePerpOrder {
+            is_long: false,    // Short to cancel it out
+            limit_price: 1u64, // TODO: make this more principled
+            max_base_quantity: (native_coin_total as u64)
+                .safe_div(dex_market.coin_lot_size)
+                .unwrap(),
+            max_quote_quantity: 1u64,
+            order_type: OrderType::Limit,
+            limit: 10,
+            client_id: 0u64,
+        }
+    };
+
+    let close_ix = Instruction {
+        accounts: accounts.to_account_metas(None),
+        data: args.data(),
+        program_id: zo_abi::ID,
     };
 
-    match result {
+    match retry_send(tx, &[close_ix], 5).await {
         Ok(tx) => {
             span.in_scope(|| {
                 info!("Successfully placed order to close position {:?}", tx)
@@ -371,7 +867,6 @@ pub fn close_position(
 }
 
 pub fn close_position_ix(
-    program: &Program,
     state: &State,
     state_key: &Pubkey,
     state_signer: &Pubkey,
@@ -419,7 +914,7 @@ pub fn close_position_ix(
             client_id: 0u64,
         }
         .data(),
-        program_id: program.id(),
+        program_id: zo_abi::ID,
     };
 
     Ok(close_ix)
@@ -3,19 +3,20 @@ use anchor_lang::{
     Owner, ZeroCopy,
 };
 
-use anchor_client::{ClientError::SolanaClientError, RequestBuilder};
-
 use solana_account_decoder::UiAccountEncoding;
 use solana_client::{
     client_error::{ClientError, ClientErrorKind},
-    rpc_client::RpcClient,
+    nonblocking::rpc_client::RpcClient,
     rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig},
     rpc_filter::{Memcmp, MemcmpEncodedBytes, RpcFilterType},
     rpc_request::{RpcError, RpcResponseErrorData},
 };
 use solana_sdk::{
-    account::Account, commitment_config::CommitmentConfig,
-    instruction::InstructionError, pubkey::Pubkey, signature::Signature,
+    account::Account,
+    commitment_config::CommitmentConfig,
+    instruction::{Instruction, InstructionError},
+    pubkey::Pubkey,
+    signature::Signature,
     transaction::TransactionError,
 };
 
@@ -25,7 +26,7 @@ use tracing::{error, warn};
 
 use zo_abi::{Cache, OpenOrdersInfo, OracleCache, Symbol, MAX_MARKETS};
 
-use crate::liquidator::error::ErrorCode;
+use crate::{liquidator::error::ErrorCode, tx::TxBuilder, Error};
 
 pub fn get_account_info<'a>(
     key: &'a Pubkey,
@@ -57,7 +58,7 @@ where
     }
 }
 
-pub fn load_program_accounts<T>(
+pub async fn load_program_accounts<T>(
     client: &RpcClient,
     program_address: &Pubkey,
 ) -> Result<Vec<(Pubkey, T)>, ClientError>
@@ -83,6 +84,7 @@ where
 
     client
         .get_program_accounts_with_config(program_address, config)
+        .await
         .map(|v| {
             v.into_iter()
                 .map(|(k, mut a)| (k, get_type_from_account::<T>(&k, &mut a)))
@@ -171,100 +173,112 @@ pub fn get_preflight_error_code(error: &RpcError) -> Option<&u32> {
     error_code
 }
 
-// TODO: Refactor to take vector of ixs
 #[tracing::instrument(skip_all, level = "error")]
-pub fn retry_send<'a>(
-    make_builder: impl Fn() -> RequestBuilder<'a>,
+pub async fn retry_send(
+    tx: &TxBuilder,
+    ixs: &[Instruction],
     retries: usize,
 ) -> Result<Signature, ErrorCode> {
-    let mut last_error: Option<_> = None;
-
-    for _i in 0..retries {
-        let request_builder = make_builder();
+    let mut last_error: Option<Error> = None;
 
-        match request_builder.send() {
+    for i in 0..retries {
+        // Each retry escalates the priority fee of the transaction.
+        match tx.send_and_confirm(ixs, i as u32).await {
             Ok(response) => {
                 return Ok(response);
             }
             Err(e) => {
-                if let SolanaClientError(ClientError { request: _, kind }) = e {
-                    match &kind {
-                        ClientErrorKind::RpcError(e) => {
-                            match get_preflight_error_code(e) {
-                                Some(&code) => {
-                                    if code == 6006
-                                        || code == 6016
-                                        || code == 6046
-                                    {
-                                        warn!(
-                                            "Retrying with smaller liquidation"
-                                        );
-                                        return Err(
-                                            ErrorCode::LiquidationOverExposure,
-                                        );
-                                    } else if code == 6007
-                                        || code == 6012
-                                        || code == 6011
-                                    {
-                                        warn!("Account is not liquidatable");
-                                        return Err(
-                                            ErrorCode::UnrecoverableTransactionError,
-                                        );
-                                    } else if code == 6017 {
-                                        warn!("Account was already liquidated");
-                                        return Err(
-                                            ErrorCode::UnrecoverableTransactionError,
-                                        );
-                                    } else if code == 6052 {
-                                        warn!("Account has unliquidated spot, possibly already liquidated. {:?}", e);
+                match &e {
+                    Error::SolanaClient(ClientError { request: _, kind }) => {
+                        match kind {
+                            ClientErrorKind::RpcError(e) => {
+                                match get_preflight_error_code(e) {
+                                    Some(&code) => {
+                                        if code == 6006
+                                            || code == 6016
+                                            || code == 6046
+                                        {
+                                            warn!(
+                                                "Retrying with smaller liquidation"
+                                            );
+                                            return Err(
+                                                ErrorCode::LiquidationOverExposure,
+                                            );
+                                        } else if code == 6007
+                                            || code == 6012
+                                            || code == 6011
+                                        {
+                                            warn!(
+                                                "Account is not liquidatable"
+                                            );
+                                            return Err(
+                                                ErrorCode::UnrecoverableTransactionError,
+                                            );
+                                        } else if code == 6017 {
+                                            warn!("Account was already liquidated");
+                                            return Err(
+                                                ErrorCode::UnrecoverableTransactionError,
+                                            );
+                                        } else if code == 6052 {
+                                            warn!("Account has unliquidated spot, possibly already liquidated. {:?}", e);
+                                            return Err(
+                                                ErrorCode::UnrecoverableTransactionError,
+                                            );
+                                        }
+                                    }
+                                    None => {
+                                        warn!("Got rpc error: {:?}", e);
                                         return Err(
                                             ErrorCode::UnrecoverableTransactionError,
                                         );
                                     }
                                 }
-                                None => {
-                                    warn!("Got rpc error: {:?}", e);
-                                    return Err(
-                                        ErrorCode::UnrecoverableTransactionError,
-                                    );
-                                }
+                            }
+                            ClientErrorKind::Reqwest(e) => {
+                                warn!("Got reqwest error: {:?}", e);
+                            }
+                            ClientErrorKind::TransactionError(e) => {
+                                warn!("Got transaction error: {:?}", e);
+                            }
+                            _ => {
+                                return Err(
+                                    ErrorCode::UnrecoverableTransactionError,
+                                );
                             }
                         }
-                        ClientErrorKind::Reqwest(e) => {
-                            warn!("Got reqwest error: {:?}", e);
-                        }
-                        ClientErrorKind::TransactionError(e) => {
-                            warn!("Got transaction error: {:?}", e);
-                        }
-                        _ => {
-                            return Err(
-                                ErrorCode::UnrecoverableTransactionError,
-                            );
-                        }
                     }
-                    last_error = Some(kind);
+                    Error::TransactionError(e) => {
+                        warn!("Got transaction error: {:?}", e);
+                    }
+                    Error::ConfirmationTimeout(sg) => {
+                        warn!("Failed to confirm {}, retrying", sg);
+                    }
+                    _ => {
+                        return Err(ErrorCode::UnrecoverableTransactionError);
+                    }
                 }
+                last_error = Some(e);
             }
         };
     }
 
-    if let Some(e) = last_error {
-        if let ClientErrorKind::RpcError(RpcError::RpcResponseError {
-            code: c,
-            message: error_msg,
-            data: d,
-        }) = e
-        {
+    match last_error {
+        Some(Error::SolanaClient(ClientError {
+            kind:
+                ClientErrorKind::RpcError(RpcError::RpcResponseError {
+                    code: c,
+                    message: error_msg,
+                    data: d,
+                }),
+            ..
+        })) => {
             error!(
                 "Failed to send request. message: {:?}, data: {:?}. Code: {}",
                 error_msg, d, c
             );
-        } else {
-            error!("Failed to send request with error {:?}", e);
         }
-    } else {
-        let ix = make_builder().instructions().unwrap();
-        error!("Failed to send request {:?}", ix);
+        Some(e) => error!("Failed to send request with error {:?}", e),
+        None => error!("Failed to send request {:?}", ixs),
     }
 
     Err(ErrorCode::TimeoutExceeded)
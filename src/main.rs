@@ -1,76 +1,583 @@
-use anchor_client::{
-    solana_sdk::{commitment_config::CommitmentConfig, signer::keypair},
-    Cluster,
+use anchor_client::solana_sdk::{
+    commitment_config::CommitmentConfig, signature::Signature, signer::keypair,
 };
-use clap::{Parser, Subcommand};
-use std::{env, time::Duration};
+use clap::{Args, Parser, Subcommand};
+use std::{env, path::PathBuf, time::Duration};
 use zo_keeper as lib;
 
+/// Flags left out take their value from the `--config` file, or
+/// their default if the file doesn't set them either.
 #[derive(Parser)]
 #[clap(term_width = 72, disable_help_subcommand = true)]
 struct Cli {
-    /// RPC endpoint.
-    #[clap(short, long, env = "SOLANA_RPC_URL")]
-    rpc_url: String,
+    /// Configuration file, see `config dump` for its layout
+    #[clap(short, long, env = "ZO_KEEPER_CONFIG")]
+    config: Option<PathBuf>,
 
-    /// Websocket endpoint.
-    #[clap(long, env = "SOLANA_WS_URL")]
-    ws_url: String,
+    /// RPC endpoints, separated by commas. Requests go to the
+    /// healthiest one, and fail over to the others.
+    #[clap(short, long, env = "SOLANA_RPC_URL", use_delimiter = true)]
+    rpc_url: Vec<String>,
+
+    /// Websocket endpoints, one for each RPC endpoint, in the same
+    /// order.
+    #[clap(long, env = "SOLANA_WS_URL", use_delimiter = true)]
+    ws_url: Vec<String>,
+
+    #[clap(flatten)]
+    rpc: RpcArgs,
 
     /// Path to keypair. If not set, the JSON encoded keypair is read
     /// from $SOLANA_PAYER_KEY instead.
     #[clap(short, long)]
-    payer: Option<std::path::PathBuf>,
+    payer: Option<PathBuf>,
+
+    /// Simulate transactions instead of sending them
+    #[clap(long)]
+    dry_run: bool,
+
+    /// File to append the simulated transactions to, as JSON lines.
+    /// Implies --dry-run
+    #[clap(long)]
+    dry_run_output: Option<PathBuf>,
+
+    /// Address to serve Prometheus metrics and the `/healthz` and
+    /// `/readyz` checks on, e.g. `0.0.0.0:9100`
+    #[clap(long, env = "METRICS_ADDR")]
+    metrics_addr: Option<std::net::SocketAddr>,
+
+    /// Address to serve only the `/healthz` and `/readyz` checks on,
+    /// for probing health without --metrics-addr
+    #[clap(long, env = "HEALTH_ADDR")]
+    health_addr: Option<std::net::SocketAddr>,
+
+    #[clap(flatten)]
+    health: HealthArgs,
+
+    /// Time to wait for the work in flight on SIGINT or SIGTERM, in
+    /// seconds [default: 30]
+    #[clap(long, parse(try_from_str = parse_seconds))]
+    shutdown_timeout: Option<Duration>,
 
     #[clap(subcommand)]
     command: Command,
 }
 
+/// Overrides `dst` with the flag, if it was given.
+fn set<T>(dst: &mut T, flag: Option<T>) {
+    if let Some(x) = flag {
+        *dst = x;
+    }
+}
+
+#[derive(Args)]
+struct RpcArgs {
+    /// Number of RPC endpoints each transaction is sent to
+    /// [default: 2]
+    #[clap(long)]
+    rpc_fanout: Option<usize>,
+
+    /// Slots an RPC endpoint may be behind the others before it is
+    /// avoided [default: 50]
+    #[clap(long)]
+    rpc_max_slot_lag: Option<u64>,
+
+    /// How often the slot of every RPC endpoint is polled, in seconds
+    /// [default: 5]
+    #[clap(long, parse(try_from_str = parse_seconds))]
+    rpc_slot_interval: Option<Duration>,
+
+    /// Weight of the RPC requests sent per second, across all
+    /// keepers [default: 50]
+    #[clap(long)]
+    rpc_rate_limit: Option<f64>,
+
+    /// Weight of the RPC requests which may be sent at once
+    /// [default: 100]
+    #[clap(long)]
+    rpc_burst: Option<f64>,
+
+    /// Weight of an RPC method, as `<method>=<weight>`, e.g.
+    /// `getProgramAccounts=10`. Methods left out weigh 1. Can be
+    /// repeated
+    #[clap(long)]
+    rpc_weight: Vec<lib::limiter::Quota>,
+
+    /// Rate of a keeper's RPC requests, as `<keeper>=<rate>`, e.g.
+    /// `recorder=20`, below the total rate limit. Can be repeated
+    #[clap(long)]
+    rpc_budget: Vec<lib::limiter::Quota>,
+}
+
+impl RpcArgs {
+    fn apply(self, c: &mut lib::rpc::PoolConfig) {
+        set(&mut c.fanout, self.rpc_fanout);
+        set(&mut c.max_slot_lag, self.rpc_max_slot_lag);
+        set(&mut c.slot_interval, self.rpc_slot_interval);
+        set(&mut c.limit.rate, self.rpc_rate_limit);
+        set(&mut c.limit.burst, self.rpc_burst);
+
+        if !self.rpc_weight.is_empty() {
+            c.limit.weights = self.rpc_weight;
+        }
+
+        if !self.rpc_budget.is_empty() {
+            c.limit.budgets = self.rpc_budget;
+        }
+    }
+}
+
+#[derive(Args)]
+struct HealthArgs {
+    /// Time without a landed crank instruction, per instruction and
+    /// symbol, after which the keeper is unhealthy, in seconds
+    /// [default: 60]
+    #[clap(long, parse(try_from_str = parse_seconds))]
+    stale_crank: Option<Duration>,
+
+    /// Time without reading an event queue, per market, after which
+    /// the keeper is unhealthy, in seconds [default: 300]
+    #[clap(long, parse(try_from_str = parse_seconds))]
+    stale_event_queue: Option<Duration>,
+
+    /// Time without a websocket message after which the keeper is
+    /// unhealthy, in seconds [default: 60]
+    #[clap(long, parse(try_from_str = parse_seconds))]
+    stale_listener: Option<Duration>,
+
+    /// Time without writing to the store after which the recorder is
+    /// unhealthy, in seconds [default: 900]
+    #[clap(long, parse(try_from_str = parse_seconds))]
+    stale_db_write: Option<Duration>,
+}
+
+impl HealthArgs {
+    fn apply(self, c: &mut lib::heartbeat::HealthConfig) {
+        set(&mut c.crank, self.stale_crank);
+        set(&mut c.event_queue, self.stale_event_queue);
+        set(&mut c.listener, self.stale_listener);
+        set(&mut c.db_write, self.stale_db_write);
+    }
+}
+
+#[derive(Args)]
+struct FeeArgs {
+    /// Compute unit price, either `fixed:<micro-lamports>` or
+    /// `p<percentile>` of the recent fees paid for the accounts
+    /// written by the transaction [default: fixed:10000]
+    #[clap(long)]
+    priority_fee: Option<lib::tx::PriorityFee>,
+
+    /// Multiplier applied to the compute unit price on each retry
+    /// [default: 1.5]
+    #[clap(long)]
+    fee_escalation: Option<f64>,
+
+    /// Maximum compute unit price, in micro-lamports
+    /// [default: 1000000]
+    #[clap(long)]
+    max_priority_fee: Option<u64>,
+
+    /// Headroom added to the simulated compute units, as a fraction
+    /// [default: 0.1]
+    #[clap(long)]
+    compute_unit_margin: Option<f64>,
+
+    /// How long the simulated compute units of a kind of transaction
+    /// are reused for, in seconds [default: 300]
+    #[clap(long, parse(try_from_str = parse_seconds))]
+    compute_unit_ttl: Option<Duration>,
+}
+
+impl FeeArgs {
+    fn apply(self, c: &mut lib::tx::FeeConfig) {
+        set(&mut c.priority_fee, self.priority_fee);
+        set(&mut c.escalation, self.fee_escalation);
+        set(&mut c.max_price, self.max_priority_fee);
+        set(&mut c.compute_unit_margin, self.compute_unit_margin);
+        set(&mut c.compute_unit_ttl, self.compute_unit_ttl);
+    }
+}
+
+#[derive(Args)]
+struct RiskArgs {
+    /// Maximum gross notional of the liquidator's positions, as a
+    /// multiple of its account value [default: 5]
+    #[clap(long)]
+    max_leverage: Option<f64>,
+
+    /// Maximum notional on a perp market, in USD, either
+    /// `<symbol>=<usd>` or `<usd>` for every market. Can be repeated
+    #[clap(long)]
+    max_market_notional: Vec<lib::liquidator::NotionalLimit>,
+
+    /// Maximum notional of a collateral, in USD, either
+    /// `<symbol>=<usd>` or `<usd>` for every collateral. Can be
+    /// repeated
+    #[clap(long)]
+    max_collateral_notional: Vec<lib::liquidator::NotionalLimit>,
+
+    /// Maximum gross notional across all markets and collaterals,
+    /// in USD
+    #[clap(long)]
+    max_inventory: Option<f64>,
+
+    /// Multiplier on the maintenance fraction below which accounts
+    /// are liquidated [default: 0.99995]
+    #[clap(long)]
+    maintenance_tolerance: Option<f64>,
+
+    /// Multiplier on the cancel fraction below which orders are
+    /// cancelled [default: 0.99995]
+    #[clap(long)]
+    cancel_tolerance: Option<f64>,
+}
+
+impl RiskArgs {
+    fn apply(self, c: &mut lib::liquidator::RiskConfig) {
+        set(&mut c.max_leverage, self.max_leverage);
+
+        if !self.max_market_notional.is_empty() {
+            c.max_market_notional = self.max_market_notional;
+        }
+
+        if !self.max_collateral_notional.is_empty() {
+            c.max_collateral_notional = self.max_collateral_notional;
+        }
+
+        c.max_inventory = self.max_inventory.or(c.max_inventory);
+        set(&mut c.tolerance.maintenance, self.maintenance_tolerance);
+        set(&mut c.tolerance.cancel, self.cancel_tolerance);
+    }
+}
+
+#[derive(Args)]
+struct CrankArgs {
+    /// Interval for cache oracle, in seconds [default: 2.5]
+    #[clap(long, parse(try_from_str = parse_seconds))]
+    cache_oracle_interval: Option<Duration>,
+
+    /// Interval for cache interest, in seconds [default: 5]
+    #[clap(long, parse(try_from_str = parse_seconds))]
+    cache_interest_interval: Option<Duration>,
+
+    /// Interval for update funding, in seconds [default: 15]
+    #[clap(long, parse(try_from_str = parse_seconds))]
+    update_funding_interval: Option<Duration>,
+
+    /// Oracles cached per transaction [default: 6]
+    #[clap(long)]
+    cache_oracle_chunk_size: Option<usize>,
+
+    /// Collaterals whose interest is cached per transaction
+    /// [default: 12]
+    #[clap(long)]
+    cache_interest_chunk_size: Option<usize>,
+
+    /// Attempts at sending a transaction before giving up
+    /// [default: 3]
+    #[clap(long)]
+    send_retries: Option<u32>,
+
+    #[clap(flatten)]
+    fee: FeeArgs,
+}
+
+impl CrankArgs {
+    fn apply(self, c: &mut lib::crank::CrankConfig) {
+        set(&mut c.cache_oracle_interval, self.cache_oracle_interval);
+        set(&mut c.cache_interest_interval, self.cache_interest_interval);
+        set(&mut c.update_funding_interval, self.update_funding_interval);
+        set(&mut c.cache_oracle_chunk_size, self.cache_oracle_chunk_size);
+        set(
+            &mut c.cache_interest_chunk_size,
+            self.cache_interest_chunk_size,
+        );
+        set(&mut c.send_retries, self.send_retries);
+        self.fee.apply(&mut c.fee);
+    }
+}
+
+#[derive(Args)]
+struct ConsumerArgs {
+    /// Events to consume each iteration [default: 12]
+    #[clap(long)]
+    to_consume: Option<usize>,
+
+    /// Maximum time to stay idle, in seconds [default: 30]
+    #[clap(long, parse(try_from_str = parse_seconds))]
+    max_wait: Option<Duration>,
+
+    /// Maximum queue length before processing [default: 1]
+    #[clap(long)]
+    max_queue_length: Option<usize>,
+
+    /// Interval for polling the event queues while their websocket
+    /// is down, in seconds [default: 0.25]
+    #[clap(long, parse(try_from_str = parse_seconds))]
+    poll_interval: Option<Duration>,
+
+    /// Attempts at sending a transaction before giving up
+    /// [default: 3]
+    #[clap(long)]
+    send_retries: Option<u32>,
+
+    #[clap(flatten)]
+    fee: FeeArgs,
+}
+
+impl ConsumerArgs {
+    fn apply(self, c: &mut lib::consumer::ConsumerConfig) {
+        set(&mut c.to_consume, self.to_consume);
+        set(&mut c.max_wait, self.max_wait);
+        set(&mut c.max_queue_length, self.max_queue_length);
+        set(&mut c.poll_interval, self.poll_interval);
+        set(&mut c.send_retries, self.send_retries);
+        self.fee.apply(&mut c.fee);
+    }
+}
+
+#[derive(Args)]
+struct LiquidatorArgs {
+    /// The total number of bots run [default: 1]
+    #[clap(long)]
+    worker_count: Option<u8>,
+
+    /// The slice of addresses this bot is responsible for
+    /// [default: 0]
+    #[clap(long)]
+    worker_index: Option<u8>,
+
+    /// Number of liquidations and cancellations run at once
+    /// [default: 8]
+    #[clap(long)]
+    job_concurrency: Option<usize>,
+
+    /// Interval for scanning the accounts, in seconds
+    /// [default: 0.25]
+    #[clap(long, parse(try_from_str = parse_seconds))]
+    scan_interval: Option<Duration>,
+
+    /// Interval for fetching every account again, in seconds
+    /// [default: 300]
+    #[clap(long, parse(try_from_str = parse_seconds))]
+    refresh_interval: Option<Duration>,
+
+    /// Interval for swapping leftover inventory back, in seconds
+    /// [default: 30]
+    #[clap(long, parse(try_from_str = parse_seconds))]
+    rebalance_interval: Option<Duration>,
+
+    /// Maximum distance from the oracle price swaps may fill at,
+    /// in basis points, serum fees included [default: 50]
+    #[clap(long)]
+    max_slippage_bps: Option<u16>,
+
+    /// Minimum expected profit of a liquidation, in USD, net of
+    /// swap and transaction fees [default: 0]
+    #[clap(long)]
+    min_profit: Option<f64>,
+
+    #[clap(flatten)]
+    risk: RiskArgs,
+
+    #[clap(flatten)]
+    fee: FeeArgs,
+}
+
+impl LiquidatorArgs {
+    fn apply(self, c: &mut lib::liquidator::LiquidatorConfig) {
+        set(&mut c.worker_count, self.worker_count);
+        set(&mut c.worker_index, self.worker_index);
+        set(&mut c.job_concurrency, self.job_concurrency);
+        set(&mut c.scan_interval, self.scan_interval);
+        set(&mut c.refresh_interval, self.refresh_interval);
+        set(&mut c.rebalance_interval, self.rebalance_interval);
+        set(&mut c.swap.max_slippage_bps, self.max_slippage_bps);
+        set(&mut c.profit.min_profit, self.min_profit);
+        self.risk.apply(&mut c.risk);
+        self.fee.apply(&mut c.fee);
+    }
+}
+
+#[derive(Args)]
+struct RecorderArgs {
+    /// Store URL, one of `mongodb://`, `postgres://`,
+    /// `sqlite://<path>` or `jsonl://<directory>`
+    #[clap(long, env = "DATABASE_URL")]
+    store: Option<String>,
+
+    /// Maximum number of transactions processed at once
+    /// [default: 32]
+    #[clap(long)]
+    process_concurrency: Option<usize>,
+
+    /// Maximum number of transactions fetched from the RPC at once
+    /// [default: 8]
+    #[clap(long)]
+    fetch_concurrency: Option<usize>,
+
+    /// Attempts at processing a transaction before giving up
+    /// [default: 5]
+    #[clap(long)]
+    process_attempts: Option<u32>,
+
+    /// Wait before retrying a transaction, in seconds, multiplied by
+    /// the attempts so far [default: 2]
+    #[clap(long, parse(try_from_str = parse_seconds))]
+    process_retry_wait: Option<Duration>,
+
+    /// Interval for saving the high-water slot, in seconds
+    /// [default: 10]
+    #[clap(long, parse(try_from_str = parse_seconds))]
+    high_water_interval: Option<Duration>,
+
+    /// Interval for polling recent transactions, in seconds
+    /// [default: 0.25]
+    #[clap(long, parse(try_from_str = parse_seconds))]
+    poll_logs_interval: Option<Duration>,
+
+    /// Interval for recording funding, in seconds [default: 10]
+    #[clap(long, parse(try_from_str = parse_seconds))]
+    update_funding_interval: Option<Duration>,
+
+    /// Interval for recording open interest, in seconds
+    /// [default: 300]
+    #[clap(long, parse(try_from_str = parse_seconds))]
+    open_interest_interval: Option<Duration>,
+
+    /// Interval for recording mark TWAPs, in seconds [default: 30]
+    #[clap(long, parse(try_from_str = parse_seconds))]
+    mark_twap_interval: Option<Duration>,
+}
+
+impl RecorderArgs {
+    fn apply(self, c: &mut lib::recorder::RecorderConfig) {
+        set(&mut c.store, self.store);
+        set(&mut c.process_concurrency, self.process_concurrency);
+        set(&mut c.fetch_concurrency, self.fetch_concurrency);
+        set(&mut c.process_attempts, self.process_attempts);
+        set(&mut c.process_retry_wait, self.process_retry_wait);
+        set(&mut c.high_water_interval, self.high_water_interval);
+        set(&mut c.poll_logs_interval, self.poll_logs_interval);
+        set(&mut c.update_funding_interval, self.update_funding_interval);
+        set(&mut c.open_interest_interval, self.open_interest_interval);
+        set(&mut c.mark_twap_interval, self.mark_twap_interval);
+    }
+}
+
+#[derive(Args)]
+struct BackfillArgs {
+    /// Store URL, see `recorder --help`
+    #[clap(long, env = "DATABASE_URL")]
+    store: Option<String>,
+
+    /// Oldest slot to backfill
+    #[clap(long)]
+    from_slot: Option<u64>,
+
+    /// Newest slot to backfill
+    #[clap(long)]
+    to_slot: Option<u64>,
+
+    /// Start with the transactions before this signature
+    #[clap(long)]
+    before: Option<Signature>,
+
+    /// Stop once this signature is reached
+    #[clap(long)]
+    until: Option<Signature>,
+
+    /// Maximum number of transactions fetched at once [default: 8]
+    #[clap(long)]
+    concurrency: Option<usize>,
+
+    /// Ignore saved progress and start over
+    #[clap(long)]
+    restart: bool,
+}
+
+impl BackfillArgs {
+    fn apply(self, c: &mut lib::backfill::BackfillConfig) {
+        set(&mut c.store, self.store);
+        c.from_slot = self.from_slot.or(c.from_slot);
+        c.to_slot = self.to_slot.or(c.to_slot);
+        c.before = self.before.or(c.before);
+        c.until = self.until.or(c.until);
+        set(&mut c.concurrency, self.concurrency);
+        c.restart |= self.restart;
+    }
+}
+
+#[derive(Args)]
+struct RunArgs {
+    /// Keepers to run, e.g. `crank,consumer,recorder`. Each is
+    /// configured by its section of the `--config` file
+    #[clap(long, use_delimiter = true)]
+    with: Vec<lib::supervisor::Keeper>,
+
+    /// Wait before restarting a failed keeper, in seconds, doubled
+    /// on every failure in a row [default: 1]
+    #[clap(long, parse(try_from_str = parse_seconds))]
+    min_restart_wait: Option<Duration>,
+
+    /// Maximum wait before restarting a failed keeper, in seconds
+    /// [default: 60]
+    #[clap(long, parse(try_from_str = parse_seconds))]
+    max_restart_wait: Option<Duration>,
+}
+
+impl RunArgs {
+    fn apply(self, c: &mut lib::supervisor::RunConfig) {
+        if !self.with.is_empty() {
+            c.enable(&self.with);
+        }
+
+        set(&mut c.min_restart_wait, self.min_restart_wait);
+        set(&mut c.max_restart_wait, self.max_restart_wait);
+    }
+}
+
 #[derive(Subcommand)]
 enum Command {
     /// Run caching and update funding instructions
-    Crank {
-        /// Interval for cache oracle, in seconds
-        #[clap(long, default_value = "2.5", parse(try_from_str = parse_seconds))]
-        cache_oracle_interval: Duration,
+    Crank(CrankArgs),
 
-        /// Interval for cache interest, in seconds
-        #[clap(long, default_value = "5", parse(try_from_str = parse_seconds))]
-        cache_interest_interval: Duration,
+    /// Consume events for each market
+    Consumer(ConsumerArgs),
 
-        /// Interval for update funding, in seconds
-        #[clap(long, default_value = "15", parse(try_from_str = parse_seconds))]
-        update_funding_interval: Duration,
-    },
+    /// Find liquidatable accounts and liquidate them
+    Liquidator(LiquidatorArgs),
 
-    /// Consume events for each market
-    Consumer {
-        /// Events to consume each iteration
-        #[clap(long, default_value = "12")]
-        to_consume: usize,
+    /// Listen and store events into a database
+    Recorder(RecorderArgs),
 
-        /// Maximum time to stay idle, in seconds
-        #[clap(long, default_value = "30", parse(try_from_str = parse_seconds))]
-        max_wait: Duration,
+    /// Replay past transactions into the recorder's store
+    Backfill(BackfillArgs),
 
-        /// Maximum queue length before processing
-        #[clap(long, default_value = "1")]
-        max_queue_length: usize,
-    },
+    /// Run several keepers in one process, restarting those that fail
+    Run(RunArgs),
 
-    /// Find liquidatable accounts and liquidate them
-    Liquidator {
-        /// The total number of bots run
-        #[clap(long, default_value = "1")]
-        worker_count: u8,
+    /// Inspect the configuration
+    #[clap(subcommand)]
+    Config(ConfigCommand),
+}
 
-        /// The slice of addresses this bot is responsible for
-        #[clap(long, default_value = "0")]
-        worker_index: u8,
-    },
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// Print the effective configuration, as TOML
+    Dump,
+}
 
-    /// Listen and store events into a database
+/// The keeper picked by the command, once its flags are applied.
+#[derive(Clone, Copy)]
+enum Keeper {
+    Crank,
+    Consumer,
+    Liquidator,
     Recorder,
+    Backfill,
+    Run,
 }
 
 fn main() -> Result<(), lib::Error> {
@@ -88,14 +595,104 @@ fn main() -> Result<(), lib::Error> {
     }
 
     let Cli {
+        config,
         rpc_url,
         ws_url,
+        rpc,
         payer,
+        dry_run,
+        dry_run_output,
+        metrics_addr,
+        health_addr,
+        health,
+        shutdown_timeout,
         command,
     } = Cli::parse();
 
-    let payer = match payer {
-        Some(p) => keypair::read_keypair_file(&p).unwrap_or_else(|_| {
+    let mut cfg = lib::config::Config::load(config.as_deref())?;
+
+    if !rpc_url.is_empty() {
+        cfg.rpc_url = rpc_url;
+    }
+    if !ws_url.is_empty() {
+        cfg.ws_url = ws_url;
+    }
+    rpc.apply(&mut cfg.rpc);
+    cfg.payer = payer.or(cfg.payer);
+    cfg.dry_run |= dry_run;
+    cfg.dry_run_output = dry_run_output.or(cfg.dry_run_output);
+    cfg.metrics_addr = metrics_addr.or(cfg.metrics_addr);
+    cfg.health_addr = health_addr.or(cfg.health_addr);
+    health.apply(&mut cfg.health);
+    set(&mut cfg.shutdown.timeout, shutdown_timeout);
+
+    let keeper = match command {
+        Command::Crank(x) => {
+            x.apply(&mut cfg.crank);
+            Keeper::Crank
+        }
+        Command::Consumer(x) => {
+            x.apply(&mut cfg.consumer);
+            Keeper::Consumer
+        }
+        Command::Liquidator(x) => {
+            x.apply(&mut cfg.liquidator);
+            Keeper::Liquidator
+        }
+        Command::Recorder(x) => {
+            x.apply(&mut cfg.recorder);
+            Keeper::Recorder
+        }
+        Command::Backfill(x) => {
+            x.apply(&mut cfg.backfill);
+            Keeper::Backfill
+        }
+        Command::Run(x) => {
+            x.apply(&mut cfg.run);
+            Keeper::Run
+        }
+        Command::Config(ConfigCommand::Dump) => {
+            // Only read by the recorder and backfill flags, which
+            // aren't parsed here.
+            if let Ok(store) = env::var("DATABASE_URL") {
+                cfg.recorder.store = store.clone();
+                cfg.backfill.store = store;
+            }
+
+            cfg.validate()?;
+            print!("{}", cfg.dump()?);
+            return Ok(());
+        }
+    };
+
+    cfg.rpc.validate()?;
+    cfg.health.validate()?;
+    cfg.validate_addrs()?;
+    match keeper {
+        Keeper::Crank => cfg.crank.validate()?,
+        Keeper::Consumer => cfg.consumer.validate()?,
+        Keeper::Liquidator => cfg.liquidator.validate()?,
+        Keeper::Recorder => cfg.recorder.validate()?,
+        Keeper::Backfill => cfg.backfill.validate()?,
+        Keeper::Run => {
+            use lib::supervisor::Keeper as K;
+
+            cfg.run.validate()?;
+            for k in cfg.run.keepers() {
+                match k {
+                    K::Crank => cfg.crank.validate()?,
+                    K::Consumer => cfg.consumer.validate()?,
+                    K::Liquidator => cfg.liquidator.validate()?,
+                    K::Recorder => cfg.recorder.validate()?,
+                }
+            }
+        }
+    }
+
+    let endpoints = cfg.endpoints()?;
+
+    let payer = match &cfg.payer {
+        Some(p) => keypair::read_keypair_file(p).unwrap_or_else(|_| {
             panic!("Failed to read keypair from {}", p.to_string_lossy())
         }),
         None => match env::var("SOLANA_PAYER_KEY").ok() {
@@ -105,59 +702,95 @@ fn main() -> Result<(), lib::Error> {
         },
     };
 
-    let cluster = Cluster::Custom(rpc_url, ws_url);
-    let commitment = match command {
-        Command::Crank { .. } => CommitmentConfig::processed(),
+    let commitment = match keeper {
+        Keeper::Crank => CommitmentConfig::processed(),
         _ => CommitmentConfig::confirmed(),
     };
 
-    let app_state: &'static _ =
-        Box::leak(Box::new(lib::AppState::new(cluster, commitment, payer)));
+    let dry_run = match (cfg.dry_run, &cfg.dry_run_output) {
+        (_, Some(p)) => Some(lib::tx::DryRun::new(Some(p))?),
+        (true, None) => Some(lib::tx::DryRun::new(None)?),
+        (false, None) => None,
+    };
+
+    let rpc = lib::rpc::RpcPool::new(endpoints, commitment, cfg.rpc.clone());
+    let app_state: &'static _ = Box::leak(Box::new(lib::AppState::new(
+        rpc, commitment, payer, dry_run,
+    )));
 
     let rt = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .build()
         .unwrap();
 
-    match command {
-        Command::Liquidator {
-            worker_count,
-            worker_index,
-        } => {
-            rt.block_on(lib::liquidator::run(
-                app_state,
-                worker_count,
-                worker_index,
-            ))?;
-        }
-        Command::Crank {
-            cache_oracle_interval,
-            cache_interest_interval,
-            update_funding_interval,
-        } => rt.block_on(lib::crank::run(
-            app_state,
-            lib::crank::CrankConfig {
-                cache_oracle_interval,
-                cache_interest_interval,
-                update_funding_interval,
-            },
-        ))?,
-        Command::Consumer {
-            to_consume,
-            max_wait,
-            max_queue_length,
-        } => rt.block_on(lib::consumer::run(
-            app_state,
-            lib::consumer::ConsumerConfig {
-                to_consume,
-                max_wait,
-                max_queue_length,
-            },
-        ))?,
-        Command::Recorder => rt.block_on(lib::recorder::run(app_state))?,
-    };
+    // Keep the zo State and Cache up to date for every keeper.
+    rt.spawn(app_state.listen());
+    rt.spawn(app_state.rpc.monitor());
+
+    let health: &'static lib::heartbeat::HealthConfig =
+        Box::leak(Box::new(cfg.health.clone()));
+
+    if let Some(addr) = cfg.metrics_addr {
+        rt.spawn(async move {
+            if let Err(e) = lib::metrics::serve(addr, health).await {
+                tracing::error!("metrics server failed: {}", e);
+            }
+        });
+    }
+
+    if let Some(addr) = cfg.health_addr {
+        rt.spawn(async move {
+            if let Err(e) = lib::metrics::serve_health(addr, health).await {
+                tracing::error!("health server failed: {}", e);
+            }
+        });
+    }
+
+    let shutdown = &app_state.shutdown;
+    rt.spawn(async move {
+        if let Err(e) = lib::shutdown::listen(shutdown).await {
+            tracing::error!("failed to listen for signals: {}", e);
+        }
+    });
+
+    let timeout = cfg.shutdown.timeout;
+    let res = rt.block_on(async move {
+        let run = async move {
+            match keeper {
+                Keeper::Liquidator => {
+                    lib::liquidator::run(app_state, cfg.liquidator).await
+                }
+                Keeper::Crank => lib::crank::run(app_state, cfg.crank).await,
+                Keeper::Consumer => {
+                    lib::consumer::run(app_state, cfg.consumer).await
+                }
+                Keeper::Recorder => {
+                    lib::recorder::run(app_state, cfg.recorder).await
+                }
+                Keeper::Backfill => {
+                    lib::backfill::run(app_state, cfg.backfill).await
+                }
+                Keeper::Run => lib::supervisor::run(app_state, cfg).await,
+            }
+        };
+
+        tokio::select! {
+            r = run => r,
+            _ = shutdown.deadline(timeout) => Ok(()),
+        }
+    });
+
+    if let Some(s) = shutdown.summary() {
+        match s.is_clean() {
+            true => tracing::info!("{}", s),
+            false => tracing::warn!("{}", s),
+        }
+    }
+
+    // Don't wait for the blocking tasks left behind by the timeout.
+    rt.shutdown_background();
 
-    Ok(())
+    res
 }
 
 fn parse_seconds(s: &str) -> Result<Duration, std::num::ParseFloatError> {
@@ -0,0 +1,226 @@
+use crate::{
+    error::Error,
+    heartbeat::{self, HealthConfig},
+};
+use hyper::{
+    header::CONTENT_TYPE,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use once_cell::sync::Lazy;
+use prometheus::{
+    register_counter_vec, register_histogram, register_int_counter,
+    register_int_counter_vec, register_int_gauge, register_int_gauge_vec,
+    CounterVec, Encoder, Histogram, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, TextEncoder,
+};
+use std::{convert::Infallible, net::SocketAddr};
+use tracing::info;
+
+pub static CRANK_TRANSACTIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "zo_keeper_crank_transactions_total",
+        "Crank transactions sent, by instruction, symbol and result",
+        &["instruction", "symbol", "result"]
+    )
+    .unwrap()
+});
+
+pub static CONSUMER_QUEUE_DEPTH: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "zo_keeper_consumer_queue_depth",
+        "Events in the event queue when last observed, by market",
+        &["market"]
+    )
+    .unwrap()
+});
+
+pub static CONSUMER_EVENTS_CONSUMED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "zo_keeper_consumer_events_consumed_total",
+        "Events consumed, by market",
+        &["market"]
+    )
+    .unwrap()
+});
+
+pub static LIQUIDATOR_SCAN_SECONDS: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "zo_keeper_liquidator_scan_seconds",
+        "Time taken to scan the accounts for liquidations",
+        vec![0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0]
+    )
+    .unwrap()
+});
+
+pub static LIQUIDATOR_ACCOUNTS: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "zo_keeper_liquidator_accounts",
+        "Accounts tracked by the liquidator"
+    )
+    .unwrap()
+});
+
+pub static LIQUIDATOR_ACTIONABLE: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "zo_keeper_liquidator_actionable_accounts",
+        "Accounts found in the last scan, by action",
+        &["action"]
+    )
+    .unwrap()
+});
+
+pub static LIQUIDATOR_OUTCOMES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "zo_keeper_liquidator_outcomes_total",
+        "Liquidator jobs run, by action and result",
+        &["action", "result"]
+    )
+    .unwrap()
+});
+
+pub static LISTENER_RECONNECTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "zo_keeper_listener_reconnects_total",
+        "Websocket subscriptions dropped or failed, by listener",
+        &["listener"]
+    )
+    .unwrap()
+});
+
+pub static RECORDER_INSERTED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "zo_keeper_recorder_documents_inserted_total",
+        "Documents inserted into the store, by collection",
+        &["collection"]
+    )
+    .unwrap()
+});
+
+pub static RECORDER_BLOCK_TIME_FALLBACKS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "zo_keeper_recorder_block_time_fallbacks_total",
+        "Trades stamped with the current time, for want of a block time"
+    )
+    .unwrap()
+});
+
+pub static RPC_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "zo_keeper_rpc_requests_total",
+        "RPC requests, by endpoint, method and result",
+        &["endpoint", "method", "result"]
+    )
+    .unwrap()
+});
+
+pub static RPC_SLOT_LAG: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "zo_keeper_rpc_slot_lag",
+        "Slots each endpoint is behind the most recent one",
+        &["endpoint"]
+    )
+    .unwrap()
+});
+
+pub static RPC_THROTTLED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "zo_keeper_rpc_throttled_total",
+        "RPC requests delayed by the rate limiter, by budget and method",
+        &["budget", "method"]
+    )
+    .unwrap()
+});
+
+pub static RPC_THROTTLED_SECONDS: Lazy<CounterVec> = Lazy::new(|| {
+    register_counter_vec!(
+        "zo_keeper_rpc_throttled_seconds_total",
+        "Time RPC requests were delayed by the rate limiter, by budget",
+        &["budget"]
+    )
+    .unwrap()
+});
+
+pub static KEEPER_RESTARTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "zo_keeper_restarts_total",
+        "Keepers restarted by `run` after failing, by keeper",
+        &["keeper"]
+    )
+    .unwrap()
+});
+
+/// Serves the metrics on `addr` until the process exits, along with
+/// `/healthz` and `/readyz`, see `heartbeat::check`.
+pub async fn serve(
+    addr: SocketAddr,
+    health: &'static HealthConfig,
+) -> Result<(), Error> {
+    info!("serving metrics and health checks on http://{}", addr);
+    serve_routes(addr, true, health).await
+}
+
+/// Serves only `/healthz` and `/readyz` on `addr`, for deployments
+/// that probe health without scraping metrics.
+pub async fn serve_health(
+    addr: SocketAddr,
+    health: &'static HealthConfig,
+) -> Result<(), Error> {
+    info!("serving health checks on http://{}", addr);
+    serve_routes(addr, false, health).await
+}
+
+async fn serve_routes(
+    addr: SocketAddr,
+    metrics: bool,
+    health: &'static HealthConfig,
+) -> Result<(), Error> {
+    let make_svc = make_service_fn(move |_| async move {
+        Ok::<_, Infallible>(service_fn(move |req| async move {
+            Ok::<_, Infallible>(route(req, metrics, health))
+        }))
+    });
+
+    Server::try_bind(&addr)?.serve(make_svc).await?;
+
+    Ok(())
+}
+
+fn route(
+    req: Request<Body>,
+    metrics: bool,
+    health: &HealthConfig,
+) -> Response<Body> {
+    match (req.method(), req.uri().path()) {
+        (&Method::GET, "/metrics") if metrics => {
+            let encoder = TextEncoder::new();
+            let mut buf = Vec::new();
+            encoder.encode(&prometheus::gather(), &mut buf).unwrap();
+
+            Response::builder()
+                .header(CONTENT_TYPE, encoder.format_type())
+                .body(Body::from(buf))
+                .unwrap()
+        }
+        (&Method::GET, path @ ("/healthz" | "/readyz")) => {
+            let report = heartbeat::check(health);
+            let ok = match path {
+                "/healthz" => report.is_healthy(),
+                _ => report.is_ready(),
+            };
+            let status = match ok {
+                true => StatusCode::OK,
+                false => StatusCode::SERVICE_UNAVAILABLE,
+            };
+
+            Response::builder()
+                .status(status)
+                .body(Body::from(report.to_string()))
+                .unwrap()
+        }
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())
+            .unwrap(),
+    }
+}
@@ -1,4 +1,15 @@
-use crate::{db, error::Error, AppState};
+use crate::{
+    config::{check, seconds},
+    db,
+    error::Error,
+    heartbeat::{Heartbeat, Task},
+    ledger::{Ledger, Status},
+    limiter::Budget,
+    metrics,
+    shutdown::Work,
+    utils::{run_per_listing, wait_for_dex_market},
+    AppState,
+};
 use anchor_client::{
     solana_client::rpc_config::{
         RpcAccountInfoConfig, RpcTransactionConfig, RpcTransactionLogsConfig,
@@ -11,77 +22,388 @@ use anchor_client::{
 };
 use futures::{StreamExt, TryFutureExt};
 use jsonrpc_core_client::transports::ws;
+use serde::{Deserialize, Serialize};
 use solana_account_decoder::{UiAccountData, UiAccountEncoding};
 use solana_rpc::rpc_pubsub::RpcSolPubSubClient;
 use solana_transaction_status::UiTransactionEncoding;
 use std::{
     collections::HashMap,
-    env,
-    sync::atomic::{AtomicU64, Ordering},
+    sync::Arc,
     time::{Duration, SystemTime},
 };
+use tokio::sync::{mpsc, Semaphore};
 use tracing::{debug, error, info, trace, warn, Instrument};
 
-#[cfg(not(feature = "devnet"))]
-static DB_NAME: &str = "keeper";
-
-#[cfg(feature = "devnet")]
-static DB_NAME: &str = "keeper-devnet";
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct RecorderConfig {
+    /// URL of the store, see `db::connect` for the supported schemes.
+    pub store: String,
+    /// Maximum number of transactions processed at once.
+    pub process_concurrency: usize,
+    /// Maximum number of transactions fetched from the RPC at once,
+    /// out of those being processed.
+    pub fetch_concurrency: usize,
+    pub process_attempts: u32,
+    /// Wait before retrying a transaction, multiplied by the number
+    /// of attempts so far.
+    #[serde(with = "seconds")]
+    pub process_retry_wait: Duration,
+    /// How often the high-water slot is persisted.
+    #[serde(with = "seconds")]
+    pub high_water_interval: Duration,
+    /// How often the recent signatures are polled, to catch the
+    /// transactions the websocket missed.
+    #[serde(with = "seconds")]
+    pub poll_logs_interval: Duration,
+    #[serde(with = "seconds")]
+    pub update_funding_interval: Duration,
+    #[serde(with = "seconds")]
+    pub open_interest_interval: Duration,
+    #[serde(with = "seconds")]
+    pub mark_twap_interval: Duration,
+}
 
-pub async fn run(st: &'static AppState) -> Result<(), Error> {
-    let db = mongodb::Client::with_uri_str(env::var("DATABASE_URL")?)
-        .await?
-        .database(DB_NAME);
+impl Default for RecorderConfig {
+    fn default() -> Self {
+        Self {
+            store: String::new(),
+            process_concurrency: 32,
+            fetch_concurrency: 8,
+            process_attempts: 5,
+            process_retry_wait: Duration::from_secs(2),
+            high_water_interval: Duration::from_secs(10),
+            poll_logs_interval: Duration::from_millis(250),
+            update_funding_interval: Duration::from_secs(10),
+            open_interest_interval: Duration::from_secs(300),
+            mark_twap_interval: Duration::from_secs(30),
+        }
+    }
+}
 
-    let db: &'static _ = Box::leak(Box::new(db));
+impl RecorderConfig {
+    pub fn validate(&self) -> Result<(), Error> {
+        check(!self.store.is_empty(), "a store URL is required")?;
+        check(
+            self.process_concurrency > 0
+                && self.fetch_concurrency > 0
+                && self.process_attempts > 0,
+            "process and fetch concurrency and attempts must be positive",
+        )?;
+        check(
+            [
+                self.high_water_interval,
+                self.poll_logs_interval,
+                self.update_funding_interval,
+                self.open_interest_interval,
+                self.mark_twap_interval,
+            ]
+            .iter()
+            .all(|x| !x.is_zero()),
+            "recorder intervals must be positive",
+        )
+    }
+}
 
-    let listen_event_q_tasks =
-        st.load_dex_markets()?
-            .into_iter()
-            .map(|(symbol, dex_market)| {
-                listen_event_queue(st, db, symbol, dex_market)
-            });
+/// Number of finished signatures remembered to drop duplicates.
+const LEDGER_CAPACITY: usize = 100_000;
 
-    futures::join!(
-        listen_logs(st, db),
-        poll_logs(st, db),
-        poll_update_funding(st, db),
-        poll_open_interest(st, db),
-        poll_mark_twap(st, db),
-        futures::future::join_all(listen_event_q_tasks),
+pub async fn run(
+    st: &'static AppState,
+    cfg: RecorderConfig,
+) -> Result<(), Error> {
+    // Owned by this future rather than leaked, so that a restart by
+    // the supervisor closes the store and frees the ledger.
+    let cfg = Arc::new(cfg);
+    let db: Arc<dyn db::Store> = db::connect(&cfg.store).await?.into();
+    let _hb = Heartbeat::new(Task::DbWrite, db::HEARTBEAT);
+
+    // Resume from the persisted high-water slot, so that the
+    // transactions which landed while the recorder was down are
+    // picked up by `poll_logs`, as far as it looks back.
+    let start_slot = match db.high_water_slot().await? {
+        Some(x) => {
+            info!("resuming from slot {}", x);
+            x as u64
+        }
+        None => {
+            st.rpc
+                .call(Budget::Recorder, "getAccountInfo", |c| {
+                    c.get_account_with_commitment(
+                        &st.zo_state_pubkey,
+                        CommitmentConfig::confirmed(),
+                    )
+                })
+                .await?
+                .context
+                .slot
+        }
+    };
+
+    let (tx, rx) = mpsc::unbounded_channel();
+    let queue = Arc::new(Queue {
+        ledger: Ledger::new(LEDGER_CAPACITY, start_slot),
+        tx,
+        fetches: Semaphore::new(cfg.fetch_concurrency),
+    });
+
+    let listen_event_q_tasks = run_per_listing(
+        st,
+        || {
+            st.iter_markets()
+                .map(|m| (m.symbol.into(), m.dex_market))
+                .collect::<Vec<(String, Pubkey)>>()
+        },
+        |(symbol, key): (String, Pubkey)| {
+            let db = db.clone();
+
+            async move {
+                let mkt = wait_for_dex_market(st, key).await;
+                listen_event_queue(st, db, symbol, mkt).await
+            }
+        },
     );
 
+    // The writers return once shutting down and done writing, at
+    // which point the listeners are dropped.
+    tokio::select! {
+        _ = async {
+            futures::join!(
+                listen_logs(st, &queue),
+                poll_logs(st, &queue, start_slot, cfg.poll_logs_interval),
+                listen_event_q_tasks,
+            )
+        } => {}
+        _ = async {
+            futures::join!(
+                process_queue(st, &db, &queue, &cfg, rx),
+                poll_update_funding(st, &*db, cfg.update_funding_interval),
+                poll_open_interest(st, &*db, cfg.open_interest_interval),
+                poll_mark_twap(st, &db, cfg.mark_twap_interval),
+            )
+        } => {}
+    };
+
     Ok(())
 }
 
+/// A transaction waiting to be processed.
+struct Job {
+    signature: String,
+    slot: u64,
+    block_time: Option<i64>,
+    /// The logs, if the source had them. Otherwise they are fetched
+    /// when processing.
+    logs: Option<Vec<String>>,
+    attempts: u32,
+}
+
+/// The single entry point of every transaction the recorder sees.
+/// Signatures are checked against the ledger on the way in, so that
+/// duplicates never reach the store.
+struct Queue {
+    ledger: Ledger,
+    tx: mpsc::UnboundedSender<Job>,
+    // Bounds the `getTransaction` calls in flight, so that a burst
+    // of signatures does not flood the RPC.
+    fetches: Semaphore,
+}
+
+impl Queue {
+    fn push(&self, job: Job) {
+        match self.ledger.admit(&job.signature, job.slot) {
+            Ok(()) => {
+                // The receiver lives as long as the recorder.
+                let _ = self.tx.send(job);
+            }
+            Err(s) => {
+                trace!("skipping {} ({:?})", job.signature, s);
+            }
+        }
+    }
+}
+
 #[tracing::instrument(skip_all, level = "error")]
-async fn listen_logs(st: &'static AppState, db: &'static mongodb::Database) {
+async fn process_queue(
+    st: &'static AppState,
+    db: &Arc<dyn db::Store>,
+    queue: &Arc<Queue>,
+    cfg: &Arc<RecorderConfig>,
+    mut rx: mpsc::UnboundedReceiver<Job>,
+) {
+    let permits = Arc::new(Semaphore::new(cfg.process_concurrency));
+
+    let mut interval = tokio::time::interval(cfg.high_water_interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut high_water = queue.ledger.high_water_slot();
+
+    loop {
+        tokio::select! {
+            Some(job) = rx.recv() => {
+                let permit = permits.clone().acquire_owned().await.unwrap();
+                let work = match st.shutdown.begin(Work::DbWrite) {
+                    Some(x) => x,
+                    None => break,
+                };
+                let span = tracing::Span::current();
+                let (db, queue, cfg) = (db.clone(), queue.clone(), cfg.clone());
+
+                tokio::spawn(
+                    async move {
+                        let _permit = permit;
+                        let _work = work;
+                        process_job(st, &*db, &queue, &cfg, job).await
+                    }
+                    .instrument(span),
+                );
+            }
+            _ = interval.tick() => {
+                flush_high_water(&**db, queue, &mut high_water).await;
+            }
+            _ = st.shutdown.triggered() => break,
+        }
+    }
+
+    // Whatever is left in the channel is picked up again by
+    // `poll_logs` on restart, as it resumes from the checkpoint.
+    st.shutdown.drained().await;
+    flush_high_water(&**db, queue, &mut high_water).await;
+    info!("high-water slot {} saved", high_water);
+}
+
+/// Persists the ledger's high-water slot, if it moved past `saved`.
+async fn flush_high_water(db: &dyn db::Store, queue: &Queue, saved: &mut u64) {
+    let slot = queue.ledger.high_water_slot();
+
+    if slot <= *saved {
+        return;
+    }
+
+    match db.set_high_water_slot(slot as i64).await {
+        Ok(()) => {
+            debug!(
+                "high-water slot {}, {} pending",
+                slot,
+                queue.ledger.pending(),
+            );
+            *saved = slot;
+        }
+        Err(e) => warn!("{}", e),
+    }
+}
+
+async fn process_job(
+    st: &'static AppState,
+    db: &dyn db::Store,
+    queue: &Queue,
+    cfg: &RecorderConfig,
+    mut job: Job,
+) {
+    queue.ledger.set_status(&job.signature, Status::Processing);
+
+    let e = match try_process_job(st, db, &queue.fetches, &mut job).await {
+        Ok(()) => {
+            queue.ledger.set_status(&job.signature, Status::Done);
+            return;
+        }
+        Err(e) => e,
+    };
+
+    job.attempts += 1;
+
+    if job.attempts >= cfg.process_attempts {
+        error!(
+            "failed to process {} after {} attempts, holding the \
+             high-water slot below {} until a restart: {}",
+            job.signature, job.attempts, job.slot, e
+        );
+        queue.ledger.set_status(&job.signature, Status::Failed);
+        return;
+    }
+
+    warn!("failed to process {}, retrying: {}", job.signature, e);
+    queue
+        .ledger
+        .set_status(&job.signature, Status::Retrying(job.attempts));
+
+    // Wait outside of the processing permit, and go around the
+    // ledger since the signature is already admitted.
+    let wait = cfg.process_retry_wait * job.attempts;
+    let tx = queue.tx.clone();
+
+    tokio::spawn(async move {
+        tokio::time::sleep(wait).await;
+        let _ = tx.send(job);
+    });
+}
+
+async fn try_process_job(
+    st: &'static AppState,
+    db: &dyn db::Store,
+    fetches: &Semaphore,
+    job: &mut Job,
+) -> Result<(), Error> {
+    if job.logs.is_none() {
+        let _permit = fetches.acquire().await.unwrap();
+        let tx = fetch_logs(st, Budget::Recorder, &job.signature).await?;
+
+        // Keep the logs, so a retry does not fetch them again.
+        match tx {
+            Some(tx) => {
+                job.slot = tx.slot;
+                job.block_time = tx.block_time.or(job.block_time);
+                job.logs = Some(tx.logs);
+            }
+            None => return Ok(()),
+        }
+    }
+
+    debug!("processing: {}", job.signature);
+
+    crate::events::process(
+        st,
+        db,
+        job.logs.clone().unwrap_or_default(),
+        job.signature.clone(),
+        job.slot,
+        job.block_time,
+    )
+    .await
+}
+
+#[tracing::instrument(skip_all, level = "error")]
+async fn listen_logs(st: &'static AppState, queue: &Queue) {
     let mut interval = tokio::time::interval(Duration::from_secs(5));
     interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let hb = Heartbeat::new(Task::Listener, "logs");
 
     loop {
         // On disconnect, retry every 5s.
         interval.tick().await;
 
-        let sub =
-            match ws::try_connect::<RpcSolPubSubClient>(st.cluster.ws_url()) {
-                Ok(x) => x.await,
-                Err(e) => Err(e),
-            }
-            .and_then(|p| {
-                p.logs_subscribe(
-                    RpcTransactionLogsFilter::Mentions(vec![
-                        zo_abi::ID.to_string()
-                    ]),
-                    Some(RpcTransactionLogsConfig { commitment: None }),
-                )
-            });
+        let ws_url = st.rpc.ws_url();
+        let sub = match ws::try_connect::<RpcSolPubSubClient>(&ws_url) {
+            Ok(x) => x.await,
+            Err(e) => Err(e),
+        }
+        .and_then(|p| {
+            p.logs_subscribe(
+                RpcTransactionLogsFilter::Mentions(
+                    vec![zo_abi::ID.to_string()],
+                ),
+                Some(RpcTransactionLogsConfig { commitment: None }),
+            )
+        });
 
         let mut sub = match sub {
             Ok(x) => x,
             Err(e) => {
                 let e = Error::from(e);
                 warn!("{}", e);
+                st.rpc.ws_failed(&ws_url);
+                metrics::LISTENER_RECONNECTS
+                    .with_label_values(&["logs"])
+                    .inc();
                 continue;
             }
         };
@@ -92,38 +414,39 @@ async fn listen_logs(st: &'static AppState, db: &'static mongodb::Database) {
                 Err(_) => continue,
             };
 
+            hb.beat();
+
             if resp.value.err.is_some() {
                 continue;
             }
 
-            tokio::spawn(
-                crate::events::process(
-                    st,
-                    db,
-                    resp.value.logs,
-                    resp.value.signature,
-                )
-                .instrument(tracing::Span::current()),
-            );
+            queue.push(Job {
+                signature: resp.value.signature,
+                slot: resp.context.slot,
+                block_time: None,
+                logs: Some(resp.value.logs),
+                attempts: 0,
+            });
         }
+
+        warn!("disconnect");
+        st.rpc.ws_failed(&ws_url);
+        metrics::LISTENER_RECONNECTS
+            .with_label_values(&["logs"])
+            .inc();
     }
 }
 
 #[tracing::instrument(skip_all, level = "error")]
-async fn poll_logs(st: &'static AppState, db: &'static mongodb::Database) {
-    let mut interval = tokio::time::interval(Duration::from_millis(250));
+async fn poll_logs(
+    st: &'static AppState,
+    queue: &Queue,
+    mut last_slot: u64,
+    period: Duration,
+) {
+    let mut interval = tokio::time::interval(period);
     interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
 
-    let mut last_slot: u64 = st
-        .rpc
-        .get_account_with_commitment(
-            &st.zo_state_pubkey,
-            CommitmentConfig::confirmed(),
-        )
-        .unwrap()
-        .context
-        .slot;
-
     loop {
         interval.tick().await;
 
@@ -131,11 +454,12 @@ async fn poll_logs(st: &'static AppState, db: &'static mongodb::Database) {
         // > information, ordered from newest to oldest transaction.
         //
         // https://docs.solana.com/developing/clients/jsonrpc-api#getsignaturesforaddress
-        let sigs = tokio::task::spawn_blocking(move || {
-            st.rpc.get_signatures_for_address(&st.zo_state_pubkey)
-        })
-        .await
-        .unwrap();
+        let sigs = st
+            .rpc
+            .call(Budget::Recorder, "getSignaturesForAddress", |c| {
+                c.get_signatures_for_address(&st.zo_state_pubkey)
+            })
+            .await;
 
         let sigs = match sigs {
             Ok(x) => x
@@ -155,61 +479,66 @@ async fn poll_logs(st: &'static AppState, db: &'static mongodb::Database) {
             continue;
         }
 
-        debug!("processing {} signatures", sigs.len());
-
-        let handle = tokio::runtime::Handle::try_current().unwrap();
-        let span = tracing::Span::current();
+        debug!("found {} signatures", sigs.len());
 
         for sg in sigs {
-            let handle = handle.clone();
-            let span = span.clone();
-
             last_slot = std::cmp::max(last_slot, sg.slot);
 
-            tokio::task::spawn_blocking(move || {
-                use std::str::FromStr;
-                let _g = span.enter();
-                debug!("processing: {}", sg.signature);
-
-                // The signatures are received with "finalized" commitment,
-                // and the transaction itself is received with "confirmed".
-                // This avoid the issue where the transaction returns null
-                // sometimes even though the signature is finalized.
-                let res = st.rpc.get_transaction_with_config(
-                    &Signature::from_str(&sg.signature).unwrap(),
-                    RpcTransactionConfig {
-                        encoding: Some(UiTransactionEncoding::Base64),
-                        commitment: Some(CommitmentConfig::confirmed()),
-                    },
-                );
-
-                match res {
-                    Ok(tx) => {
-                        if let Some(ss) =
-                            tx.transaction.meta.and_then(|x| x.log_messages)
-                        {
-                            handle.block_on(
-                                crate::events::process(
-                                    st,
-                                    db,
-                                    ss,
-                                    sg.signature,
-                                )
-                                .instrument(span.clone()),
-                            );
-                        }
-                    }
-                    Err(e) => {
-                        let e = Error::from(e);
-                        warn!("{}", e);
-                        return;
-                    }
-                };
+            queue.push(Job {
+                signature: sg.signature,
+                slot: sg.slot,
+                block_time: sg.block_time,
+                logs: None,
+                attempts: 0,
             });
         }
     }
 }
 
+pub(crate) struct TransactionLogs {
+    pub slot: u64,
+    pub block_time: Option<i64>,
+    pub logs: Vec<String>,
+}
+
+/// Fetches the logs of a transaction. Returns `None` if the
+/// transaction was found but has no logs.
+pub(crate) async fn fetch_logs(
+    st: &AppState,
+    budget: Budget,
+    signature: &str,
+) -> Result<Option<TransactionLogs>, Error> {
+    use std::str::FromStr;
+
+    // The signatures are received with "finalized" commitment,
+    // and the transaction itself is received with "confirmed".
+    // This avoid the issue where the transaction returns null
+    // sometimes even though the signature is finalized.
+    let signature = Signature::from_str(signature).unwrap();
+    let tx = st
+        .rpc
+        .call(budget, "getTransaction", |c| {
+            c.get_transaction_with_config(
+                &signature,
+                RpcTransactionConfig {
+                    encoding: Some(UiTransactionEncoding::Base64),
+                    commitment: Some(CommitmentConfig::confirmed()),
+                },
+            )
+        })
+        .await?;
+
+    Ok(tx
+        .transaction
+        .meta
+        .and_then(|x| x.log_messages)
+        .map(|logs| TransactionLogs {
+            slot: tx.slot,
+            block_time: tx.block_time,
+            logs,
+        }))
+}
+
 #[tracing::instrument(
     skip_all,
     level = "error",
@@ -218,36 +547,41 @@ async fn poll_logs(st: &'static AppState, db: &'static mongodb::Database) {
 )]
 async fn listen_event_queue(
     st: &'static AppState,
-    db: &'static mongodb::Database,
+    db: Arc<dyn db::Store>,
     symbol: String,
     mkt: zo_abi::dex::ZoDexMarket,
 ) {
+    let hb = Heartbeat::new(Task::EventQueue, symbol.clone());
     let symbol = std::sync::Arc::new(symbol);
     let event_q = mkt.event_q.to_string();
     let base_decimals = mkt.coin_decimals as u8;
     let quote_decimals = 6u8;
 
     loop {
-        let sub =
-            match ws::try_connect::<RpcSolPubSubClient>(st.cluster.ws_url()) {
-                Ok(x) => x.await,
-                Err(e) => Err(e),
-            }
-            .and_then(|p| {
-                p.account_subscribe(
-                    event_q.clone(),
-                    Some(RpcAccountInfoConfig {
-                        encoding: Some(UiAccountEncoding::Base64),
-                        data_slice: None,
-                        commitment: None,
-                    }),
-                )
-            });
+        let ws_url = st.rpc.ws_url();
+        let sub = match ws::try_connect::<RpcSolPubSubClient>(&ws_url) {
+            Ok(x) => x.await,
+            Err(e) => Err(e),
+        }
+        .and_then(|p| {
+            p.account_subscribe(
+                event_q.clone(),
+                Some(RpcAccountInfoConfig {
+                    encoding: Some(UiAccountEncoding::Base64),
+                    data_slice: None,
+                    commitment: None,
+                }),
+            )
+        });
 
         let mut sub = match sub {
             Err(e) => {
                 let e = Error::from(e);
                 warn!("{}", e);
+                st.rpc.ws_failed(&ws_url);
+                metrics::LISTENER_RECONNECTS
+                    .with_label_values(&["recorder_event_queue"])
+                    .inc();
                 continue;
             }
             Ok(x) => x,
@@ -261,57 +595,89 @@ async fn listen_event_queue(
                 Err(_) => continue,
             };
 
+            hb.beat();
+
             let buf = match resp.value.data {
                 UiAccountData::Binary(b, _) => base64::decode(b).unwrap(),
                 _ => panic!(),
             };
 
+            // The trades and the sequence number checkpoint are
+            // written together, so the update is not interrupted.
+            let work = match st.shutdown.begin(Work::DbWrite) {
+                Some(x) => x,
+                None => return,
+            };
             let symbol = symbol.clone();
+            let db = db.clone();
             let span = tracing::Span::current();
+            let slot = resp.context.slot;
 
             tokio::spawn(async move {
+                let _work = work;
+                let time = block_time_or_now(st, slot).await;
+
                 db::Trade::update(
-                    db,
+                    &*db,
                     &symbol,
                     base_decimals,
                     quote_decimals,
+                    slot,
+                    time,
                     &buf,
                 )
                 .instrument(span)
                 .map_err(|e| {
-                    let e = Error::from(e);
                     warn!("{}", e);
                 })
                 .await
             });
         }
+
+        warn!("disconnect");
+        st.rpc.ws_failed(&ws_url);
+        metrics::LISTENER_RECONNECTS
+            .with_label_values(&["recorder_event_queue"])
+            .inc();
+    }
+}
+
+/// The block time of `slot`, or the current time if the node cannot
+/// tell, e.g. because the block is not confirmed yet. Only for the
+/// live trades, which are never fetched again, unlike transactions.
+async fn block_time_or_now(st: &'static AppState, slot: u64) -> i64 {
+    match st.block_time(slot).await {
+        Ok(t) => t,
+        Err(e) => {
+            warn!("no block time for slot {}, using now: {}", slot, e);
+            metrics::RECORDER_BLOCK_TIME_FALLBACKS.inc();
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs() as i64
+        }
     }
 }
 
 #[tracing::instrument(skip_all, level = "error", name = "update_funding")]
 async fn poll_update_funding(
     st: &'static AppState,
-    db: &'static mongodb::Database,
+    db: &dyn db::Store,
+    period: Duration,
 ) {
-    let mut interval = tokio::time::interval(Duration::from_secs(10));
+    let mut interval = tokio::time::interval(period);
     interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
 
     // Previous update funding time. The funding is only
     // inserted into the DB if the funding time increases.
-    let prev: HashMap<String, AtomicU64> = st
-        .load_dex_markets()
-        .unwrap()
-        .into_iter()
-        .map(|(s, _)| (s, AtomicU64::new(0)))
-        .collect();
-
-    loop {
-        interval.tick().await;
+    // Markets listed later on start from zero.
+    let mut prev: HashMap<String, u64> = HashMap::new();
 
-        let markets = match st.load_dex_markets() {
+    while st.shutdown.tick(&mut interval).await {
+        let markets = match st.load_dex_markets().await {
             Ok(x) => x,
             Err(e) => {
-                warn!("{}", Error::from(e));
+                warn!("{}", e);
                 continue;
             }
         };
@@ -319,10 +685,7 @@ async fn poll_update_funding(
         let to_update: Vec<_> = markets
             .into_iter()
             .filter(|(symbol, m)| {
-                let prev_update = prev
-                    .get(symbol)
-                    .map(|x| x.load(Ordering::Relaxed))
-                    .unwrap();
+                let prev_update = prev.get(symbol).copied().unwrap_or(0);
 
                 m.last_updated > prev_update
             })
@@ -342,8 +705,7 @@ async fn poll_update_funding(
             })
             .collect();
 
-        if let Err(e) = db::Funding::update(db, &new_entries).await {
-            let e = Error::from(e);
+        if let Err(e) = db.insert_funding(&new_entries).await {
             warn!("{}", e);
             continue;
         }
@@ -352,9 +714,7 @@ async fn poll_update_funding(
             to_update.iter().map(|(s, _)| s).cloned().collect();
 
         for (s, m) in to_update.into_iter() {
-            prev.get(&s)
-                .unwrap()
-                .store(m.last_updated, Ordering::Relaxed);
+            prev.insert(s, m.last_updated);
         }
 
         info!("inserted {}", updated.join(", "));
@@ -364,87 +724,72 @@ async fn poll_update_funding(
 #[tracing::instrument(skip_all, level = "error", name = "open_interest")]
 async fn poll_open_interest(
     st: &'static AppState,
-    db: &'static mongodb::Database,
+    db: &dyn db::Store,
+    period: Duration,
 ) {
-    let mut interval = tokio::time::interval(Duration::from_secs(300));
+    let mut interval = tokio::time::interval(period);
     interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
 
-    loop {
-        interval.tick().await;
-
+    while st.shutdown.tick(&mut interval).await {
         let time = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
             .unwrap()
             .as_secs() as i64;
 
-        let val: Result<_, Error> = tokio::task::spawn_blocking(move || {
-            let mut r = vec![0i64; st.zo_state.total_markets as usize];
-
-            crate::utils::load_program_accounts::<zo_abi::Control>(&st.rpc)?
-                .into_iter()
-                .for_each(|(_, a)| {
-                    for (i, e) in r.iter_mut().enumerate() {
-                        let x = a.open_orders_agg[i].pos_size;
-                        if x > 0 {
-                            *e += x;
-                        }
-                    }
-                });
-
-            Ok(st
-                .iter_markets()
-                .enumerate()
-                .map(|(i, m)| (m.symbol.into(), r[i]))
-                .collect::<HashMap<String, i64>>())
-        })
-        .await
-        .unwrap();
+        let controls = st
+            .rpc
+            .call(Budget::Recorder, "getProgramAccounts", |c| {
+                crate::utils::load_program_accounts::<zo_abi::Control>(c)
+            })
+            .await;
 
-        let val = match val {
+        let controls = match controls {
             Ok(x) => x,
             Err(e) => {
-                warn!("{}", e);
+                warn!("{}", Error::from(e));
                 continue;
             }
         };
 
-        if let Err(e) = db::OpenInterest::insert(db, time, val).await {
-            let e = Error::from(e);
+        let mut r = vec![0i64; st.zo_state().total_markets as usize];
+        controls.into_iter().for_each(|(_, a)| {
+            for (i, e) in r.iter_mut().enumerate() {
+                let x = a.open_orders_agg[i].pos_size;
+                if x > 0 {
+                    *e += x;
+                }
+            }
+        });
+
+        let val = st
+            .iter_markets()
+            .enumerate()
+            .map(|(i, m)| (m.symbol.into(), r[i]))
+            .collect::<HashMap<String, i64>>();
+
+        let oi = db::OpenInterest { time, values: val };
+
+        if let Err(e) = db.insert_open_interest(&oi).await {
             warn!("{}", e);
         }
     }
 }
 
 #[tracing::instrument(skip_all, level = "error", name = "oracle_twap")]
-async fn poll_mark_twap(st: &'static AppState, db: &'static mongodb::Database) {
-    let mut interval = tokio::time::interval(Duration::from_secs(30));
+async fn poll_mark_twap(
+    st: &'static AppState,
+    db: &Arc<dyn db::Store>,
+    period: Duration,
+) {
+    let mut interval = tokio::time::interval(period);
     interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
 
-    loop {
-        interval.tick().await;
-
-        let cache: Result<Result<zo_abi::Cache, _>, _> =
-            tokio::task::spawn_blocking(move || {
-                st.program().account(st.zo_cache_pubkey)
-            })
-            .await;
+    while st.shutdown.tick(&mut interval).await {
+        // Kept up to date by `AppState::listen`.
+        let cache = st.zo_cache();
 
-        let cache = match cache {
-            Err(e) => {
-                error!("task panicked: {}", e);
-                continue;
-            }
-            Ok(x) => match x {
-                Err(e) => {
-                    warn!("{}", Error::from(e));
-                    continue;
-                }
-                Ok(x) => x,
-            },
-        };
-
-        let tasks = st
-            .zo_state
+        let state = st.zo_state();
+        let tasks = state
             .perp_markets
             .iter()
             .zip(cache.marks.iter())
@@ -463,15 +808,18 @@ async fn poll_mark_twap(st: &'static AppState, db: &'static mongodb::Database) {
                     / I80F48::from_num(4);
 
                 db::MarkTwap {
-                    last_sample_start_time: c.twap.last_sample_start_time as i64,
+                    last_sample_start_time: c.twap.last_sample_start_time
+                        as i64,
                     symbol: m.symbol.into(),
                     twap: twap.to_num::<f64>(),
                 }
             })
             .map(|t| {
+                let db = db.clone();
+
                 tokio::spawn(async move {
-                    if let Err(e) = db::MarkTwap::upsert(db, &t).await {
-                        warn!("{}", Error::from(e));
+                    if let Err(e) = db.upsert_mark_twap(&t).await {
+                        warn!("{}", e);
                     }
                 })
             });
@@ -0,0 +1,477 @@
+//! A pool of RPC endpoints, each paired with its websocket. Requests go
+//! to the endpoint with the best score, from its latency and recent
+//! errors, and fail over to the next ones when an endpoint is down.
+//! Endpoints lagging behind the others are only used as a last resort.
+//! Requests are rate limited by the `limiter` beforehand, and go
+//! through the nonblocking client, so that waiting on them takes no
+//! thread.
+
+use crate::{
+    config::{check, seconds},
+    error::Error,
+    limiter::{Budget, LimitConfig, Limiter},
+    metrics,
+};
+use anchor_client::{
+    solana_sdk::{
+        commitment_config::CommitmentConfig, signature::Signature,
+        transaction::Transaction,
+    },
+    Cluster,
+};
+use serde::{Deserialize, Serialize};
+use solana_client::{
+    client_error::{ClientError, ClientErrorKind, Result as ClientResult},
+    nonblocking::rpc_client::RpcClient,
+    rpc_request::RpcError,
+};
+use std::{
+    future::Future,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tracing::{debug, warn};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct PoolConfig {
+    /// Number of endpoints each transaction is sent to.
+    pub fanout: usize,
+    /// Slots an endpoint may be behind the most recent one before it
+    /// is avoided.
+    pub max_slot_lag: u64,
+    /// How often the slot of every endpoint is polled.
+    #[serde(with = "seconds")]
+    pub slot_interval: Duration,
+    pub limit: LimitConfig,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            fanout: 2,
+            max_slot_lag: 50,
+            slot_interval: Duration::from_secs(5),
+            limit: LimitConfig::default(),
+        }
+    }
+}
+
+impl PoolConfig {
+    pub fn validate(&self) -> Result<(), Error> {
+        check(self.fanout > 0, "fanout must be positive")?;
+        check(
+            !self.slot_interval.is_zero(),
+            "slot interval must be positive",
+        )?;
+        self.limit.validate()
+    }
+}
+
+/// Weight of the latest sample in the moving averages.
+const DECAY: f64 = 0.2;
+
+/// How much a full error rate multiplies the latency by, in the score.
+const ERROR_PENALTY: f64 = 20.0;
+
+#[derive(Default)]
+struct Stats {
+    // Moving averages, of the latency in seconds and of the error rate.
+    latency: f64,
+    errors: f64,
+    slot: u64,
+}
+
+pub struct Endpoint {
+    pub url: String,
+    pub ws_url: String,
+    /// The host, for logs and metrics, since URLs often embed API keys.
+    pub name: String,
+    client: RpcClient,
+    stats: Mutex<Stats>,
+}
+
+impl Endpoint {
+    fn record(&self, latency: Duration, failed: bool) {
+        let mut s = self.stats.lock().unwrap();
+        let failed = if failed { 1.0 } else { 0.0 };
+
+        s.latency += DECAY * (latency.as_secs_f64() - s.latency);
+        s.errors += DECAY * (failed - s.errors);
+    }
+
+    fn record_error(&self) {
+        let mut s = self.stats.lock().unwrap();
+        s.errors += DECAY * (1.0 - s.errors);
+    }
+
+    async fn send_transaction(
+        &self,
+        tx: &Transaction,
+    ) -> ClientResult<Signature> {
+        let t = Instant::now();
+        let res = self.client.send_transaction(tx).await;
+        let failed = res.as_ref().err().map_or(false, is_down);
+
+        self.record(t.elapsed(), failed);
+        metrics::RPC_REQUESTS
+            .with_label_values(&[
+                &self.name,
+                "sendTransaction",
+                result_label(&res),
+            ])
+            .inc();
+
+        if let Err(e) = &res {
+            debug!("sendTransaction failed on {}: {}", self.name, e);
+        }
+
+        res
+    }
+
+    fn lag(&self, tip: u64) -> u64 {
+        tip.saturating_sub(self.stats.lock().unwrap().slot)
+    }
+
+    fn score(&self) -> f64 {
+        let s = self.stats.lock().unwrap();
+        s.latency.max(0.001) * (1.0 + ERROR_PENALTY * s.errors)
+    }
+}
+
+pub struct RpcPool {
+    endpoints: Vec<Endpoint>,
+    limiter: Limiter,
+    cfg: PoolConfig,
+}
+
+impl RpcPool {
+    /// `endpoints` are pairs of RPC and websocket URLs, in order of
+    /// preference until their scores are known.
+    pub fn new(
+        endpoints: Vec<(String, String)>,
+        commitment: CommitmentConfig,
+        cfg: PoolConfig,
+    ) -> Self {
+        assert!(!endpoints.is_empty());
+
+        let endpoints = endpoints
+            .into_iter()
+            .map(|(url, ws_url)| Endpoint {
+                name: host(&url),
+                client: RpcClient::new_with_commitment(url.clone(), commitment),
+                stats: Mutex::default(),
+                url,
+                ws_url,
+            })
+            .collect();
+
+        Self {
+            endpoints,
+            limiter: Limiter::new(&cfg.limit),
+            cfg,
+        }
+    }
+
+    /// The endpoints by preference: those keeping up first, then by
+    /// score.
+    pub fn endpoints(&self) -> Vec<&Endpoint> {
+        let tip = self.tip();
+        let mut v: Vec<_> = self
+            .endpoints
+            .iter()
+            .map(|e| (e.lag(tip) > self.cfg.max_slot_lag, e.score(), e))
+            .collect();
+
+        // The sort is stable, so ties keep the configured order.
+        v.sort_by(|a, b| (a.0, a.1).partial_cmp(&(b.0, b.1)).unwrap());
+        v.into_iter().map(|(_, _, e)| e).collect()
+    }
+
+    fn best(&self) -> &Endpoint {
+        self.endpoints()[0]
+    }
+
+    fn tip(&self) -> u64 {
+        self.endpoints
+            .iter()
+            .map(|e| e.stats.lock().unwrap().slot)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// The client of the best endpoint, for calls whose errors are not
+    /// `ClientError`s. Prefer `call`, which fails over.
+    pub fn client(&self) -> &RpcClient {
+        &self.best().client
+    }
+
+    /// The best endpoint, for the anchor client.
+    pub fn cluster(&self) -> Cluster {
+        let e = self.best();
+        Cluster::Custom(e.url.clone(), e.ws_url.clone())
+    }
+
+    /// The websocket of the best endpoint. Listeners take it anew on
+    /// every reconnect, which is how subscriptions fail over.
+    pub fn ws_url(&self) -> String {
+        self.best().ws_url.clone()
+    }
+
+    /// Counts a failed subscription against the endpoint of `ws_url`.
+    pub fn ws_failed(&self, ws_url: &str) {
+        if let Some(e) = self.endpoints.iter().find(|e| e.ws_url == ws_url) {
+            e.record_error();
+            metrics::RPC_REQUESTS
+                .with_label_values(&[&e.name, "subscribe", "failover"])
+                .inc();
+        }
+    }
+
+    /// Calls `f` on the endpoints by preference until one of them is
+    /// up, once the rate limit allows it. Errors from the request
+    /// itself, such as a missing account, are returned right away.
+    pub async fn call<'a, T, F, Fut>(
+        &'a self,
+        budget: Budget,
+        method: &str,
+        f: F,
+    ) -> ClientResult<T>
+    where
+        F: Fn(&'a RpcClient) -> Fut,
+        Fut: Future<Output = ClientResult<T>>,
+    {
+        let mut last = None;
+        self.limiter.acquire(budget, method).await;
+
+        for e in self.endpoints() {
+            let t = Instant::now();
+            let res = f(&e.client).await;
+            let failed = res.as_ref().err().map_or(false, is_down);
+
+            e.record(t.elapsed(), failed);
+            metrics::RPC_REQUESTS
+                .with_label_values(&[&e.name, method, result_label(&res)])
+                .inc();
+
+            match res {
+                Err(err) if failed => {
+                    warn!("{} failed on {}: {}", method, e.name, err);
+                    last = Some(err);
+                }
+                x => return x,
+            }
+        }
+
+        Err(last.unwrap())
+    }
+
+    /// Sends `tx` to the best `fanout` endpoints at once, so that it
+    /// lands even if some of them drop it. Returns the first success,
+    /// or else a failed preflight check, or else the last error.
+    pub async fn send_transaction(
+        &self,
+        budget: Budget,
+        tx: &Transaction,
+    ) -> ClientResult<Signature> {
+        self.limiter.acquire(budget, "sendTransaction").await;
+
+        let sends = self
+            .endpoints()
+            .into_iter()
+            .take(self.cfg.fanout)
+            .map(|e| e.send_transaction(tx));
+
+        let mut sent = None;
+        let mut rejected = None;
+        let mut last = None;
+
+        for res in futures::future::join_all(sends).await {
+            match res {
+                Ok(sg) => sent = sent.or(Some(sg)),
+                // The preflight check failed, which it would on any
+                // other endpoint too.
+                Err(err) if !is_down(&err) => rejected = Some(err),
+                Err(err) => last = Some(err),
+            }
+        }
+
+        match (sent, rejected.or(last)) {
+            (Some(sg), _) => Ok(sg),
+            (None, Some(e)) => Err(e),
+            (None, None) => unreachable!(),
+        }
+    }
+
+    /// Polls the slot of every endpoint, to tell which ones lag. This
+    /// also keeps the scores of idle endpoints current, so that one
+    /// which recovers is picked up again. The polls are not rate
+    /// limited, as each goes to a different endpoint.
+    pub async fn monitor(&'static self) {
+        let mut interval = tokio::time::interval(self.cfg.slot_interval);
+        interval
+            .set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+
+            let polls = self.endpoints.iter().map(|e| async move {
+                let t = Instant::now();
+                let res = e.client.get_slot().await;
+                e.record(t.elapsed(), res.is_err());
+
+                match res {
+                    Ok(slot) => e.stats.lock().unwrap().slot = slot,
+                    Err(err) => warn!("{} is down: {}", e.name, err),
+                }
+            });
+
+            futures::future::join_all(polls).await;
+
+            let tip = self.tip();
+            for e in &self.endpoints {
+                let lag = e.lag(tip);
+                metrics::RPC_SLOT_LAG
+                    .with_label_values(&[&e.name])
+                    .set(lag as i64);
+
+                if lag > self.cfg.max_slot_lag {
+                    warn!("{} is {} slots behind", e.name, lag);
+                }
+            }
+        }
+    }
+}
+
+/// Whether the error is the endpoint's fault, rather than the
+/// request's, so that another endpoint may do better.
+fn is_down(e: &ClientError) -> bool {
+    match e.kind() {
+        ClientErrorKind::Io(_) | ClientErrorKind::Reqwest(_) => true,
+        // Block not available, node unhealthy, and block status not
+        // available yet, see `solana_client::rpc_custom_error`.
+        ClientErrorKind::RpcError(RpcError::RpcResponseError {
+            code, ..
+        }) => matches!(code, -32004 | -32005 | -32014),
+        _ => false,
+    }
+}
+
+fn result_label<T>(res: &ClientResult<T>) -> &'static str {
+    match res {
+        Ok(_) => "success",
+        Err(e) if is_down(e) => "failover",
+        Err(_) => "error",
+    }
+}
+
+fn host(url: &str) -> String {
+    let s = url.split_once("://").map_or(url, |(_, x)| x);
+    s.split(|c| c == '/' || c == '?')
+        .next()
+        .unwrap_or(s)
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use solana_client::rpc_request::RpcResponseErrorData;
+
+    fn pool(n: usize) -> RpcPool {
+        let endpoints = (0..n)
+            .map(|i| {
+                (
+                    format!("https://rpc{}.example.com/key", i),
+                    format!("wss://rpc{}.example.com/key", i),
+                )
+            })
+            .collect();
+
+        RpcPool::new(
+            endpoints,
+            CommitmentConfig::confirmed(),
+            PoolConfig::default(),
+        )
+    }
+
+    fn set(e: &Endpoint, latency: f64, errors: f64, slot: u64) {
+        *e.stats.lock().unwrap() = Stats {
+            latency,
+            errors,
+            slot,
+        };
+    }
+
+    fn names(p: &RpcPool) -> Vec<&str> {
+        p.endpoints().into_iter().map(|e| e.name.as_str()).collect()
+    }
+
+    fn response_error(code: i64) -> ClientError {
+        RpcError::RpcResponseError {
+            code,
+            message: String::new(),
+            data: RpcResponseErrorData::Empty,
+        }
+        .into()
+    }
+
+    #[test]
+    fn test_endpoints_by_score() {
+        let p = pool(3);
+        assert_eq!(
+            names(&p),
+            ["rpc0.example.com", "rpc1.example.com", "rpc2.example.com"],
+        );
+
+        set(&p.endpoints[0], 0.3, 0.0, 100);
+        set(&p.endpoints[1], 0.1, 0.0, 100);
+        set(&p.endpoints[2], 0.2, 0.0, 100);
+        assert_eq!(
+            names(&p),
+            ["rpc1.example.com", "rpc2.example.com", "rpc0.example.com"],
+        );
+
+        // Errors outweigh latency.
+        set(&p.endpoints[1], 0.1, 0.5, 100);
+        assert_eq!(
+            names(&p),
+            ["rpc2.example.com", "rpc0.example.com", "rpc1.example.com"],
+        );
+    }
+
+    #[test]
+    fn test_endpoints_by_lag() {
+        let p = pool(3);
+        let lag = p.cfg.max_slot_lag;
+
+        set(&p.endpoints[0], 0.1, 0.0, 1000 - lag - 1);
+        set(&p.endpoints[1], 0.3, 0.0, 1000);
+        set(&p.endpoints[2], 0.2, 0.0, 1000 - lag);
+        assert_eq!(
+            names(&p),
+            ["rpc2.example.com", "rpc1.example.com", "rpc0.example.com"],
+        );
+    }
+
+    #[test]
+    fn test_is_down() {
+        let io = std::io::Error::new(std::io::ErrorKind::Other, "reset");
+        assert!(is_down(&io.into()));
+
+        for code in [-32004, -32005, -32014] {
+            assert!(is_down(&response_error(code)));
+        }
+
+        // Preflight failures and the like are the request's fault.
+        assert!(!is_down(&response_error(-32002)));
+        assert!(!is_down(&ClientErrorKind::Custom("x".into()).into()));
+    }
+
+    #[test]
+    fn test_host() {
+        assert_eq!(host("https://a.com/key?x=1"), "a.com");
+        assert_eq!(host("https://a.com?x=1"), "a.com");
+        assert_eq!(host("a.com:8899"), "a.com:8899");
+    }
+}
@@ -0,0 +1,336 @@
+//! Graceful shutdown on SIGINT or SIGTERM. Once triggered, the keepers
+//! stop scheduling new work, and wait for the work already started to
+//! finish, e.g. a liquidation whose rebalance is still to be sent, or
+//! trades whose event queue checkpoint is still to be written.
+
+use crate::{config::seconds, error::Error};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::{watch, Notify},
+    time::Interval,
+};
+use tracing::{info, warn};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ShutdownConfig {
+    /// How long to wait for the work in flight once shutting down.
+    #[serde(with = "seconds")]
+    pub timeout: Duration,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Work {
+    /// A crank or rebalance transaction being sent.
+    Transaction,
+    /// A liquidation, along with the swaps that close its position.
+    Liquidation,
+    /// Records being written to the store.
+    DbWrite,
+}
+
+impl fmt::Display for Work {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Work::Transaction => "transaction",
+            Work::Liquidation => "liquidation",
+            Work::DbWrite => "db_write",
+        })
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    triggered_at: Option<Instant>,
+    in_flight: BTreeMap<Work, usize>,
+    // What was in flight when triggered, for the summary.
+    at_trigger: BTreeMap<Work, usize>,
+}
+
+pub struct Shutdown {
+    inner: Mutex<Inner>,
+    tx: watch::Sender<bool>,
+    rx: watch::Receiver<bool>,
+    idle: Notify,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        let (tx, rx) = watch::channel(false);
+
+        Self {
+            inner: Mutex::default(),
+            tx,
+            rx,
+            idle: Notify::new(),
+        }
+    }
+}
+
+impl Shutdown {
+    /// Starts shutting down. Calls after the first do nothing.
+    pub fn trigger(&self) {
+        let mut inner = self.inner.lock().unwrap();
+
+        if inner.triggered_at.is_some() {
+            return;
+        }
+
+        inner.triggered_at = Some(Instant::now());
+        inner.at_trigger = inner.in_flight.clone();
+        drop(inner);
+
+        // The receiver lives in `self`.
+        let _ = self.tx.send(true);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.rx.borrow()
+    }
+
+    /// Resolves once shutting down.
+    pub async fn triggered(&self) {
+        let mut rx = self.rx.clone();
+
+        while !*rx.borrow() {
+            if rx.changed().await.is_err() {
+                futures::future::pending::<()>().await;
+            }
+        }
+    }
+
+    /// Waits for the next tick of `interval`. Returns `false` instead
+    /// once shutting down, for loops to stop at.
+    pub async fn tick(&self, interval: &mut Interval) -> bool {
+        tokio::select! {
+            _ = interval.tick() => !self.is_triggered(),
+            _ = self.triggered() => false,
+        }
+    }
+
+    /// Registers `work` which is about to start, and is done when the
+    /// returned guard is dropped. Returns `None` once shutting down,
+    /// in which case the work should not be started at all.
+    pub fn begin(&self, work: Work) -> Option<InFlight<'_>> {
+        let mut inner = self.inner.lock().unwrap();
+
+        if inner.triggered_at.is_some() {
+            return None;
+        }
+
+        *inner.in_flight.entry(work).or_default() += 1;
+        Some(InFlight {
+            shutdown: self,
+            work,
+        })
+    }
+
+    /// Resolves once no work is in flight.
+    pub async fn drained(&self) {
+        loop {
+            // Created before checking, so that it is not missed.
+            let idle = self.idle.notified();
+
+            if self.inner.lock().unwrap().in_flight.is_empty() {
+                return;
+            }
+
+            idle.await;
+        }
+    }
+
+    /// Resolves `timeout` after shutting down, to give up on the work
+    /// still in flight.
+    pub async fn deadline(&self, timeout: Duration) {
+        self.triggered().await;
+        tokio::time::sleep(timeout).await;
+    }
+
+    /// What happened since shutting down, if it was triggered.
+    pub fn summary(&self) -> Option<Summary> {
+        let inner = self.inner.lock().unwrap();
+
+        inner.triggered_at.map(|t| Summary {
+            elapsed: t.elapsed(),
+            waited_for: inner.at_trigger.clone(),
+            unfinished: inner.in_flight.clone(),
+        })
+    }
+}
+
+pub struct InFlight<'a> {
+    shutdown: &'a Shutdown,
+    work: Work,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        let mut inner = self.shutdown.inner.lock().unwrap();
+
+        if let Some(n) = inner.in_flight.get_mut(&self.work) {
+            *n -= 1;
+            if *n == 0 {
+                inner.in_flight.remove(&self.work);
+            }
+        }
+
+        if inner.in_flight.is_empty() {
+            self.shutdown.idle.notify_waiters();
+        }
+    }
+}
+
+pub struct Summary {
+    pub elapsed: Duration,
+    /// The work in flight when shutting down.
+    pub waited_for: BTreeMap<Work, usize>,
+    /// The work still in flight, if the timeout was reached.
+    pub unfinished: BTreeMap<Work, usize>,
+}
+
+impl Summary {
+    pub fn is_clean(&self) -> bool {
+        self.unfinished.is_empty()
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn counts(m: &BTreeMap<Work, usize>) -> String {
+            match m.is_empty() {
+                true => "nothing".to_string(),
+                false => m
+                    .iter()
+                    .map(|(w, n)| format!("{} {}", n, w))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            }
+        }
+
+        write!(
+            f,
+            "shut down after {:.1}s, waited for {}, left {} unfinished",
+            self.elapsed.as_secs_f64(),
+            counts(&self.waited_for),
+            counts(&self.unfinished),
+        )
+    }
+}
+
+/// Triggers `shutdown` on SIGINT or SIGTERM. A second signal exits
+/// right away, without waiting for the work in flight.
+pub async fn listen(shutdown: &Shutdown) -> Result<(), Error> {
+    signal().await?;
+    info!("shutting down, waiting for the work in flight");
+    shutdown.trigger();
+
+    signal().await?;
+    warn!("exiting without waiting");
+    std::process::exit(1);
+}
+
+#[cfg(unix)]
+async fn signal() -> Result<(), Error> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut term = signal(SignalKind::terminate())?;
+
+    tokio::select! {
+        r = tokio::signal::ctrl_c() => r?,
+        _ = term.recv() => {}
+    };
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn signal() -> Result<(), Error> {
+    tokio::signal::ctrl_c().await.map_err(Into::into)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_begin() {
+        let s = Shutdown::default();
+        assert!(s.summary().is_none());
+
+        let a = s.begin(Work::Liquidation);
+        let b = s.begin(Work::Liquidation);
+        let c = s.begin(Work::DbWrite);
+        assert!(a.is_some() && b.is_some() && c.is_some());
+
+        drop(c);
+        s.trigger();
+        assert!(s.is_triggered());
+        assert!(s.begin(Work::Transaction).is_none());
+
+        let summary = s.summary().unwrap();
+        assert_eq!(
+            summary.waited_for,
+            BTreeMap::from([(Work::Liquidation, 2)])
+        );
+        assert!(!summary.is_clean());
+
+        drop((a, b));
+        let summary = s.summary().unwrap();
+        assert!(summary.is_clean());
+        assert_eq!(
+            summary.waited_for,
+            BTreeMap::from([(Work::Liquidation, 2)])
+        );
+    }
+
+    #[test]
+    fn test_summary_display() {
+        let s = Shutdown::default();
+        let a = s.begin(Work::Transaction);
+        let b = s.begin(Work::DbWrite);
+        s.trigger();
+        drop(a);
+
+        let summary = s.summary().unwrap().to_string();
+        assert!(summary.contains("waited for 1 transaction, 1 db_write"));
+        assert!(summary.ends_with("left 1 db_write unfinished"));
+
+        drop(b);
+        let summary = s.summary().unwrap().to_string();
+        assert!(summary.ends_with("left nothing unfinished"));
+    }
+
+    #[tokio::test]
+    async fn test_drained() {
+        let s = Shutdown::default();
+        s.drained().await;
+
+        let a = s.begin(Work::Transaction).unwrap();
+        let drained = s.drained();
+        tokio::pin!(drained);
+
+        tokio::select! {
+            _ = &mut drained => panic!("drained with work in flight"),
+            _ = tokio::time::sleep(Duration::from_millis(10)) => {}
+        }
+
+        drop(a);
+        tokio::time::timeout(Duration::from_secs(1), drained)
+            .await
+            .unwrap();
+    }
+}
@@ -1,38 +1,64 @@
+use crate::{
+    error::Error, limiter::Budget, metrics, rpc::RpcPool, shutdown::Shutdown,
+    tx::DryRun, utils::load_buf,
+};
 use anchor_client::{
-    solana_client::rpc_client::RpcClient,
+    solana_client::rpc_config::RpcAccountInfoConfig,
     solana_sdk::{
         commitment_config::CommitmentConfig, pubkey::Pubkey,
         signer::keypair::Keypair,
     },
-    Client, Cluster, Program,
+    Client, Program,
+};
+use arc_swap::ArcSwap;
+use futures::StreamExt;
+use jsonrpc_core_client::transports::ws;
+use lru::LruCache;
+use solana_account_decoder::{UiAccountData, UiAccountEncoding};
+use solana_rpc::rpc_pubsub::RpcSolPubSubClient;
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
 };
+use tokio::sync::watch;
+use tracing::{info, warn};
 
 pub struct AppState {
     payer: Keypair,
     commitment: CommitmentConfig,
-    pub cluster: Cluster,
-    pub rpc: RpcClient,
-    pub zo_state: zo_abi::State,
-    pub zo_cache: zo_abi::Cache,
+    pub rpc: RpcPool,
+    zo_state: ArcSwap<zo_abi::State>,
+    zo_cache: ArcSwap<zo_abi::Cache>,
     pub zo_state_pubkey: Pubkey,
     pub zo_cache_pubkey: Pubkey,
     pub zo_state_signer_pubkey: Pubkey,
+    // Bumped whenever a market, collateral or oracle is listed or
+    // delisted, so that keepers can reconcile their workers.
+    listing_tx: watch::Sender<u64>,
+    listing_rx: watch::Receiver<u64>,
+    block_times: Mutex<LruCache<u64, i64>>,
+    /// If set, transactions are simulated instead of sent.
+    pub dry_run: Option<DryRun>,
+    pub shutdown: Shutdown,
 }
 
+/// Number of slots whose block time is kept around.
+const BLOCK_TIME_CACHE_SIZE: usize = 4096;
+
 impl AppState {
     pub fn new(
-        cluster: Cluster,
+        rpc: RpcPool,
         commitment: CommitmentConfig,
         payer: Keypair,
+        dry_run: Option<DryRun>,
     ) -> Self {
         let program = Client::new_with_options(
-            cluster.clone(),
+            rpc.cluster(),
             std::rc::Rc::new(Keypair::from_bytes(&payer.to_bytes()).unwrap()),
             commitment.clone(),
         )
         .program(zo_abi::ID);
 
-        let rpc = program.rpc();
         let zo_state_pubkey = zo_abi::ZO_STATE_ID;
         let zo_state: zo_abi::State = program.account(zo_state_pubkey).unwrap();
         let zo_cache: zo_abi::Cache = program.account(zo_state.cache).unwrap();
@@ -46,16 +72,22 @@ impl AppState {
             panic!("Invalid state signer nonce");
         }
 
+        let (listing_tx, listing_rx) = watch::channel(0);
+
         Self {
             payer,
             commitment: CommitmentConfig::confirmed(),
-            cluster,
             rpc,
-            zo_state,
-            zo_cache,
+            zo_state: ArcSwap::from_pointee(zo_state),
+            zo_cache: ArcSwap::from_pointee(zo_cache),
             zo_state_pubkey,
             zo_cache_pubkey: zo_state.cache,
             zo_state_signer_pubkey,
+            listing_tx,
+            listing_rx,
+            block_times: Mutex::new(LruCache::new(BLOCK_TIME_CACHE_SIZE)),
+            dry_run,
+            shutdown: Shutdown::default(),
         }
     }
 
@@ -68,9 +100,10 @@ impl AppState {
         &self.payer
     }
 
+    /// A client of the best endpoint at the time.
     pub fn client(&self) -> Client {
         Client::new_with_options(
-            self.cluster.clone(),
+            self.rpc.cluster(),
             std::rc::Rc::new(
                 Keypair::from_bytes(&self.payer.to_bytes()).unwrap(),
             ),
@@ -82,41 +115,203 @@ impl AppState {
         self.client().program(zo_abi::ID)
     }
 
-    pub fn iter_markets(
-        &self,
-    ) -> impl Iterator<Item = &zo_abi::PerpMarketInfo> {
-        self.zo_state
+    /// The latest known zo State account.
+    pub fn zo_state(&self) -> Arc<zo_abi::State> {
+        self.zo_state.load_full()
+    }
+
+    /// The latest known zo Cache account.
+    pub fn zo_cache(&self) -> Arc<zo_abi::Cache> {
+        self.zo_cache.load_full()
+    }
+
+    /// Receiver that is notified whenever the set of markets,
+    /// collaterals or oracles changes.
+    pub fn listings(&self) -> watch::Receiver<u64> {
+        self.listing_rx.clone()
+    }
+
+    /// The unix timestamp of the block at `slot`. Cached, since the
+    /// events of a transaction, and often of a block, share a slot.
+    pub async fn block_time(&self, slot: u64) -> Result<i64, Error> {
+        if let Some(t) = self.block_times.lock().unwrap().get(&slot) {
+            return Ok(*t);
+        }
+
+        let t = self
+            .rpc
+            .call(Budget::Shared, "getBlockTime", |c| c.get_block_time(slot))
+            .await?;
+        self.block_times.lock().unwrap().put(slot, t);
+        Ok(t)
+    }
+
+    pub fn iter_markets(&self) -> impl Iterator<Item = zo_abi::PerpMarketInfo> {
+        self.zo_state()
             .perp_markets
             .iter()
             .filter(|market| market.dex_market != Pubkey::default())
+            .copied()
+            .collect::<Vec<_>>()
+            .into_iter()
     }
 
-    pub fn load_dex_markets(
+    pub async fn load_dex_market(
         &self,
-    ) -> Result<Vec<(String, zo_abi::dex::ZoDexMarket)>, crate::Error> {
-        self.iter_markets()
-            .map(|m| {
-                Ok((
-                    m.symbol.into(),
-                    *zo_abi::dex::ZoDexMarket::deserialize(
-                        &self.rpc.get_account_data(&m.dex_market)?,
-                    )
-                    .unwrap(),
-                ))
+        key: &Pubkey,
+    ) -> Result<zo_abi::dex::ZoDexMarket, Error> {
+        let buf = self
+            .rpc
+            .call(Budget::Shared, "getAccountInfo", |c| {
+                c.get_account_data(key)
             })
-            .collect()
+            .await?;
+        Ok(*zo_abi::dex::ZoDexMarket::deserialize(&buf).unwrap())
     }
 
-    pub fn iter_oracles(&self) -> impl Iterator<Item = &zo_abi::OracleCache> {
-        self.zo_cache.oracles.iter().filter(|x| !x.symbol.is_nil())
+    pub async fn load_dex_markets(
+        &self,
+    ) -> Result<Vec<(String, zo_abi::dex::ZoDexMarket)>, Error> {
+        let markets = self.iter_markets().map(|m| async move {
+            let market = self.load_dex_market(&m.dex_market).await?;
+            Ok::<_, Error>((m.symbol.into(), market))
+        });
+
+        futures::future::try_join_all(markets).await
+    }
+
+    pub fn iter_oracles(&self) -> impl Iterator<Item = zo_abi::OracleCache> {
+        self.zo_cache()
+            .oracles
+            .iter()
+            .filter(|x| !x.symbol.is_nil())
+            .copied()
+            .collect::<Vec<_>>()
+            .into_iter()
     }
 
     pub fn iter_collaterals(
         &self,
-    ) -> impl Iterator<Item = &zo_abi::CollateralInfo> {
-        self.zo_state
+    ) -> impl Iterator<Item = zo_abi::CollateralInfo> {
+        self.zo_state()
             .collaterals
             .iter()
             .filter(|x| x.mint != Pubkey::default())
+            .copied()
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    /// Keeps the State and Cache up to date by subscribing to both
+    /// accounts. Runs forever, and should be spawned once per process.
+    pub async fn listen(&'static self) {
+        futures::join!(
+            self.listen_account(self.zo_state_pubkey, |buf| {
+                let x = load_buf::<zo_abi::State>(buf)?;
+                let changed =
+                    state_listings(&self.zo_state()) != state_listings(x);
+                self.zo_state.store(Arc::new(*x));
+                Some(changed)
+            }),
+            self.listen_account(self.zo_cache_pubkey, |buf| {
+                let x = load_buf::<zo_abi::Cache>(buf)?;
+                let changed =
+                    cache_listings(&self.zo_cache()) != cache_listings(x);
+                self.zo_cache.store(Arc::new(*x));
+                Some(changed)
+            }),
+        );
+    }
+
+    #[tracing::instrument(skip_all, level = "error", fields(key = %key))]
+    async fn listen_account(
+        &self,
+        key: Pubkey,
+        update: impl Fn(&[u8]) -> Option<bool>,
+    ) {
+        let mut interval = tokio::time::interval(Duration::from_secs(5));
+        interval
+            .set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            // On disconnect, retry every 5s.
+            interval.tick().await;
+
+            let ws_url = self.rpc.ws_url();
+            let sub = match ws::try_connect::<RpcSolPubSubClient>(&ws_url) {
+                Ok(x) => x.await,
+                Err(e) => Err(e),
+            }
+            .and_then(|p| {
+                p.account_subscribe(
+                    key.to_string(),
+                    Some(RpcAccountInfoConfig {
+                        encoding: Some(UiAccountEncoding::Base64),
+                        data_slice: None,
+                        commitment: Some(self.commitment),
+                    }),
+                )
+            });
+
+            let mut sub = match sub {
+                Ok(x) => x,
+                Err(e) => {
+                    warn!("{}", Error::from(e));
+                    self.rpc.ws_failed(&ws_url);
+                    metrics::LISTENER_RECONNECTS
+                        .with_label_values(&["account"])
+                        .inc();
+                    continue;
+                }
+            };
+
+            while let Some(resp) = sub.next().await {
+                let resp = match resp {
+                    Ok(x) => x,
+                    Err(e) => {
+                        warn!("{}", Error::from(e));
+                        continue;
+                    }
+                };
+
+                let buf = match resp.value.data {
+                    UiAccountData::Binary(b, _) => base64::decode(b).unwrap(),
+                    _ => panic!(),
+                };
+
+                match update(&buf) {
+                    Some(true) => {
+                        info!("listings changed at slot {}", resp.context.slot);
+                        let v = *self.listing_rx.borrow() + 1;
+                        let _ = self.listing_tx.send(v);
+                    }
+                    Some(false) => {}
+                    None => warn!("failed to decode account"),
+                }
+            }
+
+            warn!("disconnect");
+            self.rpc.ws_failed(&ws_url);
+            metrics::LISTENER_RECONNECTS
+                .with_label_values(&["account"])
+                .inc();
+        }
     }
 }
+
+/// The parts of the State that keepers spawn workers for.
+fn state_listings(x: &zo_abi::State) -> Vec<Pubkey> {
+    x.perp_markets
+        .iter()
+        .map(|m| m.dex_market)
+        .chain(x.collaterals.iter().map(|c| c.mint))
+        .collect()
+}
+
+/// The parts of the Cache that keepers spawn workers for.
+fn cache_listings(x: &zo_abi::Cache) -> Vec<(String, Pubkey)> {
+    x.oracles
+        .iter()
+        .map(|o| (o.symbol.into(), o.sources[0].key))
+        .collect()
+}
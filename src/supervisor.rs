@@ -0,0 +1,217 @@
+//! Several keepers hosted in one process, sharing the `AppState` and
+//! with it the RPC client and the State and Cache subscription. A
+//! keeper which fails or panics is restarted with a backoff, without
+//! disturbing the others.
+
+use crate::{
+    config::{check, seconds, serde_from_str, Config},
+    error::Error,
+    metrics,
+    utils::AbortOnDrop,
+    AppState,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    future::Future,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+use tracing::{error, info, warn, Instrument};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Keeper {
+    Crank,
+    Consumer,
+    Liquidator,
+    Recorder,
+}
+
+impl FromStr for Keeper {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "crank" => Ok(Self::Crank),
+            "consumer" => Ok(Self::Consumer),
+            "liquidator" => Ok(Self::Liquidator),
+            "recorder" => Ok(Self::Recorder),
+            _ => Err(format!("unknown keeper: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Keeper {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Crank => "crank",
+            Self::Consumer => "consumer",
+            Self::Liquidator => "liquidator",
+            Self::Recorder => "recorder",
+        })
+    }
+}
+
+serde_from_str!(Keeper);
+
+/// The keepers hosted by `run`, each configured by its own section.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct RunConfig {
+    pub crank: bool,
+    pub consumer: bool,
+    pub liquidator: bool,
+    pub recorder: bool,
+    /// Wait before restarting a failed keeper, doubled on every
+    /// failure in a row.
+    #[serde(with = "seconds")]
+    pub min_restart_wait: Duration,
+    /// Cap on the restart wait. A keeper which ran for longer than
+    /// this before failing starts over from `min_restart_wait`.
+    #[serde(with = "seconds")]
+    pub max_restart_wait: Duration,
+}
+
+impl Default for RunConfig {
+    fn default() -> Self {
+        Self {
+            crank: false,
+            consumer: false,
+            liquidator: false,
+            recorder: false,
+            min_restart_wait: Duration::from_secs(1),
+            max_restart_wait: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RunConfig {
+    /// Enables exactly the keepers in `keepers`.
+    pub fn enable(&mut self, keepers: &[Keeper]) {
+        self.crank = keepers.contains(&Keeper::Crank);
+        self.consumer = keepers.contains(&Keeper::Consumer);
+        self.liquidator = keepers.contains(&Keeper::Liquidator);
+        self.recorder = keepers.contains(&Keeper::Recorder);
+    }
+
+    pub fn keepers(&self) -> Vec<Keeper> {
+        [
+            (self.crank, Keeper::Crank),
+            (self.consumer, Keeper::Consumer),
+            (self.liquidator, Keeper::Liquidator),
+            (self.recorder, Keeper::Recorder),
+        ]
+        .into_iter()
+        .filter_map(|(on, k)| on.then(|| k))
+        .collect()
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        check(!self.keepers().is_empty(), "no keeper is enabled")?;
+        check(
+            !self.min_restart_wait.is_zero()
+                && self.min_restart_wait <= self.max_restart_wait,
+            "restart waits must be positive and in order",
+        )
+    }
+}
+
+/// Runs the keepers enabled in `cfg.run` until shutting down. Each
+/// keeper is supervised on its own task, so that one which blocks or
+/// panics does not hold up the others.
+pub async fn run(st: &'static AppState, cfg: Config) -> Result<(), Error> {
+    let mut tasks = Vec::new();
+
+    for keeper in cfg.run.keepers() {
+        let run_cfg = cfg.run.clone();
+        let cfg = cfg.clone();
+
+        let task = match keeper {
+            Keeper::Crank => {
+                tokio::spawn(supervise(st, keeper, run_cfg, move || {
+                    crate::crank::run(st, cfg.crank.clone())
+                }))
+            }
+            Keeper::Consumer => {
+                tokio::spawn(supervise(st, keeper, run_cfg, move || {
+                    crate::consumer::run(st, cfg.consumer.clone())
+                }))
+            }
+            Keeper::Liquidator => {
+                tokio::spawn(supervise(st, keeper, run_cfg, move || {
+                    crate::liquidator::run(st, cfg.liquidator.clone())
+                }))
+            }
+            Keeper::Recorder => {
+                tokio::spawn(supervise(st, keeper, run_cfg, move || {
+                    crate::recorder::run(st, cfg.recorder.clone())
+                }))
+            }
+        };
+
+        // Dropped when giving up on shutting down cleanly, which
+        // aborts the keepers.
+        tasks.push(AbortOnDrop(task));
+    }
+
+    for t in &mut tasks {
+        // `supervise` handles the keeper's panics, so it only fails
+        // by being aborted.
+        let _ = (&mut t.0).await;
+    }
+
+    Ok(())
+}
+
+/// Runs the keeper started by `f` on a task of its own, restarting it
+/// whenever it fails, panics or returns, until shutting down. Dropping
+/// a keeper aborts the tasks it spawned, so that a restart does not
+/// duplicate them.
+async fn supervise<F, Fut>(
+    st: &'static AppState,
+    keeper: Keeper,
+    cfg: RunConfig,
+    f: F,
+) where
+    F: Fn() -> Fut + Send + 'static,
+    Fut: Future<Output = Result<(), Error>> + Send + 'static,
+{
+    let span = tracing::info_span!("keeper", %keeper);
+    let mut wait = cfg.min_restart_wait;
+
+    loop {
+        info!("starting {}", keeper);
+        let started = Instant::now();
+        let mut task = AbortOnDrop(tokio::spawn(f().instrument(span.clone())));
+        let res = (&mut task.0).await;
+
+        if st.shutdown.is_triggered() {
+            return;
+        }
+
+        match res {
+            Ok(Ok(())) => warn!("{} stopped", keeper),
+            Ok(Err(e)) => error!("{} failed: {}", keeper, e),
+            // The message was printed by the panic hook.
+            Err(e) if e.is_panic() => error!("{} panicked", keeper),
+            Err(e) => error!("{} was cancelled: {}", keeper, e),
+        }
+
+        metrics::KEEPER_RESTARTS
+            .with_label_values(&[&keeper.to_string()])
+            .inc();
+
+        if started.elapsed() > cfg.max_restart_wait {
+            wait = cfg.min_restart_wait;
+        }
+
+        info!("restarting {} in {:?}", keeper, wait);
+
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {}
+            _ = st.shutdown.triggered() => return,
+        }
+
+        wait = std::cmp::min(wait * 2, cfg.max_restart_wait);
+    }
+}
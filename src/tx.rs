@@ -0,0 +1,695 @@
+//! Transaction building shared by every keeper. Transactions are
+//! prefixed with compute budget instructions, so that they can still
+//! land when the cluster is congested.
+
+use crate::{
+    config::{check, seconds, serde_from_str},
+    error::Error,
+    limiter::Budget,
+    rpc::RpcPool,
+    AppState,
+};
+use anchor_client::solana_sdk::{
+    commitment_config::CommitmentConfig, hash::Hash, instruction::Instruction,
+    pubkey::Pubkey, signature::Signature, signer::Signer as _,
+    transaction::Transaction,
+};
+use serde::{Deserialize, Serialize};
+use solana_client::{
+    nonblocking::rpc_client::RpcClient, rpc_request::RpcRequest,
+};
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt,
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tracing::{debug, info, warn};
+
+mod compute_budget {
+    // The compute budget instructions used below are not exposed
+    // by the pinned sdk version, so they are encoded by hand.
+    solana_program::declare_id!("ComputeBudget111111111111111111111111111111");
+}
+
+/// The maximum compute units a transaction may request.
+const MAX_COMPUTE_UNIT_LIMIT: u32 = 1_400_000;
+
+/// The base fee of a transaction, paid per signature.
+const LAMPORTS_PER_SIGNATURE: u64 = 5000;
+
+/// The maximum number of writable accounts `getRecentPrioritizationFees`
+/// accepts.
+const MAX_FEE_ACCOUNTS: usize = 128;
+
+const GET_STATUS_RETRIES: usize = 25;
+const GET_STATUS_WAIT: Duration = Duration::from_millis(2000);
+
+/// How the compute unit price of a transaction is picked.
+#[derive(Clone, Copy, Debug)]
+pub enum PriorityFee {
+    /// A fixed price, in micro-lamports per compute unit.
+    Fixed(u64),
+    /// A percentile of the prices paid in recent slots for
+    /// the accounts the transaction writes to.
+    Percentile(u8),
+}
+
+impl FromStr for PriorityFee {
+    type Err = String;
+
+    /// Parses either `fixed:<price>` or `p<percentile>`,
+    /// e.g. `fixed:5000` or `p75`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(x) = s.strip_prefix("fixed:") {
+            return x
+                .parse()
+                .map(Self::Fixed)
+                .map_err(|e| format!("invalid price: {}", e));
+        }
+
+        if let Some(x) = s.strip_prefix('p') {
+            return match x.parse() {
+                Ok(p) if p <= 100 => Ok(Self::Percentile(p)),
+                Ok(_) => Err("percentile must be at most 100".to_string()),
+                Err(e) => Err(format!("invalid percentile: {}", e)),
+            };
+        }
+
+        Err(format!(
+            "expected `fixed:<price>` or `p<percentile>`, got `{}`",
+            s
+        ))
+    }
+}
+
+impl fmt::Display for PriorityFee {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Fixed(x) => write!(f, "fixed:{}", x),
+            Self::Percentile(x) => write!(f, "p{}", x),
+        }
+    }
+}
+
+serde_from_str!(PriorityFee);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct FeeConfig {
+    /// The default of 10000 micro-lamports per unit costs 2000
+    /// lamports for a transaction using 200k units, on top of the
+    /// 5000 lamports base fee.
+    pub priority_fee: PriorityFee,
+    /// Multiplier applied to the compute unit price on each retry.
+    pub escalation: f64,
+    /// Upper bound on the compute unit price, in micro-lamports.
+    pub max_price: u64,
+    /// Fraction of the simulated compute units added as headroom.
+    pub compute_unit_margin: f64,
+    /// How long the simulated compute units of a kind of transaction
+    /// are reused for, rather than simulating every transaction.
+    #[serde(with = "seconds")]
+    pub compute_unit_ttl: Duration,
+}
+
+impl Default for FeeConfig {
+    fn default() -> Self {
+        Self {
+            priority_fee: PriorityFee::Fixed(10_000),
+            escalation: 1.5,
+            max_price: 1_000_000,
+            compute_unit_margin: 0.1,
+            compute_unit_ttl: Duration::from_secs(300),
+        }
+    }
+}
+
+impl FeeConfig {
+    pub fn validate(&self) -> Result<(), Error> {
+        check(self.escalation >= 1.0, "fee escalation must be at least 1")?;
+        check(
+            self.compute_unit_margin >= 0.0,
+            "compute unit margin must not be negative",
+        )
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PrioritizationFee {
+    #[allow(dead_code)]
+    slot: u64,
+    prioritization_fee: u64,
+}
+
+/// Simulates transactions instead of sending them, so that keepers can
+/// be tried out against a live cluster. Every transaction is logged
+/// with its simulated outcome, and appended to the output file as JSON
+/// lines if there is one.
+pub struct DryRun {
+    output: Option<Mutex<File>>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DryRunRecord<'a> {
+    time: String,
+    signature: String,
+    /// The base64 encoded message, including the compute budget
+    /// instructions.
+    message: String,
+    units_consumed: Option<u64>,
+    err: Option<String>,
+    logs: &'a [String],
+}
+
+impl DryRun {
+    pub fn new(output: Option<&Path>) -> Result<Self, Error> {
+        let output = match output {
+            Some(p) => Some(Mutex::new(
+                OpenOptions::new().create(true).append(true).open(p)?,
+            )),
+            None => None,
+        };
+
+        Ok(Self { output })
+    }
+
+    /// Simulates the transaction, returning the signature it would
+    /// have had, or the error it would have failed with.
+    async fn simulate(
+        &self,
+        rpc: &RpcPool,
+        budget: Budget,
+        tx: &Transaction,
+    ) -> Result<Signature, Error> {
+        let sg = tx.signatures[0];
+        let r = rpc
+            .call(budget, "simulateTransaction", |c| {
+                c.simulate_transaction(tx)
+            })
+            .await?
+            .value;
+        let logs = r.logs.unwrap_or_default();
+        let units = consumed_units(&logs);
+
+        match &r.err {
+            None => info!("dry run {}: ok, {:?} compute units", sg, units),
+            Some(e) => {
+                warn!("dry run {}: {}, {:?} compute units", sg, e, units)
+            }
+        }
+
+        for l in &logs {
+            debug!("{}", l);
+        }
+
+        if let Some(out) = &self.output {
+            let line = serde_json::to_string(&DryRunRecord {
+                time: chrono::Utc::now().to_rfc3339(),
+                signature: sg.to_string(),
+                message: base64::encode(tx.message_data()),
+                units_consumed: units,
+                err: r.err.as_ref().map(ToString::to_string),
+                logs: &logs,
+            })?;
+            writeln!(out.lock().unwrap(), "{}", line)?;
+        }
+
+        match r.err {
+            Some(e) => Err(e.into()),
+            None => Ok(sg),
+        }
+    }
+}
+
+/// Identifies transactions consuming about as many compute units: the
+/// program, anchor discriminator and number of accounts of each of its
+/// instructions.
+type UnitKey = Vec<(Pubkey, Vec<u8>, usize)>;
+
+fn unit_key(ixs: &[Instruction]) -> UnitKey {
+    ixs.iter()
+        .map(|ix| {
+            let tag = ix.data.iter().take(8).copied().collect();
+            (ix.program_id, tag, ix.accounts.len())
+        })
+        .collect()
+}
+
+#[derive(Clone)]
+pub struct TxBuilder {
+    st: &'static AppState,
+    cfg: FeeConfig,
+    budget: Budget,
+    // The compute unit limits found by simulation, and when. Shared
+    // by the clones of the builder.
+    units: Arc<Mutex<HashMap<UnitKey, (u32, Instant)>>>,
+}
+
+impl TxBuilder {
+    /// The RPC requests made by the builder are charged to `budget`.
+    pub fn new(st: &'static AppState, cfg: FeeConfig, budget: Budget) -> Self {
+        Self {
+            st,
+            cfg,
+            budget,
+            units: Arc::default(),
+        }
+    }
+
+    /// Builds a transaction signed by the payer, with the compute unit
+    /// limit and price instructions prepended. `attempt` is the number
+    /// of previous failed attempts, used to escalate the price.
+    pub async fn build(
+        &self,
+        ixs: &[Instruction],
+        attempt: u32,
+    ) -> Result<(Transaction, Hash), Error> {
+        let price = self.compute_unit_price(ixs, attempt).await;
+        let bh = self
+            .st
+            .rpc
+            .call(
+                self.budget,
+                "getLatestBlockhash",
+                RpcClient::get_latest_blockhash,
+            )
+            .await?;
+
+        let units = self.unit_limit(ixs, price, bh).await;
+        debug!("compute units: {}, price: {}", units, price);
+
+        Ok((self.sign(ixs, units, price, bh), bh))
+    }
+
+    /// Builds and sends a transaction, returning its signature and the
+    /// blockhash it was built with. Preflight checks are performed.
+    /// In dry-run mode, the transaction is only simulated.
+    pub async fn send(
+        &self,
+        ixs: &[Instruction],
+        attempt: u32,
+    ) -> Result<(Signature, Hash), Error> {
+        let (tx, bh) = self.build(ixs, attempt).await?;
+
+        let res = match &self.st.dry_run {
+            Some(d) => d.simulate(&self.st.rpc, self.budget, &tx).await,
+            None => self
+                .st
+                .rpc
+                .send_transaction(self.budget, &tx)
+                .await
+                .map_err(Into::into),
+        };
+
+        // The cached limit may be too low for this one, e.g. if the
+        // failure is running out of compute units.
+        if res.is_err() {
+            self.units.lock().unwrap().remove(&unit_key(ixs));
+        }
+
+        Ok((res?, bh))
+    }
+
+    /// The fee a transaction would pay on its first attempt, in
+    /// lamports, with the compute unit limit it would be built with.
+    pub async fn estimate_fee(&self, ixs: &[Instruction]) -> u64 {
+        let price = self.compute_unit_price(ixs, 0).await;
+
+        if let Some(units) = self.cached_units(ixs) {
+            return fee(price, units);
+        }
+
+        let bh = self
+            .st
+            .rpc
+            .call(
+                self.budget,
+                "getLatestBlockhash",
+                RpcClient::get_latest_blockhash,
+            )
+            .await;
+
+        let units = match bh {
+            Ok(bh) => self.unit_limit(ixs, price, bh).await,
+            Err(e) => {
+                warn!("failed to estimate compute units: {}", Error::from(e));
+                MAX_COMPUTE_UNIT_LIMIT
+            }
+        };
+
+        fee(price, units)
+    }
+
+    /// Sends a transaction and waits until it is confirmed, or until
+    /// its blockhash expires.
+    pub async fn send_and_confirm(
+        &self,
+        ixs: &[Instruction],
+        attempt: u32,
+    ) -> Result<Signature, Error> {
+        // This emulates the same logic as the solana client's
+        // `send_and_confirm_transaction` function, but does not
+        // retry `usize::MAX` times as that ends up spawning too
+        // many processes.
+        let (sg, bh) = self.send(ixs, attempt).await?;
+        let rpc = &self.st.rpc;
+
+        // There is nothing to confirm.
+        if self.st.dry_run.is_some() {
+            return Ok(sg);
+        }
+
+        for _ in 0..GET_STATUS_RETRIES {
+            let status = rpc
+                .call(self.budget, "getSignatureStatuses", |c| {
+                    c.get_signature_status(&sg)
+                })
+                .await?;
+
+            match status {
+                Some(Ok(_)) => return Ok(sg),
+                Some(Err(e)) => return Err(e.into()),
+                None => {
+                    let valid = rpc
+                        .call(self.budget, "isBlockhashValid", |c| {
+                            c.is_blockhash_valid(
+                                &bh,
+                                CommitmentConfig::processed(),
+                            )
+                        })
+                        .await?;
+
+                    if !valid {
+                        break;
+                    }
+
+                    tokio::time::sleep(GET_STATUS_WAIT).await;
+                }
+            }
+        }
+
+        Err(Error::ConfirmationTimeout(sg))
+    }
+
+    /// Like `send_and_confirm`, but resends with an escalated price
+    /// whenever the transaction fails to confirm in time.
+    pub async fn send_with_retries(
+        &self,
+        ixs: &[Instruction],
+        retries: u32,
+    ) -> Result<Signature, Error> {
+        let mut attempt = 0;
+
+        loop {
+            match self.send_and_confirm(ixs, attempt).await {
+                Err(Error::ConfirmationTimeout(sg))
+                    if attempt + 1 < retries =>
+                {
+                    warn!("{} not confirmed, retrying", sg);
+                    attempt += 1;
+                }
+                x => return x,
+            }
+        }
+    }
+
+    fn sign(
+        &self,
+        ixs: &[Instruction],
+        units: u32,
+        price: u64,
+        bh: Hash,
+    ) -> Transaction {
+        let payer = self.st.payer_key();
+        let ixs: Vec<_> =
+            [set_compute_unit_limit(units), set_compute_unit_price(price)]
+                .into_iter()
+                .chain(ixs.iter().cloned())
+                .collect();
+
+        Transaction::new_signed_with_payer(
+            &ixs,
+            Some(&payer.pubkey()),
+            // NOTE: Keepers never need a signer other than the payer.
+            &[payer],
+            bh,
+        )
+    }
+
+    /// The compute unit limit of a transaction: the units it is
+    /// simulated to consume plus the configured margin, or the
+    /// maximum if the simulation fails. Transactions of the same kind
+    /// reuse the limit for `compute_unit_ttl`.
+    async fn unit_limit(
+        &self,
+        ixs: &[Instruction],
+        price: u64,
+        bh: Hash,
+    ) -> u32 {
+        if let Some(x) = self.cached_units(ixs) {
+            return x;
+        }
+
+        match self.simulate_units(ixs, price, bh).await {
+            Some(x) => {
+                let x = x as f64 * (1.0 + self.cfg.compute_unit_margin);
+                let x = (x.ceil() as u32).min(MAX_COMPUTE_UNIT_LIMIT);
+
+                self.units
+                    .lock()
+                    .unwrap()
+                    .insert(unit_key(ixs), (x, Instant::now()));
+                x
+            }
+            None => MAX_COMPUTE_UNIT_LIMIT,
+        }
+    }
+
+    fn cached_units(&self, ixs: &[Instruction]) -> Option<u32> {
+        let mut units = self.units.lock().unwrap();
+        let key = unit_key(ixs);
+
+        match units.get(&key) {
+            Some((x, t)) if t.elapsed() < self.cfg.compute_unit_ttl => Some(*x),
+            Some(_) => {
+                units.remove(&key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Simulates the transaction to find the compute units it consumes.
+    /// Returns `None` if the simulation fails, in which case the
+    /// transaction is sent with the maximum limit so that any error
+    /// surfaces from the preflight check instead.
+    async fn simulate_units(
+        &self,
+        ixs: &[Instruction],
+        price: u64,
+        bh: Hash,
+    ) -> Option<u64> {
+        let tx = self.sign(ixs, MAX_COMPUTE_UNIT_LIMIT, price, bh);
+
+        let res = self
+            .st
+            .rpc
+            .call(self.budget, "simulateTransaction", |c| {
+                c.simulate_transaction(&tx)
+            })
+            .await;
+
+        match res {
+            Ok(r) if r.value.err.is_none() => {
+                consumed_units(r.value.logs.as_deref().unwrap_or_default())
+            }
+            Ok(r) => {
+                debug!("simulation failed: {:?}", r.value.err);
+                None
+            }
+            Err(e) => {
+                warn!("simulation failed: {}", Error::from(e));
+                None
+            }
+        }
+    }
+
+    async fn compute_unit_price(
+        &self,
+        ixs: &[Instruction],
+        attempt: u32,
+    ) -> u64 {
+        let base = match self.cfg.priority_fee {
+            PriorityFee::Fixed(x) => x,
+            PriorityFee::Percentile(p) => {
+                match self.recent_fee_percentile(ixs, p).await {
+                    Ok(x) => x,
+                    Err(e) => {
+                        warn!("failed to fetch recent fees: {}", e);
+                        0
+                    }
+                }
+            }
+        };
+
+        let price = base as f64 * self.cfg.escalation.powi(attempt as i32);
+        (price as u64).min(self.cfg.max_price)
+    }
+
+    async fn recent_fee_percentile(
+        &self,
+        ixs: &[Instruction],
+        percentile: u8,
+    ) -> Result<u64, Error> {
+        let accounts: BTreeSet<String> = ixs
+            .iter()
+            .flat_map(|ix| ix.accounts.iter())
+            .filter(|m| m.is_writable)
+            .map(|m| m.pubkey.to_string())
+            .take(MAX_FEE_ACCOUNTS)
+            .collect();
+
+        let mut fees: Vec<u64> = self
+            .st
+            .rpc
+            .call(self.budget, "getRecentPrioritizationFees", |c| {
+                c.send::<Vec<PrioritizationFee>>(
+                    RpcRequest::Custom {
+                        method: "getRecentPrioritizationFees",
+                    },
+                    serde_json::json!([accounts]),
+                )
+            })
+            .await?
+            .into_iter()
+            .map(|x| x.prioritization_fee)
+            .collect();
+
+        if fees.is_empty() {
+            return Ok(0);
+        }
+
+        fees.sort_unstable();
+        let i = (fees.len() - 1) * percentile as usize / 100;
+        Ok(fees[i])
+    }
+}
+
+/// The fee of a transaction with one signature, in lamports.
+fn fee(price: u64, units: u32) -> u64 {
+    LAMPORTS_PER_SIGNATURE + price.saturating_mul(units as u64) / 1_000_000
+}
+
+fn set_compute_unit_limit(units: u32) -> Instruction {
+    let mut data = vec![2u8];
+    data.extend_from_slice(&units.to_le_bytes());
+    Instruction::new_with_bytes(compute_budget::ID, &data, vec![])
+}
+
+fn set_compute_unit_price(micro_lamports: u64) -> Instruction {
+    let mut data = vec![3u8];
+    data.extend_from_slice(&micro_lamports.to_le_bytes());
+    Instruction::new_with_bytes(compute_budget::ID, &data, vec![])
+}
+
+/// Sums the compute units consumed by the top level instructions,
+/// from lines such as `Program <id> consumed 1234 of 200000 compute
+/// units`. Inner instructions are already included in the total of
+/// their caller, so only lines at invocation depth 1 are counted.
+fn consumed_units(logs: &[String]) -> Option<u64> {
+    let mut depth = 0usize;
+    let mut total = None;
+
+    for l in logs {
+        let mut it = l.split_whitespace();
+
+        if it.next() != Some("Program") {
+            continue;
+        }
+
+        let _program = it.next();
+
+        match (it.next(), it.next()) {
+            (Some("invoke"), Some(d)) => {
+                depth = d
+                    .trim_matches(|c| c == '[' || c == ']')
+                    .parse()
+                    .unwrap_or(depth + 1);
+            }
+            (Some("success"), _) | (Some("failed:"), _) => {
+                depth = depth.saturating_sub(1);
+            }
+            (Some("consumed"), Some(n)) if depth == 1 => {
+                if let Ok(n) = n.parse::<u64>() {
+                    total = Some(total.unwrap_or(0) + n);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    total
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn logs(xs: &[&str]) -> Vec<String> {
+        xs.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn test_consumed_units() {
+        let l = logs(&[
+            "Program ComputeBudget111111111111111111111111111111 invoke [1]",
+            "Program ComputeBudget111111111111111111111111111111 success",
+            "Program Zo1 invoke [1]",
+            "Program log: Instruction: Liquidate",
+            "Program Dex1 invoke [2]",
+            "Program Dex1 consumed 5000 of 180000 compute units",
+            "Program Dex1 success",
+            "Program Zo1 consumed 20000 of 200000 compute units",
+            "Program Zo1 success",
+            "Program Zo1 invoke [1]",
+            "Program Zo1 consumed 1234 of 180000 compute units",
+            "Program Zo1 failed: custom program error: 0x1",
+        ]);
+
+        // The inner 5000 are part of the outer 20000.
+        assert_eq!(consumed_units(&l), Some(21234));
+        assert_eq!(consumed_units(&l[..2]), None);
+        assert_eq!(consumed_units(&[]), None);
+    }
+
+    #[test]
+    fn test_priority_fee_from_str() {
+        assert!(matches!(
+            "fixed:5000".parse::<PriorityFee>(),
+            Ok(PriorityFee::Fixed(5000))
+        ));
+        assert!(matches!(
+            "p75".parse::<PriorityFee>(),
+            Ok(PriorityFee::Percentile(75))
+        ));
+        assert!(matches!(
+            "p100".parse::<PriorityFee>(),
+            Ok(PriorityFee::Percentile(100))
+        ));
+
+        for s in ["p101", "p", "fixed:", "fixed:-1", "75", ""] {
+            assert!(s.parse::<PriorityFee>().is_err(), "{}", s);
+        }
+
+        for s in ["fixed:5000", "p75"] {
+            assert_eq!(s.parse::<PriorityFee>().unwrap().to_string(), s);
+        }
+    }
+}
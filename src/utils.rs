@@ -1,8 +1,9 @@
-use crate::Error;
+use crate::AppState;
 use anchor_client::{
-    anchor_lang::{prelude::AccountLoader, Owner, ZeroCopy},
+    anchor_lang::{prelude::AccountLoader, Discriminator, Owner, ZeroCopy},
     solana_client::{
-        rpc_client::RpcClient,
+        client_error::Result as ClientResult,
+        nonblocking::rpc_client::RpcClient,
         rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig},
         rpc_filter::{Memcmp, MemcmpEncodedBytes, RpcFilterType},
     },
@@ -11,7 +12,14 @@ use anchor_client::{
         commitment_config::CommitmentConfig, pubkey::Pubkey,
     },
 };
+use bytemuck::Pod;
 use solana_account_decoder::UiAccountEncoding;
+use std::{
+    collections::HashMap, fmt::Debug, future::Future, hash::Hash,
+    time::Duration,
+};
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
 
 fn load_account<'a, T>(key: &'a Pubkey, account: &'a mut Account) -> T
 where
@@ -24,9 +32,18 @@ where
     account
 }
 
-pub fn load_program_accounts<T>(
+pub fn load_buf<T: Pod + Discriminator>(b: &[u8]) -> Option<&T> {
+    match b.len() == 8 + std::mem::size_of::<T>()
+        && b[..8] == T::discriminator()
+    {
+        false => None,
+        true => bytemuck::try_from_bytes(&b[8..]).ok(),
+    }
+}
+
+pub async fn load_program_accounts<T>(
     client: &RpcClient,
-) -> Result<Vec<(Pubkey, T)>, Error>
+) -> ClientResult<Vec<(Pubkey, T)>>
 where
     T: ZeroCopy + Owner,
 {
@@ -49,10 +66,81 @@ where
 
     client
         .get_program_accounts_with_config(&zo_abi::ID, config)
+        .await
         .map(|v| {
             v.into_iter()
                 .map(|(k, mut a)| (k, load_account::<T>(&k, &mut a)))
                 .collect()
         })
-        .map_err(Into::into)
+}
+
+/// Aborts the task when dropped, so that child tasks do not outlive
+/// the worker that spawned them.
+pub struct AbortOnDrop<T>(pub JoinHandle<T>);
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Runs one task per key returned by `keys`, re-evaluating the keys
+/// whenever the listings in the zo State or Cache change. Tasks are
+/// spawned for new keys and aborted for keys that are gone, while
+/// tasks for unchanged keys are left running.
+pub async fn run_per_listing<K, Ks, F, Fut>(
+    st: &'static AppState,
+    mut keys: Ks,
+    mut spawn: F,
+) where
+    K: Eq + Hash + Clone + Debug,
+    Ks: FnMut() -> Vec<K>,
+    F: FnMut(K) -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let mut listings = st.listings();
+    let mut tasks: HashMap<K, AbortOnDrop<()>> = HashMap::new();
+
+    loop {
+        let keys = keys();
+
+        tasks.retain(|k, _| {
+            let keep = keys.contains(k);
+            if !keep {
+                info!("stopping worker for {:?}", k);
+            }
+            keep
+        });
+
+        for k in keys {
+            if !tasks.contains_key(&k) {
+                info!("starting worker for {:?}", k);
+                let h = tokio::spawn(spawn(k.clone()));
+                tasks.insert(k, AbortOnDrop(h));
+            }
+        }
+
+        if listings.changed().await.is_err() {
+            // The sender lives in the leaked `AppState`, so this
+            // should never happen. Keep the workers running anyway.
+            futures::future::pending::<()>().await;
+        }
+    }
+}
+
+/// Loads a dex market, retrying until it succeeds. Used by workers
+/// which cannot do anything useful until the market is known.
+pub async fn wait_for_dex_market(
+    st: &'static AppState,
+    key: Pubkey,
+) -> zo_abi::dex::ZoDexMarket {
+    loop {
+        match st.load_dex_market(&key).await {
+            Ok(x) => return x,
+            Err(e) => {
+                warn!("failed to load dex market {}: {}", key, e);
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+        }
+    }
 }